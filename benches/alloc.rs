@@ -0,0 +1,145 @@
+//! Allocation benches, counting the allocations of flushing frames through the outgoing guard
+//! with and without recycling the frames vectors. The allocations per second are the counted
+//! allocations divided by the time of the same flush.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use criterion::async_executor::FuturesExecutor;
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use raknet_rs::micro_bench::guard::FlushOpts;
+use raknet_rs::micro_bench::packet::Mix;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts the allocations and reallocations of the bench process
+struct CountingAllocator;
+
+// Safety: all calls are forwarded to the system allocator
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Measures the number of allocations instead of the wall time
+struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = u64;
+    type Value = u64;
+
+    fn start(&self) -> Self::Intermediate {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, i: Self::Intermediate) -> Self::Value {
+        ALLOCATIONS.load(Ordering::Relaxed) - i
+    }
+
+    fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
+        v1 + v2
+    }
+
+    fn zero(&self) -> Self::Value {
+        0
+    }
+
+    fn to_f64(&self, value: &Self::Value) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &AllocationsFormatter
+    }
+}
+
+struct AllocationsFormatter;
+
+impl ValueFormatter for AllocationsFormatter {
+    fn scale_values(&self, _: f64, _: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        let (n, unit) = match *throughput {
+            Throughput::Elements(n) => (n, "allocs/elem"),
+            Throughput::Bytes(n) | Throughput::BytesDecimal(n) => (n, "allocs/byte"),
+        };
+        for value in values {
+            *value /= n as f64;
+        }
+        unit
+    }
+
+    fn scale_for_machines(&self, _: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+fn flush_opts() -> [(&'static str, FlushOpts); 2] {
+    let opts = FlushOpts::new(Mix::TinyUnreliable, 1000, 1400);
+    [("pooled", opts.clone()), ("unpooled", opts.without_pool())]
+}
+
+fn allocations_benchmark(c: &mut Criterion<Allocations>) {
+    let mut group = c.benchmark_group("alloc");
+
+    for (name, opts) in flush_opts() {
+        group.throughput(Throughput::Elements(opts.elements()));
+        group.bench_function(format!("flush_allocs_{name}"), |bencher| {
+            bencher.to_async(FuturesExecutor).iter_batched(
+                || opts.clone(),
+                |o| o.run_bench(),
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+fn time_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("alloc");
+
+    for (name, opts) in flush_opts() {
+        group.throughput(Throughput::Elements(opts.elements()));
+        group.bench_function(format!("flush_time_{name}"), |bencher| {
+            bencher.to_async(FuturesExecutor).iter_batched(
+                || opts.clone(),
+                |o| o.run_bench(),
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group! {
+    name = allocations;
+    // the counts barely vary, which the plots can not draw
+    config = Criterion::default().with_measurement(Allocations).without_plots();
+    targets = allocations_benchmark
+}
+criterion_group!(time, time_benchmark);
+criterion_main!(allocations, time);
@@ -2,7 +2,7 @@
 
 use std::iter::repeat;
 
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use criterion::async_executor::FuturesExecutor;
 use criterion::measurement::WallTime;
 use criterion::{
@@ -10,6 +10,8 @@ use criterion::{
 };
 use raknet_rs::micro_bench;
 use raknet_rs::micro_bench::codec::BenchOpts;
+use raknet_rs::micro_bench::guard::FlushOpts;
+use raknet_rs::micro_bench::packet::{AckOpts, Mix, PacketOpts};
 
 pub fn codec_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("codec");
@@ -93,5 +95,65 @@ pub fn codec_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, codec_benchmark);
+const MIXES: [Mix; 3] = [Mix::TinyUnreliable, Mix::FragmentedReliable, Mix::Mixed];
+
+pub fn packet_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packet");
+
+    for mix in MIXES {
+        for checksum in [false, true] {
+            let opts = PacketOpts::new(mix, 1000, 1400, checksum);
+            group.throughput(Throughput::Bytes(opts.bytes()));
+            group.bench_function(format!("encode_{mix:?}_checksum-{checksum}"), |bencher| {
+                let mut buf = BytesMut::with_capacity(1400);
+                bencher.iter(|| black_box(opts.encode(&mut buf)));
+            });
+            group.bench_function(format!("decode_{mix:?}_checksum-{checksum}"), |bencher| {
+                bencher.iter_batched(
+                    || opts.datagrams(),
+                    |datagrams| black_box(opts.decode(datagrams)),
+                    BatchSize::SmallInput,
+                );
+            });
+        }
+    }
+
+    // the consecutive sequence numbers compress into ranges, the sparse ones into singles
+    for (name, run) in [("ranges", 64), ("singles", 1)] {
+        let opts = AckOpts::new(8192, run, 1400);
+        group.throughput(Throughput::Elements(opts.elements()));
+        group.bench_function(format!("ack_extend_from_{name}"), |bencher| {
+            bencher.iter(|| black_box(opts.extend()));
+        });
+        group.bench_function(format!("ack_read_{name}"), |bencher| {
+            bencher.iter_batched(
+                || opts.datagrams(),
+                |datagrams| black_box(AckOpts::read(datagrams)),
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+pub fn guard_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("guard");
+
+    for mix in MIXES {
+        let opts = FlushOpts::new(mix, 1000, 1400);
+        group.throughput(Throughput::Elements(opts.elements()));
+        group.bench_function(format!("flush_{mix:?}"), |bencher| {
+            bencher.to_async(FuturesExecutor).iter_batched(
+                || opts.clone(),
+                |o| o.run_bench(),
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, codec_benchmark, packet_benchmark, guard_benchmark);
 criterion_main!(benches);
@@ -0,0 +1,93 @@
+//! Socket benches, sending and receiving the datagrams one by one against in batches over the
+//! loopback
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use raknet_rs::micro_bench::socket::BatchOpts;
+use tokio::net::UdpSocket;
+use tokio::runtime::Runtime;
+
+const BATCHES: [usize; 4] = [1, 8, 32, 64];
+
+fn send_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("socket");
+    let rt = Runtime::new().unwrap();
+    let (socket, peer) = rt.block_on(async {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        // never read, the kernel drops the datagrams once its receive buffer is full
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        (socket, peer)
+    });
+    let target = peer.local_addr().unwrap();
+
+    for batch in BATCHES {
+        let opts = BatchOpts {
+            datagrams: 1024,
+            size: 200,
+            batch,
+        };
+        group.throughput(Throughput::Elements(opts.elements()));
+        group.bench_function(format!("send_batch-{batch}"), |bencher| {
+            bencher.to_async(&rt).iter_batched(
+                || opts,
+                |o| {
+                    let socket = Arc::clone(&socket);
+                    async move { o.send(&socket, target).await.unwrap() }
+                },
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+fn recv_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("socket");
+    // the receiver runs on the current thread, so the wall time is its CPU time
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (sender, receiver) = rt.block_on(async {
+        let sender = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let receiver = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        (sender, receiver)
+    });
+    let target = receiver.local_addr().unwrap();
+
+    for batch in BATCHES {
+        // few enough to be queued in the default receive buffer without drops
+        let opts = BatchOpts {
+            datagrams: 64,
+            size: 200,
+            batch,
+        };
+        let fill = BatchOpts { batch: 64, ..opts };
+        group.throughput(Throughput::Elements(opts.elements()));
+        group.bench_function(format!("recv_batch-{batch}"), |bencher| {
+            bencher.to_async(&rt).iter_custom(|iters| {
+                let sender = Arc::clone(&sender);
+                let receiver = Arc::clone(&receiver);
+                async move {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        // queue the datagrams first, only the receiving is measured
+                        fill.send(&sender, target).await.unwrap();
+                        let start = Instant::now();
+                        opts.recv(&receiver).await.unwrap();
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                }
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, send_benchmark, recv_benchmark);
+criterion_main!(benches);
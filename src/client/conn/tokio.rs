@@ -1,8 +1,9 @@
 use std::io;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 
 use bytes::Bytes;
+use futures::future::Either;
 use futures::{Sink, Stream, StreamExt};
 use log::{error, trace};
 use tokio::net::UdpSocket as TokioUdpSocket;
@@ -12,22 +13,39 @@ use crate::client::handler::offline::OfflineHandler;
 use crate::client::handler::online::HandleOnline;
 use crate::codec::frame::Framed;
 use crate::codec::{Decoded, Encoded};
+use crate::errors::ConnectError;
 use crate::guard::HandleOutgoing;
 use crate::link::{Route, TransferLink};
-use crate::opts::Ping;
+use crate::opts::{
+    AckNow, ConnInfo, ConnStats, ConnectionInfo, LastFrameMeta, Ping, SendBufCap, TraceInfo,
+    TrySend,
+};
 use crate::state::{IncomingStateManage, OutgoingStateManage};
-use crate::utils::Logged;
-use crate::Message;
+use crate::utils::{
+    online_span, AutoFlushed, DisconnectOnDrop, Logged, TraceStreamExt, DEFAULT_DRIVE_INTERVAL,
+};
+use crate::{ConnId, Message};
 
 impl ConnectTo for TokioUdpSocket {
     async fn connect_to(
         self,
         addrs: impl ToSocketAddrs,
         config: super::Config,
-    ) -> io::Result<(
-        impl Stream<Item = Bytes>,
-        impl Sink<Message, Error = io::Error> + Ping,
-    )> {
+    ) -> Result<
+        (
+            impl Stream<Item = Bytes>
+                + TraceInfo
+                + ConnInfo
+                + ConnStats
+                + SendBufCap
+                + AckNow
+                + LastFrameMeta,
+            impl Sink<Message, Error = io::Error> + Ping + TrySend,
+        ),
+        ConnectError,
+    > {
+        config.validate()?;
+        config.socket_options.apply(&self)?;
         let socket = Arc::new(self);
         let mut lookups = addrs.to_socket_addrs()?;
         let addr = loop {
@@ -37,45 +55,147 @@ impl ConnectTo for TokioUdpSocket {
                 }
                 continue;
             }
-            return Err(io::Error::new(
-                io::ErrorKind::AddrNotAvailable,
-                "invalid address",
-            ));
+            return Err(ConnectError::NoAddress);
         };
+        connect(socket, addr, config).await
+    }
+}
 
-        let (mut incoming, peer) = OfflineHandler::new(
-            Framed::new(Arc::clone(&socket), config.mtu as usize), // TODO: discover MTU
-            addr,
-            config.offline_config(),
-        )
-        .await?;
-        let role = config.client_role();
+/// Connect through a shared socket without connecting it to the server, the datagrams are sent by
+/// `send_to` and the ones received from other addresses are dropped by the connection after
+/// passing the tap. The caller could keep a clone of the socket to talk to other peers with
+/// `send_to`, e.g. to send the unconnected pings for the discovery, and observe the replies by
+/// [`Config::tap`](super::Config::tap). However, the caller must not receive from the socket
+/// while the connection is alive, since the datagrams are taken by whoever receives first.
+///
+/// The first address of the same family as the local address of the socket is used.
+impl ConnectTo for Arc<TokioUdpSocket> {
+    async fn connect_to(
+        self,
+        addrs: impl ToSocketAddrs,
+        config: super::Config,
+    ) -> Result<
+        (
+            impl Stream<Item = Bytes>
+                + TraceInfo
+                + ConnInfo
+                + ConnStats
+                + SendBufCap
+                + AckNow
+                + LastFrameMeta,
+            impl Sink<Message, Error = io::Error> + Ping + TrySend,
+        ),
+        ConnectError,
+    > {
+        config.validate()?;
+        config.socket_options.apply(&self)?;
+        let local = self.local_addr()?;
+        let addr = addrs
+            .to_socket_addrs()?
+            .find(|addr| addr.is_ipv4() == local.is_ipv4())
+            .ok_or(ConnectError::NoAddress)?;
+        connect(self, addr, config).await
+    }
+}
 
-        let link = TransferLink::new_arc(role, peer);
-        let dst = Framed::new(Arc::clone(&socket), peer.mtu as usize)
-            .handle_outgoing(Arc::clone(&link), config.send_buf_cap, peer, role)
-            .frame_encoded(peer.mtu, config.codec_config(), Arc::clone(&link))
-            .manage_outgoing_state(None);
+async fn connect(
+    socket: Arc<TokioUdpSocket>,
+    addr: SocketAddr,
+    config: super::Config,
+) -> Result<
+    (
+        impl Stream<Item = Bytes>
+            + TraceInfo
+            + ConnInfo
+            + ConnStats
+            + SendBufCap
+            + AckNow
+            + LastFrameMeta,
+        impl Sink<Message, Error = io::Error> + Ping + TrySend,
+    ),
+    ConnectError,
+> {
+    let (mut incoming, peer) = OfflineHandler::new(
+        Framed::new(Arc::clone(&socket), config.mtu as usize, config.magic)
+            .recv_batch(config.recv_batch_size)
+            .checksum(config.checksum)
+            .max_ack_count(config.max_ack_count)
+            .tap(config.tap.clone()),
+        addr,
+        config.offline_config(),
+    )
+    .await?;
+    let role = config.client_role();
 
-        let (mut router, route) = Route::new(Arc::clone(&link));
+    let c_id = ConnId::new(role.guid(), peer.guid, peer.addr);
+    let link = TransferLink::with_ack_buffer(role, peer, config.loss_window, config.ack_buffer);
+    let dst = Framed::new(Arc::clone(&socket), peer.mtu as usize, config.magic)
+        .send_batch(config.send_batch_size)
+        .checksum(config.checksum)
+        .tap(config.tap.clone())
+        .handle_outgoing(
+            Arc::clone(&link),
+            config.send_buf_cap,
+            config.send_buf_bytes,
+            peer,
+            role,
+        )
+        .close_timeout(config.close_timeout)
+        .fail_expired_reliable(config.fail_expired_reliable)
+        .resend_bytes_cap(config.resend_buf_bytes)
+        .rto_bounds(config.min_rto, config.max_rto)
+        .retransmit_hook(config.retransmit_hook.clone())
+        .receipt_hook(config.receipt_hook.clone())
+        .mtu_blackhole_retries(config.mtu_blackhole_retries)
+        .crypto(config.crypto.clone())
+        .compression(config.compression.clone())
+        .pacing(config.pacing)
+        .data_first(config.data_first)
+        .flush_budget(config.flush_budget)
+        .random_initial_seq_num(config.random_initial_seq_num)
+        .checksum(config.checksum)
+        .frame_encoded(peer.mtu, config.codec_config(), Arc::clone(&link))
+        .manage_outgoing_state(None, Arc::clone(&link));
+    let flush_interval = config
+        .flush_interval
+        .or(config.self_driving.then_some(DEFAULT_DRIVE_INTERVAL));
+    let dst = match flush_interval {
+        Some(interval) => {
+            let driving = config.self_driving.then(|| Arc::clone(&link));
+            let (dst, flusher) = dst.auto_flushed(interval, c_id, driving);
+            tokio::spawn(flusher);
+            Either::Right(dst)
+        }
+        None => Either::Left(dst),
+    };
+    let dst = DisconnectOnDrop::new(dst, Arc::clone(&link), config.disconnect_on_drop);
 
-        tokio::spawn(async move {
-            while let Some(pack) = incoming.next().await {
-                // deliver the packet actively so that we do not miss ACK/NACK packets to advance
-                // the outgoing state
-                router.deliver(pack);
-            }
-        });
+    let (mut router, route) = Route::new(Arc::clone(&link), config.recv_buf_cap);
+    router.set_ack_reliable_only(config.ack_reliable_only);
 
-        let src = route
-            .frame_decoded(config.codec_config())
-            .logged(
-                move |frame| trace!("[{role}] received {frame:?} from {peer}"),
-                move |err| error!("[{role}] decode error: {err} from {peer}"),
-            )
-            .manage_incoming_state()
-            .handle_online(addr, config.client_guid, Arc::clone(&link));
+    tokio::spawn(async move {
+        while let Some(pack) = incoming.next().await {
+            // deliver the packet actively so that we do not miss ACK/NACK packets to advance
+            // the outgoing state
+            router.deliver(pack);
+        }
+    });
 
-        Ok((src, dst))
-    }
+    let src = route
+        .frame_decoded(config.codec_config(), Arc::clone(&link))
+        .logged(
+            move |frame| trace!("[{role}] received {frame:?} from {peer}"),
+            move |err| error!("[{role}] decode error: {err} from {peer}"),
+        )
+        .manage_incoming_state(Arc::clone(&link))
+        .handle_online(
+            ConnectionInfo::new(c_id, peer.mtu, config.protocol_version),
+            config.client_guid,
+            config.crypto.is_some(),
+            Arc::clone(&link),
+        )
+        .packet_handlers(config.packet_handlers.clone())
+        .enter_on_item(move || online_span(c_id, peer));
+
+    Ok((src, dst))
 }
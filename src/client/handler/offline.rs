@@ -2,19 +2,34 @@ use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
 
 use futures::{Future, Sink, SinkExt, Stream, StreamExt};
+use log::debug;
 use pin_project_lite::pin_project;
 
+use crate::errors::ConnectError;
 use crate::packet::connected::{self, FramesMut};
 use crate::packet::{unconnected, Packet};
-use crate::{Peer, Role};
+use crate::utils::{Reactor, TimerHandle, TimerKind};
+use crate::{ConnId, Peer, Role};
+
+/// The MTUs stepped down to in turn once the open connection request is not replied, it is padded
+/// to the MTU and dropped on the way if the path takes less. Only the ones below the configured MTU
+/// are tried.
+const MTU_STEPS: [u16; 3] = [1492, 1200, 576];
+
+/// How long to wait for the reply of an open connection request before trying the next MTU, the
+/// smallest one is resent until replied.
+const OPEN_CONN_REQ1_TIMEOUT: Duration = Duration::from_millis(500);
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct Config {
     pub(crate) mtu: u16,
     pub(crate) client_guid: u64,
     pub(crate) protocol_version: u8,
+    // Whether the frame bodies are encrypted, the server should agree with it
+    pub(crate) encryption: bool,
 }
 
 pin_project! {
@@ -24,6 +39,10 @@ pin_project! {
         server_addr: SocketAddr,
         config: Config,
         role: Role,
+        // The MTU of the current open connection request
+        mtu: u16,
+        deadline: Instant,
+        timer: Option<TimerHandle>,
     }
 }
 
@@ -36,20 +55,47 @@ where
     pub(crate) fn new(frame: F, server_addr: SocketAddr, config: Config) -> Self {
         Self {
             frame: Some(frame),
-            state: State::SendOpenConnReq1(unconnected::Packet::OpenConnectionRequest1 {
-                magic: (),
-                protocol_version: config.protocol_version,
-                mtu: config.mtu,
-            }),
+            state: State::SendOpenConnReq1(open_connection_request1(&config, config.mtu)),
             server_addr,
             role: Role::Client {
                 guid: config.client_guid,
             },
+            mtu: config.mtu,
+            deadline: Instant::now(),
+            timer: None,
             config,
         }
     }
 }
 
+fn open_connection_request1(config: &Config, mtu: u16) -> unconnected::Packet {
+    unconnected::Packet::OpenConnectionRequest1 {
+        magic: (),
+        protocol_version: config.protocol_version,
+        mtu,
+    }
+}
+
+/// Whether the attempt expired at `deadline`, otherwise the timer is armed to wake the task then
+fn expired(
+    timer: &mut Option<TimerHandle>,
+    c_id: ConnId,
+    deadline: Instant,
+    cx: &Context<'_>,
+) -> bool {
+    if Instant::now() >= deadline {
+        return true;
+    }
+    match timer {
+        Some(timer) => Reactor::get().reset_timer(timer, deadline, cx.waker()),
+        None => {
+            *timer =
+                Some(Reactor::get().insert_timer(c_id, TimerKind::Handshake, deadline, cx.waker()));
+        }
+    }
+    false
+}
+
 enum State {
     SendOpenConnReq1(unconnected::Packet),
     SendOpenConnReq1Flush,
@@ -65,7 +111,7 @@ where
         + Sink<(unconnected::Packet, SocketAddr), Error = io::Error>
         + Unpin,
 {
-    type Output = Result<(impl Stream<Item = connected::Packet<FramesMut>>, Peer), io::Error>;
+    type Output = Result<(impl Stream<Item = connected::Packet<FramesMut>>, Peer), ConnectError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
@@ -79,23 +125,52 @@ where
                 }
                 State::SendOpenConnReq1Flush => {
                     ready!(frame.poll_flush_unpin(cx))?;
+                    *this.deadline = Instant::now() + OPEN_CONN_REQ1_TIMEOUT;
                     *this.state = State::WaitOpenConnReply1;
                 }
                 State::WaitOpenConnReply1 => {
-                    // TODO: Add timeout
-                    let (pack, addr) = ready!(frame.poll_next_unpin(cx)).ok_or(io::Error::new(
-                        io::ErrorKind::ConnectionReset,
-                        "connection reset by peer",
-                    ))?;
+                    let Poll::Ready(received) = frame.poll_next_unpin(cx) else {
+                        // the timers are keyed by connections, the handshake takes the client
+                        // guid as the remote one before knowing the server guid
+                        let c_id = ConnId::new(
+                            this.config.client_guid,
+                            this.config.client_guid,
+                            *this.server_addr,
+                        );
+                        if !expired(this.timer, c_id, *this.deadline, cx) {
+                            return Poll::Pending;
+                        }
+                        let mtu = MTU_STEPS
+                            .into_iter()
+                            .find(|step| step < this.mtu)
+                            .unwrap_or(*this.mtu);
+                        debug!(
+                            "[{}] open connection request with MTU {} timed out, retry with {mtu}",
+                            this.role, this.mtu
+                        );
+                        *this.mtu = mtu;
+                        *this.state =
+                            State::SendOpenConnReq1(open_connection_request1(this.config, mtu));
+                        continue;
+                    };
+                    let (pack, addr) = received.ok_or(ConnectError::Reset)?;
                     if addr != *this.server_addr {
                         continue;
                     }
+                    if let Some(err) = rejection(&pack) {
+                        return Poll::Ready(Err(err));
+                    }
+                    if let Some(timer) = this.timer.take() {
+                        Reactor::get().cancel_timer(timer);
+                    }
                     let next = match pack {
                         Packet::Unconnected(unconnected::Packet::OpenConnectionReply1 {
+                            cookie,
                             mtu,
                             ..
                         }) => unconnected::Packet::OpenConnectionRequest2 {
                             magic: (),
+                            cookie,
                             server_address: *this.server_addr,
                             mtu,
                             client_guid: this.config.client_guid,
@@ -115,17 +190,33 @@ where
                 }
                 State::WaitOpenConnReply2 => {
                     // TODO: Add timeout
-                    let (pack, addr) = ready!(frame.poll_next_unpin(cx)).ok_or(io::Error::new(
-                        io::ErrorKind::ConnectionReset,
-                        "connection reset by peer",
-                    ))?;
+                    let (pack, addr) =
+                        ready!(frame.poll_next_unpin(cx)).ok_or(ConnectError::Reset)?;
                     if addr != *this.server_addr {
                         continue;
                     }
+                    if let Some(err) = rejection(&pack) {
+                        return Poll::Ready(Err(err));
+                    }
                     match pack {
                         Packet::Unconnected(unconnected::Packet::OpenConnectionReply2 {
                             server_guid: guid,
                             ..
+                        }) if guid == this.config.client_guid => {
+                            return Poll::Ready(Err(ConnectError::GuidCollision))
+                        }
+                        Packet::Unconnected(unconnected::Packet::OpenConnectionReply2 {
+                            encryption_enabled,
+                            ..
+                        }) if encryption_enabled != this.config.encryption => {
+                            return Poll::Ready(Err(ConnectError::EncryptionMismatch {
+                                server_encryption: encryption_enabled,
+                            }))
+                        }
+                        Packet::Unconnected(unconnected::Packet::OpenConnectionReply2 {
+                            server_guid: guid,
+                            mtu,
+                            ..
                         }) => {
                             return Poll::Ready(Ok((
                                 FilterConnected {
@@ -134,7 +225,7 @@ where
                                 },
                                 Peer {
                                     addr: *this.server_addr,
-                                    mtu: this.config.mtu,
+                                    mtu,
                                     guid,
                                 },
                             )))
@@ -147,6 +238,24 @@ where
     }
 }
 
+/// Map the rejection replied by the server to the error
+fn rejection(pack: &Packet<FramesMut>) -> Option<ConnectError> {
+    let Packet::Unconnected(pack) = pack else {
+        return None;
+    };
+    match pack {
+        unconnected::Packet::IncompatibleProtocol {
+            server_protocol, ..
+        } => Some(ConnectError::IncompatibleVersion {
+            server_protocol: *server_protocol,
+        }),
+        unconnected::Packet::AlreadyConnected { .. } => Some(ConnectError::AlreadyConnected),
+        unconnected::Packet::NoFreeIncomingConnections { .. } => Some(ConnectError::ServerFull),
+        unconnected::Packet::ConnectionRequestFailed { .. } => Some(ConnectError::RequestFailed),
+        _ => None,
+    }
+}
+
 pin_project! {
     struct FilterConnected<F> {
         frame: F,
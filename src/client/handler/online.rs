@@ -1,4 +1,3 @@
-use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
 
@@ -8,6 +7,10 @@ use log::debug;
 use pin_project_lite::pin_project;
 
 use crate::link::SharedLink;
+use crate::opts::{
+    AckNow, ConnInfo, ConnStats, ConnectionInfo, FrameMeta, LastFrameMeta, PacketHandlers,
+    SendBufCap, Stats,
+};
 use crate::packet::connected::FrameBody;
 use crate::utils::timestamp;
 use crate::Role;
@@ -15,8 +18,9 @@ use crate::Role;
 pub(crate) trait HandleOnline: Sized {
     fn handle_online(
         self,
-        addr: SocketAddr,
+        info: ConnectionInfo,
         client_guid: u64,
+        use_encryption: bool,
         link: SharedLink,
     ) -> OnlineHandler<Self>;
 }
@@ -27,21 +31,23 @@ where
 {
     fn handle_online(
         self,
-        addr: SocketAddr,
+        info: ConnectionInfo,
         client_guid: u64,
+        use_encryption: bool,
         link: SharedLink,
     ) -> OnlineHandler<Self> {
         link.send_frame_body(FrameBody::ConnectionRequest {
             client_guid,
             request_timestamp: timestamp(),
-            use_encryption: false,
+            use_encryption,
         });
         OnlineHandler {
             frame: self,
             state: State::WaitConnRes,
-            addr,
+            info,
             link,
             role: Role::Client { guid: client_guid },
+            handlers: PacketHandlers::default(),
         }
     }
 }
@@ -51,9 +57,17 @@ pin_project! {
         #[pin]
         frame: F,
         state: State,
-        addr: SocketAddr,
+        info: ConnectionInfo,
         link: SharedLink,
         role: Role,
+        handlers: PacketHandlers,
+    }
+
+    impl<F> PinnedDrop for OnlineHandler<F> {
+        fn drop(this: Pin<&mut Self>) {
+            // the sink of the connection is aborted on drop once the stream is dropped as well
+            this.link.on_half_dropped(None);
+        }
     }
 }
 
@@ -77,22 +91,29 @@ where
                         return Poll::Ready(None);
                     };
                     if let FrameBody::ConnectionRequestAccepted {
+                        client_address,
                         system_addresses,
+                        request_timestamp,
                         accepted_timestamp,
                         ..
                     } = body
                     {
+                        this.info.set_external_addr(client_address);
+                        // the request timestamp is ours echoed by the server
+                        this.link.record_handshake_rtt(request_timestamp);
+                        // finish the handshake before yielding any data, the timestamps of the
+                        // accept packet are echoed
                         this.link.send_frame_body(FrameBody::NewIncomingConnection {
-                            server_address: *this.addr,
+                            server_address: this.info.remote_addr(),
                             system_addresses,
-                            request_timestamp: timestamp(),
+                            request_timestamp,
                             accepted_timestamp,
                         });
                         *this.state = State::Connected;
                         debug!(
                             "[{}] connected to server {addr:?}",
                             this.role,
-                            addr = this.addr
+                            addr = this.info.remote_addr()
                         );
                         continue;
                     }
@@ -108,7 +129,11 @@ where
                                 client_timestamp: timestamp(),
                             });
                         }
-                        FrameBody::User(data) => return Poll::Ready(Some(data)),
+                        FrameBody::User(data) => {
+                            if let Some(data) = this.handlers.dispatch(this.info.conn_id(), data) {
+                                return Poll::Ready(Some(data));
+                            }
+                        }
                         _ => {
                             debug!("[{}] ignore packet {body:?} on Connected", this.role);
                         }
@@ -118,3 +143,52 @@ where
         }
     }
 }
+
+impl<F> OnlineHandler<F> {
+    /// Pass the user packets of the registered ids to their handlers instead of yielding them
+    pub(crate) fn packet_handlers(mut self, handlers: PacketHandlers) -> Self {
+        self.handlers = handlers;
+        self
+    }
+}
+
+impl<F> ConnInfo for OnlineHandler<F> {
+    fn connection_info(&self) -> ConnectionInfo {
+        self.info
+            .with_effective_mtu(self.link.mtu())
+            .with_close_reason(self.link.close_reason())
+    }
+}
+
+impl<F> ConnStats for OnlineHandler<F> {
+    fn stats(&self) -> Stats {
+        self.link.stats()
+    }
+}
+
+impl<F> LastFrameMeta for OnlineHandler<F> {
+    fn last_frame_meta(&self) -> Option<FrameMeta> {
+        self.link.last_frame_meta()
+    }
+}
+
+impl<F> SendBufCap for OnlineHandler<F> {
+    fn send_buf_cap(&self) -> usize {
+        self.link.send_buf_cap()
+    }
+
+    fn set_send_buf_cap(&self, cap: usize) {
+        assert!(cap > 0, "cap must larger than 0");
+        self.link.set_send_buf_cap(cap);
+    }
+
+    fn reserve(&self, frames: usize) {
+        self.link.request_reserve(frames);
+    }
+}
+
+impl<F> AckNow for OnlineHandler<F> {
+    fn ack_now(&self) {
+        self.link.request_ack_now();
+    }
+}
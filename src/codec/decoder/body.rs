@@ -7,6 +7,7 @@ use futures::Stream;
 use pin_project_lite::pin_project;
 
 use crate::errors::CodecError;
+use crate::opts::FrameMeta;
 use crate::packet::connected::{Frame, FrameBody, FrameSet};
 
 pin_project! {
@@ -33,7 +34,7 @@ impl<F> Stream for BodyDecoder<F>
 where
     F: Stream<Item = Result<FrameSet<Frame>, CodecError>>,
 {
-    type Item = Result<FrameBody, CodecError>;
+    type Item = Result<(FrameBody, FrameMeta), CodecError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
@@ -45,10 +46,11 @@ where
         let span = LocalSpan::enter_with_local_parent("codec.body_decoder")
             .with_properties(|| [("seq_num", frame_set.seq_num.to_string())]);
 
+        let meta = FrameMeta::new(&frame_set.set);
         match FrameBody::read(frame_set.set.body) {
             Ok(body) => {
                 let _ = span.with_property(|| ("frame_type", format!("{:?}", body)));
-                Poll::Ready(Some(Ok(body)))
+                Poll::Ready(Some(Ok((body, meta))))
             }
             Err(err) => {
                 Event::add_to_local_parent(err.to_string(), || []);
@@ -0,0 +1,135 @@
+use std::mem;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use futures::Stream;
+use pin_project_lite::pin_project;
+
+use crate::errors::CodecError;
+use crate::opts::FrameCompression;
+use crate::packet::connected::{Frame, FrameSet};
+
+pin_project! {
+    // Decompress layer, decompresses the body of each frame after reassembling.
+    pub(crate) struct Decompress<F> {
+        #[pin]
+        frame: F,
+        compression: Option<Arc<dyn FrameCompression>>,
+    }
+}
+
+pub(crate) trait Decompressed: Sized {
+    fn decompressed(self, compression: Option<Arc<dyn FrameCompression>>) -> Decompress<Self>;
+}
+
+impl<F> Decompressed for F
+where
+    F: Stream<Item = Result<FrameSet<Frame>, CodecError>>,
+{
+    fn decompressed(self, compression: Option<Arc<dyn FrameCompression>>) -> Decompress<Self> {
+        Decompress {
+            frame: self,
+            compression,
+        }
+    }
+}
+
+impl<F> Stream for Decompress<F>
+where
+    F: Stream<Item = Result<FrameSet<Frame>, CodecError>>,
+{
+    type Item = Result<FrameSet<Frame>, CodecError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let Some(mut frame_set) = ready!(this.frame.poll_next(cx)?) else {
+            return Poll::Ready(None);
+        };
+        if let Some(compression) = this.compression {
+            let body = mem::take(&mut frame_set.set.body);
+            frame_set.set.body = compression.decompress(body)?;
+        }
+        Poll::Ready(Some(Ok(frame_set)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::StreamExt;
+
+    use super::Decompressed;
+    use crate::errors::CodecError;
+    use crate::opts::FrameCompression;
+    use crate::packet::connected::{Flags, Frame, FrameSet};
+    use crate::Reliability;
+
+    // Run-length encodes the bodies in pairs of (count, byte)
+    struct RunLength;
+
+    impl FrameCompression for RunLength {
+        fn compress(&self, body: Bytes) -> Bytes {
+            let mut out = Vec::new();
+            for byte in body {
+                match out.as_mut_slice() {
+                    [.., count, last] if *last == byte && *count < u8::MAX => *count += 1,
+                    _ => out.extend([1, byte]),
+                }
+            }
+            out.into()
+        }
+
+        fn decompress(&self, body: Bytes) -> io::Result<Bytes> {
+            if body.len() % 2 != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "odd length"));
+            }
+            Ok(body
+                .chunks(2)
+                .flat_map(|pair| std::iter::repeat(pair[1]).take(pair[0] as usize))
+                .collect())
+        }
+    }
+
+    fn frame_set(body: &'static [u8]) -> FrameSet<Frame> {
+        FrameSet {
+            seq_num: 0.into(),
+            set: Frame::new(
+                Flags::new(Reliability::Unreliable, false),
+                Bytes::from_static(body),
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decompress_works() {
+        assert_eq!(
+            RunLength.compress(Bytes::from_static(b"\xfeaaab")).as_ref(),
+            b"\x01\xfe\x03a\x01b"
+        );
+        let mut decompressed =
+            futures::stream::iter([frame_set(b"\x01\xfe\x03a\x01b"), frame_set(b"\x01\xfe\x03")])
+                .map(Ok)
+                .decompressed(Some(Arc::new(RunLength)));
+        assert_eq!(
+            decompressed.next().await.unwrap().unwrap().set.body,
+            Bytes::from_static(b"\xfeaaab")
+        );
+        assert!(matches!(
+            decompressed.next().await.unwrap().unwrap_err(),
+            CodecError::IO(_)
+        ));
+        assert!(decompressed.next().await.is_none());
+
+        let mut plain = futures::stream::iter([frame_set(b"\xfe")])
+            .map(Ok)
+            .decompressed(None);
+        assert_eq!(
+            plain.next().await.unwrap().unwrap().set.body,
+            Bytes::from_static(b"\xfe")
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use futures::Stream;
+use pin_project_lite::pin_project;
+
+use crate::errors::CodecError;
+use crate::opts::FrameCrypto;
+use crate::packet::connected::{FrameSet, FramesMut};
+
+pin_project! {
+    // Decrypt layer, decrypts the body of each frame in place before reassembling.
+    pub(crate) struct Decrypt<F> {
+        #[pin]
+        frame: F,
+        crypto: Option<Arc<dyn FrameCrypto>>,
+    }
+}
+
+pub(crate) trait Decrypted: Sized {
+    fn decrypted(self, crypto: Option<Arc<dyn FrameCrypto>>) -> Decrypt<Self>;
+}
+
+impl<F> Decrypted for F
+where
+    F: Stream<Item = Result<FrameSet<FramesMut>, CodecError>>,
+{
+    fn decrypted(self, crypto: Option<Arc<dyn FrameCrypto>>) -> Decrypt<Self> {
+        Decrypt {
+            frame: self,
+            crypto,
+        }
+    }
+}
+
+impl<F> Stream for Decrypt<F>
+where
+    F: Stream<Item = Result<FrameSet<FramesMut>, CodecError>>,
+{
+    type Item = Result<FrameSet<FramesMut>, CodecError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let Some(mut frame_set) = ready!(this.frame.poll_next(cx)?) else {
+            return Poll::Ready(None);
+        };
+        if let Some(crypto) = this.crypto {
+            for frame in &mut frame_set.set {
+                crypto.decrypt(&mut frame.body)?;
+            }
+        }
+        Poll::Ready(Some(Ok(frame_set)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::sync::Arc;
+
+    use bytes::{BufMut, BytesMut};
+    use futures::StreamExt;
+
+    use super::Decrypted;
+    use crate::errors::CodecError;
+    use crate::opts::FrameCrypto;
+    use crate::packet::connected::{Flags, Frame, FrameSet};
+    use crate::{Priority, Reliability};
+
+    // Appends a tag byte, the decryption fails if it is missing
+    struct Tagged;
+
+    impl FrameCrypto for Tagged {
+        fn encrypt(&self, body: &mut BytesMut) {
+            body.put_u8(0xaa);
+        }
+
+        fn decrypt(&self, body: &mut BytesMut) -> io::Result<()> {
+            if body.last() != Some(&0xaa) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "missing tag"));
+            }
+            body.truncate(body.len() - 1);
+            Ok(())
+        }
+    }
+
+    fn frame_set(body: &'static [u8]) -> FrameSet<Vec<Frame<BytesMut>>> {
+        FrameSet {
+            seq_num: 0.into(),
+            set: vec![Frame {
+                flags: Flags::new(Reliability::Unreliable, false),
+                reliable_frame_index: None,
+                seq_frame_index: None,
+                ordered: None,
+                fragment: None,
+                priority: Priority::Normal,
+                coalesce: false,
+                received: None,
+                deadline: None,
+                abandon_at: None,
+                receipt: None,
+                retransmits: 0,
+                body: BytesMut::from(body),
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_works() {
+        let mut decrypted =
+            futures::stream::iter([frame_set(b"\xfehello\xaa"), frame_set(b"\xfe")])
+                .map(Ok)
+                .decrypted(Some(Arc::new(Tagged)));
+        assert_eq!(
+            decrypted.next().await.unwrap().unwrap(),
+            frame_set(b"\xfehello")
+        );
+        assert!(matches!(
+            decrypted.next().await.unwrap().unwrap_err(),
+            CodecError::IO(_)
+        ));
+        assert!(decrypted.next().await.is_none());
+
+        let mut plain = futures::stream::iter([frame_set(b"\xfe")])
+            .map(Ok)
+            .decrypted(None);
+        assert_eq!(plain.next().await.unwrap().unwrap(), frame_set(b"\xfe"));
+    }
+}
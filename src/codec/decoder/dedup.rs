@@ -122,6 +122,7 @@ mod test {
 
     use super::*;
     use crate::packet::connected::{Flags, Frame, FrameSet};
+    use crate::Priority;
 
     #[test]
     fn test_duplicate_windows_check_ordered() {
@@ -178,11 +179,18 @@ mod test {
             set: idx
                 .into_iter()
                 .map(|i| Frame {
-                    flags: Flags::parse(0b011_11100),
+                    flags: Flags::parse(0b011_11100).unwrap(),
                     reliable_frame_index: Some(i.into()),
                     seq_frame_index: None,
                     ordered: None,
                     fragment: None,
+                    priority: Priority::Normal,
+                    coalesce: false,
+                    received: None,
+                    deadline: None,
+                    abandon_at: None,
+                    receipt: None,
+                    retransmits: 0,
                     body: Bytes::new(),
                 })
                 .collect(),
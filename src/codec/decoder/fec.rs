@@ -0,0 +1,192 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::BytesMut;
+use futures::Stream;
+use log::debug;
+use pin_project_lite::pin_project;
+
+use crate::codec::fec::{get_header, image, xor_into, FEC_DATA, FEC_PARITY};
+use crate::errors::CodecError;
+use crate::link::SharedLink;
+use crate::opts::FecOptions;
+use crate::packet::connected::{FrameMut, FrameSet, FramesMut};
+
+/// The number of the latest groups kept for recovering
+const MAX_FEC_GROUPS: usize = 64;
+
+#[derive(Default)]
+struct Group {
+    // the images of the received frames by their indices
+    images: Vec<Option<BytesMut>>,
+    // the number of frames and the parity, received from the parity frame
+    parity: Option<(u8, BytesMut)>,
+    // the index of the recovered frame, its late arrival is dropped
+    recovered: Option<u8>,
+    // all frames of the group are received or recovered
+    done: bool,
+}
+
+impl Group {
+    fn insert(&mut self, index: u8, image: BytesMut) {
+        let index = usize::from(index);
+        if self.images.len() <= index {
+            self.images.resize(index + 1, None);
+        }
+        self.images[index] = Some(image);
+    }
+
+    /// Recover the only missing frame once the parity is received
+    fn try_recover(&mut self) -> Option<(u8, BytesMut)> {
+        if self.done {
+            return None;
+        }
+        let (count, parity) = self.parity.as_mut()?;
+        let mut missing = (0..*count).filter(|&index| {
+            self.images
+                .get(usize::from(index))
+                .map_or(true, Option::is_none)
+        });
+        let Some(lost) = missing.next() else {
+            self.done = true;
+            self.images.clear();
+            return None;
+        };
+        if missing.next().is_some() {
+            return None;
+        }
+        let mut image = std::mem::take(parity);
+        for received in self.images.iter().flatten() {
+            xor_into(&mut image, received);
+        }
+        self.done = true;
+        self.recovered = Some(lost);
+        self.images.clear();
+        Some((lost, image))
+    }
+}
+
+pin_project! {
+    // FEC layer, strips the FEC header of the protected frames and recovers the lost one of a
+    // group from its parity frame before reassembling.
+    pub(crate) struct FecDecode<F> {
+        #[pin]
+        frame: F,
+        options: Option<FecOptions>,
+        groups: HashMap<u16, Group>,
+        // the groups in the order of their arrival, the oldest ones are evicted first
+        order: VecDeque<u16>,
+        link: SharedLink,
+    }
+}
+
+pub(crate) trait FecRecovered: Sized {
+    fn fec_recovered(self, options: Option<FecOptions>, link: SharedLink) -> FecDecode<Self>;
+}
+
+impl<F> FecRecovered for F
+where
+    F: Stream<Item = Result<FrameSet<FramesMut>, CodecError>>,
+{
+    fn fec_recovered(self, options: Option<FecOptions>, link: SharedLink) -> FecDecode<Self> {
+        FecDecode {
+            frame: self,
+            options,
+            groups: HashMap::new(),
+            order: VecDeque::new(),
+            link,
+        }
+    }
+}
+
+impl<F> FecDecode<F> {
+    fn group(self: Pin<&mut Self>, group: u16) -> &mut Group {
+        let this = self.project();
+        if !this.groups.contains_key(&group) {
+            if this.order.len() >= MAX_FEC_GROUPS
+                && let Some(oldest) = this.order.pop_front()
+            {
+                this.groups.remove(&oldest);
+            }
+            this.order.push_back(group);
+        }
+        this.groups.entry(group).or_default()
+    }
+
+    /// Handle a protected frame, returns the frame to deliver, and the recovered one if any
+    fn on_protected(
+        mut self: Pin<&mut Self>,
+        mut frame: FrameMut,
+    ) -> (Option<FrameMut>, Option<FrameMut>) {
+        let Some((kind, group_id, index)) = get_header(&mut frame.body) else {
+            debug!("drop the protected frame without the FEC header");
+            return (None, None);
+        };
+        let received = frame.received;
+        let group = self.as_mut().group(group_id);
+        let deliver = match kind {
+            FEC_DATA if group.recovered == Some(index) => None,
+            FEC_DATA => {
+                if !group.done {
+                    group.insert(index, image(&frame));
+                }
+                Some(frame)
+            }
+            FEC_PARITY => {
+                group.parity.get_or_insert((index, frame.body));
+                None
+            }
+            _ => {
+                debug!("drop the protected frame of unknown FEC kind {kind}");
+                return (None, None);
+            }
+        };
+        let Some((lost, mut image)) = group.try_recover() else {
+            return (deliver, None);
+        };
+        match FrameMut::read(&mut image) {
+            Ok(mut recovered) => {
+                debug!("recover the frame {lost} of the FEC group {group_id}");
+                recovered.received = received;
+                self.link.record_fec_recovered();
+                (deliver, Some(recovered))
+            }
+            Err(err) => {
+                debug!("failed to recover the frame {lost} of the FEC group {group_id}: {err}");
+                (deliver, None)
+            }
+        }
+    }
+}
+
+impl<F> Stream for FecDecode<F>
+where
+    F: Stream<Item = Result<FrameSet<FramesMut>, CodecError>>,
+{
+    type Item = Result<FrameSet<FramesMut>, CodecError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some(mut frame_set) = ready!(self.as_mut().project().frame.poll_next(cx)?) else {
+            return Poll::Ready(None);
+        };
+        let Some(options) = self.options else {
+            return Poll::Ready(Some(Ok(frame_set)));
+        };
+        if !frame_set.set.iter().any(|frame| options.protects(frame)) {
+            return Poll::Ready(Some(Ok(frame_set)));
+        }
+        let mut frames = Vec::with_capacity(frame_set.set.len());
+        for frame in frame_set.set.drain(..) {
+            if !options.protects(&frame) {
+                frames.push(frame);
+                continue;
+            }
+            let (deliver, recovered) = self.as_mut().on_protected(frame);
+            frames.extend(deliver);
+            frames.extend(recovered);
+        }
+        frame_set.set = frames;
+        Poll::Ready(Some(Ok(frame_set)))
+    }
+}
@@ -11,7 +11,7 @@ use lru::LruCache;
 use pin_project_lite::pin_project;
 
 use crate::errors::CodecError;
-use crate::packet::connected::{Fragment, Frame, FrameMut, FrameSet, FramesMut};
+use crate::packet::connected::{Fragment, Frame, FrameMut, FrameSet, FramesMut, Received};
 
 const DEFAULT_DEFRAGMENT_BUF_SIZE: usize = 512;
 
@@ -126,6 +126,7 @@ where
                         Event::add_to_local_parent(err.clone(), || []);
                         return Poll::Ready(Some(Err(CodecError::PartedFrame(err))));
                     }
+                    let received = frame.received;
                     let frames_queue = this.parts.get_or_insert_mut(parted_id, || {
                         // init the PriorityQueue with the capacity defined by user.
                         BinaryHeap::with_capacity(parted_size as usize)
@@ -140,7 +141,7 @@ where
                     // parted_index is always less than parted_size, frames_queue length
                     // reaches parted_size and frame is hashed by parted_index, so here we
                     // get the complete frames vector
-                    let merged_frame: Frame = this
+                    let mut merged_frame: Frame = this
                         .parts
                         .pop(&parted_id)
                         .expect("parted_id should be set before")
@@ -154,6 +155,15 @@ where
                         .expect("there is at least one frame")
                         .reassembled()
                         .freeze();
+                    // keep the fragment and the receive time of the last part
+                    merged_frame.received = received.map(|last| Received {
+                        fragment: Some(Fragment {
+                            parted_size,
+                            parted_id,
+                            parted_index,
+                        }),
+                        ..last
+                    });
 
                     this.buffer.push_back(FrameSet {
                         seq_num: frame_set.seq_num,
@@ -161,6 +171,26 @@ where
                     });
                     continue;
                 }
+                if frame.flags.reliability.is_ordered()
+                    && let Some(ordered) = frame.ordered
+                    && !this.parts.is_empty()
+                {
+                    // the marker of an abandoned message has the ordered index of the message, the
+                    // received parts of it are never completed
+                    let abandoned: Vec<u16> = this
+                        .parts
+                        .iter()
+                        .filter(|(_, parts)| {
+                            parts
+                                .peek()
+                                .is_some_and(|part| part.frame.ordered == Some(ordered))
+                        })
+                        .map(|(parted_id, _)| *parted_id)
+                        .collect();
+                    for parted_id in abandoned {
+                        this.parts.pop(&parted_id);
+                    }
+                }
                 this.buffer.push_back(FrameSet {
                     seq_num: frame_set.seq_num,
                     set: frame.freeze(),
@@ -179,7 +209,8 @@ mod test {
 
     use super::*;
     use crate::errors::CodecError;
-    use crate::packet::connected::{Flags, Fragment, Frame, FrameSet, FramesMut};
+    use crate::packet::connected::{Flags, Fragment, Frame, FrameSet, FramesMut, Ordered};
+    use crate::{Priority, Reliability};
 
     fn frame_set<'a, T: AsRef<str> + 'a>(
         idx: impl IntoIterator<Item = &'a (u32, u16, u32, T)>,
@@ -189,7 +220,7 @@ mod test {
             set: idx
                 .into_iter()
                 .map(|(parted_size, parted_id, parted_index, body)| Frame {
-                    flags: Flags::parse(0b011_11100),
+                    flags: Flags::parse(0b011_11100).unwrap(),
                     reliable_frame_index: None,
                     seq_frame_index: None,
                     ordered: None,
@@ -198,6 +229,13 @@ mod test {
                         parted_id: *parted_id,
                         parted_index: *parted_index,
                     }),
+                    priority: Priority::Normal,
+                    coalesce: false,
+                    received: None,
+                    deadline: None,
+                    abandon_at: None,
+                    receipt: None,
+                    retransmits: 0,
                     body: BytesMut::from(body.as_ref()),
                 })
                 .collect(),
@@ -210,11 +248,18 @@ mod test {
             set: bodies
                 .into_iter()
                 .map(|body| Frame {
-                    flags: Flags::parse(0b011_11100),
+                    flags: Flags::parse(0b011_11100).unwrap(),
                     reliable_frame_index: None,
                     seq_frame_index: None,
                     ordered: None,
                     fragment: None,
+                    priority: Priority::Normal,
+                    coalesce: false,
+                    received: None,
+                    deadline: None,
+                    abandon_at: None,
+                    receipt: None,
+                    retransmits: 0,
                     body: BytesMut::from(body),
                 })
                 .collect(),
@@ -296,6 +341,37 @@ mod test {
         assert_eq!(frag.parts.peek(&2).unwrap().len(), 2);
     }
 
+    #[tokio::test]
+    async fn test_defragment_abandoned() {
+        let ordered = |mut frame_set: FrameSet<FramesMut>, frame_index: u32| {
+            for frame in &mut frame_set.set {
+                frame.flags = Flags::new(Reliability::ReliableOrdered, frame.fragment.is_some());
+                frame.ordered = Some(Ordered {
+                    frame_index: frame_index.into(),
+                    channel: 0,
+                });
+            }
+            frame_set
+        };
+        let frame = {
+            #[stream]
+            async move {
+                yield ordered(frame_set([&(3, 7, 0, "a"), &(3, 7, 1, "b")]), 1);
+                yield ordered(frame_set([&(3, 8, 0, "c")]), 2);
+                // the marker standing in for the message of parted id 7
+                yield ordered(no_frag_frame_set(["\x04"]), 1);
+            }
+        };
+        tokio::pin!(frame);
+        let mut frag = frame.map(Ok).defragmented(0, 512);
+        let marker = frag.next().await.unwrap().unwrap();
+        assert_eq!(marker.set.body.as_ref(), b"\x04");
+        assert!(frag.next().await.is_none());
+        // only the parts of the abandoned message are released
+        assert_eq!(frag.parts.len(), 1);
+        assert!(frag.parts.peek(&8).is_some());
+    }
+
     #[tokio::test]
     async fn test_defragment_mixed() {
         let frame = {
@@ -1,5 +1,9 @@
 mod body;
+mod compress;
+mod crypto;
 mod dedup;
+#[cfg(feature = "fec")]
+mod fec;
 mod fragment;
 mod ordered;
 
@@ -11,7 +15,11 @@ use futures::Stream;
 use pin_project_lite::pin_project;
 
 pub(super) use self::body::*;
+pub(super) use self::compress::*;
+pub(super) use self::crypto::*;
 pub(super) use self::dedup::*;
+#[cfg(feature = "fec")]
+pub(super) use self::fec::*;
 pub(super) use self::fragment::*;
 pub(super) use self::ordered::*;
 
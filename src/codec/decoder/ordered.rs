@@ -1,14 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
 
 use bytes::Buf;
 use fastrace::{Event, Span};
 use futures::Stream;
-use log::warn;
+use log::{debug, warn};
 use pin_project_lite::pin_project;
 
 use crate::errors::CodecError;
+use crate::link::SharedLink;
 use crate::packet::connected::{self, Frame, FrameSet};
 use crate::utils::u24;
 
@@ -17,6 +18,14 @@ const INITIAL_ORDERING_MAP_CAP: usize = 64;
 struct Ordering<B> {
     map: HashMap<u24, FrameSet<Frame<B>>>,
     read: u24,
+    // sequenced frames keyed by (ordered frame index, sequence index), they are read before the
+    // ordered frame with the same frame index
+    sequenced: BTreeMap<(u24, u24), FrameSet<Frame<B>>>,
+    // the newest sequence index read with the current ordered frame index
+    seq_read: Option<u24>,
+    // the missing frames before it are skipped, it is set when the buffer overflows with the
+    // sequenced frames
+    skip_to: Option<u24>,
 }
 
 impl<B> Default for Ordering<B> {
@@ -24,10 +33,74 @@ impl<B> Default for Ordering<B> {
         Self {
             map: HashMap::with_capacity(INITIAL_ORDERING_MAP_CAP),
             read: 0.into(),
+            sequenced: BTreeMap::new(),
+            seq_read: None,
+            skip_to: None,
         }
     }
 }
 
+impl<B> Ordering<B> {
+    /// Whether the sequenced frame is older than the frames that have been read
+    fn is_stale(&self, frame_index: u24, seq_index: u24) -> bool {
+        frame_index < self.read
+            || (frame_index == self.read && self.seq_read.is_some_and(|read| seq_index <= read))
+    }
+
+    fn pop_next(&mut self) -> Option<FrameSet<Frame<B>>> {
+        loop {
+            if let Some(next) = self.pop_readable() {
+                return Some(next);
+            }
+            match self.skip_to {
+                Some(skip_to) if self.read < skip_to => self.skip_gap(skip_to),
+                _ => {
+                    self.skip_to = None;
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn pop_readable(&mut self) -> Option<FrameSet<Frame<B>>> {
+        while let Some(entry) = self.sequenced.first_entry() {
+            let (frame_index, seq_index) = *entry.key();
+            if frame_index > self.read {
+                break;
+            }
+            let next = entry.remove();
+            if self.is_stale(frame_index, seq_index) {
+                continue;
+            }
+            self.seq_read = Some(seq_index);
+            return Some(next);
+        }
+        let next = self.map.remove(&self.read)?;
+        self.read += 1;
+        self.seq_read = None;
+        Some(next)
+    }
+
+    /// The number of frames buffered until the missing ones arrive
+    fn depth(&self) -> usize {
+        self.map.len() + self.sequenced.len()
+    }
+
+    /// Skip the missing frames before the earliest buffered one, but not beyond `skip_to`
+    fn skip_gap(&mut self, skip_to: u24) {
+        let next = self
+            .map
+            .keys()
+            .copied()
+            .chain(self.sequenced.keys().map(|(frame_index, _)| *frame_index))
+            .min()
+            .map_or(skip_to, |next| next.min(skip_to));
+        debug!("skip the missing ordered frames {}..{next}", self.read);
+        self.read = next;
+        self.seq_read = None;
+    }
+}
+
 pin_project! {
     // Ordering layer, ordered the packets based on ordering_frame_index.
     pub(crate) struct Order<F, B> {
@@ -35,20 +108,25 @@ pin_project! {
         frame: F,
         // Max ordered channel that will be used in detailed protocol
         max_channels: usize,
+        // Max frames buffered in each channel, 0 means no limit
+        max_depth: usize,
         ordering: Vec<Ordering<B>>,
+        link: SharedLink,
+        // the buffer overflowed with the ordered frames, nothing could be read anymore
+        overflowed: bool,
         span: Option<Span>,
     }
 }
 
 pub(crate) trait Ordered<B: Buf>: Sized {
-    fn ordered(self, max_channels: usize) -> Order<Self, B>;
+    fn ordered(self, max_channels: usize, max_depth: usize, link: SharedLink) -> Order<Self, B>;
 }
 
 impl<F, B: Buf> Ordered<B> for F
 where
     F: Stream<Item = Result<FrameSet<Frame<B>>, CodecError>>,
 {
-    fn ordered(self, max_channels: usize) -> Order<Self, B> {
+    fn ordered(self, max_channels: usize, max_depth: usize, link: SharedLink) -> Order<Self, B> {
         assert!(
             max_channels < usize::from(u8::MAX),
             "max channels should not be larger than u8::MAX"
@@ -58,9 +136,12 @@ where
         Order {
             frame: self,
             max_channels,
+            max_depth,
             ordering: std::iter::repeat_with(Ordering::default)
                 .take(max_channels)
                 .collect(),
+            link,
+            overflowed: false,
             span: None,
         }
     }
@@ -74,6 +155,9 @@ where
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
+        if *this.overflowed {
+            return Poll::Ready(None);
+        }
         loop {
             // empty each channel in order
             for channel in 0..*this.max_channels {
@@ -82,8 +166,7 @@ where
                     .get_mut(channel)
                     .expect("channel < max_channels");
                 // check if we could read next
-                if let Some(next) = ordering.map.remove(&ordering.read) {
-                    ordering.read += 1;
+                if let Some(next) = ordering.pop_next() {
                     this.span.take();
                     return Poll::Ready(Some(Ok(next)));
                 }
@@ -118,11 +201,39 @@ where
                     .ordering
                     .get_mut(channel)
                     .expect("channel < max_channels");
+                let full = *this.max_depth != 0 && ordering.depth() >= *this.max_depth;
+                if let Some(seq_index) = frame_set.set.seq_frame_index {
+                    if ordering.is_stale(frame_index, seq_index) {
+                        debug!("ignore old sequenced frame index {frame_index}-{seq_index}");
+                        continue;
+                    }
+                    if full {
+                        // the sequenced frames tolerate the gaps, read the buffered ones up to it
+                        ordering.skip_to = ordering.skip_to.max(Some(frame_index));
+                    }
+                    ordering
+                        .sequenced
+                        .insert((frame_index, seq_index), frame_set);
+                    if !full {
+                        this.link.record_reorder_depth(ordering.depth());
+                    }
+                    continue;
+                }
                 if frame_index < ordering.read {
                     warn!("ignore old ordered frame index {frame_index}");
                     continue;
                 }
+                if full && frame_index > ordering.read {
+                    let err = format!(
+                        "reorder buffer of channel {channel} exceeds {}, missing frame index {}",
+                        *this.max_depth, ordering.read
+                    );
+                    Event::add_to_local_parent(err.clone(), || []);
+                    *this.overflowed = true;
+                    return Poll::Ready(Some(Err(CodecError::OrderedFrame(err))));
+                }
                 ordering.map.insert(frame_index, frame_set);
+                this.link.record_reorder_depth(ordering.depth());
                 // we cannot read anymore
                 continue;
             }
@@ -135,20 +246,28 @@ where
 
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
+
     use bytes::Bytes;
     use futures::StreamExt;
     use futures_async_stream::stream;
 
     use super::Ordered;
     use crate::errors::CodecError;
+    use crate::link::{SharedLink, TransferLink};
     use crate::packet::connected::{Flags, Frame, FrameSet, Ordered as OrderedFlag};
+    use crate::{Peer, Priority, Reliability, Role};
+
+    fn link() -> SharedLink {
+        TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1))
+    }
 
     fn frame_sets(idx: impl IntoIterator<Item = (u8, u32)>) -> Vec<FrameSet<Frame>> {
         idx.into_iter()
             .map(|(channel, frame_index)| FrameSet {
                 seq_num: 0.into(),
                 set: Frame {
-                    flags: Flags::parse(0b011_11100),
+                    flags: Flags::parse(0b011_11100).unwrap(),
                     reliable_frame_index: None,
                     seq_frame_index: None,
                     ordered: Some(OrderedFlag {
@@ -156,6 +275,13 @@ mod test {
                         channel,
                     }),
                     fragment: None,
+                    priority: Priority::Normal,
+                    coalesce: false,
+                    received: None,
+                    deadline: None,
+                    abandon_at: None,
+                    receipt: None,
+                    retransmits: 0,
                     body: Bytes::new(),
                 },
             })
@@ -175,7 +301,7 @@ mod test {
             }
         };
         tokio::pin!(frame);
-        let mut ordered = frame.map(Ok).ordered(10);
+        let mut ordered = frame.map(Ok).ordered(10, 0, link());
         let cmp_sets = frame_sets([(0, 0), (0, 1), (0, 2), (0, 3), (0, 4)]).into_iter();
         for next in cmp_sets {
             assert_eq!(ordered.next().await.unwrap().unwrap(), next);
@@ -184,6 +310,63 @@ mod test {
         assert!(ordered.next().await.is_none());
     }
 
+    #[tokio::test]
+    async fn test_ordered_channels_independent() {
+        let frame = {
+            #[stream]
+            async {
+                // the frame 0 of the channel 0 is missing, but it does not block the channel 31
+                for frame_set in frame_sets([(0, 1), (31, 0), (0, 2), (31, 1), (0, 0)]) {
+                    yield frame_set;
+                }
+            }
+        };
+        tokio::pin!(frame);
+        let mut ordered = frame.map(Ok).ordered(32, 0, link());
+        let cmp_sets = frame_sets([(31, 0), (31, 1), (0, 0), (0, 1), (0, 2)]).into_iter();
+        for next in cmp_sets {
+            assert_eq!(ordered.next().await.unwrap().unwrap(), next);
+        }
+
+        assert!(ordered.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sequenced_works() {
+        // (sequence index, ordered frame index), the ordered ones do not have a sequence index
+        let frame_set = |seq_index: Option<u32>, frame_index: u32| {
+            let mut frame_set = frame_sets([(0, frame_index)]).pop().unwrap();
+            if let Some(seq_index) = seq_index {
+                frame_set.set.flags = Flags::new(Reliability::UnreliableSequenced, false);
+                frame_set.set.seq_frame_index = Some(seq_index.into());
+            }
+            frame_set
+        };
+        let frame = {
+            #[stream]
+            async move {
+                for (seq_index, frame_index) in [
+                    (Some(0), 1), // wait for the ordered frame 0
+                    (Some(1), 0),
+                    (Some(0), 0), // superseded by sequence index 1
+                    (None, 0),
+                    (Some(5), 0), // older than the ordered frame 0
+                ] {
+                    yield frame_set(seq_index, frame_index);
+                }
+            }
+        };
+        tokio::pin!(frame);
+        let mut ordered = frame.map(Ok).ordered(10, 0, link());
+        for (seq_index, frame_index) in [(Some(1), 0), (None, 0), (Some(0), 1)] {
+            assert_eq!(
+                ordered.next().await.unwrap().unwrap(),
+                frame_set(seq_index, frame_index)
+            );
+        }
+        assert!(ordered.next().await.is_none());
+    }
+
     #[tokio::test]
     async fn test_ordered_channel_exceed() {
         let frame = {
@@ -195,10 +378,62 @@ mod test {
             }
         };
         tokio::pin!(frame);
-        let mut ordered = frame.map(Ok).ordered(10);
+        let mut ordered = frame.map(Ok).ordered(10, 0, link());
         assert!(matches!(
             ordered.next().await.unwrap().unwrap_err(),
             CodecError::OrderedFrame(_)
         ));
     }
+
+    #[tokio::test]
+    async fn test_ordered_depth_exceed() {
+        let link = link();
+        let mut ordered = futures::stream::iter(frame_sets([(0, 1), (0, 2), (0, 3), (0, 0)]))
+            .map(Ok)
+            .ordered(1, 2, link.clone());
+        // the frame index 0 is withheld while the later ones are flooding
+        assert!(matches!(
+            ordered.next().await.unwrap().unwrap_err(),
+            CodecError::OrderedFrame(_)
+        ));
+        assert!(ordered.next().await.is_none());
+        assert_eq!(link.stats().reorder_high_water(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_ordered_depth_per_channel() {
+        // each channel buffers 2 frames while waiting for its frame index 0, under the cap of 2
+        let mut ordered =
+            futures::stream::iter(frame_sets([(0, 1), (0, 2), (3, 1), (3, 2), (3, 0), (0, 0)]))
+                .map(Ok)
+                .ordered(4, 2, link());
+        for next in frame_sets([(3, 0), (3, 1), (3, 2), (0, 0), (0, 1), (0, 2)]) {
+            assert_eq!(ordered.next().await.unwrap().unwrap(), next);
+        }
+        assert!(ordered.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sequenced_depth_exceed() {
+        let sequenced = |seq_index: u32, frame_index: u32| {
+            let mut frame_set = frame_sets([(0, frame_index)]).pop().unwrap();
+            frame_set.set.flags = Flags::new(Reliability::UnreliableSequenced, false);
+            frame_set.set.seq_frame_index = Some(seq_index.into());
+            frame_set
+        };
+        let link = link();
+        let mut ordered =
+            futures::stream::iter([sequenced(0, 1), sequenced(1, 2), sequenced(2, 3)])
+                .map(Ok)
+                .ordered(1, 2, link.clone());
+        // the ordered frame 0 is skipped, the buffered ones are read with a gap
+        for (seq_index, frame_index) in [(0, 1), (1, 2), (2, 3)] {
+            assert_eq!(
+                ordered.next().await.unwrap().unwrap(),
+                sequenced(seq_index, frame_index)
+            );
+        }
+        assert!(ordered.next().await.is_none());
+        assert_eq!(link.stats().reorder_high_water(), 2);
+    }
 }
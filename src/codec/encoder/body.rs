@@ -8,6 +8,7 @@ use pin_project_lite::pin_project;
 
 use crate::link::SharedLink;
 use crate::packet::connected::FrameBody;
+use crate::state::Aborting;
 use crate::{Message, Reliability};
 
 pin_project! {
@@ -44,6 +45,7 @@ fn encode(body: FrameBody) -> Message {
         FrameBody::NewIncomingConnection { .. } => Reliability::ReliableOrdered,
         FrameBody::DisconnectNotification => Reliability::Reliable,
         FrameBody::DetectLostConnections => Reliability::Reliable,
+        FrameBody::Abandoned => Reliability::ReliableOrdered,
         FrameBody::User(_) => {
             panic!("you should not send user packet into BodyEncoder, please send `Message`")
         }
@@ -131,8 +133,11 @@ where
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        ready!(self.as_mut().poll_empty(cx))?;
-        debug_assert!(self.link.frame_body_empty());
+        // the pending bodies are dropped along with the buffered frames when aborting
+        if cx.ext().downcast_ref::<Aborting>().is_none() {
+            ready!(self.as_mut().poll_empty(cx))?;
+            debug_assert!(self.link.frame_body_empty());
+        }
         self.project().frame.poll_close(cx)
     }
 }
@@ -0,0 +1,117 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Sink;
+use pin_project_lite::pin_project;
+
+use crate::errors::CodecError;
+use crate::packet::connected::Frame;
+use crate::packet::FRAME_SET_HEADER_SIZE;
+
+/// The maximum size of a frame body, its length in bits is written in 16 bits
+const MAX_BODY_LEN: usize = (u16::MAX >> 3) as usize;
+
+pin_project! {
+    // Cap layer, rejects the frames which could not fit in a frame set of the negotiated MTU, e.g.
+    // the ones grown by the encryption beyond its reserved overhead. They are rejected instead of
+    // being sent with a corrupted length.
+    pub(crate) struct Cap<F> {
+        #[pin]
+        frame: F,
+        // the maximum size of a frame
+        max_size: usize,
+    }
+}
+
+pub(crate) trait Capped: Sized {
+    /// `overhead` is the size appended to each frame set, e.g. the checksum
+    fn capped(self, mtu: usize, overhead: usize) -> Cap<Self>;
+}
+
+impl<F> Capped for F
+where
+    F: Sink<Frame, Error = io::Error>,
+{
+    fn capped(self, mtu: usize, overhead: usize) -> Cap<Self> {
+        Cap {
+            frame: self,
+            max_size: mtu.saturating_sub(FRAME_SET_HEADER_SIZE + overhead),
+        }
+    }
+}
+
+impl<F> Sink<Frame> for Cap<F>
+where
+    F: Sink<Frame, Error = io::Error>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Frame) -> Result<(), Self::Error> {
+        let this = self.project();
+        if frame.size() > *this.max_size || frame.body.len() > MAX_BODY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                CodecError::FrameTooLarge(frame.size(), (*this.max_size).min(MAX_BODY_LEN)),
+            ));
+        }
+        this.frame.start_send(frame)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+
+    use super::*;
+    use crate::packet::connected::Flags;
+    use crate::Reliability;
+
+    #[tokio::test]
+    async fn test_cap_frame_size() {
+        let (tx, rx) = futures::channel::mpsc::unbounded::<Frame>();
+        let mut capped = tx
+            .sink_map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+            .capped(100, 4);
+        // 4 bytes header + 3 bytes frame header + 89 bytes body + 4 bytes checksum
+        let frame = |len| {
+            Frame::new(
+                Flags::new(Reliability::Unreliable, false),
+                Bytes::from(vec![0; len]),
+            )
+        };
+        capped.send(frame(89)).await.unwrap();
+        let err = capped.send(frame(90)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let inner = err.into_inner().unwrap().downcast::<CodecError>().unwrap();
+        assert!(matches!(*inner, CodecError::FrameTooLarge(93, 92)));
+
+        // the length of the body is limited to 13 bits
+        let mut jumbo = futures::sink::drain()
+            .sink_map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+            .capped(10000, 0);
+        jumbo.send(frame(8191)).await.unwrap();
+        assert!(jumbo.send(frame(8192)).await.is_err());
+
+        drop(capped);
+        assert_eq!(
+            rx.map(|sent| sent.body.len()).collect::<Vec<_>>().await,
+            vec![89]
+        );
+    }
+}
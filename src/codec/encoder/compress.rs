@@ -0,0 +1,63 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Sink;
+use pin_project_lite::pin_project;
+
+use crate::opts::FrameCompression;
+use crate::Message;
+
+pin_project! {
+    // Compress layer, compresses the body of each message before fragmentation, so that the
+    // compressed body is split by the MTU.
+    pub(crate) struct Compress<F> {
+        #[pin]
+        frame: F,
+        compression: Option<Arc<dyn FrameCompression>>,
+    }
+}
+
+pub(crate) trait Compressed: Sized {
+    fn compressed(self, compression: Option<Arc<dyn FrameCompression>>) -> Compress<Self>;
+}
+
+impl<F> Compressed for F
+where
+    F: Sink<Message, Error = io::Error>,
+{
+    fn compressed(self, compression: Option<Arc<dyn FrameCompression>>) -> Compress<Self> {
+        Compress {
+            frame: self,
+            compression,
+        }
+    }
+}
+
+impl<F> Sink<Message> for Compress<F>
+where
+    F: Sink<Message, Error = io::Error>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, mut msg: Message) -> Result<(), Self::Error> {
+        let this = self.project();
+        if let Some(compression) = this.compression {
+            msg.map_data(|data| compression.compress(data));
+        }
+        this.frame.start_send(msg)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_close(cx)
+    }
+}
@@ -0,0 +1,66 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::BytesMut;
+use futures::Sink;
+use pin_project_lite::pin_project;
+
+use crate::opts::FrameCrypto;
+use crate::packet::connected::Frame;
+
+pin_project! {
+    // Encrypt layer, encrypts the body of each frame after fragmentation. The frames are stored
+    // encrypted for resending.
+    pub(crate) struct Encrypt<F> {
+        #[pin]
+        frame: F,
+        crypto: Option<Arc<dyn FrameCrypto>>,
+    }
+}
+
+pub(crate) trait Encrypted: Sized {
+    fn encrypted(self, crypto: Option<Arc<dyn FrameCrypto>>) -> Encrypt<Self>;
+}
+
+impl<F> Encrypted for F
+where
+    F: Sink<Frame, Error = io::Error>,
+{
+    fn encrypted(self, crypto: Option<Arc<dyn FrameCrypto>>) -> Encrypt<Self> {
+        Encrypt {
+            frame: self,
+            crypto,
+        }
+    }
+}
+
+impl<F> Sink<Frame> for Encrypt<F>
+where
+    F: Sink<Frame, Error = io::Error>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, mut frame: Frame) -> Result<(), Self::Error> {
+        let this = self.project();
+        if let Some(crypto) = this.crypto {
+            let mut body = BytesMut::from(frame.body);
+            crypto.encrypt(&mut body);
+            frame.body = body.freeze();
+        }
+        this.frame.start_send(frame)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_close(cx)
+    }
+}
@@ -0,0 +1,191 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{BufMut, BytesMut};
+use futures::Sink;
+use pin_project_lite::pin_project;
+
+use crate::codec::fec::{image, put_header, xor_into, FEC_DATA, FEC_HEADER_SIZE, FEC_PARITY};
+use crate::opts::FecOptions;
+use crate::packet::connected::{Flags, Frame};
+
+pin_project! {
+    // FEC layer, prefixes the protected frames by the FEC header and sends a parity frame after
+    // each group of them. The frames are sent right away, only the parity waits for the group.
+    pub(crate) struct FecEncode<F> {
+        #[pin]
+        frame: F,
+        options: Option<FecOptions>,
+        group: u16,
+        index: u8,
+        // the XOR of the images of the frames sent in the current group
+        parity: BytesMut,
+        // the parity frame waiting for the inner sink to be ready
+        staged: Option<Frame>,
+    }
+}
+
+pub(crate) trait FecEncoded: Sized {
+    fn fec_encoded(self, options: Option<FecOptions>) -> FecEncode<Self>;
+}
+
+impl<F> FecEncoded for F
+where
+    F: Sink<Frame, Error = io::Error>,
+{
+    fn fec_encoded(self, options: Option<FecOptions>) -> FecEncode<Self> {
+        FecEncode {
+            frame: self,
+            options,
+            group: 0,
+            index: 0,
+            parity: BytesMut::new(),
+            staged: None,
+        }
+    }
+}
+
+impl<F> FecEncode<F>
+where
+    F: Sink<Frame, Error = io::Error>,
+{
+    fn poll_staged(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        if this.staged.is_some() {
+            ready!(this.frame.as_mut().poll_ready(cx))?;
+            this.frame.start_send(this.staged.take().unwrap())?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<F> Sink<Frame> for FecEncode<F>
+where
+    F: Sink<Frame, Error = io::Error>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_staged(cx))?;
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, mut frame: Frame) -> Result<(), Self::Error> {
+        let this = self.project();
+        let Some(options) = this.options.filter(|options| options.protects(&frame)) else {
+            return this.frame.start_send(frame);
+        };
+        debug_assert!(this.staged.is_none(), "poll_ready must be called first");
+        xor_into(this.parity, &image(&frame));
+        let mut body = BytesMut::with_capacity(FEC_HEADER_SIZE + frame.body.len());
+        put_header(&mut body, FEC_DATA, *this.group, *this.index);
+        body.put(frame.body);
+        // the parity frame is modelled on the last frame of the group to be encoded as it
+        let mut parity = Frame {
+            seq_frame_index: frame.seq_frame_index,
+            ordered: frame.ordered,
+            priority: frame.priority,
+            ..Frame::new(Flags::new(options.reliability, false), Default::default())
+        };
+        frame.body = body.freeze();
+        this.frame.start_send(frame)?;
+
+        *this.index += 1;
+        if *this.index == options.group_size {
+            let mut parity_body = BytesMut::with_capacity(FEC_HEADER_SIZE + this.parity.len());
+            put_header(&mut parity_body, FEC_PARITY, *this.group, *this.index);
+            parity_body.put(this.parity.split());
+            parity.body = parity_body.freeze();
+            *this.staged = Some(parity);
+            *this.group = this.group.wrapping_add(1);
+            *this.index = 0;
+        }
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_staged(cx))?;
+        self.project().frame.poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_staged(cx))?;
+        self.project().frame.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+
+    use super::*;
+    use crate::codec::decoder::FecRecovered;
+    use crate::codec::fec::image;
+    use crate::link::TransferLink;
+    use crate::packet::connected::{FrameMut, FrameSet};
+    use crate::{Peer, Reliability, Role};
+
+    fn frame(reliability: Reliability, body: &'static [u8]) -> Frame {
+        let mut frame = Frame::new(Flags::new(reliability, false), Bytes::from_static(body));
+        if reliability.is_reliable() {
+            // assigned by the outgoing guard
+            frame.reliable_frame_index = Some(0.into());
+        }
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_fec_recover_lost_frame() {
+        let mut encoded = Vec::new()
+            .sink_map_err(|never| match never {})
+            .fec_encoded(Some(FecOptions::new(3)));
+        for (reliability, body) in [
+            (Reliability::Unreliable, &b"\xfea"[..]),
+            (Reliability::Reliable, b"\xfeb"),
+            (Reliability::Unreliable, b"\xfecc"),
+            (Reliability::Unreliable, b"\xfeddd"),
+        ] {
+            encoded.feed(frame(reliability, body)).await.unwrap();
+        }
+        encoded.flush().await.unwrap();
+        let sent = std::mem::take(encoded.frame.get_mut());
+        // 3 protected frames, 1 reliable frame and the parity frame
+        assert_eq!(sent.len(), 5);
+        assert_eq!(sent[0].body.as_ref(), b"\x00\x00\x00\x00\xfea");
+        assert_eq!(sent[1].body.as_ref(), b"\xfeb");
+        assert_eq!(sent[4].body[..4], *b"\x01\x00\x00\x03");
+
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        // lose the frame "cc"
+        let frame_sets = sent
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 2)
+            .map(|(_, frame)| {
+                Ok(FrameSet {
+                    seq_num: 0.into(),
+                    set: vec![FrameMut::read(&mut image(frame)).unwrap()],
+                })
+            });
+        let bodies: Vec<_> = futures::stream::iter(frame_sets)
+            .fec_recovered(Some(FecOptions::new(3)), link.clone())
+            .flat_map(|frame_set| futures::stream::iter(frame_set.unwrap().set))
+            .map(|frame| frame.body.freeze())
+            .collect()
+            .await;
+        assert_eq!(
+            bodies,
+            vec![
+                Bytes::from_static(b"\xfea"),
+                Bytes::from_static(b"\xfeb"),
+                Bytes::from_static(b"\xfeddd"),
+                Bytes::from_static(b"\xfecc"),
+            ]
+        );
+        assert_eq!(link.stats().fec_recovered(), 1);
+    }
+}
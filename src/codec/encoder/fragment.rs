@@ -2,11 +2,13 @@ use std::cmp::min;
 use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Instant;
 
 use bytes::Buf;
 use futures::Sink;
 use pin_project_lite::pin_project;
 
+use crate::link::SharedLink;
 use crate::packet::connected::{self, Flags, Frame, Ordered};
 use crate::packet::{FRAGMENT_PART_SIZE, FRAME_SET_HEADER_SIZE};
 use crate::utils::u24;
@@ -18,8 +20,13 @@ pin_project! {
         #[pin]
         frame: F,
         mtu: usize,
+        // follows the effective MTU of the link, minus the overhead
+        link_mtu: Option<(SharedLink, usize)>,
         reliable_write_index: u24,
         order_write_index: Vec<u24>,
+        // sequenced messages share the order index of the last ordered message in the same channel,
+        // and they are distinguished by the sequence index
+        seq_write_index: Vec<u24>,
         parted_id_write: u16,
     }
 }
@@ -36,13 +43,24 @@ where
         Fragment {
             frame: self,
             mtu,
+            link_mtu: None,
             reliable_write_index: 0.into(),
             order_write_index: std::iter::repeat(0.into()).take(max_channels).collect(),
+            seq_write_index: std::iter::repeat(0.into()).take(max_channels).collect(),
             parted_id_write: 0,
         }
     }
 }
 
+impl<F> Fragment<F> {
+    /// Split the messages by the effective MTU of the link minus `overhead` once it is reduced
+    /// below the initial one
+    pub(crate) fn follow_link_mtu(mut self, link: SharedLink, overhead: usize) -> Self {
+        self.link_mtu = Some((link, overhead));
+        self
+    }
+}
+
 impl<F> Sink<Message> for Fragment<F>
 where
     F: Sink<Frame, Error = io::Error>,
@@ -57,6 +75,12 @@ where
         let mut this = self.project();
         let mut reliability = msg.get_reliability();
         let order_channel = msg.get_order_channel() as usize;
+        let priority = msg.get_priority();
+        let coalesce = msg.get_coalesce();
+        let deadline = msg.get_ttl().map(|ttl| Instant::now() + ttl);
+        let abandon_at = msg.get_abandon_after().map(|after| Instant::now() + after);
+        let (raw_ordered_index, raw_reliable_index) = msg.get_raw_indices();
+        let receipt = msg.get_receipt().filter(|_| reliability.with_ack_receipt());
 
         debug_assert!(
             order_channel < this.order_write_index.len(),
@@ -66,8 +90,14 @@ where
 
         let mut body = msg.into_data();
 
+        let mtu = this
+            .link_mtu
+            .as_ref()
+            .map_or(*this.mtu, |(link, overhead)| {
+                min(*this.mtu, link.mtu() as usize - overhead)
+            });
         // max_len is the maximum size of the frame body (excluding the fragment part option)
-        let mut max_len = *this.mtu - FRAME_SET_HEADER_SIZE - reliability.size();
+        let mut max_len = mtu - FRAME_SET_HEADER_SIZE - reliability.size();
 
         if body.len() > max_len {
             // adjust reliability when packet needs splitting
@@ -78,38 +108,54 @@ where
                 _ => reliability,
             };
             // calculate again as we may have adjusted reliability
-            max_len = *this.mtu - FRAME_SET_HEADER_SIZE - reliability.size();
+            max_len = mtu - FRAME_SET_HEADER_SIZE - reliability.size();
+        }
+
+        // Sequence index and ordered part performs across all fragmented frames to ensure that the
+        // entire data is received in the same order as it was sent.
+        let seq_frame_index = reliability
+            .is_sequenced()
+            .then(|| this.seq_write_index[order_channel]);
+        let ordered = reliability.is_sequenced_or_ordered().then_some(Ordered {
+            frame_index: raw_ordered_index.map_or(this.order_write_index[order_channel], u24::from),
+            channel: order_channel as u8,
+        });
+        if reliability.is_sequenced() {
+            this.seq_write_index[order_channel] += 1;
+        } else if reliability.is_ordered() && raw_ordered_index.is_none() {
+            this.order_write_index[order_channel] += 1;
+            this.seq_write_index[order_channel] = 0.into();
         }
 
-        // get reliable_frame_index and ordered part for each frame
-        let mut indices_for_frame = || {
-            // reliable_frame_index performs for each frame to ensure it is not duplicated
-            let reliable_frame_index = reliability.is_reliable().then(|| {
-                let index = *this.reliable_write_index;
-                *this.reliable_write_index += 1;
+        // reliable_frame_index performs for each frame to ensure it is not duplicated
+        let mut raw_reliable_index = raw_reliable_index.map(u24::from);
+        let mut reliable_index_for_frame = || {
+            reliability.is_reliable().then(|| {
+                let next = raw_reliable_index
+                    .as_mut()
+                    .unwrap_or(&mut *this.reliable_write_index);
+                let index = *next;
+                *next += 1;
                 index
-            });
-            // Ordered performs across all fragmented frames to ensure that the entire data is
-            // received in the same order as it was sent.
-            let ordered = reliability.is_sequenced_or_ordered().then_some(Ordered {
-                frame_index: this.order_write_index[order_channel],
-                channel: order_channel as u8,
-            });
-            (reliable_frame_index, ordered)
+            })
         };
 
         if body.len() <= max_len {
             // not exceeding the mss, no need to split.
-            let (reliable_frame_index, ordered) = indices_for_frame();
-            if reliability.is_sequenced_or_ordered() {
-                this.order_write_index[order_channel] += 1;
-            }
             let frame = Frame {
                 flags: Flags::new(reliability, false),
-                reliable_frame_index,
-                seq_frame_index: None,
+                reliable_frame_index: reliable_index_for_frame(),
+                seq_frame_index,
                 ordered,
                 fragment: None,
+                priority,
+                // only the unreliable sequenced frames could be superseded
+                coalesce: coalesce && reliability == Reliability::UnreliableSequenced,
+                received: None,
+                deadline,
+                abandon_at,
+                receipt,
+                retransmits: 0,
                 body,
             };
             return this.frame.start_send(frame);
@@ -123,17 +169,23 @@ where
 
         // split the data
         for parted_index in 0..parted_size {
-            let (reliable_frame_index, ordered) = indices_for_frame();
             let frame = Frame {
                 flags: Flags::new(reliability, true),
-                reliable_frame_index,
-                seq_frame_index: None,
+                reliable_frame_index: reliable_index_for_frame(),
+                seq_frame_index,
                 ordered,
                 fragment: Some(connected::Fragment {
                     parted_size,
                     parted_id,
                     parted_index,
                 }),
+                priority,
+                coalesce: false,
+                received: None,
+                deadline,
+                abandon_at,
+                receipt,
+                retransmits: 0,
                 body: body.split_to(min(per_len, body.len())),
             };
             debug_assert!(
@@ -141,14 +193,7 @@ where
                 "split failed, the frame body is too large"
             );
             // We rely on the underlying sink to handle backpressure
-            this.frame
-                .as_mut()
-                .start_send(frame)
-                .expect("send fragmented frame failed");
-        }
-
-        if reliability.is_sequenced_or_ordered() {
-            this.order_write_index[order_channel] += 1;
+            this.frame.as_mut().start_send(frame)?;
         }
 
         debug_assert!(
@@ -170,7 +215,9 @@ where
 
 #[cfg(test)]
 mod test {
-    use bytes::Bytes;
+    use std::time::Duration;
+
+    use bytes::{Bytes, BytesMut};
     use connected::Frames;
 
     use super::*;
@@ -265,6 +312,106 @@ mod test {
         assert_eq!(dst.frame.buf[5].flags.reliability, Reliability::Reliable);
     }
 
+    #[test]
+    fn test_fragmented_sequenced() {
+        let dst = DstSink::default().fragmented(50, 8);
+        tokio::pin!(dst);
+        for reliability in [
+            Reliability::UnreliableSequenced,
+            Reliability::ReliableOrdered,
+            Reliability::UnreliableSequenced,
+            Reliability::UnreliableSequenced,
+        ] {
+            let mut msg = Message::new(reliability, 0, Bytes::from_static(b"hello"));
+            msg.set_coalesce(true);
+            dst.as_mut().start_send(msg).unwrap();
+        }
+        let indices: Vec<_> = dst
+            .frame
+            .buf
+            .iter()
+            .map(|frame| {
+                (
+                    frame.seq_frame_index.map(u24::to_u32),
+                    frame.ordered.unwrap().frame_index.to_u32(),
+                    frame.coalesce,
+                )
+            })
+            .collect();
+        // sequenced frames share the order index of the last ordered frame
+        assert_eq!(
+            indices,
+            [
+                (Some(0), 0, true),
+                (None, 0, false),
+                (Some(0), 1, true),
+                (Some(1), 1, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fragmented_ttl() {
+        let dst = DstSink::default().fragmented(50, 8);
+        tokio::pin!(dst);
+        let mut msg = Message::new(Reliability::Unreliable, 0, Bytes::from(vec![0xfe; 100]));
+        msg.set_ttl(Duration::from_millis(100));
+        let queued = Instant::now();
+        dst.as_mut().start_send(msg).unwrap();
+        dst.as_mut()
+            .start_send(Message::new(
+                Reliability::Unreliable,
+                0,
+                Bytes::from_static(b"\xfe"),
+            ))
+            .unwrap();
+        let last = dst.frame.buf.pop().unwrap();
+        assert!(last.deadline.is_none());
+        // all parts expire at the same time
+        let deadline = dst.frame.buf[0].deadline.unwrap();
+        assert!(deadline >= queued + Duration::from_millis(100));
+        assert!(dst
+            .frame
+            .buf
+            .iter()
+            .all(|frame| frame.deadline == Some(deadline)));
+    }
+
+    #[test]
+    fn test_fragmented_raw_indices() {
+        let dst = DstSink::default().fragmented(50, 8);
+        tokio::pin!(dst);
+        let mut msg = Message::new(
+            Reliability::ReliableOrdered,
+            0,
+            Bytes::from_iter(std::iter::repeat(0xfe).take(50)),
+        );
+        msg.set_raw_indices(Some(100), Some(200));
+        dst.as_mut().start_send(msg).unwrap();
+        dst.as_mut()
+            .start_send(Message::new(
+                Reliability::ReliableOrdered,
+                0,
+                Bytes::from_static(b"hello"),
+            ))
+            .unwrap();
+        let indices: Vec<_> = dst
+            .frame
+            .buf
+            .iter()
+            .map(|frame| {
+                (
+                    frame.ordered.unwrap().frame_index.to_u32(),
+                    frame.reliable_frame_index.unwrap().to_u32(),
+                )
+            })
+            .collect();
+        // the automatic indices are not advanced by the overridden ones
+        assert_eq!(indices, [(100, 200), (100, 201), (0, 0)]);
+        assert_eq!(dst.order_write_index[0].to_u32(), 1);
+        assert_eq!(dst.reliable_write_index.to_u32(), 1);
+    }
+
     #[test]
     #[should_panic]
     fn test_fragmented_panic() {
@@ -324,6 +471,30 @@ mod test {
         assert_eq!(fragment.parted_index, 1);
     }
 
+    #[test]
+    fn test_fragmented_jumbo() {
+        let dst = DstSink::default().fragmented(1200, 8);
+        tokio::pin!(dst);
+        let jumbo = Bytes::from_iter((0..1024 * 1024).map(|i| (i % 251) as u8));
+        // the body far larger than the MTU is split right at start_send
+        dst.as_mut()
+            .start_send(Message::new(Reliability::Unreliable, 0, jumbo.clone()))
+            .unwrap();
+        let parts = &dst.frame.buf;
+        assert_eq!(parts.len(), jumbo.len().div_ceil(parts[0].body.len()));
+        let mut reassembled = BytesMut::new();
+        for (index, part) in parts.iter().enumerate() {
+            assert!(part.size() <= 1200 - FRAME_SET_HEADER_SIZE);
+            // an unreliable message is sent as reliable once it is split
+            assert_eq!(part.flags.reliability, Reliability::Reliable);
+            let fragment = part.fragment.unwrap();
+            assert_eq!(fragment.parted_size as usize, parts.len());
+            assert_eq!(fragment.parted_index as usize, index);
+            reassembled.extend_from_slice(&part.body);
+        }
+        assert_eq!(reassembled, jumbo);
+    }
+
     #[test]
     fn test_fragmented_adjust_not_exceed() {
         let dst = DstSink::default().fragmented(50, 8);
@@ -1,5 +1,15 @@
 mod body;
+mod cap;
+mod compress;
+mod crypto;
+#[cfg(feature = "fec")]
+mod fec;
 mod fragment;
 
 pub(super) use self::body::*;
+pub(super) use self::cap::*;
+pub(super) use self::compress::*;
+pub(super) use self::crypto::*;
+#[cfg(feature = "fec")]
+pub(super) use self::fec::*;
 pub(super) use self::fragment::*;
@@ -0,0 +1,55 @@
+//! The wire format of the forward error correction, see [`FecOptions`](crate::opts::FecOptions).
+//!
+//! Each protected frame is prefixed by a header of the kind, the group and the index in the group,
+//! and the parity frame of a group has the same header with the number of frames in the group
+//! instead of the index. The body of the parity frame is the XOR of the images of the frames in
+//! the group, where the image is the frame as it would be on the wire without the header, so that
+//! the lost frame is recovered along with its fragment and sequencing information.
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::packet::connected::Frame;
+
+/// The size of the header of the protected and parity frames
+pub(crate) const FEC_HEADER_SIZE: usize = 4;
+
+/// The room left for the header and the parity of the frame headers, a parity frame carries the
+/// images with their headers of at most 23 bytes
+pub(crate) const FEC_OVERHEAD: usize = FEC_HEADER_SIZE + 28;
+
+/// The kind of the frames carrying data
+pub(crate) const FEC_DATA: u8 = 0;
+
+/// The kind of the parity frames
+pub(crate) const FEC_PARITY: u8 = 1;
+
+pub(crate) fn put_header(buf: &mut BytesMut, kind: u8, group: u16, index: u8) {
+    buf.put_u8(kind);
+    buf.put_u16(group);
+    buf.put_u8(index);
+}
+
+/// Returns the (kind, group, index) of the header
+pub(crate) fn get_header(body: &mut BytesMut) -> Option<(u8, u16, u8)> {
+    if body.len() < FEC_HEADER_SIZE {
+        return None;
+    }
+    Some((body.get_u8(), body.get_u16(), body.get_u8()))
+}
+
+/// Write the image of the frame
+pub(crate) fn image<B: Buf + Clone>(frame: &Frame<B>) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(frame.size() + 3);
+    frame.write_ref(&mut buf);
+    buf
+}
+
+/// XOR the image into the parity, the parity is extended with zeros if it is shorter
+pub(crate) fn xor_into(parity: &mut BytesMut, image: &[u8]) {
+    if parity.len() < image.len() {
+        parity.resize(image.len(), 0);
+    }
+    for (p, i) in parity.iter_mut().zip(image) {
+        *p ^= i;
+    }
+}
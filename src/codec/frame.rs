@@ -1,6 +1,7 @@
 use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{ready, Context, Poll};
 
 use bytes::{Buf, BytesMut};
@@ -9,13 +10,16 @@ use futures::{Sink, Stream};
 use log::error;
 
 use super::AsyncSocket;
-use crate::packet::connected::{FramesMut, FramesRef};
-use crate::packet::{unconnected, Packet};
+use crate::errors::DecodeErrorHook;
+use crate::opts::DatagramTap;
+use crate::packet::connected::{FramesMut, FramesRef, DEFAULT_MAX_ACK_COUNT};
+use crate::packet::{unconnected, Magic, Packet};
+use crate::utils::normalize_addr;
 
 /// `Framed` is a base structure for socket communication.
 /// In this project, it wraps an asynchronous UDP socket and implements the
 /// [`Stream`](futures::stream::Stream) and [`Sink`](futures::sink::Sink) traits.
-/// This allows for reading and writing RakNet frames over the socket.
+/// This allows for reading and writing `RakNet` frames over the socket.
 /// It supports both receiving and sending unconnected and connected packets.
 pub(crate) struct Framed<T> {
     /// The underlying socket that is being wrapped.
@@ -23,70 +27,171 @@ pub(crate) struct Framed<T> {
     /// [maximum transmission unit](https://en.wikipedia.org/wiki/Maximum_transmission_unit),
     /// used to pre-allocate the capacity for both the `rd` and `wr` byte buffers
     max_mtu: usize,
-    /// a buffer for storing incoming data read from the socket.
-    rd: BytesMut,
-    /// a buffer for writing bytes
+    /// the buffers for storing incoming datagrams read from the socket, one datagram per buffer,
+    /// they are received in one batch.
+    rd: Vec<BytesMut>,
+    /// the addresses of the datagrams in `rd`
+    rd_addrs: Vec<SocketAddr>,
+    /// the index of the next datagram in `rd` to decode
+    rd_pos: usize,
+    /// the number of datagrams received in `rd`. When `rd_pos` is less than it, the datagrams are
+    /// ready for frame packet decoding and will be passed to the upper layer for further
+    /// processing.
+    rd_len: usize,
+    /// a buffer for writing bytes, it holds all pending datagrams contiguously
     wr: BytesMut,
-    /// the socket address to which the data will be sent
-    out_addr: SocketAddr,
-    /// indicates whether the data has been sent to the peer, and the
-    /// `wr` buffer has been cleared
-    flushed: bool,
-    /// indicates whether data has been read from the peer and written
-    /// into the `rd` buffer. When `true`, it signifies that the data is ready for frame packet
-    /// decoding and will be passed to the upper layer for further processing.
-    is_readable: bool,
-    /// the address of the current peer
-    current_addr: Option<SocketAddr>,
+    /// the pending datagrams, each one is the end offset in `wr` and the socket address to which
+    /// the datagram will be sent. It is empty when all data has been sent and `wr` has been
+    /// cleared
+    pending: Vec<(usize, SocketAddr)>,
+    /// the number of pending datagrams that have been sent
+    sent: usize,
+    /// the maximum number of pending datagrams, they are sent in one batch when flushing
+    max_batch: usize,
+    /// the magic sequence used to validate and write unconnected packets
+    magic: Magic,
+    /// whether the CRC-32 of the frame sets is appended and validated
+    checksum: bool,
+    /// the maximum number of packets acknowledged by an incoming ACK or NACK
+    max_ack_count: usize,
+    /// the hook to receive decode errors
+    decode_err_hook: Option<DecodeErrorHook>,
+    /// the tap to observe the raw datagrams
+    tap: Option<Arc<dyn DatagramTap>>,
     decode_span: Option<Span>,
     read_span: Option<Span>,
 }
 
 impl<T: AsyncSocket> Framed<T> {
-    pub(crate) fn new(socket: T, max_mtu: usize) -> Self {
+    pub(crate) fn new(socket: T, max_mtu: usize, magic: Magic) -> Self {
         Self {
             socket,
             max_mtu,
-            rd: BytesMut::new(), // we may never use rd at all
+            rd: vec![BytesMut::new()], // we may never use rd at all
+            rd_addrs: vec![SocketAddr::from(([0, 0, 0, 0], 0))],
+            rd_pos: 0,
+            rd_len: 0,
             wr: BytesMut::with_capacity(max_mtu),
-            out_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
-            flushed: true,
-            is_readable: false,
-            current_addr: None,
+            pending: Vec::new(),
+            sent: 0,
+            max_batch: 1,
+            magic,
+            checksum: false,
+            max_ack_count: DEFAULT_MAX_ACK_COUNT,
+            decode_err_hook: None,
+            tap: None,
             decode_span: None,
             read_span: None,
         }
     }
 
+    /// Forward the decode errors to the hook
+    pub(crate) fn decode_err_hook(mut self, hook: Option<DecodeErrorHook>) -> Self {
+        self.decode_err_hook = hook;
+        self
+    }
+
+    /// Pass the raw datagrams to the tap, the incoming ones before decoding and the outgoing ones
+    /// after encoding
+    pub(crate) fn tap(mut self, tap: Option<Arc<dyn DatagramTap>>) -> Self {
+        self.tap = tap;
+        self
+    }
+
+    /// Append the CRC-32 to the outgoing frame sets and validate it on the incoming ones
+    pub(crate) fn checksum(mut self, enabled: bool) -> Self {
+        self.checksum = enabled;
+        self
+    }
+
+    /// Reject the incoming ACKs and NACKs which acknowledge more than `count` packets
+    pub(crate) fn max_ack_count(mut self, count: usize) -> Self {
+        self.max_ack_count = count;
+        self
+    }
+
+    /// Receive up to `size` datagrams in one batch, which will be received by one syscall if the
+    /// socket supports it.
+    pub(crate) fn recv_batch(mut self, size: usize) -> Self {
+        assert!(size > 0, "batch size must larger than 0");
+        self.rd.resize_with(size, BytesMut::new);
+        self.rd_addrs
+            .resize(size, SocketAddr::from(([0, 0, 0, 0], 0)));
+        self
+    }
+
+    /// Buffer up to `size` datagrams and send them in one batch when flushing, which will be
+    /// submitted by one syscall if the socket supports it.
+    pub(crate) fn send_batch(mut self, size: usize) -> Self {
+        assert!(size > 0, "batch size must larger than 0");
+        self.max_batch = size;
+        self.pending.reserve(size);
+        self
+    }
+
     #[inline]
-    fn poll_flush_0(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        if self.flushed {
+    fn poll_ready_0(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        if self.pending.len() < self.max_batch {
             return Poll::Ready(Ok(()));
         }
+        self.poll_flush_0(cx)
+    }
 
-        let Self {
-            ref socket,
-            ref mut out_addr,
-            ref mut wr,
-            ..
-        } = *self;
+    #[inline]
+    fn poll_flush_0(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        while self.sent < self.pending.len() {
+            let Self {
+                ref socket,
+                ref wr,
+                ref pending,
+                sent,
+                ..
+            } = *self;
+
+            let start = if sent == 0 { 0 } else { pending[sent - 1].0 };
+            if sent + 1 == pending.len() {
+                let (end, out_addr) = pending[sent];
+                let n = ready!(socket.poll_send_to(cx, &wr[start..end], out_addr))?;
+                if n != end - start {
+                    self.clear_pending();
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "failed to write entire datagram to socket",
+                    )));
+                }
+                self.sent += 1;
+                continue;
+            }
 
-        let n = ready!(socket.poll_send_to(cx, wr, *out_addr))?;
+            let mut offset = start;
+            let bufs: Vec<(&[u8], SocketAddr)> = pending[sent..]
+                .iter()
+                .map(|&(end, out_addr)| {
+                    let buf = &wr[offset..end];
+                    offset = end;
+                    (buf, out_addr)
+                })
+                .collect();
+            let n = ready!(socket.poll_send_batch(cx, &bufs))?;
+            self.sent += n;
+        }
+        self.clear_pending();
+        Poll::Ready(Ok(()))
+    }
 
-        let wrote_all = n == self.wr.len();
+    fn clear_pending(&mut self) {
         self.wr.clear();
-        self.flushed = true;
-
-        let res = if wrote_all {
-            Ok(())
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "failed to write entire datagram to socket",
-            ))
-        };
+        self.pending.clear();
+        self.sent = 0;
+    }
 
-        Poll::Ready(res)
+    fn push_pending(&mut self, out_addr: SocketAddr) {
+        let end = self.wr.len();
+        if let Some(tap) = &self.tap {
+            let start = self.pending.last().map_or(0, |(prev, _)| *prev);
+            tap.on_raw_out(&self.wr[start..end], out_addr);
+        }
+        self.pending.push((end, out_addr));
     }
 }
 
@@ -96,64 +201,79 @@ impl<T: AsyncSocket> Stream for Framed<T> {
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let pin = self.get_mut();
 
-        pin.rd.reserve(pin.max_mtu);
-
         loop {
-            // Are there still bytes left in the read buffer to decode?
-            if pin.is_readable {
-                pin.is_readable = false;
+            // Are there still datagrams left in the read buffers to decode?
+            if pin.rd_pos < pin.rd_len {
+                // all the layers above see the normalized address, see `normalize_addr`
+                let addr = normalize_addr(pin.rd_addrs[pin.rd_pos]);
+                let rd = &mut pin.rd[pin.rd_pos];
+                pin.rd_pos += 1;
+
+                // start a new decode span
+                pin.decode_span.get_or_insert_with(|| {
+                    Span::enter_with_local_parent("codec.frame.decode").with_properties(|| {
+                        [
+                            ("addr", addr.to_string()),
+                            ("datagram_size", rd.len().to_string()),
+                        ]
+                    })
+                });
+
+                if let Some(tap) = &pin.tap {
+                    tap.on_raw_in(rd, addr);
+                }
 
                 // decode one packet at a time
-                match Packet::read(&mut pin.rd) {
+                match Packet::read(rd, &pin.magic, pin.checksum, pin.max_ack_count) {
                     Ok(frame) => {
-                        let current_addr = pin
-                            .current_addr
-                            .expect("will always be set before this line is called");
                         Event::add_to_local_parent(
                             format!("{:?} decoded", frame.pack_type()),
                             || [],
                         );
 
                         pin.decode_span.take();
-                        pin.rd.clear();
+                        rd.clear();
 
-                        return Poll::Ready(Some((frame, current_addr)));
+                        return Poll::Ready(Some((frame, addr)));
                     }
                     Err(err) => {
                         Event::add_to_local_parent(err.to_string(), || []);
                         error!("failed to decode packet: {:?}", err);
+                        if let Some(hook) = &pin.decode_err_hook {
+                            hook.call(addr, &err);
+                        }
 
                         pin.decode_span.take();
-                        pin.rd.clear();
+                        rd.clear();
                     }
                 }
+                continue;
             }
 
             // We're out of data. Try and fetch more data to decode
+            pin.rd_pos = 0;
+            pin.rd_len = 0;
+            for rd in &mut pin.rd {
+                rd.reserve(pin.max_mtu);
+            }
             pin.read_span
                 .get_or_insert_with(|| Span::enter_with_local_parent("codec.frame.read"));
-            let addr = match ready!(pin.socket.poll_recv_from(cx, &mut pin.rd)) {
-                Ok(addr) => addr,
-                Err(err) => {
-                    error!("failed to receive data: {:?}", err);
-                    Event::add_to_local_parent(err.to_string(), || []);
-                    pin.rd.clear();
-                    continue;
-                }
-            };
+            let received =
+                match ready!(pin
+                    .socket
+                    .poll_recv_batch(cx, &mut pin.rd, &mut pin.rd_addrs))
+                {
+                    Ok(received) => received,
+                    Err(err) => {
+                        error!("failed to receive data: {:?}", err);
+                        Event::add_to_local_parent(err.to_string(), || []);
+                        pin.rd.iter_mut().for_each(BytesMut::clear);
+                        continue;
+                    }
+                };
             // finish the read span
             pin.read_span.take();
-            // start a new decode span
-            pin.decode_span.get_or_insert_with(|| {
-                Span::enter_with_local_parent("codec.frame.decode").with_properties(|| {
-                    [
-                        ("addr", addr.to_string()),
-                        ("datagram_size", pin.rd.len().to_string()),
-                    ]
-                })
-            });
-            pin.current_addr = Some(addr);
-            pin.is_readable = true;
+            pin.rd_len = received;
         }
     }
 }
@@ -165,7 +285,7 @@ impl<'a, B: Buf + Clone, T: AsyncSocket> Sink<(Packet<FramesRef<'a, B>>, SocketA
     type Error = io::Error;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.poll_flush_0(cx)
+        self.poll_ready_0(cx)
     }
 
     fn start_send(
@@ -176,9 +296,8 @@ impl<'a, B: Buf + Clone, T: AsyncSocket> Sink<(Packet<FramesRef<'a, B>>, SocketA
 
         let pin = self.get_mut();
 
-        frame.write(&mut pin.wr);
-        pin.out_addr = out_addr;
-        pin.flushed = false;
+        frame.write(&mut pin.wr, &pin.magic, pin.checksum);
+        pin.push_pending(out_addr);
 
         Ok(())
     }
@@ -199,7 +318,7 @@ impl<T: AsyncSocket> Sink<(unconnected::Packet, SocketAddr)> for Framed<T> {
     type Error = io::Error;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.poll_flush_0(cx)
+        self.poll_ready_0(cx)
     }
 
     fn start_send(
@@ -210,9 +329,8 @@ impl<T: AsyncSocket> Sink<(unconnected::Packet, SocketAddr)> for Framed<T> {
 
         let pin = self.get_mut();
 
-        frame.write(&mut pin.wr);
-        pin.out_addr = out_addr;
-        pin.flushed = false;
+        frame.write(&mut pin.wr, &pin.magic);
+        pin.push_pending(out_addr);
 
         Ok(())
     }
@@ -7,26 +7,41 @@ mod decoder;
 /// Frames pipeline encoder
 mod encoder;
 
+/// The wire format of the forward error correction
+#[cfg(feature = "fec")]
+mod fec;
+
 /// Tokio codec helper
 #[cfg(feature = "tokio-rt")]
 pub(crate) mod tokio;
 
 use std::io;
 use std::net::SocketAddr;
-use std::task::{Context, Poll};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
 
 use bytes::BytesMut;
-use futures::{Sink, Stream, StreamExt};
-
-use self::decoder::{BodyDecoded, DeFragmented, Deduplicated, Ordered, TracePending};
-use self::encoder::{BodyEncoded, Fragmented};
+use futures::{Sink, Stream, StreamExt, TryStreamExt};
+
+#[cfg(feature = "fec")]
+use self::decoder::FecRecovered;
+use self::decoder::{
+    BodyDecoded, DeFragmented, Decompressed, Decrypted, Deduplicated, Ordered, TracePending,
+};
+#[cfg(feature = "fec")]
+use self::encoder::FecEncoded;
+use self::encoder::{BodyEncoded, Capped, Compressed, Encrypted, Fragmented};
 use crate::errors::CodecError;
 use crate::link::SharedLink;
+#[cfg(feature = "fec")]
+use crate::opts::FecOptions;
+use crate::opts::{FrameCompression, FrameCrypto, FrameMeta};
 use crate::packet::connected::{Frame, FrameBody, FrameSet, FramesMut};
+use crate::packet::FRAME_SET_CHECKSUM_SIZE;
 use crate::Message;
 
 /// Codec config
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct Config {
     /// Limit the max size of a parted frames set, 0 means no limit
     /// It will abort the split frame if the `parted_size` reaches limit.
@@ -38,6 +53,19 @@ pub(crate) struct Config {
     pub(crate) max_parted_count: usize,
     /// Maximum ordered channel, the value should be less than 256
     pub(crate) max_channels: usize,
+    /// Limit the max frames buffered in each ordering channel until the missing ones arrive, 0
+    /// means no limit. The missing frames are skipped if a sequenced frame exceeds it, and the
+    /// decoding fails and ends if an ordered frame exceeds it.
+    pub(crate) max_reorder_depth: usize,
+    /// Encrypt/decrypt the frame bodies, `None` means disabled
+    pub(crate) crypto: Option<Arc<dyn FrameCrypto>>,
+    /// Compress/decompress the frame bodies, `None` means disabled
+    pub(crate) compression: Option<Arc<dyn FrameCompression>>,
+    /// Whether the CRC-32 is appended to each frame set
+    pub(crate) checksum: bool,
+    /// Protect the unreliable frames by the parity frames, `None` means disabled
+    #[cfg(feature = "fec")]
+    pub(crate) fec: Option<FecOptions>,
 }
 
 impl Default for Config {
@@ -47,6 +75,12 @@ impl Default for Config {
             max_parted_size: 256,
             max_parted_count: 256,
             max_channels: 1,
+            max_reorder_depth: 0,
+            crypto: None,
+            compression: None,
+            checksum: false,
+            #[cfg(feature = "fec")]
+            fec: None,
         }
     }
 }
@@ -66,24 +100,100 @@ pub(crate) trait AsyncSocket: Unpin {
         buf: &[u8],
         target: SocketAddr,
     ) -> Poll<io::Result<usize>>;
+
+    /// Receive a batch of datagrams, each one is received into a buffer of `bufs` and its address
+    /// is stored in `addrs` at the same index, returns the number of received datagrams. It
+    /// receives one datagram by default.
+    fn poll_recv_batch(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [BytesMut],
+        addrs: &mut [SocketAddr],
+    ) -> Poll<io::Result<usize>> {
+        addrs[0] = ready!(self.poll_recv_from(cx, &mut bufs[0]))?;
+        Poll::Ready(Ok(1))
+    }
+
+    /// Send a batch of datagrams, returns the number of datagrams sent. It sends the datagrams
+    /// one by one by default.
+    fn poll_send_batch(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &[(&[u8], SocketAddr)],
+    ) -> Poll<io::Result<usize>> {
+        let mut sent = 0;
+        for &(buf, target) in bufs {
+            match self.poll_send_to(cx, buf, target) {
+                Poll::Ready(Ok(_)) => sent += 1,
+                // report the error in the next call if some datagrams were sent
+                Poll::Ready(Err(err)) if sent == 0 => return Poll::Ready(Err(err)),
+                Poll::Pending if sent == 0 => return Poll::Pending,
+                _ => break,
+            }
+        }
+        Poll::Ready(Ok(sent))
+    }
 }
 
 /// Frames pipeline decoder
 /// It will convert the stream of raw frames into defragmented, deduplicated and ordered frames.
+///
+/// The body of an unfragmented frame is never copied: it is split from the datagram receive
+/// buffer when the frame set is read and then frozen and passed through to the user packet, so
+/// the [`bytes::Bytes`] given to the application shares the allocation of the receive buffer.
+/// Only the fragmented frames are copied once when reassembling.
 pub(crate) trait Decoded {
-    fn frame_decoded(self, config: Config) -> impl Stream<Item = Result<FrameBody, CodecError>>;
+    fn frame_decoded(
+        self,
+        config: Config,
+        link: SharedLink,
+    ) -> impl Stream<Item = Result<FrameBody, CodecError>>;
+
+    /// The variant of [`Decoded::frame_decoded`] which yields the metadata of each frame
+    /// alongside its body, e.g. the reliability, the ordering and the receive time.
+    fn frame_decoded_meta(
+        self,
+        config: Config,
+        link: SharedLink,
+    ) -> impl Stream<Item = Result<(FrameBody, FrameMeta), CodecError>>;
 }
 
 impl<F> Decoded for F
 where
     F: Stream<Item = FrameSet<FramesMut>>,
 {
-    fn frame_decoded(self, config: Config) -> impl Stream<Item = Result<FrameBody, CodecError>> {
-        self.map(Ok)
+    fn frame_decoded(
+        self,
+        config: Config,
+        link: SharedLink,
+    ) -> impl Stream<Item = Result<FrameBody, CodecError>> {
+        let meta_link = Arc::clone(&link);
+        self.frame_decoded_meta(config, link)
+            .map_ok(move |(body, meta)| {
+                // kept for the connection streams yielding the metadata alongside the payloads
+                if matches!(body, FrameBody::User(_)) {
+                    meta_link.set_last_frame_meta(meta);
+                }
+                body
+            })
+    }
+
+    fn frame_decoded_meta(
+        self,
+        config: Config,
+        link: SharedLink,
+    ) -> impl Stream<Item = Result<(FrameBody, FrameMeta), CodecError>> {
+        let frame = self
+            .map(Ok)
             .trace_pending()
             .deduplicated()
+            .decrypted(config.crypto);
+        #[cfg(feature = "fec")]
+        let frame = frame.fec_recovered(config.fec, Arc::clone(&link));
+        frame
             .defragmented(config.max_parted_size, config.max_parted_count)
-            .ordered(config.max_channels)
+            .decompressed(config.compression)
+            .ordered(config.max_channels, config.max_reorder_depth, link)
             .body_decoded()
     }
 }
@@ -109,7 +219,28 @@ where
         config: Config,
         link: SharedLink,
     ) -> impl Sink<Message, Error = io::Error> + Sink<FrameBody, Error = io::Error> {
-        self.fragmented(mtu as usize, config.max_channels)
+        // leave room for the growth of the encrypted bodies and the checksum
+        let checksum = if config.checksum {
+            FRAME_SET_CHECKSUM_SIZE
+        } else {
+            0
+        };
+        let mut overhead = checksum;
+        if let Some(crypto) = &config.crypto {
+            overhead += crypto.overhead();
+        }
+        let frame = self.capped(mtu as usize, checksum).encrypted(config.crypto);
+        #[cfg(feature = "fec")]
+        let frame = {
+            if config.fec.is_some() {
+                overhead += fec::FEC_OVERHEAD;
+            }
+            frame.fec_encoded(config.fec)
+        };
+        frame
+            .fragmented(mtu as usize - overhead, config.max_channels)
+            .follow_link_mtu(Arc::clone(&link), overhead)
+            .compressed(config.compression)
             .body_encoded(link)
     }
 }
@@ -121,6 +252,7 @@ pub mod micro_bench {
     use std::io;
     use std::pin::Pin;
     use std::task::{Context, Poll};
+    use std::time::Duration;
 
     use bytes::{Bytes, BytesMut};
     use futures::{Sink, StreamExt};
@@ -128,10 +260,11 @@ pub mod micro_bench {
     use rand::seq::SliceRandom;
     use rand::{Rng, SeedableRng};
 
-    use super::{Config, Decoded, Fragmented, FrameSet, FramesMut, Stream};
+    use super::{Config, Decoded, Fragmented, FrameSet, FramesMut, SocketAddr, Stream};
+    use crate::link::TransferLink;
     use crate::packet::connected::Frame;
     use crate::packet::FRAME_SET_HEADER_SIZE;
-    use crate::{Message, Reliability};
+    use crate::{Message, Peer, Reliability, Role};
 
     #[derive(Debug, Clone)]
     pub struct BenchOpts {
@@ -234,8 +367,17 @@ pub mod micro_bench {
             } else {
                 None
             };
+            let link = TransferLink::new_arc(
+                Role::Server { guid: 0 },
+                Peer {
+                    guid: 0,
+                    addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+                    mtu: self.mtu as u16,
+                },
+                Duration::from_secs(1),
+            );
             tokio::pin! {
-                let decoding = self.gen_inputs().frame_decoded(Config::default());
+                let decoding = self.gen_inputs().frame_decoded(Config::default(), link);
             }
             while let Some(r) = decoding.next().await {
                 assert!(r.is_ok());
@@ -284,3 +426,362 @@ pub mod micro_bench {
         opts.run_bench().await;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::pin;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use bytes::{Bytes, BytesMut};
+    use futures::{Sink, SinkExt, StreamExt};
+
+    use super::{Config, Decoded, Encoded};
+    use crate::guard::HandleOutgoing;
+    use crate::link::{Route, SharedLink, TransferLink};
+    use crate::packet::connected::{
+        self, Flags, Fragment, Frame, FrameBody, FrameSet, Ordered, Received,
+        DEFAULT_MAX_ACK_COUNT, DUMMY_SYSTEM_ADDRESS, MAX_SYSTEM_ADDRESSES_ENDPOINTS,
+    };
+    use crate::packet::{unconnected, Packet, MAGIC};
+    use crate::utils::tests::{assert_golden, WireCapture};
+    use crate::{Message, Peer, Priority, Reliability, Role};
+
+    fn link() -> SharedLink {
+        TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1))
+    }
+
+    #[tokio::test]
+    async fn test_unfragmented_body_zero_copy() {
+        let frame = Frame {
+            flags: Flags::new(Reliability::ReliableOrdered, false),
+            reliable_frame_index: Some(0.into()),
+            seq_frame_index: None,
+            ordered: Some(Ordered {
+                frame_index: 0.into(),
+                channel: 0,
+            }),
+            fragment: None,
+            priority: Priority::Normal,
+            coalesce: false,
+            received: None,
+            deadline: None,
+            abandon_at: None,
+            receipt: None,
+            retransmits: 0,
+            body: Bytes::from_static(&[0xfe; 512]),
+        };
+        let mut buf = BytesMut::new();
+        Packet::Connected(connected::Packet::FrameSet(FrameSet {
+            seq_num: 0.into(),
+            set: &[frame][..],
+        }))
+        .write(&mut buf, &MAGIC, false);
+        // the allocation of the receive buffer
+        let start = buf.as_ptr() as usize;
+        let range = start..start + buf.len();
+
+        let Packet::Connected(connected::Packet::FrameSet(frame_set)) =
+            Packet::read(&mut buf, &MAGIC, false, DEFAULT_MAX_ACK_COUNT).unwrap()
+        else {
+            panic!("expect a frame set");
+        };
+        let mut decoded = std::pin::pin!(
+            futures::stream::iter([frame_set]).frame_decoded(Config::default(), link())
+        );
+        let Some(Ok(FrameBody::User(body))) = decoded.next().await else {
+            panic!("expect a user packet");
+        };
+        assert_eq!(body.len(), 512);
+        // the body points into the receive buffer, no intermediate copy occurs
+        assert!(range.contains(&(body.as_ptr() as usize)));
+        assert!(range.contains(&(body.as_ptr() as usize + body.len() - 1)));
+    }
+
+    #[tokio::test]
+    async fn test_decoded_meta_works() {
+        let at = Instant::now();
+        let part = |parted_index: u32, body: &'static [u8]| Frame {
+            flags: Flags::new(Reliability::ReliableOrdered, true),
+            reliable_frame_index: Some(parted_index.into()),
+            seq_frame_index: None,
+            ordered: Some(Ordered {
+                frame_index: 0.into(),
+                channel: 0,
+            }),
+            fragment: Some(Fragment {
+                parted_size: 2,
+                parted_id: 7,
+                parted_index,
+            }),
+            priority: Priority::Normal,
+            coalesce: false,
+            received: Some(Received {
+                at: at + Duration::from_millis(u64::from(parted_index)),
+                fragment: None,
+            }),
+            deadline: None,
+            abandon_at: None,
+            receipt: None,
+            retransmits: 0,
+            body: BytesMut::from(body),
+        };
+        let frame_sets = [
+            FrameSet {
+                seq_num: 1.into(),
+                set: vec![part(1, b"lo")],
+            },
+            FrameSet {
+                seq_num: 0.into(),
+                set: vec![part(0, b"\xfehel")],
+            },
+        ];
+        let mut decoded = std::pin::pin!(futures::stream::iter(frame_sets).frame_decoded_meta(
+            Config {
+                max_channels: 1,
+                ..Config::default()
+            },
+            link()
+        ));
+        let Some(Ok((FrameBody::User(body), meta))) = decoded.next().await else {
+            panic!("expect a user packet");
+        };
+        assert_eq!(body, Bytes::from_static(b"\xfehello"));
+        assert_eq!(meta.reliability(), Reliability::ReliableOrdered);
+        assert_eq!(meta.order_channel(), Some(0));
+        assert_eq!(meta.order_index(), Some(0));
+        assert_eq!(meta.sequence_index(), None);
+        assert_eq!(meta.parted_size(), Some(2));
+        assert_eq!(meta.parted_id(), Some(7));
+        // the receive time of the last part
+        assert_eq!(meta.received_at(), at);
+    }
+
+    const MAGIC_DUMP: &str = "00 ff ff 00 fe fe fe fe fd fd fd fd 12 34 56 78";
+    // 127.0.0.1:19132
+    const SERVER_ADDR_DUMP: &str = "04 80 ff ff fe 4a bc";
+    // 192.168.1.2:19132
+    const CLIENT_ADDR_DUMP: &str = "04 3f 57 fe fd 4a bc";
+
+    /// The outgoing pipeline of a connection sending to the wire capture
+    fn wire_pipeline(
+        role: Role,
+        peer: Peer,
+        config: Config,
+    ) -> (
+        SharedLink,
+        WireCapture,
+        impl Sink<Message, Error = io::Error> + Sink<FrameBody, Error = io::Error>,
+    ) {
+        let link = TransferLink::new_arc(role, peer, Duration::from_secs(1));
+        let capture = WireCapture::default();
+        let pipeline = capture
+            .clone()
+            .handle_outgoing(Arc::clone(&link), 1024, 0, peer, role)
+            .frame_encoded(peer.mtu, config, Arc::clone(&link));
+        (link, capture, pipeline)
+    }
+
+    // the system addresses: 0.0.0.0:0 followed by 19 dummy addresses 255.255.255.255:0
+    fn system_addresses() -> ([SocketAddr; MAX_SYSTEM_ADDRESSES_ENDPOINTS], String) {
+        let mut addresses = [DUMMY_SYSTEM_ADDRESS; MAX_SYSTEM_ADDRESSES_ENDPOINTS];
+        addresses[0] = "0.0.0.0:0".parse().unwrap();
+        let dump = format!(
+            "04 ff ff ff ff 00 00 {}",
+            "04 00 00 00 00 00 00 ".repeat(MAX_SYSTEM_ADDRESSES_ENDPOINTS - 1)
+        );
+        (addresses, dump)
+    }
+
+    #[tokio::test]
+    async fn test_golden_handshake() {
+        let server_addr: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let client_addr: SocketAddr = "192.168.1.2:19132".parse().unwrap();
+        let (client_link, client_capture, client) = wire_pipeline(
+            Role::Client { guid: 256 },
+            Peer {
+                guid: 1,
+                addr: server_addr,
+                mtu: 1400,
+            },
+            Config::default(),
+        );
+        let (server_link, server_capture, server) = wire_pipeline(
+            Role::Server { guid: 1 },
+            Peer {
+                guid: 256,
+                addr: client_addr,
+                mtu: 1400,
+            },
+            Config::default(),
+        );
+        let mut client = pin!(client);
+        let mut server = pin!(server);
+        let (system_addresses, system_dump) = system_addresses();
+
+        client_link.send_unconnected(unconnected::Packet::OpenConnectionRequest1 {
+            magic: (),
+            protocol_version: 11,
+            mtu: 1400,
+        });
+        SinkExt::<Message>::flush(&mut client).await.unwrap();
+        server_link.send_unconnected(unconnected::Packet::OpenConnectionReply1 {
+            magic: (),
+            server_guid: 1,
+            cookie: None,
+            mtu: 1400,
+        });
+        SinkExt::<Message>::flush(&mut server).await.unwrap();
+        client_link.send_unconnected(unconnected::Packet::OpenConnectionRequest2 {
+            magic: (),
+            cookie: None,
+            server_address: server_addr,
+            mtu: 1400,
+            client_guid: 256,
+        });
+        SinkExt::<Message>::flush(&mut client).await.unwrap();
+        server_link.send_unconnected(unconnected::Packet::OpenConnectionReply2 {
+            magic: (),
+            server_guid: 1,
+            client_address: client_addr,
+            mtu: 1400,
+            encryption_enabled: false,
+        });
+        SinkExt::<Message>::flush(&mut server).await.unwrap();
+        client
+            .send(FrameBody::ConnectionRequest {
+                client_guid: 256,
+                request_timestamp: 123_456,
+                use_encryption: false,
+            })
+            .await
+            .unwrap();
+        server
+            .send(FrameBody::ConnectionRequestAccepted {
+                client_address: client_addr,
+                system_index: 0,
+                system_addresses,
+                request_timestamp: 123_456,
+                accepted_timestamp: 123_457,
+            })
+            .await
+            .unwrap();
+        client
+            .send(FrameBody::NewIncomingConnection {
+                server_address: server_addr,
+                system_addresses,
+                request_timestamp: 123_456,
+                accepted_timestamp: 123_457,
+            })
+            .await
+            .unwrap();
+
+        let from_client = client_capture.take();
+        assert_eq!(from_client.len(), 4);
+        // the request 1 is padded to the MTU without the IP and the UDP headers
+        assert_eq!(from_client[0].len(), 1400 - 28);
+        assert_golden(&from_client[0][..18], &format!("05 {MAGIC_DUMP} 0b"));
+        assert!(from_client[0][18..].iter().all(|byte| *byte == 0));
+        assert_eq!(
+            Packet::read(
+                &mut BytesMut::from(&from_client[0][..]),
+                &MAGIC,
+                false,
+                DEFAULT_MAX_ACK_COUNT
+            )
+            .unwrap(),
+            Packet::Unconnected(unconnected::Packet::OpenConnectionRequest1 {
+                magic: (),
+                protocol_version: 11,
+                mtu: 1400,
+            })
+        );
+        assert_golden(
+            &from_client[1],
+            &format!("07 {MAGIC_DUMP} {SERVER_ADDR_DUMP} 05 78 00 00 00 00 00 00 01 00"),
+        );
+        // reliable ordered, 144 bits, reliable index 0, order index 0 on channel 0
+        assert_golden(
+            &from_client[2],
+            "84 00 00 00 60 00 90 00 00 00 00 00 00 00
+             09 00 00 00 00 00 00 01 00 00 00 00 00 00 01 e2 40 00",
+        );
+        // reliable ordered, 1312 bits, reliable index 1, order index 1 on channel 0
+        assert_golden(
+            &from_client[3],
+            &format!(
+                "84 01 00 00 60 05 20 01 00 00 01 00 00 00
+                 13 {SERVER_ADDR_DUMP} {system_dump}
+                 00 00 00 00 00 01 e2 40 00 00 00 00 00 01 e2 41"
+            ),
+        );
+
+        let from_server = server_capture.take();
+        assert_eq!(from_server.len(), 3);
+        assert_golden(
+            &from_server[0],
+            &format!("06 {MAGIC_DUMP} 00 00 00 00 00 00 00 01 00 05 78"),
+        );
+        assert_golden(
+            &from_server[1],
+            &format!("08 {MAGIC_DUMP} 00 00 00 00 00 00 00 01 {CLIENT_ADDR_DUMP} 05 78 00"),
+        );
+        // reliable, 1328 bits, reliable index 0
+        assert_golden(
+            &from_server[2],
+            &format!(
+                "84 00 00 00 40 05 30 00 00 00
+                 10 {CLIENT_ADDR_DUMP} 00 00 {system_dump}
+                 00 00 00 00 00 01 e2 40 00 00 00 00 00 01 e2 41"
+            ),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_golden_reliable_ordered() {
+        let (link, capture, pipeline) = wire_pipeline(
+            Role::test_server(),
+            Peer::test(),
+            Config {
+                max_channels: 2,
+                ..Config::default()
+            },
+        );
+        let mut pipeline = pin!(pipeline);
+        // the frame set 3 is missing
+        let (mut route, _rx) = Route::new(Arc::clone(&link), 16);
+        for seq_num in [0, 1, 2, 4] {
+            assert!(route.deliver(connected::Packet::FrameSet(FrameSet {
+                seq_num: seq_num.into(),
+                set: vec![],
+            })));
+        }
+        for body in [b"\xfehi", b"\xfeok"] {
+            pipeline
+                .as_mut()
+                .start_send(Message::new(
+                    Reliability::ReliableOrdered,
+                    1,
+                    Bytes::from_static(body),
+                ))
+                .unwrap();
+        }
+        SinkExt::<Message>::flush(&mut pipeline).await.unwrap();
+
+        let sent = capture.take();
+        assert_eq!(sent.len(), 3);
+        // a single record of 3
+        assert_golden(&sent[0], "a0 00 01 01 03 00 00");
+        // a range of [0, 2] and a single record of 4
+        assert_golden(&sent[1], "c0 00 02 00 00 00 00 02 00 00 01 04 00 00");
+        // two frames of 24 bits, reliable index 0 and 1, order index 0 and 1 on channel 1
+        assert_golden(
+            &sent[2],
+            "84 00 00 00
+             60 00 18 00 00 00 00 00 00 01 fe 68 69
+             60 00 18 01 00 00 01 00 00 01 fe 6f 6b",
+        );
+    }
+}
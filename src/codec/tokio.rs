@@ -7,6 +7,61 @@ use bytes::{BufMut, BytesMut};
 use tokio::net::UdpSocket as TokioUdpSocket;
 
 use super::AsyncSocket;
+use crate::opts::SocketOptions;
+
+impl SocketOptions {
+    /// Apply the options to the socket
+    pub(crate) fn apply(&self, socket: &TokioUdpSocket) -> io::Result<()> {
+        let sock = socket2::SockRef::from(socket);
+        if let Some(size) = self.recv_buf_size {
+            sock.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buf_size {
+            sock.set_send_buffer_size(size)?;
+        }
+        if let Some(dscp) = self.dscp {
+            // the DSCP takes the upper 6 bits of the ToS/traffic class, ECN is left as 0
+            let tos = u32::from(dscp) << 2;
+            if socket.local_addr()?.is_ipv6() {
+                set_tclass_v6(&sock, tos)?;
+            } else {
+                sock.set_tos_v4(tos)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "illumos",
+))]
+fn set_tclass_v6(sock: &socket2::SockRef<'_>, tclass: u32) -> io::Result<()> {
+    sock.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "illumos",
+)))]
+fn set_tclass_v6(_sock: &socket2::SockRef<'_>, _tclass: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the DSCP of IPv6 sockets is not supported on this platform",
+    ))
+}
 
 impl<S: AsRef<TokioUdpSocket> + Unpin> AsyncSocket for S {
     fn poll_recv_from(
@@ -39,4 +94,394 @@ impl<S: AsRef<TokioUdpSocket> + Unpin> AsyncSocket for S {
     ) -> Poll<io::Result<usize>> {
         self.as_ref().poll_send_to(cx, buf, target)
     }
+
+    /// Receive the batch by one `recvmmsg` syscall
+    #[cfg(target_os = "linux")]
+    fn poll_recv_batch(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [BytesMut],
+        addrs: &mut [SocketAddr],
+    ) -> Poll<io::Result<usize>> {
+        use std::os::fd::AsRawFd;
+
+        if bufs.len() == 1 {
+            addrs[0] = ready!(self.poll_recv_from(cx, &mut bufs[0]))?;
+            return Poll::Ready(Ok(1));
+        }
+        let socket = self.as_ref();
+        loop {
+            ready!(socket.poll_recv_ready(cx))?;
+            match socket.try_io(tokio::io::Interest::READABLE, || {
+                recvmmsg(socket.as_raw_fd(), bufs, addrs)
+            }) {
+                // the readiness is cleared, poll again to register the waker
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+
+    /// Submit the batch by one `sendmmsg` syscall
+    #[cfg(target_os = "linux")]
+    fn poll_send_batch(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &[(&[u8], SocketAddr)],
+    ) -> Poll<io::Result<usize>> {
+        use std::os::fd::AsRawFd;
+
+        let socket = self.as_ref();
+        loop {
+            ready!(socket.poll_send_ready(cx))?;
+            match socket.try_io(tokio::io::Interest::WRITABLE, || {
+                sendmmsg(socket.as_raw_fd(), bufs)
+            }) {
+                // the readiness is cleared, poll again to register the waker
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sendmmsg(fd: std::os::fd::RawFd, bufs: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+    use std::mem;
+
+    let mut addrs = Vec::with_capacity(bufs.len());
+    let mut iovecs = Vec::with_capacity(bufs.len());
+    for &(buf, target) in bufs {
+        addrs.push(sockaddr(target));
+        iovecs.push(libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        });
+    }
+    let mut msgs: Vec<libc::mmsghdr> = addrs
+        .iter_mut()
+        .zip(iovecs.iter_mut())
+        .map(|((addr, len), iovec)| {
+            // Safety: all-zero is a valid value of `msghdr`
+            let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+            hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+            hdr.msg_namelen = *len;
+            hdr.msg_iov = iovec;
+            hdr.msg_iovlen = 1;
+            libc::mmsghdr {
+                msg_hdr: hdr,
+                msg_len: 0,
+            }
+        })
+        .collect();
+
+    // Safety: the pointers in `msgs` point to `addrs`, `iovecs` and `bufs`, which outlive the call
+    let n = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as libc::c_uint, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+#[cfg(target_os = "linux")]
+fn recvmmsg(
+    fd: std::os::fd::RawFd,
+    bufs: &mut [BytesMut],
+    addrs: &mut [SocketAddr],
+) -> io::Result<usize> {
+    use std::{mem, ptr};
+
+    // Safety: all-zero is a valid value of `sockaddr_storage`
+    let mut storages: Vec<libc::sockaddr_storage> =
+        (0..bufs.len()).map(|_| unsafe { mem::zeroed() }).collect();
+    let mut iovecs: Vec<libc::iovec> = bufs
+        .iter_mut()
+        .map(|buf| {
+            let chunk = buf.chunk_mut();
+            libc::iovec {
+                iov_base: chunk.as_mut_ptr() as *mut libc::c_void,
+                iov_len: chunk.len(),
+            }
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = storages
+        .iter_mut()
+        .zip(iovecs.iter_mut())
+        .map(|(storage, iovec)| {
+            // Safety: all-zero is a valid value of `msghdr`
+            let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+            hdr.msg_name = storage as *mut libc::sockaddr_storage as *mut libc::c_void;
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = iovec;
+            hdr.msg_iovlen = 1;
+            libc::mmsghdr {
+                msg_hdr: hdr,
+                msg_len: 0,
+            }
+        })
+        .collect();
+
+    // Safety: the pointers in `msgs` point to `storages`, `iovecs` and the spare capacity of
+    // `bufs`, which outlive the call
+    let n = unsafe {
+        libc::recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            0,
+            ptr::null_mut(),
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let n = n as usize;
+    for ((buf, addr), (msg, storage)) in bufs
+        .iter_mut()
+        .zip(addrs.iter_mut())
+        .zip(msgs.iter().zip(storages.iter()))
+        .take(n)
+    {
+        // Safety: the kernel has initialized `msg_len` bytes of the spare capacity
+        unsafe { buf.advance_mut(msg.msg_len as usize) };
+        *addr = socket_addr(storage)?;
+    }
+    Ok(n)
+}
+
+#[cfg(target_os = "linux")]
+fn socket_addr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // Safety: the storage holds a `sockaddr_in` as the family indicates
+            let sin =
+                unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            Ok(SocketAddr::from((
+                Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // Safety: the storage holds a `sockaddr_in6` as the family indicates
+            let sin6 = unsafe {
+                &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6)
+            };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported address family",
+        )),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    use std::mem;
+
+    // Safety: all-zero is a valid value of `sockaddr_storage`
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            // Safety: `sockaddr_storage` is large enough to hold any socket address
+            unsafe {
+                (&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in).write(sin);
+            }
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            // Safety: `sockaddr_storage` is large enough to hold any socket address
+            unsafe {
+                (&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6)
+                    .write(sin6);
+            }
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Micro bench helper
+#[cfg(feature = "micro-bench")]
+pub mod micro_bench {
+    use std::future::poll_fn;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+    use tokio::net::UdpSocket;
+
+    use super::AsyncSocket;
+
+    /// The datagrams sent or received over the socket, `batch` datagrams for each batched call
+    #[derive(Debug, Clone, Copy)]
+    pub struct BatchOpts {
+        pub datagrams: usize,
+        pub size: usize,
+        pub batch: usize,
+    }
+
+    impl BatchOpts {
+        /// Send all datagrams to the target by `poll_send_batch`, returns the number of the
+        /// datagrams sent
+        #[allow(clippy::missing_errors_doc)]
+        pub async fn send(&self, socket: &Arc<UdpSocket>, target: SocketAddr) -> io::Result<usize> {
+            let payload = vec![0xfe; self.size];
+            let bufs = vec![(&payload[..], target); self.batch];
+            let mut sent = 0;
+            while sent < self.datagrams {
+                let n = self.batch.min(self.datagrams - sent);
+                sent += poll_fn(|cx| socket.poll_send_batch(cx, &bufs[..n])).await?;
+            }
+            Ok(sent)
+        }
+
+        /// Receive the datagrams queued in the socket by `poll_recv_batch`, returns the number of
+        /// the datagrams received
+        #[allow(clippy::missing_errors_doc)]
+        pub async fn recv(&self, socket: &Arc<UdpSocket>) -> io::Result<usize> {
+            let mut bufs: Vec<BytesMut> = (0..self.batch)
+                .map(|_| BytesMut::with_capacity(self.size))
+                .collect();
+            let mut addrs = vec![SocketAddr::from(([0, 0, 0, 0], 0)); self.batch];
+            let mut received = 0;
+            while received < self.datagrams {
+                bufs.iter_mut().for_each(BytesMut::clear);
+                let n = self.batch.min(self.datagrams - received);
+                received +=
+                    poll_fn(|cx| socket.poll_recv_batch(cx, &mut bufs[..n], &mut addrs[..n]))
+                        .await?;
+            }
+            Ok(received)
+        }
+
+        pub fn elements(&self) -> u64 {
+            self.datagrams as u64
+        }
+    }
+
+    #[cfg(test)]
+    #[tokio::test]
+    async fn test_bench() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let opts = BatchOpts {
+            datagrams: 50,
+            size: 200,
+            batch: 32,
+        };
+        let sent = opts
+            .send(&socket, peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(sent, 50);
+        let mut buf = [0; 256];
+        let (len, _) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(len, 200);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::poll_fn;
+    use std::sync::Arc;
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    async fn batch_io(local: &str) {
+        let socket = Arc::new(UdpSocket::bind(local).await.unwrap());
+        let peer1 = UdpSocket::bind(local).await.unwrap();
+        let peer2 = UdpSocket::bind(local).await.unwrap();
+        let bufs: [(&[u8], SocketAddr); 3] = [
+            (b"1", peer1.local_addr().unwrap()),
+            (b"22", peer2.local_addr().unwrap()),
+            (b"333", peer1.local_addr().unwrap()),
+        ];
+        let n = poll_fn(|cx| socket.poll_send_batch(cx, &bufs))
+            .await
+            .unwrap();
+        assert_eq!(n, 3);
+
+        let peer1 = Arc::new(peer1);
+        let mut rd = vec![BytesMut::with_capacity(8); 4];
+        let mut addrs = [SocketAddr::from(([0, 0, 0, 0], 0)); 4];
+        let mut received = 0;
+        while received < 2 {
+            received += poll_fn(|cx| {
+                peer1.poll_recv_batch(cx, &mut rd[received..], &mut addrs[received..])
+            })
+            .await
+            .unwrap();
+        }
+        assert_eq!(received, 2);
+        assert_eq!(&rd[0][..], b"1");
+        assert_eq!(&rd[1][..], b"333");
+        assert_eq!(addrs[0], socket.local_addr().unwrap());
+        assert_eq!(addrs[1], socket.local_addr().unwrap());
+
+        let mut buf = [0; 8];
+        let (len, _) = peer2.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"22");
+    }
+
+    #[tokio::test]
+    async fn test_batch_io_works() {
+        batch_io("127.0.0.1:0").await;
+        if crate::utils::tests::ipv6_available() {
+            batch_io("[::1]:0").await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socket_options_works() {
+        let options = SocketOptions::new()
+            .recv_buf_size(64 * 1024)
+            .send_buf_size(64 * 1024)
+            .dscp(46);
+
+        let v4 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        options.apply(&v4).unwrap();
+        let sock = socket2::SockRef::from(&v4);
+        // the kernel may round the sizes up
+        assert!(sock.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert!(sock.send_buffer_size().unwrap() >= 64 * 1024);
+        assert_eq!(sock.tos_v4().unwrap(), 46 << 2);
+
+        if !crate::utils::tests::ipv6_available() {
+            return;
+        }
+        let socket = UdpSocket::bind("[::1]:0").await.unwrap();
+        options.apply(&socket).unwrap();
+        assert_eq!(
+            socket2::SockRef::from(&socket).tclass_v6().unwrap(),
+            46 << 2
+        );
+    }
 }
@@ -0,0 +1,55 @@
+use alloc::string::String;
+
+/// The errors of the runtime
+#[cfg(feature = "std")]
+mod runtime;
+
+#[cfg(feature = "std")]
+pub use self::runtime::*;
+
+/// Errors occurred when decoding packets, it only implements `Debug` without `std`
+#[derive(Debug)]
+#[cfg_attr(feature = "std", derive(thiserror::Error))]
+pub enum CodecError {
+    #[cfg(feature = "std")]
+    #[error("io error {0}")]
+    IO(#[from] std::io::Error),
+    #[cfg_attr(feature = "std", error("invalid ip version {0}"))]
+    InvalidIPVer(u8),
+    #[cfg_attr(feature = "std", error("expect IPv6 family 0x17, got {0}"))]
+    InvalidIPV6Family(u16),
+    #[cfg_attr(feature = "std", error("invalid packet length when decode {0}"))]
+    InvalidPacketLength(&'static str),
+    #[cfg_attr(feature = "std", error("invalid record type {0}"))]
+    InvalidRecordType(u8),
+    #[cfg_attr(feature = "std", error("invalid record range {0}-{1}"))]
+    InvalidRecordRange(u32, u32),
+    #[cfg_attr(feature = "std", error("invalid reliability {0}"))]
+    InvalidReliability(u8),
+    #[cfg_attr(
+        feature = "std",
+        error("invalid packet type {0}, maybe it is a user packet")
+    )]
+    InvalidPacketType(u8),
+    #[cfg_attr(feature = "std", error("parted frame error, reason: {0}"))]
+    PartedFrame(String),
+    #[cfg_attr(feature = "std", error("ordered frame error, reason: {0}"))]
+    OrderedFrame(String),
+    #[cfg_attr(
+        feature = "std",
+        error("maximum amount of packets in acknowledgement exceeded")
+    )]
+    AckCountExceed,
+    #[cfg_attr(feature = "std", error("magic number not matched, pos {0}, byte {1}"))]
+    MagicNotMatched(usize, u8),
+    #[cfg_attr(
+        feature = "std",
+        error("frame set checksum mismatch, expect {0:#010x}, got {1:#010x}")
+    )]
+    ChecksumMismatch(u32, u32),
+    #[cfg_attr(
+        feature = "std",
+        error("frame of {0} bytes exceeds the limit {1} of a frame set")
+    )]
+    FrameTooLarge(usize, usize),
+}
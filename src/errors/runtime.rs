@@ -0,0 +1,180 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::CodecError;
+
+/// The error carried by the [`std::io::ErrorKind::TimedOut`] error returned from closing, when the
+/// outstanding reliable frames were not acknowledged before the close timeout and were abandoned
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("close timed out with {unacked} unacked frames")]
+pub struct CloseTimedOut {
+    unacked: usize,
+}
+
+impl CloseTimedOut {
+    pub(crate) fn new(unacked: usize) -> Self {
+        Self { unacked }
+    }
+
+    /// Get the number of abandoned frames which were not acknowledged by the peer
+    pub fn unacked(&self) -> usize {
+        self.unacked
+    }
+}
+
+/// The error carried by the [`std::io::ErrorKind::TimedOut`] error returned from flushing, when a
+/// reliable frame expired before it was sent and the expired reliable frames are configured to
+/// fail. The expired frame is dropped, so the connection should be closed as the peer never
+/// receives it.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("reliable frame expired {late:?} ago before sending")]
+pub struct FrameExpired {
+    late: Duration,
+}
+
+impl FrameExpired {
+    pub(crate) fn new(late: Duration) -> Self {
+        Self { late }
+    }
+
+    /// Get how long the frame had expired when it was going to be sent
+    pub fn late(&self) -> Duration {
+        self.late
+    }
+}
+
+/// The error carried by the [`std::io::ErrorKind::TimedOut`] error returned from receiving with a
+/// deadline, when nothing was received from the peer before it
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("receive timed out after waiting {waited:?}")]
+pub struct RecvTimedOut {
+    waited: Duration,
+}
+
+impl RecvTimedOut {
+    pub(crate) fn new(waited: Duration) -> Self {
+        Self { waited }
+    }
+
+    /// Get how long the receiving had waited
+    pub fn waited(&self) -> Duration {
+        self.waited
+    }
+}
+
+/// The error returned from building a config, it tells which option is invalid. It converts into
+/// [`io::Error`] of [`io::ErrorKind::InvalidInput`] carrying it.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("invalid config option `{option}`, {reason}")]
+pub struct ConfigError {
+    option: &'static str,
+    reason: &'static str,
+}
+
+impl ConfigError {
+    pub(crate) fn new(option: &'static str, reason: &'static str) -> Self {
+        Self { option, reason }
+    }
+
+    /// Get the name of the invalid option
+    pub fn option(&self) -> &'static str {
+        self.option
+    }
+
+    /// Get why the option is invalid
+    pub fn reason(&self) -> &'static str {
+        self.reason
+    }
+}
+
+impl From<ConfigError> for io::Error {
+    fn from(err: ConfigError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// The error returned from connecting to a server, it tells why the offline handshake failed. It
+/// converts into [`io::Error`] of the matching kind carrying it.
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum ConnectError {
+    /// None of the addresses could be connected
+    #[error("no address available to connect")]
+    NoAddress,
+    /// The server does not support the protocol version of the client
+    #[error("incompatible protocol version, the server protocol is {server_protocol}")]
+    IncompatibleVersion {
+        /// The latest protocol version supported by the server
+        server_protocol: u8,
+    },
+    /// The server has a connection from the same address
+    #[error("already connected")]
+    AlreadyConnected,
+    /// The guid of the client collides with the server
+    #[error("the client guid collides with the server")]
+    GuidCollision,
+    /// The server reached its limit of connections
+    #[error("no free incoming connections on the server")]
+    ServerFull,
+    /// The server refused the connection request
+    #[error("connection request failed")]
+    RequestFailed,
+    /// Only one of the server and the client encrypts the frame bodies
+    #[error("encryption mismatch, the server encryption is {server_encryption}")]
+    EncryptionMismatch {
+        /// Whether the server encrypts the frame bodies
+        server_encryption: bool,
+    },
+    /// The socket ended before the handshake finished
+    #[error("connection reset by peer")]
+    Reset,
+    /// The config of the client is invalid
+    #[error("{0}")]
+    InvalidConfig(#[from] ConfigError),
+    /// The IO error of the socket
+    #[error("io error {0}")]
+    Io(#[from] io::Error),
+}
+
+impl From<ConnectError> for io::Error {
+    fn from(err: ConnectError) -> Self {
+        let kind = match err {
+            ConnectError::Io(err) => return err,
+            ConnectError::NoAddress => io::ErrorKind::AddrNotAvailable,
+            ConnectError::AlreadyConnected | ConnectError::GuidCollision => {
+                io::ErrorKind::AddrInUse
+            }
+            ConnectError::IncompatibleVersion { .. }
+            | ConnectError::ServerFull
+            | ConnectError::RequestFailed
+            | ConnectError::EncryptionMismatch { .. } => io::ErrorKind::ConnectionRefused,
+            ConnectError::Reset => io::ErrorKind::ConnectionReset,
+            ConnectError::InvalidConfig(err) => return err.into(),
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+type DecodeErrorFn = dyn Fn(SocketAddr, &CodecError) + Send + Sync;
+
+/// The hook receives the decode errors with the address of the peer
+#[derive(Clone)]
+pub(crate) struct DecodeErrorHook(Arc<DecodeErrorFn>);
+
+impl std::fmt::Debug for DecodeErrorHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DecodeErrorHook(..)")
+    }
+}
+
+impl DecodeErrorHook {
+    pub(crate) fn new(hook: impl Fn(SocketAddr, &CodecError) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+
+    pub(crate) fn call(&self, addr: SocketAddr, err: &CodecError) {
+        (self.0)(addr, err);
+    }
+}
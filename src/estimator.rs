@@ -1,9 +1,15 @@
 use std::cmp;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 /// The granularity of the timer
 const TIMER_GRANULARITY: Duration = Duration::from_millis(1);
 
+/// The default lower bound of the RTO, RFC6298 2.4 suggests 1 second
+pub(crate) const DEFAULT_MIN_RTO: Duration = Duration::from_secs(1);
+
+/// The default upper bound of the RTO, RFC6298 2.5 allows a maximum of at least 60 seconds
+pub(crate) const DEFAULT_MAX_RTO: Duration = Duration::from_secs(60);
+
 pub(crate) trait Estimator {
     /// The current RTO estimation.
     fn rto(&self) -> Duration;
@@ -11,6 +17,10 @@ pub(crate) trait Estimator {
     /// Update the RTT estimator with a new RTT sample.
     fn update(&mut self, rtt: Duration);
 
+    /// Initialize the estimator with an RTT sample taken before any packet is acknowledged, e.g.
+    /// in the handshake. It is ignored if the estimator has been initialized.
+    fn seed(&mut self, rtt: Duration);
+
     /// Clear the estimator's state.
     fn clear(&mut self);
 }
@@ -24,14 +34,25 @@ pub struct RFC6298Impl {
     smoothed: Option<Duration>,
     /// The RTT variance, computed as described in RFC6298
     var: Duration,
+    /// The RTO is clamped to `[min, max]`
+    min: Duration,
+    max: Duration,
 }
 
 impl RFC6298Impl {
     pub(crate) fn new() -> Self {
+        Self::with_bounds(DEFAULT_MIN_RTO, DEFAULT_MAX_RTO)
+    }
+
+    /// Create an estimator whose RTO is clamped to `[min, max]`
+    pub(crate) fn with_bounds(min: Duration, max: Duration) -> Self {
+        debug_assert!(min <= max, "min RTO should not be larger than max RTO");
         Self {
             latest: Duration::from_secs(0),
             smoothed: None,
             var: Duration::from_secs(0),
+            min,
+            max,
         }
     }
 
@@ -42,13 +63,7 @@ impl RFC6298Impl {
 
     /// The current RTO estimation.
     pub(crate) fn rto(&self) -> Duration {
-        // TODO:
-        // RFC6298 2.4 suggests a minimum of 1 second, which may be
-        // a conservative choice for some applications.
-        cmp::max(
-            self.get() + cmp::max(TIMER_GRANULARITY, 4 * self.var),
-            Duration::from_secs(1),
-        )
+        (self.get() + cmp::max(TIMER_GRANULARITY, 4 * self.var)).clamp(self.min, self.max)
     }
 
     /// Once smoothed and var are cleared, they should be initialized with the next RTT sample
@@ -82,7 +97,113 @@ impl Estimator for RFC6298Impl {
         self.update(rtt);
     }
 
+    fn seed(&mut self, rtt: Duration) {
+        if self.smoothed.is_none() {
+            self.update(rtt);
+        }
+    }
+
     fn clear(&mut self) {
         self.clear();
     }
 }
+
+/// Loss estimation, the loss ratio of each window is smoothed into the estimation by EWMA with the
+/// same gain as the smoothed RTT (1/8). The windows without any packet are skipped.
+pub(crate) struct LossEstimator {
+    window: Duration,
+    window_start: Instant,
+    /// The number of packets in current window
+    total: u64,
+    /// The number of lost packets in current window
+    lost: u64,
+    smoothed: Option<f64>,
+}
+
+impl LossEstimator {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            window_start: Instant::now(),
+            total: 0,
+            lost: 0,
+            smoothed: None,
+        }
+    }
+
+    /// Fold the current window into the estimation if it is over
+    fn roll(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.window_start) < self.window {
+            return;
+        }
+        if self.total > 0 {
+            let sample = (self.lost as f64 / self.total as f64).min(1.0);
+            self.smoothed = Some(
+                self.smoothed
+                    .map_or(sample, |smoothed| (7.0 * smoothed + sample) / 8.0),
+            );
+        }
+        self.window_start = now;
+        self.total = 0;
+        self.lost = 0;
+    }
+
+    /// Record `total` packets of which `lost` are lost
+    pub(crate) fn record(&mut self, total: usize, lost: usize, now: Instant) {
+        self.roll(now);
+        self.total += total as u64;
+        self.lost += lost as u64;
+    }
+
+    /// The current loss rate estimation in `[0, 1]`, it is 0 before the first window is over
+    pub(crate) fn get(&mut self, now: Instant) -> f64 {
+        self.roll(now);
+        self.smoothed.unwrap_or(0.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{LossEstimator, RFC6298Impl};
+
+    #[test]
+    fn test_rfc6298_rto_bounds() {
+        let mut estimator = RFC6298Impl::new();
+        // no sample yet, the RTO starts at the lower bound
+        assert_eq!(estimator.rto(), Duration::from_secs(1));
+        // SRTT = 800ms, RTTVAR = 400ms
+        estimator.update(Duration::from_millis(800));
+        assert_eq!(estimator.rto(), Duration::from_millis(2400));
+        // RTTVAR = (3 * 400ms + |800ms - 400ms|) / 4 = 400ms, SRTT = (7 * 800ms + 400ms) / 8
+        estimator.update(Duration::from_millis(400));
+        assert_eq!(estimator.rto(), Duration::from_millis(2350));
+
+        let mut bounded =
+            RFC6298Impl::with_bounds(Duration::from_millis(100), Duration::from_millis(500));
+        bounded.update(Duration::from_millis(20));
+        // SRTT + 4 * RTTVAR = 60ms
+        assert_eq!(bounded.rto(), Duration::from_millis(100));
+        bounded.update(Duration::from_secs(10));
+        assert_eq!(bounded.rto(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_loss_estimator_works() {
+        let window = Duration::from_secs(1);
+        let mut estimator = LossEstimator::new(window);
+        let start = Instant::now();
+        estimator.record(10, 5, start);
+        // the window is not over yet
+        assert!(estimator.get(start).abs() < f64::EPSILON);
+
+        let second = start + window;
+        assert!((estimator.get(second) - 0.5).abs() < f64::EPSILON);
+        estimator.record(10, 0, second);
+        // the empty windows are skipped
+        let forth = second + window * 3;
+        assert!((estimator.get(forth) - 0.5 * 7.0 / 8.0).abs() < f64::EPSILON);
+        assert!((estimator.get(forth + window) - 0.5 * 7.0 / 8.0).abs() < f64::EPSILON);
+    }
+}
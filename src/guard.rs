@@ -2,20 +2,45 @@ use std::collections::{HashMap, VecDeque};
 use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{ready, Context, Poll};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use bytes::BytesMut;
+use fastrace::collector::SpanContext;
+use fastrace::Span;
 use futures::Sink;
-use log::trace;
+use log::{debug, trace, warn};
 use pin_project_lite::pin_project;
 
+use crate::errors::{CloseTimedOut, FrameExpired};
 use crate::estimator::{Estimator, RFC6298Impl};
 use crate::link::SharedLink;
-use crate::opts::FlushStrategy;
-use crate::packet::connected::{self, AckOrNack, Frame, FrameSet, Frames, FramesRef, Record};
-use crate::packet::{Packet, FRAME_SET_HEADER_SIZE};
-use crate::utils::{u24, ConnId, Reactor};
-use crate::{Peer, Role};
+use crate::opts::{
+    AckReceipt, FlushStrategy, FrameCompression, FrameCrypto, ReceiptHook, Retransmission,
+    RetransmitCause, RetransmitHook,
+};
+use crate::packet::connected::{
+    self, AckOrNack, Flags, Frame, FrameBody, FrameSet, Frames, FramesRef, Ordered, Record,
+};
+use crate::packet::{Packet, FRAME_SET_CHECKSUM_SIZE, FRAME_SET_HEADER_SIZE};
+use crate::state::Aborting;
+use crate::utils::{add_event, u24, FairShare, FairTurn, Pacer, Reactor, TimerHandle, TimerKind};
+use crate::{ConnId, Peer, Priority, Role};
+
+/// The maximum number of ACK (and NACK) packets sent before each frame set when flushing. A
+/// backlog of acknowledgements is drained in fewer rounds without starving the data.
+const MAX_ACK_PACKETS_PER_ROUND: usize = 4;
+
+#[cfg(test)]
+std::thread_local! {
+    /// Replaces the random initial sequence number of the guards created on this thread, so the
+    /// tests are able to start near the wrap around
+    pub(crate) static INITIAL_SEQ_NUM: std::cell::Cell<Option<u32>> = const { std::cell::Cell::new(None) };
+}
+
+/// The effective MTU is never reduced below the minimum datagram size every IPv4 host accepts
+const MIN_BLACKHOLE_MTU: usize = 576;
 
 pin_project! {
     // OutgoingGuard equips with ACK/NACK flusher and packets buffer and provides
@@ -25,19 +50,44 @@ pin_project! {
         frame: F,
         link: SharedLink,
         seq_num_write_index: u24,
-        buf: VecDeque<Frame>,
+        buf: OutgoingBuf,
         peer: Peer,
         role: Role,
-        cap: usize,
+        bytes_cap: usize,
         resend: ResendMap,
+        close_timeout: Option<Duration>,
+        close_deadline: Option<Instant>,
+        // the timer waking up at the close deadline
+        close_timer: Option<TimerHandle>,
+        // the number of unacked frames abandoned by closing timeout
+        abandoned: Option<usize>,
+        // the size of the frame set header, plus the CRC-32 if the checksum is enabled
+        frame_set_overhead: usize,
+        // whether the flushing fails when a reliable frame expired before it was sent
+        fail_expired_reliable: bool,
+        // the turn sharing the socket with other connections
+        fair_turn: Option<FairTurn>,
+        // limits the total size of the frames waiting for acknowledgement, 0 means no limit
+        resend_bytes_cap: usize,
+        // whether a buffered frame set is sent before the acks
+        data_first: bool,
+        // the acks were deferred for a frame set, they are not deferred again until sent
+        ack_deferred: bool,
+        // the maximum number of packets sent in one poll of flushing, 0 means no limit
+        flush_budget: usize,
+        // the span of the current flush round, the reliability events are recorded to it
+        span: Option<Span>,
     }
 }
 
 pub(crate) trait HandleOutgoing: Sized {
+    /// `cap` limits the number of buffered frames, and `bytes_cap` limits the total size of the
+    /// buffered frames in bytes (0 means no limit).
     fn handle_outgoing(
         self,
         link: SharedLink,
         cap: usize,
+        bytes_cap: usize,
         peer: Peer,
         role: Role,
     ) -> OutgoingGuard<Self>;
@@ -51,20 +101,249 @@ where
         self,
         link: SharedLink,
         cap: usize,
+        bytes_cap: usize,
         peer: Peer,
         role: Role,
     ) -> OutgoingGuard<Self> {
         assert!(cap > 0, "cap must larger than 0");
+        // the capacity is kept in the link so that it could be adjusted at runtime
+        link.set_send_buf_cap(cap);
         OutgoingGuard {
             frame: self,
             link,
             seq_num_write_index: 0.into(),
-            buf: VecDeque::with_capacity(cap),
+            buf: OutgoingBuf::with_capacity(cap),
             peer,
             role,
-            cap,
+            bytes_cap,
             resend: ResendMap::new(role, peer, Box::new(RFC6298Impl::new())),
+            close_timeout: None,
+            close_deadline: None,
+            close_timer: None,
+            abandoned: None,
+            frame_set_overhead: FRAME_SET_HEADER_SIZE,
+            fail_expired_reliable: false,
+            fair_turn: None,
+            resend_bytes_cap: 0,
+            data_first: false,
+            ack_deferred: false,
+            flush_budget: 0,
+            span: None,
+        }
+    }
+}
+
+impl<F> OutgoingGuard<F> {
+    /// Limit the time to wait for the outstanding reliable frames to be acknowledged when
+    /// closing, the unacked frames will be abandoned after that.
+    pub(crate) fn close_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.close_timeout = timeout;
+        self
+    }
+
+    /// Start the sequence numbers of the frame sets at a random number instead of 0, which makes
+    /// the off-path injection harder. The peer learns it from the first frame set.
+    pub(crate) fn random_initial_seq_num(mut self, enabled: bool) -> Self {
+        if enabled {
+            let seq_num = rand::random::<u32>() & 0xff_ffff;
+            #[cfg(test)]
+            let seq_num = INITIAL_SEQ_NUM
+                .with(std::cell::Cell::get)
+                .unwrap_or(seq_num);
+            self.seq_num_write_index = u24::from(seq_num);
+            self.resend.sent_until(self.seq_num_write_index);
+        }
+        self
+    }
+
+    /// Fail the flushing when a reliable frame expired before it was sent instead of sending it
+    /// late, the expired frame is dropped.
+    pub(crate) fn fail_expired_reliable(mut self, enabled: bool) -> Self {
+        self.fail_expired_reliable = enabled;
+        self
+    }
+
+    /// Share the send capacity of the socket with other connections on it, the guard yields after
+    /// sending a quantum of packets if other connections are backlogged.
+    pub(crate) fn fair_share(mut self, share: Option<&Arc<FairShare>>) -> Self {
+        self.fair_turn = share.map(FairShare::turn);
+        self
+    }
+
+    /// Yield after sending `budget` packets in one poll of flushing, the task is woken up to send
+    /// the rest so that other tasks on the executor get their turns (0 means no limit).
+    pub(crate) fn flush_budget(mut self, budget: usize) -> Self {
+        self.flush_budget = budget;
+        self
+    }
+
+    /// Limit the total size of the frames waiting for acknowledgement, no more frames are accepted
+    /// until the acknowledgements free the room (0 means no limit).
+    pub(crate) fn resend_bytes_cap(mut self, bytes: usize) -> Self {
+        self.resend_bytes_cap = bytes;
+        self
+    }
+
+    /// Clamp the RTO estimated from the acknowledged frame sets to `[min, max]`
+    pub(crate) fn rto_bounds(mut self, min: Duration, max: Duration) -> Self {
+        self.resend.estimator = Box::new(RFC6298Impl::with_bounds(min, max));
+        self
+    }
+
+    /// Invoke the hook whenever the frames of a lost frame set are queued to be resent
+    pub(crate) fn retransmit_hook(mut self, hook: Option<RetransmitHook>) -> Self {
+        self.resend.hook = hook;
+        self
+    }
+
+    /// Invoke the hook once the messages tagged with a receipt are acknowledged or lost
+    pub(crate) fn receipt_hook(mut self, hook: Option<ReceiptHook>) -> Self {
+        self.resend.receipts.hook = hook;
+        self
+    }
+
+    /// Reduce the effective MTU of the link after the frame sets larger than any acknowledged one
+    /// time out `retries` times in a row while the smaller ones get through (0 disables it).
+    pub(crate) fn mtu_blackhole_retries(mut self, retries: usize) -> Self {
+        self.resend.blackhole.retries = retries;
+        self
+    }
+
+    /// Encrypt the markers standing in for the abandoned frames as the other frame bodies
+    pub(crate) fn crypto(mut self, crypto: Option<Arc<dyn FrameCrypto>>) -> Self {
+        self.resend.crypto = crypto;
+        self
+    }
+
+    /// Send a buffered frame set before the acks instead of after them, the acks are deferred by
+    /// at most one frame set so that the peer's RTT samples are barely affected.
+    pub(crate) fn data_first(mut self, enabled: bool) -> Self {
+        self.data_first = enabled;
+        self
+    }
+
+    /// Space out the frame sets by the pacing rate, which starts conservatively and ramps up on
+    /// the acknowledgements
+    pub(crate) fn pacing(mut self, enabled: bool) -> Self {
+        let now = Instant::now();
+        let window = CongestionWindow::new(self.peer.mtu as usize);
+        self.resend.pacer = if enabled {
+            Pacer::new(window.rate(), PACING_BURST, now)
+        } else {
+            Pacer::unlimited()
+        };
+        self.resend.congestion = enabled.then_some(window);
+        self
+    }
+
+    /// Compress the markers standing in for the abandoned frames as the other frame bodies
+    pub(crate) fn compression(mut self, compression: Option<Arc<dyn FrameCompression>>) -> Self {
+        self.resend.compression = compression;
+        self
+    }
+
+    /// Run `f` under the span of the current flush round, which starts with the first frame sent
+    /// after the last round. The span ends once `f` is ready if `end` is set.
+    fn in_span<R>(
+        mut self: Pin<&mut Self>,
+        end: bool,
+        f: impl FnOnce(Pin<&mut Self>) -> Poll<R>,
+    ) -> Poll<R> {
+        let guard = self.as_mut().span().set_local_parent();
+        let res = f(self.as_mut());
+        drop(guard);
+        self.record_buffered();
+        if end && res.is_ready() {
+            self.project().span.take();
+        }
+        res
+    }
+
+    fn span(self: Pin<&mut Self>) -> &Span {
+        let this = self.project();
+        let (role, peer) = (*this.role, *this.peer);
+        this.span.get_or_insert_with(|| {
+            Span::root("outgoing", SpanContext::random()).with_properties(|| {
+                [
+                    (
+                        "conn_id",
+                        ConnId::new(role.guid(), peer.guid, peer.addr).to_string(),
+                    ),
+                    ("peer_guid", peer.guid.to_string()),
+                    ("peer_addr", peer.addr.to_string()),
+                ]
+            })
+        })
+    }
+
+    /// Whether the frames waiting for acknowledgement reached the limit
+    fn resend_full(&self) -> bool {
+        self.resend_bytes_cap > 0 && self.resend.size() >= self.resend_bytes_cap
+    }
+
+    /// Publish the buffered frames to the link, so that the room of the buffer could be checked
+    /// without polling the guard
+    fn record_buffered(&self) {
+        let blocked =
+            self.resend_full() || (self.bytes_cap > 0 && self.buf.size() >= self.bytes_cap);
+        self.link.record_buffered(self.buf.len(), blocked);
+    }
+
+    /// Drop the buffered and the unacked frames without waiting, the connection is aborted
+    fn abort(self: Pin<&mut Self>) {
+        let this = self.project();
+        for frame in this.buf.iter() {
+            this.resend.receipts.on_dropped(frame);
+        }
+        let dropped = this.buf.clear() + this.resend.abandon();
+        this.link.record_resend_bytes(0);
+        this.link.turn_off_waking();
+        if let Some(timer) = this.close_timer.take() {
+            Reactor::get().cancel_timer(timer);
+        }
+        trace!(
+            role:% = this.role,
+            peer:% = this.peer,
+            dropped = dropped;
+            "abort the connection"
+        );
+    }
+
+    /// Wait for the turn to send the next frame set in `buf`, it yields to other tasks after
+    /// `sent` packets exhaust the `budget` of this poll, and to other connections on the socket
+    /// after `sent` packets in a turn
+    fn poll_turn(
+        fair_turn: &mut Option<FairTurn>,
+        buf: &OutgoingBuf,
+        sent: usize,
+        budget: usize,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        if buf.is_empty() {
+            return Poll::Ready(());
+        }
+        if let Some(turn) = fair_turn {
+            turn.set_backlogged(true);
+        }
+        let exhausted = budget > 0 && sent >= budget;
+        if exhausted
+            || fair_turn
+                .as_ref()
+                .is_some_and(|turn| turn.should_yield(sent))
+        {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        Poll::Ready(())
+    }
+
+    /// Reserve the room for the CRC-32 appended to each frame set
+    pub(crate) fn checksum(mut self, enabled: bool) -> Self {
+        self.frame_set_overhead = FRAME_SET_HEADER_SIZE;
+        if enabled {
+            self.frame_set_overhead += FRAME_SET_CHECKSUM_SIZE;
         }
+        self
     }
 }
 
@@ -72,60 +351,194 @@ impl<F> OutgoingGuard<F>
 where
     F: for<'a> Sink<(Packet<FramesRef<'a>>, SocketAddr), Error = io::Error>,
 {
-    /// Try to empty the outgoing buffer
+    /// Send the pending ACKs ahead of the flush strategy if the application asked by
+    /// [`AckNow::ack_now`](crate::opts::AckNow::ack_now), the request is kept until they are sent
+    fn poll_ack_now(
+        frame: Pin<&mut F>,
+        cx: &mut Context<'_>,
+        link: &SharedLink,
+        peer: &Peer,
+        role: Role,
+    ) -> Poll<io::Result<usize>> {
+        if !link.take_ack_now() {
+            return Poll::Ready(Ok(0));
+        }
+        let res = Self::poll_send_acks(frame, cx, link, peer, role, false);
+        if res.is_pending() || !link.outgoing_ack_empty() {
+            link.restore_ack_now();
+        }
+        res
+    }
+
+    /// Send at most [`MAX_ACK_PACKETS_PER_ROUND`] NACK or ACK packets, each one is sized to the
+    /// MTU. Returns the number of sequence numbers reported.
+    fn poll_send_acks(
+        mut frame: Pin<&mut F>,
+        cx: &mut Context<'_>,
+        link: &SharedLink,
+        peer: &Peer,
+        role: Role,
+        nack: bool,
+    ) -> Poll<io::Result<usize>> {
+        let mut cnt = 0;
+        for _ in 0..MAX_ACK_PACKETS_PER_ROUND {
+            ready!(backpressured(frame.as_mut().poll_ready(cx), cx))?;
+            let records = if nack {
+                link.process_outgoing_nack(link.mtu())
+            } else {
+                link.process_outgoing_ack(link.mtu())
+            };
+            let Some(records) = records else {
+                break;
+            };
+            let total = records.total_cnt();
+            trace!(
+                role:% = role,
+                peer:% = peer,
+                kind = if nack { "nack" } else { "ack" },
+                records:? = records,
+                total = total;
+                "send acknowledgement"
+            );
+            cnt += total;
+            let packet = if nack {
+                connected::Packet::Nack(records)
+            } else {
+                connected::Packet::Ack(records)
+            };
+            frame
+                .as_mut()
+                .start_send((Packet::Connected(packet), link.peer_addr()))?;
+        }
+        Poll::Ready(Ok(cnt))
+    }
+
+    /// Drop the expired frame at the back of the buffer, return whether it is dropped. The expired
+    /// reliable frame is kept to be sent late, or dropped with the error if it should fail.
+    fn drop_expired(
+        buf: &mut OutgoingBuf,
+        receipts: &mut ReceiptTracker,
+        link: &SharedLink,
+        role: Role,
+        peer: Peer,
+        fail_reliable: bool,
+        now: Instant,
+    ) -> io::Result<bool> {
+        let Some(frame) = buf.back() else {
+            return Ok(false);
+        };
+        let Some(deadline) = frame.deadline.filter(|deadline| *deadline <= now) else {
+            return Ok(false);
+        };
+        let reliable = frame.flags.reliability.is_reliable();
+        if reliable && !fail_reliable {
+            return Ok(false);
+        }
+        trace!(
+            role:% = role,
+            peer:% = peer,
+            first_byte = frame.body[0];
+            "drop the expired frame"
+        );
+        receipts.on_dropped(frame);
+        buf.pop_back();
+        link.record_expired();
+        if reliable {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                FrameExpired::new(now - deadline),
+            ));
+        }
+        Ok(true)
+    }
+
+    /// Abandon the frame at the back of the buffer if it exceeds `capacity`, which happens to the
+    /// frames split for a larger MTU before it was reduced. They could not be split again as their
+    /// indices are taken, so they are abandoned like the expired ones and replaced by the marker.
+    fn abandon_oversized(
+        buf: &mut OutgoingBuf,
+        resend: &mut ResendMap,
+        role: Role,
+        capacity: usize,
+    ) -> bool {
+        let Some(frame) = buf.pop_back_if(|frame| frame.size() > capacity) else {
+            return false;
+        };
+        warn!(
+            "[{role}] abandon the frame of size {} exceeding the reduced MTU",
+            frame.size()
+        );
+        if let Some(marker) = resend.abandon_oversized(&frame) {
+            buf.extend([marker]);
+        }
+        true
+    }
+
+    /// Try to empty the outgoing buffer.
+    ///
+    /// Each datagram carries exactly one packet, as the first byte tells whether it is a frame
+    /// set, an ACK, a NACK or an unconnected packet, so the acks could not be piggybacked on the
+    /// frame sets. They are sent in the order of NACK, ACK, unconnected packets and frame sets:
+    /// the NACKs trigger the resending of the peer at the earliest, the ACKs free its resend
+    /// buffer and feed its RTT estimation without the queuing delay of our data, and the frame
+    /// sets come last as they are the largest. `data_first` sends a frame set ahead of the ACKs
+    /// to cut the latency of the data when the buffer is data-heavy.
     fn try_empty(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
         let mut this = self.project();
 
+        this.resend.seed_handshake_rtt(this.link);
         this.link
             .process_ack()
             .for_each(|(ack, received_at)| this.resend.on_ack(ack, received_at));
-        this.link
+        let nack_resent: usize = this
+            .link
             .process_nack()
-            .for_each(|nack| this.resend.on_nack_into(nack, this.buf));
-        this.resend.process_stales(this.buf);
-        let strategy = cx
-            .ext()
-            .downcast_ref::<FlushStrategy>()
-            .copied()
-            .unwrap_or_default();
-        let mut ack_cnt = 0;
+            .map(|nack| this.resend.on_nack_into(nack, this.buf))
+            .sum();
+        let stale_resent = this.resend.process_stales(this.buf);
+        this.link.record_resent(nack_resent, stale_resent);
+        this.link.record_unsent_acks(this.resend.take_unsent_acks());
+        this.resend
+            .reduce_blackhole_mtu(this.link, *this.frame_set_overhead);
+        this.link.record_resend_bytes(this.resend.size());
+        let strategy = FlushStrategy::mark_resent_in(cx, nack_resent, stale_resent);
+        let mut ack_cnt = ready!(Self::poll_ack_now(
+            this.frame.as_mut(),
+            cx,
+            this.link,
+            this.peer,
+            *this.role
+        ))?;
         let mut nack_cnt = 0;
         let mut pack_cnt = 0;
 
         while !strategy.check_flushed(this.link, this.buf) {
             // 1st. empty the nack
-            ready!(this.frame.as_mut().poll_ready(cx))?;
-            if strategy.flush_nack()
-                && let Some(nack) = this.link.process_outgoing_nack(this.peer.mtu)
-            {
-                trace!(
-                    "[{}] send nack {nack:?} to {}, total count: {}",
-                    this.role,
+            if strategy.flush_nack() {
+                nack_cnt += ready!(Self::poll_send_acks(
+                    this.frame.as_mut(),
+                    cx,
+                    this.link,
                     this.peer,
-                    nack.total_cnt()
-                );
-                nack_cnt += nack.total_cnt();
-                this.frame.as_mut().start_send((
-                    Packet::Connected(connected::Packet::Nack(nack)),
-                    this.peer.addr,
+                    *this.role,
+                    true
                 ))?;
             }
 
-            // 2nd. empty the ack
-            ready!(this.frame.as_mut().poll_ready(cx))?;
-            if strategy.flush_ack()
-                && let Some(ack) = this.link.process_outgoing_ack(this.peer.mtu)
-            {
-                trace!(
-                    "[{}] send ack {ack:?} to {}, total count: {}",
-                    this.role,
+            // 2nd. empty the ack, unless it is deferred for a frame set
+            let defer_ack = *this.data_first
+                && !*this.ack_deferred
+                && strategy.flush_pack()
+                && !this.buf.is_empty();
+            *this.ack_deferred = defer_ack;
+            if strategy.flush_ack() && !defer_ack {
+                ack_cnt += ready!(Self::poll_send_acks(
+                    this.frame.as_mut(),
+                    cx,
+                    this.link,
                     this.peer,
-                    ack.total_cnt()
-                );
-                ack_cnt += ack.total_cnt();
-                this.frame.as_mut().start_send((
-                    Packet::Connected(connected::Packet::Ack(ack)),
-                    this.peer.addr,
+                    *this.role,
+                    false
                 ))?;
             }
 
@@ -135,40 +548,69 @@ where
             }
 
             // 3rd. empty the unconnected packets
-            ready!(this.frame.as_mut().poll_ready(cx))?;
+            ready!(backpressured(this.frame.as_mut().poll_ready(cx), cx))?;
             // only poll one packet each time
             if let Some(packet) = this.link.process_unconnected().next() {
                 trace!(
-                    "[{}] send unconnected packet to {}, type: {:?}",
-                    this.role,
-                    this.peer,
-                    packet.pack_type()
+                    role:% = this.role,
+                    peer:% = this.peer,
+                    pack_type:? = packet.pack_type();
+                    "send unconnected packet"
                 );
                 this.frame
                     .as_mut()
-                    .start_send((Packet::Unconnected(packet), this.peer.addr))?;
+                    .start_send((Packet::Unconnected(packet), this.link.peer_addr()))?;
                 pack_cnt += 1;
             }
 
             // 4th. empty the frame set
-            ready!(this.frame.as_mut().poll_ready(cx))?;
-            let mut frames = Vec::with_capacity(this.buf.len());
+            ready!(Self::poll_turn(
+                this.fair_turn,
+                this.buf,
+                pack_cnt,
+                *this.flush_budget,
+                cx
+            ));
+            ready!(backpressured(this.frame.as_mut().poll_ready(cx), cx))?;
+            let mut frames = this.resend.take_frames(this.buf.len());
             let mut reliable = false;
-            let mut remain = this.peer.mtu as usize - FRAME_SET_HEADER_SIZE;
-            while let Some(frame) = this.buf.back() {
-                if remain >= frame.size() {
-                    if frame.flags.reliability.is_reliable() {
-                        reliable = true;
+            let capacity = this.link.mtu() as usize - *this.frame_set_overhead;
+            let mut remain = capacity;
+            let now = Instant::now();
+            while !this.buf.is_empty() {
+                match Self::drop_expired(
+                    this.buf,
+                    &mut this.resend.receipts,
+                    this.link,
+                    *this.role,
+                    *this.peer,
+                    *this.fail_expired_reliable,
+                    now,
+                ) {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(err) => {
+                        // the frames are not on the wire, put them back in order
+                        this.buf.extend(frames.drain(..).rev());
+                        this.resend.recycle_frames(frames);
+                        return Poll::Ready(Err(err));
                     }
-                    remain -= frame.size();
+                }
+                if Self::abandon_oversized(this.buf, this.resend, *this.role, capacity) {
+                    continue;
+                }
+                let frame = this.buf.back().unwrap();
+                if remain >= frame.size() {
+                    reliable |= frame.flags.reliability.is_reliable();
+                    remain = remain.saturating_sub(frame.size());
                     trace!(
-                        "[{}] send frame to {}, seq_num: {}, reliable: {}, first byte: 0x{:02x}, size: {}",
-                        this.role,
-                        this.peer,
-                        *this.seq_num_write_index,
-                        reliable,
-                        frame.body[0],
-                        frame.size()
+                        role:% = this.role,
+                        peer:% = this.peer,
+                        seq_num = this.seq_num_write_index.to_u32(),
+                        reliable = reliable,
+                        first_byte = frame.body[0],
+                        size = frame.size();
+                        "send frame"
                     );
                     frames.push(this.buf.pop_back().unwrap());
                     continue;
@@ -180,23 +622,50 @@ where
                 "every frame size should not exceed MTU"
             );
             if !frames.is_empty() {
+                let mut frames = ready!(this.resend.poll_pace(this.buf, frames, cx));
                 let frame_set = FrameSet {
                     seq_num: *this.seq_num_write_index,
                     set: &frames[..],
                 };
-                this.frame.as_mut().start_send((
+                if let Err(err) = this.frame.as_mut().start_send((
                     Packet::Connected(connected::Packet::FrameSet(frame_set)),
-                    this.peer.addr,
-                ))?;
+                    this.link.peer_addr(),
+                )) {
+                    // the frames are neither on the wire nor recorded, put them back in order
+                    this.buf.extend(frames.drain(..).rev());
+                    this.resend.recycle_frames(frames);
+                    return backpressured(Poll::Ready(Err(err)), cx);
+                }
+                this.link.record_outgoing(frames.len(), 0);
+                this.link
+                    .record_pacing_rate(this.resend.pacer.rate().unwrap_or_default());
+                add_event("frame_set.sent", || {
+                    [
+                        ("seq_num", this.seq_num_write_index.to_string()),
+                        ("frames", frames.len().to_string()),
+                        ("reliable", reliable.to_string()),
+                    ]
+                });
                 if reliable {
                     // keep for resending
                     this.resend.record(*this.seq_num_write_index, frames);
+                    this.link.record_resend_bytes(this.resend.size());
+                } else {
+                    this.resend
+                        .record_unreliable(*this.seq_num_write_index, frames);
                 }
-                *this.seq_num_write_index += 1;
+                *this.seq_num_write_index = this.seq_num_write_index.wrapping_add(1);
+                this.resend.sent_until(*this.seq_num_write_index);
                 pack_cnt += 1;
+            } else {
+                this.resend.recycle_frames(frames);
             }
         }
 
+        this.link.record_abandoned(this.resend.take_abandoned());
+        if let Some(turn) = this.fair_turn {
+            turn.set_backlogged(false);
+        }
         // mark flushed count
         if let Some(strategy_) = cx.ext().downcast_mut::<FlushStrategy>() {
             strategy_.mark_flushed_ack(ack_cnt);
@@ -214,10 +683,85 @@ where
 {
     type Error = io::Error;
 
-    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.in_span(false, |guard| guard.poll_ready_frames(cx))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: Frame) -> Result<(), Self::Error> {
+        let _guard = self.as_mut().span().set_local_parent();
+        add_event("frame.enqueued", || {
+            [
+                ("reliability", format!("{:?}", frame.flags.reliability)),
+                ("size", frame.size().to_string()),
+            ]
+        });
+        let this = self.as_mut().project();
+        if frame.coalesce {
+            let dropped = this.buf.drop_superseded(&frame, |superseded| {
+                this.resend.receipts.on_dropped(superseded);
+            });
+            if dropped > 0 {
+                trace!(
+                    role:% = this.role,
+                    peer:% = this.peer,
+                    dropped = dropped;
+                    "drop superseded frames"
+                );
+            }
+        }
+        this.resend.receipts.on_queued(&frame);
+        this.buf.push_front(frame);
+        self.record_buffered();
+        // Always success
+        Ok(())
+    }
+
+    /// Flush the outgoing guard, it also waits for all frames to be received by the peer if the
+    /// [`FlushStrategy`] asks for it.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.in_span(true, |guard| guard.poll_flush_frames(cx))
+    }
+
+    /// Close the outgoing guard, notice that it may resend infinitely if you do not cancel it or
+    /// set the close timeout.
+    /// Insure all frames are received by the peer at the point of closing, or return a
+    /// `TimedOut` error carrying [`CloseTimedOut`] if the close timeout is reached.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.in_span(true, |guard| guard.poll_close_frames(cx))
+    }
+}
+
+impl<F> OutgoingGuard<F>
+where
+    F: for<'a> Sink<(Packet<FramesRef<'a>>, SocketAddr), Error = io::Error>,
+{
+    fn poll_ready_frames(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let reserve = self.link.take_reserve();
+        if reserve > 0 {
+            let this = self.as_mut().project();
+            this.buf.reserve(reserve);
+            this.resend.reserve(reserve);
+        }
         let upstream = self.as_mut().try_empty(cx)?;
 
-        if self.buf.len() >= self.cap {
+        if self.resend_full() {
+            trace!(
+                role:% = self.role,
+                peer:% = self.peer,
+                unacked_bytes = self.resend.size();
+                "apply backpressure on the bytes waiting for acknowledgement"
+            );
+            // wake up when receiving acks, or resending the stale frames
+            self.link.turn_on_waking();
+            if self.resend.poll_wait(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+            return Poll::Pending;
+        }
+
+        if self.buf.len() >= self.link.send_buf_cap()
+            || (self.bytes_cap > 0 && self.buf.size() >= self.bytes_cap)
+        {
             debug_assert!(
                 upstream == Poll::Pending,
                 "OutgoingGuard::try_empty returns Ready but buffer still remains!"
@@ -228,23 +772,51 @@ where
         }
     }
 
-    fn start_send(self: Pin<&mut Self>, frame: Frame) -> Result<(), Self::Error> {
-        let this = self.project();
-        this.buf.push_front(frame);
-        // Always success
-        Ok(())
-    }
-
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        ready!(self.as_mut().try_empty(cx))?;
-        self.project().frame.poll_flush(cx)
+    fn poll_flush_frames(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(strategy) = cx.ext().downcast_mut::<FlushStrategy>() {
+            strategy.reset_last_flushed();
+        }
+        let wait_acked = cx
+            .ext()
+            .downcast_ref::<FlushStrategy>()
+            .is_some_and(FlushStrategy::should_wait_acked);
+        if !wait_acked {
+            ready!(self.as_mut().try_empty(cx))?;
+            return backpressured(self.project().frame.poll_flush(cx), cx);
+        }
+        // wake up when receiving acks
+        self.link.turn_on_waking();
+        loop {
+            ready!(self.as_mut().try_empty(cx))?;
+            ready!(backpressured(
+                self.as_mut().project().frame.poll_flush(cx),
+                cx
+            ))?;
+            if self.resend.is_empty() {
+                trace!(
+                    role:% = self.role,
+                    peer:% = self.peer;
+                    "all frames are received, finish the reliable flush"
+                );
+                break;
+            }
+            ready!(self.resend.poll_wait(cx));
+        }
+        self.link.turn_off_waking();
+        Poll::Ready(Ok(()))
     }
 
-    /// Close the outgoing guard, notice that it may resend infinitely if you do not cancel it.
-    /// Insure all frames are received by the peer at the point of closing
-    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_close_frames(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if cx.ext().downcast_ref::<Aborting>().is_some() {
+            self.abort();
+            return Poll::Ready(Ok(()));
+        }
         // maybe go to sleep, turn on the waking
         self.link.turn_on_waking();
+        if self.close_deadline.is_none() {
+            let this = self.as_mut().project();
+            *this.close_deadline = this.close_timeout.map(|timeout| Instant::now() + timeout);
+        }
         loop {
             ready!(self.as_mut().try_empty(cx))?;
             debug_assert!(
@@ -253,134 +825,923 @@ where
                     && self.link.outgoing_ack_empty()
                     && self.link.outgoing_nack_empty()
             );
-            ready!(self.as_mut().project().frame.poll_flush(cx))?;
+            ready!(backpressured(
+                self.as_mut().project().frame.poll_flush(cx),
+                cx
+            ))?;
             if self.resend.is_empty() {
                 trace!(
-                    "[{}] all frames are received by {}, close the outgoing guard",
-                    self.role,
-                    self.peer,
+                    role:% = self.role,
+                    peer:% = self.peer;
+                    "all frames are received, close the outgoing guard"
                 );
                 break;
             }
+            if let Some(deadline) = self.close_deadline {
+                if Instant::now() >= deadline {
+                    let this = self.as_mut().project();
+                    let unacked = this.resend.abandon();
+                    warn!(
+                        role:% = this.role,
+                        peer:% = this.peer,
+                        unacked = unacked;
+                        "close timed out, abandon the unacked frames"
+                    );
+                    *this.abandoned = Some(unacked);
+                    break;
+                }
+                // wake up at the deadline if the peer keeps silent
+                let this = self.as_mut().project();
+                match this.close_timer {
+                    Some(timer) => Reactor::get().reset_timer(timer, deadline, cx.waker()),
+                    None => {
+                        let c_id = ConnId::new(this.role.guid(), this.peer.guid, this.peer.addr);
+                        *this.close_timer = Some(Reactor::get().insert_timer(
+                            c_id,
+                            TimerKind::CloseTimeout,
+                            deadline,
+                            cx.waker(),
+                        ));
+                    }
+                }
+            }
             ready!(self.resend.poll_wait(cx));
         }
         // no need to wake up
         self.link.turn_off_waking();
-        self.project().frame.poll_close(cx)
+        let this = self.project();
+        if let Some(timer) = this.close_timer.take() {
+            Reactor::get().cancel_timer(timer);
+        }
+        ready!(backpressured(this.frame.poll_close(cx), cx))?;
+        if let Some(unacked) = this.abandoned.take() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                CloseTimedOut::new(unacked),
+            )));
+        }
+        Poll::Ready(Ok(()))
     }
 }
 
-struct ResendEntry {
-    frames: Option<Frames>,
-    send_at: Instant,
-    expired_at: Instant,
+/// Treat the `WouldBlock` error of the transport as the backpressure rather than a failure, the
+/// transport does not register the waker in that case, so it is woken up to retry soon.
+fn backpressured(
+    res: Poll<Result<(), io::Error>>,
+    cx: &mut Context<'_>,
+) -> Poll<Result<(), io::Error>> {
+    match res {
+        Poll::Ready(Err(err)) if err.kind() == io::ErrorKind::WouldBlock => {
+            trace!("transport would block, retry later");
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+        res => res,
+    }
 }
 
-struct ResendMap {
-    map: HashMap<u24, ResendEntry>,
-    role: Role,
-    peer: Peer,
-    last_record_expired_at: Instant,
-    estimator: Box<dyn Estimator + Send + Sync + 'static>,
+/// The outgoing frames buffer, consists of a queue for each [`Priority`].
+/// New frames are pushed to the front and the frames are popped from the back of the queue
+/// with the highest priority, so the frames with the same priority keep their order.
+pub(crate) struct OutgoingBuf {
+    queues: [VecDeque<Frame>; Priority::LEVELS],
+    // the total size of all buffered frames
+    size: usize,
 }
 
-impl ResendMap {
-    fn new(role: Role, peer: Peer, estimator: Box<dyn Estimator + Send + Sync + 'static>) -> Self {
+impl OutgoingBuf {
+    fn with_capacity(cap: usize) -> Self {
         Self {
-            map: HashMap::new(),
-            role,
-            peer,
-            last_record_expired_at: Instant::now(),
-            estimator,
+            queues: std::array::from_fn(|_| VecDeque::with_capacity(cap)),
+            size: 0,
         }
     }
 
-    fn record(&mut self, seq_num: u24, frames: Frames) {
-        let now = Instant::now();
-        self.map.insert(
-            seq_num,
-            ResendEntry {
-                frames: Some(frames),
-                send_at: now,
-                expired_at: now + self.estimator.rto(),
-            },
-        );
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
     }
 
-    fn on_ack(&mut self, ack: AckOrNack, received_at: Instant) {
-        for record in ack.records {
-            match record {
-                Record::Range(start, end) => {
-                    for i in start.to_u32()..=end.to_u32() {
-                        if let Some(ResendEntry { send_at, .. }) = self.map.remove(&i.into()) {
-                            let rtt = received_at.saturating_duration_since(send_at);
-                            self.estimator.update(rtt);
-                            trace!(
-                                "[{}] seq_num {i} is ACKed by {}, RTT: {rtt:?}, estimated RTO: {:?}",
-                                self.role,
-                                self.peer,
-                                self.estimator.rto()
-                            );
-                        }
-                    }
-                }
-                Record::Single(seq_num) => {
-                    if let Some(ResendEntry { send_at, .. }) = self.map.remove(&seq_num) {
-                        let rtt = received_at.saturating_duration_since(send_at);
-                        self.estimator.update(rtt);
-                        trace!(
-                            "[{}] seq_num {seq_num} is ACKed by {}, RTT: {rtt:?}, estimated RTO: {:?}",
-                            self.role,
-                            self.peer,
-                            self.estimator.rto()
-                        );
-                    }
-                }
-            }
-        }
+    fn size(&self) -> usize {
+        self.size
     }
 
-    fn on_nack_into(&mut self, nack: AckOrNack, buffer: &mut VecDeque<Frame>) {
-        trace!("[{}] receive NACKs {nack:?} from {}", self.role, self.peer);
-        for record in nack.records {
-            match record {
-                Record::Range(start, end) => {
-                    for i in start.to_u32()..=end.to_u32() {
-                        if let Some(entry) = self.map.remove(&i.into()) {
-                            buffer.extend(entry.frames.unwrap());
-                        }
-                    }
-                }
-                Record::Single(seq_num) => {
-                    if let Some(entry) = self.map.remove(&seq_num) {
-                        buffer.extend(entry.frames.unwrap());
-                    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+
+    /// Reserve the room for at least `additional` more frames of the default priority
+    fn reserve(&mut self, additional: usize) {
+        self.queues[Priority::default().index()].reserve(additional);
+    }
+
+    fn push_front(&mut self, frame: Frame) {
+        self.size += frame.size();
+        self.queues[frame.priority.index()].push_front(frame);
+    }
+
+    /// Drop the buffered coalescing frames superseded by the new one, i.e. those with the same
+    /// ordered part. Buffered frames have not been placed on the wire yet, so it is safe to drop
+    /// them. Each dropped frame is passed to `on_dropped`. Returns the number of dropped frames.
+    fn drop_superseded(&mut self, frame: &Frame, mut on_dropped: impl FnMut(&Frame)) -> usize {
+        debug_assert!(frame.coalesce && frame.ordered.is_some());
+        let mut dropped = 0;
+        for queue in &mut self.queues {
+            queue.retain(|buffered| {
+                if buffered.coalesce && buffered.ordered == frame.ordered {
+                    on_dropped(buffered);
+                    self.size -= buffered.size();
+                    dropped += 1;
+                    return false;
                 }
-            }
+                true
+            });
         }
+        dropped
     }
 
-    /// `process_stales` collect all stale frames into buffer and remove the expired entries
-    fn process_stales(&mut self, buffer: &mut VecDeque<Frame>) {
-        if self.map.is_empty() {
-            return;
+    fn iter(&self) -> impl Iterator<Item = &Frame> {
+        self.queues.iter().flatten()
+    }
+
+    /// Drop all buffered frames, returns the number of dropped frames
+    fn clear(&mut self) -> usize {
+        let len = self.len();
+        self.queues.iter_mut().for_each(VecDeque::clear);
+        self.size = 0;
+        len
+    }
+
+    fn back(&self) -> Option<&Frame> {
+        self.queues.iter().find_map(VecDeque::back)
+    }
+
+    fn pop_back(&mut self) -> Option<Frame> {
+        let frame = self.queues.iter_mut().find_map(VecDeque::pop_back)?;
+        self.size -= frame.size();
+        Some(frame)
+    }
+
+    fn pop_back_if(&mut self, predicate: impl FnOnce(&Frame) -> bool) -> Option<Frame> {
+        if !predicate(self.back()?) {
+            return None;
         }
+        self.pop_back()
+    }
+}
 
-        let now = Instant::now();
-        if now < self.last_record_expired_at {
-            trace!(
-                "[{}] skip scanning the resend map, last record expired at {:?}",
-                self.role,
-                self.last_record_expired_at - now
-            );
-            return;
+/// Resending frames are extended to the back, so they will be sent first among their priority
+impl Extend<Frame> for OutgoingBuf {
+    fn extend<T: IntoIterator<Item = Frame>>(&mut self, iter: T) {
+        for frame in iter {
+            self.size += frame.size();
+            self.queues[frame.priority.index()].push_back(frame);
         }
-        // find the first expired_at larger than now
+    }
+}
+
+struct ResendEntry {
+    frames: Option<Frames>,
+    // the total size of the frames
+    size: usize,
+    send_at: Instant,
+    expired_at: Instant,
+}
+
+/// Record the retransmission to the trace and tell the hook
+fn retransmitted(hook: Option<&RetransmitHook>, retransmission: Retransmission) {
+    add_event("retransmit", || {
+        [
+            ("seq_num", retransmission.seq_num.to_string()),
+            ("retransmits", retransmission.retransmits.to_string()),
+            ("cause", format!("{:?}", retransmission.cause)),
+        ]
+    });
+    if let Some(hook) = hook {
+        hook.call(retransmission);
+    }
+}
+
+/// The retransmit count of a lost frame set, which is counted by its most resent frame
+fn retransmit_count(frames: &[Frame]) -> u32 {
+    frames
+        .iter()
+        .map(|frame| frame.retransmits)
+        .max()
+        .unwrap_or_default()
+        .saturating_add(1)
+}
+
+/// Queue the frames of a lost frame set into the buffer to be resent. The ones which passed their
+/// abandon deadline are dropped instead, see [`abandon`]. Returns the number of abandoned frames.
+fn requeue(
+    frames: &mut Frames,
+    buffer: &mut impl Extend<Frame>,
+    abandoned_parts: &mut HashMap<u16, Ordered>,
+    receipts: &mut ReceiptTracker,
+    crypto: Option<&dyn FrameCrypto>,
+    compression: Option<&dyn FrameCompression>,
+    now: Instant,
+) -> usize {
+    let mut abandoned = 0;
+    buffer.extend(frames.drain(..).filter_map(|mut frame| {
+        if frame.abandon_at.map_or(true, |abandon_at| abandon_at > now) {
+            frame.retransmits = frame.retransmits.saturating_add(1);
+            return Some(frame);
+        }
+        abandoned += 1;
+        abandon(&frame, abandoned_parts, receipts, crypto, compression)
+    }));
+    if abandoned > 0 {
+        debug!(abandoned = abandoned; "abandon the frames which are not acknowledged in time");
+    }
+    abandoned
+}
+
+/// Abandon a frame. An ordered one is replaced by the returned marker [`FrameBody::Abandoned`] with
+/// the same indices, so that the receiver fills the gap of the ordering channel with it. The parts
+/// of a fragmented message share one marker, which are told by their parted id and their ordered
+/// index recorded in `abandoned_parts`. The receipt of the message is reported lost.
+fn abandon(
+    frame: &Frame,
+    abandoned_parts: &mut HashMap<u16, Ordered>,
+    receipts: &mut ReceiptTracker,
+    crypto: Option<&dyn FrameCrypto>,
+    compression: Option<&dyn FrameCompression>,
+) -> Option<Frame> {
+    receipts.on_dropped(frame);
+    let ordered = frame
+        .ordered
+        .filter(|_| frame.flags.reliability.is_ordered())?;
+    if let Some(fragment) = frame.fragment
+        && abandoned_parts.insert(fragment.parted_id, ordered) == Some(ordered)
+    {
+        // the marker of the message has been queued with another part
+        return None;
+    }
+    let mut body = BytesMut::new();
+    FrameBody::Abandoned.write(&mut body);
+    if let Some(compression) = compression {
+        body = BytesMut::from(compression.compress(body.freeze()));
+    }
+    if let Some(crypto) = crypto {
+        crypto.encrypt(&mut body);
+    }
+    let mut marker = Frame::new(Flags::new(frame.flags.reliability, false), body.freeze());
+    marker.reliable_frame_index = frame.reliable_frame_index;
+    marker.ordered = Some(ordered);
+    marker.priority = frame.priority;
+    Some(marker)
+}
+
+/// The max number of idle frames vectors kept in [`FramesPool`]
+const FRAMES_POOL_CAP: usize = 32;
+
+/// `FramesPool` recycles the vectors of frames once the frame sets are sent (unreliable) or
+/// acknowledged (reliable), so that flushing does not allocate a new vector for each frame set.
+/// Only the allocation of the vector is reused, frames are dropped before recycling so that the
+/// bodies are released to their shared `Bytes` buffers.
+struct FramesPool {
+    idle: Vec<Frames>,
+    // the max number of idle vectors kept
+    cap: usize,
+}
+
+impl Default for FramesPool {
+    fn default() -> Self {
+        Self {
+            idle: Vec::new(),
+            cap: FRAMES_POOL_CAP,
+        }
+    }
+}
+
+impl FramesPool {
+    fn take(&mut self, cap: usize) -> Frames {
+        let mut frames = self.idle.pop().unwrap_or_default();
+        frames.reserve(cap);
+        frames
+    }
+
+    fn put(&mut self, mut frames: Frames) {
+        if self.idle.len() >= self.cap || frames.capacity() == 0 {
+            return;
+        }
+        frames.clear();
+        self.idle.push(frames);
+    }
+}
+
+/// Detects the path MTU blackhole, where the frame sets larger than some size are silently
+/// dropped on the path while the smaller ones still get through.
+#[derive(Default)]
+struct BlackholeDetector {
+    // the number of consecutive losses before reporting the blackhole, 0 disables it
+    retries: usize,
+    // the number of consecutive losses of the frame sets larger than the acknowledged ones
+    strikes: usize,
+    // the size of the largest frame set acknowledged since the last loss
+    largest_acked: usize,
+    // the size of the largest frame set lost in the current strikes
+    lost: usize,
+}
+
+impl BlackholeDetector {
+    fn on_acked(&mut self, size: usize) {
+        self.largest_acked = self.largest_acked.max(size);
+        if self.lost > 0 && size >= self.lost {
+            // the large frame sets get through, it was an ordinary loss
+            self.strikes = 0;
+            self.lost = 0;
+        }
+    }
+
+    /// Called with the size of the largest frame set lost in a NACK or timed out in a scan of the
+    /// resend map, returns the size of frames fitting the path once the blackhole is detected.
+    fn on_lost(&mut self, size: usize) -> Option<usize> {
+        if self.retries == 0 || self.largest_acked == 0 {
+            // the peer acknowledges nothing, the link may be just broken
+            return None;
+        }
+        let fits = std::mem::take(&mut self.largest_acked);
+        if size <= fits {
+            self.strikes = 0;
+            self.lost = 0;
+            return None;
+        }
+        self.strikes += 1;
+        self.lost = self.lost.max(size);
+        if self.strikes < self.retries {
+            return None;
+        }
+        self.strikes = 0;
+        self.lost = 0;
+        Some(fits)
+    }
+}
+
+/// Tracks the messages tagged with a receipt and tells the hook once they are acknowledged or
+/// lost. A reliable message is acknowledged once all of its parts are. An unreliable one is never
+/// fragmented (it becomes reliable instead), so the frame set carrying it tells its fate.
+struct ReceiptTracker {
+    hook: Option<ReceiptHook>,
+    addr: SocketAddr,
+    // the parts of the reliable messages not acknowledged yet by their receipts
+    pending: HashMap<u32, usize>,
+    // the receipts of the unreliable frames by the frame sets carrying them, along with the time
+    // they are considered lost
+    unreliable: HashMap<u24, (Vec<u32>, Instant)>,
+}
+
+impl ReceiptTracker {
+    fn new(addr: SocketAddr) -> Self {
+        Self {
+            hook: None,
+            addr,
+            pending: HashMap::new(),
+            unreliable: HashMap::new(),
+        }
+    }
+
+    fn report(&self, receipt: u32, acked: bool) {
+        if let Some(hook) = &self.hook {
+            hook.call(AckReceipt {
+                addr: self.addr,
+                receipt,
+                acked,
+            });
+        }
+    }
+
+    /// Count the parts of a reliable message when they are queued
+    fn on_queued(&mut self, frame: &Frame) {
+        if self.hook.is_some()
+            && frame.flags.reliability.is_reliable()
+            && let Some(receipt) = frame.receipt
+        {
+            *self.pending.entry(receipt).or_default() += 1;
+        }
+    }
+
+    /// Track the receipts of the unreliable frames sent in the frame set `seq_num`
+    fn on_sent(&mut self, seq_num: u24, frames: &[Frame], lost_at: Instant) {
+        if self.hook.is_none() {
+            return;
+        }
+        let receipts: Vec<u32> = frames
+            .iter()
+            .filter(|frame| !frame.flags.reliability.is_reliable())
+            .filter_map(|frame| frame.receipt)
+            .collect();
+        if !receipts.is_empty() {
+            self.unreliable.insert(seq_num, (receipts, lost_at));
+        }
+    }
+
+    /// The frame set `seq_num` is acknowledged, `frames` are the ones recorded for resending
+    fn on_acked(&mut self, seq_num: u24, frames: &[Frame]) {
+        if self.hook.is_none() {
+            return;
+        }
+        for frame in frames {
+            if !frame.flags.reliability.is_reliable() {
+                continue;
+            }
+            let Some(receipt) = frame.receipt else {
+                continue;
+            };
+            if let Some(parts) = self.pending.get_mut(&receipt) {
+                *parts -= 1;
+                if *parts == 0 {
+                    self.pending.remove(&receipt);
+                    self.report(receipt, true);
+                }
+            }
+        }
+        if let Some((receipts, _)) = self.unreliable.remove(&seq_num) {
+            for receipt in receipts {
+                self.report(receipt, true);
+            }
+        }
+    }
+
+    /// The frame set `seq_num` is reported lost by a NACK
+    fn on_lost(&mut self, seq_num: u24) {
+        if let Some((receipts, _)) = self.unreliable.remove(&seq_num) {
+            for receipt in receipts {
+                self.report(receipt, false);
+            }
+        }
+    }
+
+    /// The frame is dropped before it is acknowledged, e.g. expired, superseded or abandoned, so
+    /// its message is lost. The other parts of a reliable message are not counted anymore.
+    fn on_dropped(&mut self, frame: &Frame) {
+        let Some(receipt) = frame.receipt.filter(|_| self.hook.is_some()) else {
+            return;
+        };
+        if !frame.flags.reliability.is_reliable() || self.pending.remove(&receipt).is_some() {
+            self.report(receipt, false);
+        }
+    }
+
+    /// The connection is closed without waiting for the acknowledgements, all messages not
+    /// acknowledged yet are lost
+    fn on_closed(&mut self) {
+        let lost: Vec<u32> = self
+            .pending
+            .drain()
+            .map(|(receipt, _)| receipt)
+            .chain(
+                self.unreliable
+                    .drain()
+                    .flat_map(|(_, (receipts, _))| receipts),
+            )
+            .collect();
+        for receipt in lost {
+            self.report(receipt, false);
+        }
+    }
+
+    /// The unreliable frames not acknowledged in time are lost
+    fn expire(&mut self, now: Instant) {
+        if self.unreliable.is_empty() {
+            return;
+        }
+        let mut lost = vec![];
+        self.unreliable.retain(|_, (receipts, lost_at)| {
+            if *lost_at > now {
+                return true;
+            }
+            lost.append(receipts);
+            false
+        });
+        for receipt in lost {
+            self.report(receipt, false);
+        }
+    }
+}
+
+/// The initial congestion window of the pacer in frame sets, as the initial window of TCP
+const PACING_INITIAL_WINDOW: usize = 10;
+/// The minimum congestion window of the pacer in frame sets
+const PACING_MIN_WINDOW: usize = 2;
+/// The RTT assumed by the pacer before the first sample
+const PACING_INITIAL_RTT: Duration = Duration::from_millis(333);
+/// The frame sets are sent in a burst of at most this duration at the pacing rate
+const PACING_BURST: Duration = Duration::from_millis(2);
+
+/// The congestion window giving the pacing rate of `gain * cwnd / srtt` bytes per second. It starts
+/// at [`PACING_INITIAL_WINDOW`] frame sets and grows by the acknowledged bytes in the slow start,
+/// then by about a frame set every RTT once it reaches the slow start threshold.
+///
+/// A loss reported by NACK reduces the window to 70%, the peer is still receiving the frame sets
+/// around it. A timeout halves the slow start threshold and collapses the window to
+/// [`PACING_MIN_WINDOW`], as nothing is known about the path anymore. The losses of the frame sets
+/// sent before the last reduction belong to the same recovery epoch, they reduce it only once.
+struct CongestionWindow {
+    mtu: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    srtt: Option<Duration>,
+    // the last time the window was reduced, the start of the recovery epoch
+    reduced_at: Option<Instant>,
+}
+
+impl CongestionWindow {
+    fn new(mtu: usize) -> Self {
+        Self {
+            mtu,
+            cwnd: PACING_INITIAL_WINDOW * mtu,
+            ssthresh: usize::MAX,
+            srtt: None,
+            reduced_at: None,
+        }
+    }
+
+    fn srtt(&self) -> Duration {
+        self.srtt.unwrap_or(PACING_INITIAL_RTT)
+    }
+
+    /// The pacing rate in bytes per second, it paces faster in the slow start to let the window
+    /// grow
+    fn rate(&self) -> u64 {
+        let gain = if self.cwnd < self.ssthresh { 2.0 } else { 1.25 };
+        (self.cwnd as f64 * gain / self.srtt().as_secs_f64().max(1e-6)) as u64
+    }
+
+    /// Take the RTT sample of the handshake as the smoothed RTT if there is none
+    fn seed(&mut self, rtt: Duration) {
+        self.srtt.get_or_insert(rtt);
+    }
+
+    fn on_acked(&mut self, size: usize, rtt: Duration) {
+        self.srtt = Some(self.srtt.map_or(rtt, |srtt| (srtt * 7 + rtt) / 8));
+        if self.cwnd < self.ssthresh {
+            self.cwnd += size;
+        } else {
+            self.cwnd += (self.mtu * size / self.cwnd).max(1);
+        }
+    }
+
+    /// Whether the loss of the frame set sent at `sent_at` starts a new recovery epoch
+    fn new_epoch(&self, sent_at: Instant) -> bool {
+        self.reduced_at
+            .map_or(true, |reduced_at| sent_at > reduced_at)
+    }
+
+    /// The peer reported the frame set sent at `sent_at` lost by NACK
+    fn on_nacked(&mut self, sent_at: Instant, now: Instant) {
+        if !self.new_epoch(sent_at) {
+            return;
+        }
+        self.reduced_at = Some(now);
+        self.ssthresh = (self.cwnd * 7 / 10).max(PACING_MIN_WINDOW * self.mtu);
+        self.cwnd = self.ssthresh;
+    }
+
+    /// The frame set sent at `sent_at` was not acknowledged within the retransmission timeout
+    fn on_timeout(&mut self, sent_at: Instant, now: Instant) {
+        if self.new_epoch(sent_at) {
+            self.reduced_at = Some(now);
+            self.ssthresh = (self.cwnd / 2).max(PACING_MIN_WINDOW * self.mtu);
+        }
+        self.cwnd = PACING_MIN_WINDOW * self.mtu;
+    }
+}
+
+struct ResendMap {
+    map: HashMap<u24, ResendEntry>,
+    // the total size of the frames waiting for acknowledgement
+    size: usize,
+    role: Role,
+    peer: Peer,
+    last_record_expired_at: Instant,
+    estimator: Box<dyn Estimator + Send + Sync + 'static>,
+    pool: FramesPool,
+    hook: Option<RetransmitHook>,
+    // encrypts the markers of the abandoned frames
+    crypto: Option<Arc<dyn FrameCrypto>>,
+    // compresses the markers of the abandoned frames
+    compression: Option<Arc<dyn FrameCompression>>,
+    // the number of frames abandoned since the last `take_abandoned`
+    abandoned: usize,
+    // the ordered indices of the fragmented messages whose marker has been queued, by their
+    // parted ids
+    abandoned_parts: HashMap<u16, Ordered>,
+    // the sequence number of the next frame set, the ones after it are not sent yet
+    next_seq_num: u24,
+    // the number of acknowledged sequence numbers not sent yet since the last `take_unsent_acks`
+    unsent_acks: usize,
+    blackhole: BlackholeDetector,
+    // the size of frames fitting the path, reported by the blackhole detector
+    fits: Option<usize>,
+    congestion: Option<CongestionWindow>,
+    // spaces out the frame sets by the rate of the congestion window, unlimited without it
+    pacer: Pacer,
+    receipts: ReceiptTracker,
+}
+
+impl ResendMap {
+    fn new(role: Role, peer: Peer, estimator: Box<dyn Estimator + Send + Sync + 'static>) -> Self {
+        Self {
+            map: HashMap::new(),
+            size: 0,
+            role,
+            peer,
+            last_record_expired_at: Instant::now(),
+            estimator,
+            pool: FramesPool::default(),
+            hook: None,
+            crypto: None,
+            compression: None,
+            abandoned: 0,
+            abandoned_parts: HashMap::new(),
+            next_seq_num: 0.into(),
+            unsent_acks: 0,
+            blackhole: BlackholeDetector::default(),
+            fits: None,
+            congestion: None,
+            pacer: Pacer::unlimited(),
+            receipts: ReceiptTracker::new(peer.addr),
+        }
+    }
+
+    /// Take an empty frames vector with at least `cap` capacity from the pool
+    fn take_frames(&mut self, cap: usize) -> Frames {
+        self.pool.take(cap)
+    }
+
+    /// Reserve the room for at least `additional` more frame sets waiting for acknowledgement
+    fn reserve(&mut self, additional: usize) {
+        self.map.reserve(additional);
+    }
+
+    /// Give back a frames vector which will not be resent
+    fn recycle_frames(&mut self, frames: Frames) {
+        self.pool.put(frames);
+    }
+
+    fn record(&mut self, seq_num: u24, frames: Frames) {
+        let now = Instant::now();
+        let size = frames.iter().map(Frame::size).sum();
+        let expired_at = now + self.estimator.rto();
+        self.size += size;
+        self.next_seq_num = seq_num.wrapping_add(1);
+        self.receipts.on_sent(seq_num, &frames, expired_at);
+        self.map.insert(
+            seq_num,
+            ResendEntry {
+                frames: Some(frames),
+                size,
+                send_at: now,
+                expired_at,
+            },
+        );
+    }
+
+    /// Give back the frames of the unreliable frame set `seq_num`, the receipts they carry are
+    /// tracked until it is acknowledged or considered lost
+    fn record_unreliable(&mut self, seq_num: u24, frames: Frames) {
+        self.receipts
+            .on_sent(seq_num, &frames, Instant::now() + self.estimator.rto());
+        self.pool.put(frames);
+    }
+
+    fn remove(&mut self, seq_num: u24) -> Option<ResendEntry> {
+        let entry = self.map.remove(&seq_num)?;
+        self.size -= entry.size;
+        Some(entry)
+    }
+
+    /// The total size of the frames waiting for acknowledgement
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Mark the frame sets before `next` as sent, including the unreliable ones not recorded
+    fn sent_until(&mut self, next: u24) {
+        self.next_seq_num = next;
+    }
+
+    /// Whether the frame set `seq_num` is not sent yet, the half of the sequence number space
+    /// from the next one is considered unsent
+    fn is_unsent(&self, seq_num: u24) -> bool {
+        seq_num.to_u32().wrapping_sub(self.next_seq_num.to_u32()) & 0xff_ffff < 1 << 23
+    }
+
+    /// Take the number of acknowledged sequence numbers not sent yet, which are ignored as they
+    /// may be spoofed
+    fn take_unsent_acks(&mut self) -> usize {
+        std::mem::take(&mut self.unsent_acks)
+    }
+
+    fn on_ack(&mut self, ack: AckOrNack, received_at: Instant) {
+        for record in ack.records {
+            match record {
+                Record::Range(start, end) => {
+                    for i in start.to_u32()..=end.to_u32() {
+                        if self.is_unsent(i.into()) {
+                            self.unsent_acks += 1;
+                            continue;
+                        }
+                        let entry = self.remove(i.into());
+                        self.on_receipts_acked(i.into(), entry.as_ref());
+                        if let Some(ResendEntry {
+                            frames,
+                            size,
+                            send_at,
+                            ..
+                        }) = entry
+                        {
+                            self.pool.put(frames.unwrap());
+                            self.blackhole.on_acked(size);
+                            let rtt = received_at.saturating_duration_since(send_at);
+                            self.estimator.update(rtt);
+                            self.on_congestion(|window| window.on_acked(size, rtt));
+                            add_event("ack.received", || {
+                                [("seq_num", i.to_string()), ("rtt", format!("{rtt:?}"))]
+                            });
+                            trace!(
+                                role:% = self.role,
+                                peer:% = self.peer,
+                                seq_num = i,
+                                rtt:? = rtt,
+                                rto:? = self.estimator.rto();
+                                "frame set is acked"
+                            );
+                        }
+                    }
+                }
+                Record::Single(seq_num) => {
+                    if self.is_unsent(seq_num) {
+                        self.unsent_acks += 1;
+                        continue;
+                    }
+                    let entry = self.remove(seq_num);
+                    self.on_receipts_acked(seq_num, entry.as_ref());
+                    if let Some(ResendEntry {
+                        frames,
+                        size,
+                        send_at,
+                        ..
+                    }) = entry
+                    {
+                        self.pool.put(frames.unwrap());
+                        self.blackhole.on_acked(size);
+                        let rtt = received_at.saturating_duration_since(send_at);
+                        self.estimator.update(rtt);
+                        self.on_congestion(|window| window.on_acked(size, rtt));
+                        add_event("ack.received", || {
+                            [
+                                ("seq_num", seq_num.to_string()),
+                                ("rtt", format!("{rtt:?}")),
+                            ]
+                        });
+                        trace!(
+                            role:% = self.role,
+                            peer:% = self.peer,
+                            seq_num = seq_num.to_u32(),
+                            rtt:? = rtt,
+                            rto:? = self.estimator.rto();
+                            "frame set is acked"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tell the receipts of the frame set `seq_num` acknowledged, `entry` is its record for
+    /// resending if it is reliable
+    fn on_receipts_acked(&mut self, seq_num: u24, entry: Option<&ResendEntry>) {
+        let frames = entry.and_then(|resend| resend.frames.as_deref());
+        self.receipts.on_acked(seq_num, frames.unwrap_or_default());
+    }
+
+    /// `on_nack_into` performs the fast retransmit, it collects the frames reported lost by the
+    /// peer into buffer and returns the number of resent packets.
+    /// Unlike the timeout, the NACK is an explicit signal of loss (e.g. reordering), so the
+    /// estimator is kept as it is.
+    fn on_nack_into(&mut self, nack: AckOrNack, buffer: &mut impl Extend<Frame>) -> usize {
+        trace!(
+            role:% = self.role,
+            peer:% = self.peer,
+            records:? = nack;
+            "receive nack"
+        );
+        add_event("nack.received", || {
+            [("records", format!("{:?}", nack.records))]
+        });
+        let mut resent = 0;
+        let mut largest_lost = 0;
+        let mut latest_sent = None;
+        for record in nack.records {
+            let (start, end) = match record {
+                Record::Range(start, end) => (start, end),
+                Record::Single(seq_num) => (seq_num, seq_num),
+            };
+            for i in start.to_u32()..=end.to_u32() {
+                self.receipts.on_lost(i.into());
+                if let Some(entry) = self.remove(i.into()) {
+                    largest_lost = largest_lost.max(entry.size);
+                    latest_sent = latest_sent.max(Some(entry.send_at));
+                    let mut frames = entry.frames.unwrap();
+                    self.notify(i, retransmit_count(&frames), RetransmitCause::Nack);
+                    self.abandoned += requeue(
+                        &mut frames,
+                        buffer,
+                        &mut self.abandoned_parts,
+                        &mut self.receipts,
+                        self.crypto.as_deref(),
+                        self.compression.as_deref(),
+                        Instant::now(),
+                    );
+                    self.pool.put(frames);
+                    resent += 1;
+                }
+            }
+        }
+        if let Some(sent_at) = latest_sent {
+            if let Some(fits) = self.blackhole.on_lost(largest_lost) {
+                self.fits = Some(fits);
+            }
+            self.on_congestion(|window| window.on_nacked(sent_at, Instant::now()));
+        }
+        resent
+    }
+
+    /// Tell the hook that the frames of the frame set `seq_num` are queued to be resent
+    fn notify(&self, seq_num: u32, retransmits: u32, cause: RetransmitCause) {
+        retransmitted(
+            self.hook.as_ref(),
+            Retransmission {
+                addr: self.peer.addr,
+                seq_num,
+                retransmits,
+                cause,
+            },
+        );
+    }
+
+    /// `process_stales` collect all stale frames into buffer and remove the expired entries,
+    /// returns the number of resent packets
+    fn process_stales(&mut self, buffer: &mut impl Extend<Frame>) -> usize {
+        self.receipts.expire(Instant::now());
+        if self.map.is_empty() {
+            // no part of the abandoned messages is in flight anymore
+            self.abandoned_parts.clear();
+            return 0;
+        }
+
+        let now = Instant::now();
+        if now < self.last_record_expired_at {
+            trace!(
+                role:% = self.role,
+                peer:% = self.peer,
+                expired_in:? = self.last_record_expired_at - now;
+                "skip scanning the resend map"
+            );
+            return 0;
+        }
+        // find the first expired_at larger than now
         let mut min_expired_at = now + self.estimator.rto();
         let len_before = self.map.len();
-        self.map.retain(|_, entry| {
+        let pool = &mut self.pool;
+        let size = &mut self.size;
+        let hook = self.hook.as_ref();
+        let crypto = self.crypto.as_deref();
+        let compression = self.compression.as_deref();
+        let abandoned = &mut self.abandoned;
+        let abandoned_parts = &mut self.abandoned_parts;
+        let receipts = &mut self.receipts;
+        let addr = self.peer.addr;
+        let mut largest_stale = 0;
+        let mut latest_sent = None;
+        self.map.retain(|seq_num, entry| {
             if entry.expired_at <= now {
-                buffer.extend(entry.frames.take().unwrap());
+                *size -= entry.size;
+                largest_stale = largest_stale.max(entry.size);
+                latest_sent = latest_sent.max(Some(entry.send_at));
+                let mut frames = entry.frames.take().unwrap();
+                retransmitted(
+                    hook,
+                    Retransmission {
+                        addr,
+                        seq_num: seq_num.to_u32(),
+                        retransmits: retransmit_count(&frames),
+                        cause: RetransmitCause::Timeout,
+                    },
+                );
+                *abandoned += requeue(
+                    &mut frames,
+                    buffer,
+                    abandoned_parts,
+                    receipts,
+                    crypto,
+                    compression,
+                    now,
+                );
+                pool.put(frames);
                 false
             } else {
                 min_expired_at = min_expired_at.min(entry.expired_at);
@@ -392,22 +1753,106 @@ impl ResendMap {
         self.last_record_expired_at = min_expired_at;
 
         let len = self.map.len();
-        if len_before > len {
+        if let Some(sent_at) = latest_sent {
             // clear the estimator if detected packet loss
             self.estimator.clear();
+            if let Some(fits) = self.blackhole.on_lost(largest_stale) {
+                self.fits = Some(fits);
+            }
+            self.on_congestion(|window| window.on_timeout(sent_at, now));
         }
         trace!(
-            "[{}]: resend {} stales, {} entries remains",
-            self.role,
-            len_before - len,
-            len,
+            role:% = self.role,
+            peer:% = self.peer,
+            resent = len_before - len,
+            remaining = len;
+            "resend the stale frame sets"
         );
+        len_before - len
     }
 
     fn is_empty(&self) -> bool {
         self.map.is_empty()
     }
 
+    /// Take the number of frames abandoned since the last call
+    fn take_abandoned(&mut self) -> usize {
+        std::mem::take(&mut self.abandoned)
+    }
+
+    /// Abandon a frame split for a larger MTU before it was reduced, which the path drops.
+    /// Returns the marker of it to be sent instead.
+    fn abandon_oversized(&mut self, frame: &Frame) -> Option<Frame> {
+        self.abandoned += 1;
+        abandon(
+            frame,
+            &mut self.abandoned_parts,
+            &mut self.receipts,
+            self.crypto.as_deref(),
+            self.compression.as_deref(),
+        )
+    }
+
+    /// Reduce the effective MTU of the link to fit the path once the MTU blackhole is detected,
+    /// `overhead` is the size of the frame set header
+    fn reduce_blackhole_mtu(&mut self, link: &SharedLink, overhead: usize) {
+        if let Some(fits) = self.fits.take() {
+            let mtu = (fits + overhead).max(MIN_BLACKHOLE_MTU);
+            link.reduce_mtu(u16::try_from(mtu).unwrap_or(u16::MAX));
+        }
+    }
+
+    /// Seed the RTT estimation with the round trip of the handshake once it is recorded, so that
+    /// the first retransmission timeout does not start cold
+    fn seed_handshake_rtt(&mut self, link: &SharedLink) {
+        if let Some(rtt) = link.take_handshake_rtt() {
+            trace!(role:% = self.role, peer:% = self.peer, rtt:?; "seed rtt from handshake");
+            self.estimator.seed(rtt);
+            self.on_congestion(|window| window.seed(rtt));
+        }
+    }
+
+    /// Abandon all frames waiting for acknowledgement, returns the number of abandoned frames. The
+    /// receipts not acknowledged yet are reported lost.
+    fn abandon(&mut self) -> usize {
+        self.receipts.on_closed();
+        self.size = 0;
+        self.map
+            .drain()
+            .map(|(_, entry)| entry.frames.map_or(0, |frames| frames.len()))
+            .sum()
+    }
+
+    /// Update the congestion window if pacing is enabled, and the pacing rate along with it
+    fn on_congestion(&mut self, update: impl FnOnce(&mut CongestionWindow)) {
+        if let Some(window) = &mut self.congestion {
+            update(window);
+            self.pacer.set_rate(window.rate(), Instant::now());
+        }
+    }
+
+    /// `poll_pace` consumes the pacing tokens of the frame set to be sent, or puts the frames back
+    /// to `buf` in order and suspends the task until the tokens are refilled. The frames are
+    /// marked as never expiring once they could be sent, since they are kept for resending.
+    fn poll_pace(
+        &mut self,
+        buf: &mut OutgoingBuf,
+        mut frames: Frames,
+        cx: &mut Context<'_>,
+    ) -> Poll<Frames> {
+        let c_id = ConnId::new(self.role.guid(), self.peer.guid, self.peer.addr);
+        let size = frames.iter().map(Frame::size).sum();
+        if self.pacer.poll_consume(c_id, size, cx).is_pending() {
+            buf.extend(frames.drain(..).rev());
+            self.recycle_frames(frames);
+            return Poll::Pending;
+        }
+        for frame in &mut frames {
+            frame.deadline = None;
+        }
+        Poll::Ready(frames)
+    }
+
     /// `poll_wait` suspends the task when the resend map needs to wait for the next resend
     fn poll_wait(&self, cx: &mut Context<'_>) -> Poll<()> {
         let expired_at;
@@ -421,34 +1866,179 @@ impl ResendMap {
         } else {
             return Poll::Ready(());
         }
-        let c_id = ConnId::new(self.role.guid(), self.peer.guid);
+        let c_id = ConnId::new(self.role.guid(), self.peer.guid, self.peer.addr);
         trace!(
-            "[{}]: wait on {c_id:?} for resend seq_num {} to {} within {:?}",
-            self.role,
-            seq_num,
-            self.peer,
-            expired_at - now
+            role:% = self.role,
+            peer:% = self.peer,
+            seq_num = seq_num.to_u32(),
+            wait:? = expired_at - now;
+            "wait for resending"
         );
-        Reactor::get().insert_timer(c_id, expired_at, cx.waker());
+        Reactor::get().insert_timer(c_id, TimerKind::Retransmit, expired_at, cx.waker());
         Poll::Pending
     }
 }
 
-#[cfg(test)]
-mod test {
-    use std::collections::VecDeque;
+/// Micro bench helper
+#[cfg(feature = "micro-bench")]
+pub mod micro_bench {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
     use std::task::{Context, Poll};
-    use std::time::{Duration, Instant};
+    use std::time::Duration;
 
-    use bytes::Bytes;
+    use bytes::BytesMut;
+    use futures::{Sink, SinkExt};
 
-    use super::ResendMap;
-    use crate::estimator::RFC6298Impl;
-    use crate::packet::connected::{AckOrNack, Flags, Frame};
-    use crate::utils::tests::{test_trace_log_setup, TestWaker};
-    use crate::{Peer, Reliability, Role};
+    use super::HandleOutgoing;
+    use crate::link::TransferLink;
+    use crate::packet::connected::micro_bench::Mix;
+    use crate::packet::connected::{Frames, FramesRef};
+    use crate::packet::{Packet, MAGIC};
+    use crate::{Peer, Role};
 
-    const TEST_RTO: Duration = Duration::from_millis(1200);
+    /// The in-memory transport encoding the packets flushed by the outgoing guard
+    #[derive(Debug, Default)]
+    struct MemoryTransport {
+        buf: BytesMut,
+        datagrams: usize,
+    }
+
+    impl Sink<(Packet<FramesRef<'_>>, SocketAddr)> for MemoryTransport {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(
+            self: Pin<&mut Self>,
+            (packet, _): (Packet<FramesRef<'_>>, SocketAddr),
+        ) -> Result<(), Self::Error> {
+            let this = self.get_mut();
+            this.buf.clear();
+            packet.write(&mut this.buf, &MAGIC, false);
+            this.datagrams += 1;
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// The frames to be flushed through the outgoing guard
+    #[derive(Debug, Clone)]
+    pub struct FlushOpts {
+        frames: Frames,
+        mtu: u16,
+        pooled: bool,
+    }
+
+    impl FlushOpts {
+        pub fn new(mix: Mix, cnt: usize, mtu: u16) -> Self {
+            Self {
+                frames: mix.frames(cnt, mtu as usize),
+                mtu,
+                pooled: true,
+            }
+        }
+
+        /// Allocate a new frames vector for each frame set instead of recycling them, as the
+        /// outgoing guard did before pooling
+        #[must_use]
+        pub fn without_pool(mut self) -> Self {
+            self.pooled = false;
+            self
+        }
+
+        /// Send all frames to the outgoing guard over the in-memory transport and flush them,
+        /// returns the number of the datagrams sent
+        #[allow(clippy::missing_panics_doc)]
+        pub async fn run_bench(self) -> usize {
+            let peer = Peer {
+                guid: 0,
+                addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+                mtu: self.mtu,
+            };
+            let role = Role::Server { guid: 0 };
+            let link = TransferLink::new_arc(role, peer, Duration::from_secs(1));
+            let mut guard = MemoryTransport::default().handle_outgoing(
+                link,
+                self.frames.len().max(1),
+                0,
+                peer,
+                role,
+            );
+            if !self.pooled {
+                guard.resend.pool.cap = 0;
+            }
+            for frame in self.frames {
+                guard.feed(frame).await.unwrap();
+            }
+            guard.flush().await.unwrap();
+            guard.frame.datagrams
+        }
+
+        pub fn bytes(&self) -> u64 {
+            self.frames
+                .iter()
+                .map(|frame| frame.body.len() as u64)
+                .sum()
+        }
+
+        pub fn elements(&self) -> u64 {
+            self.frames.len() as u64
+        }
+    }
+
+    #[cfg(test)]
+    #[tokio::test]
+    async fn test_bench() {
+        let opts = FlushOpts::new(Mix::Mixed, 100, 1400);
+        assert_eq!(opts.elements(), 100);
+        // the buffered frames are sent eagerly when polling ready, one frame set for each
+        assert_eq!(opts.clone().run_bench().await, 100);
+        // the pool only saves the allocations
+        assert_eq!(opts.without_pool().run_bench().await, 100);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, ContextBuilder, Poll};
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+    use futures::Sink;
+
+    use super::{
+        BlackholeDetector, CongestionWindow, HandleOutgoing, OutgoingBuf, OutgoingGuard, ResendMap,
+    };
+    use crate::errors::FrameExpired;
+    use crate::estimator::RFC6298Impl;
+    use crate::link::{Route, SharedLink, TransferLink};
+    use crate::opts::{FlushStrategy, ReceiptHook, RetransmitCause, RetransmitHook};
+    use crate::packet::connected::{
+        self, AckOrNack, Flags, Fragment, Frame, FrameBody, FrameSet, FramesRef, Ordered,
+    };
+    use crate::packet::{PackType, Packet};
+    use crate::utils::tests::{test_trace_log_setup, TestWaker};
+    use crate::utils::{timestamp, u24, FairShare};
+    use crate::{Peer, Priority, Reliability, Role};
+
+    const TEST_RTO: Duration = Duration::from_millis(1200);
 
     #[test]
     fn test_resend_map_works() {
@@ -476,6 +2066,13 @@ mod test {
                 seq_frame_index: None,
                 ordered: None,
                 fragment: None,
+                priority: Priority::Normal,
+                coalesce: false,
+                received: None,
+                deadline: None,
+                abandon_at: None,
+                receipt: None,
+                retransmits: 0,
                 body: Bytes::from_static(b"1"),
             }],
         );
@@ -488,6 +2085,13 @@ mod test {
                     seq_frame_index: None,
                     ordered: None,
                     fragment: None,
+                    priority: Priority::Normal,
+                    coalesce: false,
+                    received: None,
+                    deadline: None,
+                    abandon_at: None,
+                    receipt: None,
+                    retransmits: 0,
                     body: Bytes::from_static(b"2"),
                 },
                 Frame {
@@ -496,15 +2100,23 @@ mod test {
                     seq_frame_index: None,
                     ordered: None,
                     fragment: None,
+                    priority: Priority::Normal,
+                    coalesce: false,
+                    received: None,
+                    deadline: None,
+                    abandon_at: None,
+                    receipt: None,
+                    retransmits: 0,
                     body: Bytes::from_static(b"3"),
                 },
             ],
         );
         let mut buffer = VecDeque::default();
-        map.on_nack_into(
-            AckOrNack::extend_from([4, 5].into_iter().map(Into::into), 100).unwrap(),
+        let resent = map.on_nack_into(
+            AckOrNack::extend_from([4, 5, 6].into_iter().map(Into::into), 100).unwrap(),
             &mut buffer,
         );
+        assert_eq!(resent, 2);
         assert!(map.is_empty());
         assert_eq!(buffer.len(), 3);
         assert_eq!(buffer.pop_front().unwrap().body, Bytes::from_static(b"1"));
@@ -512,6 +2124,93 @@ mod test {
         assert_eq!(buffer.pop_front().unwrap().body, Bytes::from_static(b"3"));
     }
 
+    #[test]
+    fn test_resend_map_ack_flood() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let mut map = ResendMap::new(
+            Role::test_server(),
+            Peer::test(),
+            Box::new(RFC6298Impl::new()),
+        );
+        // flood more acks than the ack buffer could hold
+        for i in 0..4096 {
+            map.record(i.into(), vec![]);
+            link.incoming_ack(AckOrNack::extend_from(std::iter::once(i.into()), 100).unwrap());
+        }
+        link.process_ack()
+            .for_each(|(ack, received_at)| map.on_ack(ack, received_at));
+        // no ack is lost, so nothing will be resent
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_resend_map_unsent_acks() {
+        let mut map = ResendMap::new(
+            Role::test_server(),
+            Peer::test(),
+            Box::new(RFC6298Impl::new()),
+        );
+        map.record(0.into(), vec![]);
+        map.record(1.into(), vec![]);
+        // the unreliable frame set 2 is sent but not recorded
+        map.sent_until(3.into());
+
+        map.on_ack(
+            AckOrNack::extend_from([2, 3, 4, 100].into_iter().map(Into::into), 100).unwrap(),
+            Instant::now(),
+        );
+        assert_eq!(map.take_unsent_acks(), 3);
+        assert_eq!(map.take_unsent_acks(), 0);
+        // the outstanding frame sets are untouched
+        assert_eq!(map.map.len(), 2);
+
+        // the sequence numbers wrapped behind the next one are sent
+        map.sent_until(2.into());
+        map.on_ack(
+            AckOrNack::extend_from([0xff_fff0, 0, 1].into_iter().map(Into::into), 100).unwrap(),
+            Instant::now(),
+        );
+        assert_eq!(map.take_unsent_acks(), 0);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_resend_map_recycle_frames() {
+        let mut map = ResendMap::new(
+            Role::test_server(),
+            Peer::test(),
+            Box::new(RFC6298Impl::new()),
+        );
+        let body = Bytes::from(vec![0xfe; 8]);
+        let mut frames = map.take_frames(4);
+        frames.push(Frame {
+            flags: Flags::new(Reliability::Reliable, false),
+            reliable_frame_index: Some(0.into()),
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            priority: Priority::Normal,
+            coalesce: false,
+            received: None,
+            deadline: None,
+            abandon_at: None,
+            receipt: None,
+            retransmits: 0,
+            body: body.clone(),
+        });
+        map.record(0.into(), frames);
+        assert!(!body.is_unique());
+        map.on_ack(
+            AckOrNack::extend_from(std::iter::once(0.into()), 100).unwrap(),
+            Instant::now(),
+        );
+        // the frame is dropped and the body is released
+        assert!(body.is_unique());
+        let recycled = map.take_frames(0);
+        assert!(recycled.is_empty());
+        assert!(recycled.capacity() >= 4);
+    }
+
     #[test]
     fn test_resend_map_stales() {
         let mut map = ResendMap::new(
@@ -525,10 +2224,470 @@ mod test {
         std::thread::sleep(TEST_RTO);
         map.record(3.into(), vec![]);
         let mut buffer = VecDeque::default();
-        map.process_stales(&mut buffer);
+        assert_eq!(map.process_stales(&mut buffer), 3);
         assert_eq!(map.map.len(), 1);
     }
 
+    #[test]
+    fn test_resend_map_retransmit_hook() {
+        let retransmissions = Arc::new(Mutex::new(Vec::new()));
+        let mut map = ResendMap::new(
+            Role::test_server(),
+            Peer::test(),
+            Box::new(RFC6298Impl::new()),
+        );
+        map.hook = Some(RetransmitHook::new({
+            let retransmissions = Arc::clone(&retransmissions);
+            move |retransmission| retransmissions.lock().unwrap().push(retransmission)
+        }));
+        map.record(0.into(), vec![]);
+        map.record(
+            1.into(),
+            vec![Frame::new(
+                Flags::new(Reliability::Reliable, false),
+                Bytes::from_static(b"1"),
+            )],
+        );
+        let mut buffer = VecDeque::default();
+        map.on_nack_into(
+            AckOrNack::extend_from([1, 2].into_iter().map(Into::into), 100).unwrap(),
+            &mut buffer,
+        );
+        std::thread::sleep(TEST_RTO);
+        map.process_stales(&mut buffer);
+        // the resent frame is lost again in a new frame set
+        map.record(3.into(), buffer.drain(..).collect());
+        map.on_nack_into(
+            AckOrNack::extend_from([3].into_iter().map(Into::into), 100).unwrap(),
+            &mut buffer,
+        );
+
+        let retransmissions = retransmissions.lock().unwrap();
+        assert_eq!(retransmissions.len(), 3);
+        assert_eq!(retransmissions[0].seq_num(), 1);
+        assert_eq!(retransmissions[0].retransmits(), 1);
+        assert_eq!(retransmissions[0].cause(), RetransmitCause::Nack);
+        assert_eq!(retransmissions[0].addr(), Peer::test().addr);
+        assert_eq!(retransmissions[1].seq_num(), 0);
+        assert_eq!(retransmissions[1].retransmits(), 1);
+        assert_eq!(retransmissions[1].cause(), RetransmitCause::Timeout);
+        assert_eq!(retransmissions[2].seq_num(), 3);
+        assert_eq!(retransmissions[2].retransmits(), 2);
+    }
+
+    #[test]
+    fn test_resend_map_ack_receipt() {
+        let receipts = Arc::new(Mutex::new(Vec::new()));
+        let mut map = ResendMap::new(
+            Role::test_server(),
+            Peer::test(),
+            Box::new(RFC6298Impl::new()),
+        );
+        map.receipts.hook = Some(ReceiptHook::new({
+            let receipts = Arc::clone(&receipts);
+            move |receipt| receipts.lock().unwrap().push(receipt)
+        }));
+        let frame = |reliability, receipt| {
+            let mut frame = Frame::new(Flags::new(reliability, false), Bytes::from_static(b"1"));
+            frame.receipt = Some(receipt);
+            frame
+        };
+        let take = || {
+            receipts
+                .lock()
+                .unwrap()
+                .drain(..)
+                .map(|receipt| (receipt.receipt(), receipt.acked()))
+                .collect::<Vec<_>>()
+        };
+
+        // a reliable message of two parts
+        let parts = [
+            frame(Reliability::ReliableWithAckReceipt, 1),
+            frame(Reliability::ReliableWithAckReceipt, 1),
+        ];
+        parts.iter().for_each(|part| map.receipts.on_queued(part));
+        map.record(0.into(), vec![parts[0].clone()]);
+        map.record(1.into(), vec![parts[1].clone()]);
+        for (seq_num, receipt) in [(2, 2), (3, 3), (4, 4)] {
+            map.record_unreliable(
+                seq_num.into(),
+                vec![frame(Reliability::UnreliableWithAckReceipt, receipt)],
+            );
+        }
+        map.sent_until(5.into());
+
+        map.on_ack(
+            AckOrNack::extend_from([0, 2].into_iter().map(Into::into), 100).unwrap(),
+            Instant::now(),
+        );
+        assert_eq!(take(), vec![(2, true)]);
+        map.on_ack(
+            AckOrNack::extend_from([1].into_iter().map(Into::into), 100).unwrap(),
+            Instant::now(),
+        );
+        assert_eq!(take(), vec![(1, true)]);
+
+        let mut buffer = VecDeque::default();
+        map.on_nack_into(
+            AckOrNack::extend_from([3].into_iter().map(Into::into), 100).unwrap(),
+            &mut buffer,
+        );
+        assert_eq!(take(), vec![(3, false)]);
+        map.receipts.expire(Instant::now() + TEST_RTO);
+        assert_eq!(take(), vec![(4, false)]);
+        assert!(map.receipts.pending.is_empty());
+        assert!(map.receipts.unreliable.is_empty());
+    }
+
+    #[test]
+    fn test_resend_map_lost_receipts() {
+        let receipts = Arc::new(Mutex::new(Vec::new()));
+        let mut map = ResendMap::new(
+            Role::test_server(),
+            Peer::test(),
+            Box::new(RFC6298Impl::new()),
+        );
+        map.receipts.hook = Some(ReceiptHook::new({
+            let receipts = Arc::clone(&receipts);
+            move |receipt| receipts.lock().unwrap().push(receipt)
+        }));
+        let frame = |reliability, receipt| {
+            let mut frame = Frame::new(Flags::new(reliability, false), Bytes::from_static(b"1"));
+            frame.receipt = Some(receipt);
+            frame.ordered = Some(Ordered {
+                frame_index: 0.into(),
+                channel: 0,
+            });
+            frame
+        };
+        let take = || {
+            let mut taken = receipts
+                .lock()
+                .unwrap()
+                .drain(..)
+                .map(|receipt| (receipt.receipt(), receipt.acked()))
+                .collect::<Vec<_>>();
+            taken.sort_unstable();
+            taken
+        };
+
+        // expired before sent
+        let now = Instant::now();
+        let mut buf = OutgoingBuf::with_capacity(2);
+        let mut expired = frame(Reliability::UnreliableWithAckReceipt, 1);
+        expired.deadline = Some(now);
+        buf.push_front(expired);
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        assert!(OutgoingGuard::<StuckTransport>::drop_expired(
+            &mut buf,
+            &mut map.receipts,
+            &link,
+            Role::test_server(),
+            Peer::test(),
+            false,
+            now,
+        )
+        .unwrap());
+        assert_eq!(take(), vec![(1, false)]);
+
+        // superseded by a coalescing frame
+        let mut superseded = frame(Reliability::ReliableOrderedWithAckReceipt, 2);
+        superseded.coalesce = true;
+        map.receipts.on_queued(&superseded);
+        buf.push_front(superseded.clone());
+        buf.drop_superseded(&superseded, |dropped| map.receipts.on_dropped(dropped));
+        assert_eq!(take(), vec![(2, false)]);
+
+        // abandoned when resending, the other part is not reported again once acked
+        let mut parts = [
+            frame(Reliability::ReliableOrderedWithAckReceipt, 3),
+            frame(Reliability::ReliableOrderedWithAckReceipt, 3),
+        ];
+        parts[0].abandon_at = Some(now);
+        parts.iter().for_each(|part| map.receipts.on_queued(part));
+        map.record(0.into(), vec![parts[0].clone()]);
+        map.record(1.into(), vec![parts[1].clone()]);
+        let mut buffer = VecDeque::default();
+        map.on_nack_into(
+            AckOrNack::extend_from([0].into_iter().map(Into::into), 100).unwrap(),
+            &mut buffer,
+        );
+        map.on_ack(
+            AckOrNack::extend_from([1].into_iter().map(Into::into), 100).unwrap(),
+            Instant::now(),
+        );
+        assert_eq!(take(), vec![(3, false)]);
+
+        // abandoned exceeding the reduced MTU
+        let oversized = frame(Reliability::ReliableOrderedWithAckReceipt, 4);
+        map.receipts.on_queued(&oversized);
+        assert!(map.abandon_oversized(&oversized).is_some());
+        assert_eq!(take(), vec![(4, false)]);
+
+        // closed without the acknowledgements
+        let unacked = frame(Reliability::ReliableWithAckReceipt, 5);
+        map.receipts.on_queued(&unacked);
+        map.record(2.into(), vec![unacked]);
+        map.record_unreliable(
+            3.into(),
+            vec![frame(Reliability::UnreliableWithAckReceipt, 6)],
+        );
+        map.sent_until(4.into());
+        map.abandon();
+        assert_eq!(take(), vec![(5, false), (6, false)]);
+        assert!(map.receipts.pending.is_empty());
+        assert!(map.receipts.unreliable.is_empty());
+    }
+
+    #[test]
+    fn test_resend_map_abandon() {
+        let mut map = ResendMap::new(
+            Role::test_server(),
+            Peer::test(),
+            Box::new(RFC6298Impl::new()),
+        );
+        let abandoned = Instant::now();
+        let frame = |reliability: Reliability, abandon_at: Option<Instant>| {
+            let mut frame = Frame::new(
+                Flags::new(reliability, false),
+                Bytes::from_static(b"\xfeabandon"),
+            );
+            frame.reliable_frame_index = Some(7.into());
+            frame.ordered = reliability.is_ordered().then_some(Ordered {
+                frame_index: 3.into(),
+                channel: 1,
+            });
+            frame.abandon_at = abandon_at;
+            frame
+        };
+        map.record(
+            0.into(),
+            vec![
+                frame(Reliability::Reliable, None),
+                frame(Reliability::Reliable, Some(abandoned)),
+                frame(Reliability::ReliableOrdered, Some(abandoned)),
+                frame(
+                    Reliability::ReliableOrdered,
+                    Some(abandoned + Duration::from_secs(10)),
+                ),
+            ],
+        );
+        let mut buffer = VecDeque::default();
+        map.on_nack_into(
+            AckOrNack::extend_from([0].into_iter().map(Into::into), 100).unwrap(),
+            &mut buffer,
+        );
+        assert_eq!(map.take_abandoned(), 2);
+        assert_eq!(map.take_abandoned(), 0);
+        assert!(map.is_empty());
+
+        // the abandoned unordered frame is dropped, the ordered one is replaced by the marker
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer[0].body, Bytes::from_static(b"\xfeabandon"));
+        let marker = &buffer[1];
+        assert_eq!(marker.body.as_ref(), [PackType::SndReceiptLoss as u8]);
+        assert!(matches!(
+            FrameBody::read(marker.body.clone()),
+            Ok(FrameBody::Abandoned)
+        ));
+        assert_eq!(marker.reliable_frame_index, Some(7.into()));
+        assert_eq!(marker.ordered.unwrap().frame_index, 3.into());
+        assert_eq!(marker.ordered.unwrap().channel, 1);
+        assert!(marker.abandon_at.is_none());
+        assert_eq!(buffer[2].body, Bytes::from_static(b"\xfeabandon"));
+    }
+
+    #[test]
+    fn test_resend_map_abandon_fragmented() {
+        let mut map = ResendMap::new(
+            Role::test_server(),
+            Peer::test(),
+            Box::new(RFC6298Impl::new()),
+        );
+        let abandoned = Instant::now();
+        let part = |parted_id: u16, parted_index: u32| {
+            let mut frame = Frame::new(
+                Flags::new(Reliability::ReliableOrdered, true),
+                Bytes::from_static(b"\xfepart"),
+            );
+            frame.reliable_frame_index = Some(parted_index.into());
+            frame.ordered = Some(Ordered {
+                frame_index: u24::from(u32::from(parted_id)),
+                channel: 0,
+            });
+            frame.fragment = Some(Fragment {
+                parted_size: 3,
+                parted_id,
+                parted_index,
+            });
+            frame.abandon_at = Some(abandoned);
+            frame
+        };
+        // a message of 3 parts, each of them is sent in its own frame set
+        map.record(0.into(), vec![part(5, 0)]);
+        map.record(1.into(), vec![part(5, 1), part(6, 0)]);
+        map.record(2.into(), vec![part(5, 2)]);
+        let mut buffer = VecDeque::default();
+        map.on_nack_into(
+            AckOrNack::extend_from([0, 1].into_iter().map(Into::into), 100).unwrap(),
+            &mut buffer,
+        );
+        map.on_nack_into(
+            AckOrNack::extend_from([2].into_iter().map(Into::into), 100).unwrap(),
+            &mut buffer,
+        );
+        assert_eq!(map.take_abandoned(), 4);
+
+        // one unfragmented marker for each message
+        assert_eq!(buffer.len(), 2);
+        for (marker, frame_index) in buffer.iter().zip([5, 6]) {
+            assert!(matches!(
+                FrameBody::read(marker.body.clone()),
+                Ok(FrameBody::Abandoned)
+            ));
+            assert!(!marker.flags.parted);
+            assert!(marker.fragment.is_none());
+            assert_eq!(marker.ordered.unwrap().frame_index, frame_index.into());
+        }
+
+        // the parted id is reused by a later message
+        let mut reused = part(5, 0);
+        reused.ordered = Some(Ordered {
+            frame_index: 7.into(),
+            channel: 0,
+        });
+        map.record(3.into(), vec![reused]);
+        map.on_nack_into(
+            AckOrNack::extend_from([3].into_iter().map(Into::into), 100).unwrap(),
+            &mut buffer,
+        );
+        assert_eq!(buffer.len(), 3);
+        assert!(map.is_empty());
+        map.process_stales(&mut buffer);
+        assert!(map.abandoned_parts.is_empty());
+    }
+
+    #[test]
+    fn test_blackhole_detector() {
+        let mut detector = BlackholeDetector {
+            retries: 2,
+            ..Default::default()
+        };
+        // nothing acknowledged yet
+        assert_eq!(detector.on_lost(1400), None);
+
+        detector.on_acked(500);
+        assert_eq!(detector.on_lost(1400), None);
+        // the large frame set gets through, the strikes restart
+        detector.on_acked(1400);
+        detector.on_acked(500);
+        assert_eq!(detector.on_lost(1400), None);
+        // the small ones are lost as well, it is an ordinary loss
+        detector.on_acked(500);
+        assert_eq!(detector.on_lost(300), None);
+
+        detector.on_acked(600);
+        assert_eq!(detector.on_lost(1400), None);
+        detector.on_acked(500);
+        assert_eq!(detector.on_lost(1400), Some(500));
+        assert_eq!(detector.strikes, 0);
+
+        let mut disabled = BlackholeDetector::default();
+        disabled.on_acked(500);
+        assert_eq!(disabled.on_lost(1400), None);
+    }
+
+    #[test]
+    fn test_resend_map_handshake_rtt() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let mut map = ResendMap::new(
+            Role::test_server(),
+            Peer::test(),
+            Box::new(RFC6298Impl::new()),
+        );
+        map.congestion = Some(CongestionWindow::new(1000));
+        // nothing is recorded, the estimator is cold
+        map.seed_handshake_rtt(&link);
+        assert_eq!(map.estimator.rto(), Duration::from_secs(1));
+
+        // the timestamp in the future is ignored
+        link.record_handshake_rtt(timestamp() + 1000);
+        assert_eq!(link.take_handshake_rtt(), None);
+
+        link.record_handshake_rtt(timestamp() - 500);
+        map.seed_handshake_rtt(&link);
+        assert_eq!(link.take_handshake_rtt(), None);
+        let rtt = map.congestion.as_ref().unwrap().srtt.unwrap();
+        assert!(rtt >= Duration::from_millis(500) && rtt < Duration::from_millis(600));
+        // the seeded RTT is the first sample: srtt + 4 * (srtt / 2)
+        assert_eq!(map.estimator.rto(), rtt * 3);
+
+        // seeded only once
+        link.record_handshake_rtt(timestamp() - 100);
+        map.seed_handshake_rtt(&link);
+        assert_eq!(map.congestion.as_ref().unwrap().srtt, Some(rtt));
+        assert_eq!(map.estimator.rto(), rtt * 3);
+    }
+
+    #[test]
+    fn test_congestion_window_works() {
+        let now = Instant::now();
+        let mut window = CongestionWindow::new(1000);
+        // 2 * 10000 bytes per 333ms before any RTT sample
+        assert_eq!(window.rate(), 60060);
+
+        // the slow start doubles the window every RTT
+        window.on_acked(10000, Duration::from_millis(100));
+        assert_eq!(window.cwnd, 20000);
+        assert_eq!(window.rate(), 400000);
+
+        window.on_nacked(now, now + Duration::from_millis(10));
+        assert_eq!(window.ssthresh, 14000);
+        assert_eq!(window.cwnd, 14000);
+        // the window is reduced once in a recovery epoch
+        window.on_nacked(now, now + Duration::from_millis(50));
+        assert_eq!(window.cwnd, 14000);
+        assert_eq!(window.rate(), 175000);
+
+        // about a frame set every RTT after the slow start
+        window.on_acked(14000, Duration::from_millis(100));
+        assert_eq!(window.cwnd, 15000);
+
+        // a frame set sent after the reduction starts a new epoch
+        window.on_nacked(now + Duration::from_secs(1), now + Duration::from_secs(2));
+        assert_eq!(window.cwnd, 10500);
+        for secs in 3..8 {
+            let sent_at = now + Duration::from_secs(secs);
+            window.on_nacked(sent_at, sent_at + Duration::from_millis(10));
+        }
+        assert_eq!(window.cwnd, 2000);
+    }
+
+    #[test]
+    fn test_congestion_window_nack_and_timeout() {
+        let now = Instant::now();
+        let mut nacked = CongestionWindow::new(1000);
+        let mut timed_out = CongestionWindow::new(1000);
+        nacked.on_nacked(now, now + Duration::from_millis(10));
+        timed_out.on_timeout(now, now + Duration::from_millis(10));
+        assert_eq!((nacked.ssthresh, nacked.cwnd), (7000, 7000));
+        // the timeout collapses the window and probes by the slow start again
+        assert_eq!((timed_out.ssthresh, timed_out.cwnd), (5000, 2000));
+        timed_out.on_acked(2000, Duration::from_millis(100));
+        assert_eq!(timed_out.cwnd, 4000);
+
+        // a timeout in the same epoch collapses the window without halving the threshold again
+        nacked.on_timeout(now, now + Duration::from_millis(20));
+        assert_eq!((nacked.ssthresh, nacked.cwnd), (7000, 2000));
+        timed_out.on_timeout(now, now + Duration::from_millis(20));
+        assert_eq!((timed_out.ssthresh, timed_out.cwnd), (5000, 2000));
+        // nor does a NACK after it
+        timed_out.on_acked(3000, Duration::from_millis(100));
+        timed_out.on_nacked(now, now + Duration::from_millis(30));
+        assert_eq!((timed_out.ssthresh, timed_out.cwnd), (5000, 5000));
+    }
+
     #[tokio::test]
     async fn test_resend_map_poll_wait() {
         let _guard = test_trace_log_setup();
@@ -556,4 +2715,763 @@ mod test {
         map.process_stales(&mut buffer);
         assert!(map.map.len() < 3);
     }
+
+    #[test]
+    fn test_outgoing_buf_priority() {
+        let frame = |priority, body| Frame {
+            flags: Flags::new(Reliability::Unreliable, false),
+            reliable_frame_index: None,
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            priority,
+            coalesce: false,
+            received: None,
+            deadline: None,
+            abandon_at: None,
+            receipt: None,
+            retransmits: 0,
+            body: Bytes::from_static(body),
+        };
+        let mut buf = OutgoingBuf::with_capacity(4);
+        buf.push_front(frame(Priority::Low, b"1"));
+        buf.push_front(frame(Priority::Normal, b"2"));
+        buf.push_front(frame(Priority::High, b"3"));
+        buf.push_front(frame(Priority::Normal, b"4"));
+        buf.push_front(frame(Priority::High, b"5"));
+        // resending frames go first among the same priority
+        buf.extend([frame(Priority::Normal, b"6")]);
+        assert_eq!(buf.len(), 6);
+        assert_eq!(buf.size(), 6 * frame(Priority::Normal, b"0").size());
+
+        let bodies: Vec<_> = std::iter::from_fn(|| buf.pop_back())
+            .map(|f| f.body)
+            .collect();
+        assert_eq!(bodies, [&b"3"[..], b"5", b"6", b"2", b"4", b"1"]);
+        assert!(buf.is_empty());
+        assert_eq!(buf.size(), 0);
+    }
+
+    #[test]
+    fn test_outgoing_buf_drop_superseded() {
+        let frame = |channel, priority, coalesce, body| Frame {
+            flags: Flags::new(Reliability::UnreliableSequenced, false),
+            reliable_frame_index: None,
+            seq_frame_index: Some(0.into()),
+            ordered: Some(Ordered {
+                frame_index: 0.into(),
+                channel,
+            }),
+            fragment: None,
+            priority,
+            coalesce,
+            received: None,
+            deadline: None,
+            abandon_at: None,
+            receipt: None,
+            retransmits: 0,
+            body: Bytes::from_static(body),
+        };
+        let mut buf = OutgoingBuf::with_capacity(4);
+        buf.push_front(frame(0, Priority::Normal, true, b"1"));
+        buf.push_front(frame(0, Priority::High, true, b"2"));
+        buf.push_front(frame(0, Priority::Normal, false, b"3"));
+        buf.push_front(frame(1, Priority::Normal, true, b"4"));
+
+        let newer = frame(0, Priority::Normal, true, b"5");
+        // only the coalescing frames in the same channel are dropped
+        assert_eq!(buf.drop_superseded(&newer, |_| {}), 2);
+        buf.push_front(newer);
+        assert_eq!(
+            buf.size(),
+            3 * frame(0, Priority::Normal, true, b"0").size()
+        );
+
+        let bodies: Vec<_> = std::iter::from_fn(|| buf.pop_back())
+            .map(|f| f.body)
+            .collect();
+        assert_eq!(bodies, [&b"3"[..], b"4", b"5"]);
+        assert_eq!(buf.size(), 0);
+    }
+
+    // Returns `WouldBlock` for every other send
+    #[derive(Default)]
+    struct FlakyTransport {
+        blocked: bool,
+        sent: Vec<(u24, Vec<Bytes>)>,
+    }
+
+    impl<'a> Sink<(Packet<FramesRef<'a>>, SocketAddr)> for FlakyTransport {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(
+            self: Pin<&mut Self>,
+            (pack, _): (Packet<FramesRef<'a>>, SocketAddr),
+        ) -> Result<(), Self::Error> {
+            let this = self.get_mut();
+            this.blocked = !this.blocked;
+            if this.blocked {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            if let Packet::Connected(connected::Packet::FrameSet(frame_set)) = pack {
+                let bodies = frame_set
+                    .set
+                    .iter()
+                    .map(|frame| frame.body.clone())
+                    .collect();
+                this.sent.push((frame_set.seq_num, bodies));
+            }
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_outgoing_guard_would_block() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let mut guard = FlakyTransport::default().handle_outgoing(
+            link,
+            8,
+            0,
+            Peer::test(),
+            Role::test_server(),
+        );
+        // each frame takes a frame set
+        let bodies = [Bytes::from(vec![0xfe; 1000]), Bytes::from(vec![0xfd; 1000])];
+        for body in &bodies {
+            Pin::new(&mut guard)
+                .start_send(Frame {
+                    flags: Flags::new(Reliability::Unreliable, false),
+                    reliable_frame_index: None,
+                    seq_frame_index: None,
+                    ordered: None,
+                    fragment: None,
+                    priority: Priority::Normal,
+                    coalesce: false,
+                    received: None,
+                    deadline: None,
+                    abandon_at: None,
+                    receipt: None,
+                    retransmits: 0,
+                    body: body.clone(),
+                })
+                .unwrap();
+        }
+
+        let (waker, test_waker) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+        // each frame set is blocked once and then retried
+        for _ in 0..2 {
+            assert!(Pin::new(&mut guard).poll_flush(&mut cx).is_pending());
+            assert!(test_waker.woken.swap(false, Ordering::Relaxed));
+        }
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert!(guard.buf.is_empty());
+        assert_eq!(
+            guard.frame.sent,
+            vec![
+                (0.into(), vec![bodies[0].clone()]),
+                (1.into(), vec![bodies[1].clone()])
+            ]
+        );
+    }
+
+    // Never ready to send
+    struct StuckTransport;
+
+    impl<'a> Sink<(Packet<FramesRef<'a>>, SocketAddr)> for StuckTransport {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Pending
+        }
+
+        fn start_send(
+            self: Pin<&mut Self>,
+            _: (Packet<FramesRef<'a>>, SocketAddr),
+        ) -> Result<(), Self::Error> {
+            unreachable!("never ready")
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_outgoing_guard_adjust_cap() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let mut guard =
+            StuckTransport.handle_outgoing(link.clone(), 2, 0, Peer::test(), Role::test_server());
+        assert_eq!(link.send_buf_cap(), 2);
+        let frame = Frame::new(
+            Flags::new(Reliability::Unreliable, false),
+            Bytes::from_static(b"\xfecap"),
+        );
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        for _ in 0..2 {
+            assert!(Pin::new(&mut guard).poll_ready(&mut cx).is_ready());
+            Pin::new(&mut guard).start_send(frame.clone()).unwrap();
+        }
+        assert!(Pin::new(&mut guard).poll_ready(&mut cx).is_pending());
+
+        link.set_send_buf_cap(4);
+        assert!(Pin::new(&mut guard).poll_ready(&mut cx).is_ready());
+        Pin::new(&mut guard).start_send(frame).unwrap();
+
+        // shrunk below the buffered frames, wait for them to be drained
+        link.set_send_buf_cap(1);
+        assert!(Pin::new(&mut guard).poll_ready(&mut cx).is_pending());
+        assert_eq!(guard.buf.len(), 3);
+    }
+
+    #[test]
+    fn test_outgoing_guard_reserve() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let mut guard =
+            StuckTransport.handle_outgoing(link.clone(), 2, 0, Peer::test(), Role::test_server());
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        link.request_reserve(100);
+        link.request_reserve(500);
+        assert!(Pin::new(&mut guard).poll_ready(&mut cx).is_ready());
+        let normal = &guard.buf.queues[Priority::Normal.index()];
+        assert!(normal.capacity() >= 500);
+        assert!(guard.resend.map.capacity() >= 500);
+
+        // no-op if the capacity already suffices
+        let cap = normal.capacity();
+        link.request_reserve(10);
+        assert!(Pin::new(&mut guard).poll_ready(&mut cx).is_ready());
+        assert_eq!(guard.buf.queues[Priority::Normal.index()].capacity(), cap);
+        assert_eq!(link.take_reserve(), 0);
+    }
+
+    // Records the sequence number and the bodies of each sent frame set
+    #[derive(Default)]
+    struct FrameSetTransport {
+        sent: Vec<(u24, Vec<Bytes>)>,
+    }
+
+    impl<'a> Sink<(Packet<FramesRef<'a>>, SocketAddr)> for FrameSetTransport {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(
+            self: Pin<&mut Self>,
+            (pack, _): (Packet<FramesRef<'a>>, SocketAddr),
+        ) -> Result<(), Self::Error> {
+            if let Packet::Connected(connected::Packet::FrameSet(frame_set)) = pack {
+                let bodies = frame_set
+                    .set
+                    .iter()
+                    .map(|frame| frame.body.clone())
+                    .collect();
+                self.get_mut().sent.push((frame_set.seq_num, bodies));
+            }
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // Send two reliable frames in two frame sets
+    fn send_reliable_frames(link: &SharedLink) -> (OutgoingGuard<FrameSetTransport>, [Bytes; 2]) {
+        let mut guard = FrameSetTransport::default().handle_outgoing(
+            link.clone(),
+            8,
+            0,
+            Peer::test(),
+            Role::test_server(),
+        );
+        let bodies = [Bytes::from(vec![0xfe; 1000]), Bytes::from(vec![0xfd; 1000])];
+        for (index, body) in bodies.iter().enumerate() {
+            Pin::new(&mut guard)
+                .start_send(Frame {
+                    flags: Flags::new(Reliability::Reliable, false),
+                    reliable_frame_index: Some(u24::from(index as u32)),
+                    seq_frame_index: None,
+                    ordered: None,
+                    fragment: None,
+                    priority: Priority::Normal,
+                    coalesce: false,
+                    received: None,
+                    deadline: None,
+                    abandon_at: None,
+                    receipt: None,
+                    retransmits: 0,
+                    body: body.clone(),
+                })
+                .unwrap();
+        }
+        (guard, bodies)
+    }
+
+    // Records the type of each sent packet
+    #[derive(Default)]
+    struct RecordTransport {
+        sent: Vec<PackType>,
+    }
+
+    impl<'a> Sink<(Packet<FramesRef<'a>>, SocketAddr)> for RecordTransport {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(
+            self: Pin<&mut Self>,
+            (pack, _): (Packet<FramesRef<'a>>, SocketAddr),
+        ) -> Result<(), Self::Error> {
+            let pack_type = match pack {
+                Packet::Unconnected(pack) => pack.pack_type(),
+                Packet::Connected(pack) => pack.pack_type(),
+            };
+            self.get_mut().sent.push(pack_type);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_outgoing_guard_ack_backlog() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (mut route, _rx) = Route::new(link.clone(), 4096);
+        // every other frame set is received, neither acks nor nacks could be merged into ranges
+        for seq_num in (0..5000).step_by(2) {
+            assert!(route.deliver(connected::Packet::FrameSet(FrameSet {
+                seq_num: u24::from(seq_num),
+                set: vec![],
+            })));
+        }
+        let mut guard = RecordTransport::default().handle_outgoing(
+            link.clone(),
+            8,
+            0,
+            Peer::test(),
+            Role::test_server(),
+        );
+        for _ in 0..2 {
+            Pin::new(&mut guard)
+                .start_send(Frame {
+                    flags: Flags::new(Reliability::Unreliable, false),
+                    reliable_frame_index: None,
+                    seq_frame_index: None,
+                    ordered: None,
+                    fragment: None,
+                    priority: Priority::Normal,
+                    coalesce: false,
+                    received: None,
+                    deadline: None,
+                    abandon_at: None,
+                    receipt: None,
+                    retransmits: 0,
+                    body: Bytes::from(vec![0xfe; 1500]),
+                })
+                .unwrap();
+        }
+
+        let (waker, _) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert!(link.outgoing_ack_empty());
+        assert!(link.outgoing_nack_empty());
+
+        // about 478 records fit in an ack of the MTU, there are 6 nacks and 6 acks in total, the
+        // data is sent after at most 4 nacks and 4 acks in each round
+        let mut expected = vec![PackType::Nack; 4];
+        expected.extend([PackType::Ack; 4]);
+        expected.push(PackType::FrameSet);
+        expected.extend([PackType::Nack; 2]);
+        expected.extend([PackType::Ack; 2]);
+        expected.push(PackType::FrameSet);
+        assert_eq!(guard.frame.sent, expected);
+    }
+
+    #[test]
+    fn test_outgoing_guard_data_first() {
+        for data_first in [false, true] {
+            let link =
+                TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+            let (mut route, _rx) = Route::new(link.clone(), 16);
+            assert!(route.deliver(connected::Packet::FrameSet(FrameSet {
+                seq_num: 0.into(),
+                set: vec![],
+            })));
+            let mut guard = RecordTransport::default()
+                .handle_outgoing(link.clone(), 8, 0, Peer::test(), Role::test_server())
+                .data_first(data_first);
+            for _ in 0..2 {
+                Pin::new(&mut guard)
+                    .start_send(Frame::new(
+                        Flags::new(Reliability::Unreliable, false),
+                        Bytes::from(vec![0xfe; 1000]),
+                    ))
+                    .unwrap();
+            }
+            let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+            assert!(matches!(
+                Pin::new(&mut guard).poll_flush(&mut cx),
+                Poll::Ready(Ok(()))
+            ));
+            let expected = if data_first {
+                // the acks are deferred by one frame set only
+                [PackType::FrameSet, PackType::Ack, PackType::FrameSet]
+            } else {
+                [PackType::Ack, PackType::FrameSet, PackType::FrameSet]
+            };
+            assert_eq!(guard.frame.sent, expected);
+        }
+    }
+
+    #[test]
+    fn test_outgoing_guard_ack_now() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (mut route, _rx) = Route::new(link.clone(), 16);
+        assert!(route.deliver(connected::Packet::FrameSet(FrameSet {
+            seq_num: 0.into(),
+            set: vec![],
+        })));
+        let mut guard = RecordTransport::default().handle_outgoing(
+            link.clone(),
+            8,
+            0,
+            Peer::test(),
+            Role::test_server(),
+        );
+        // the acks are delayed by the strategy
+        let mut strategy = FlushStrategy::new(false, true, true);
+        let waker = futures::task::noop_waker_ref();
+        let mut cx = ContextBuilder::from_waker(waker).ext(&mut strategy).build();
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert!(guard.frame.sent.is_empty());
+        assert!(!link.outgoing_ack_empty());
+
+        link.request_ack_now();
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert_eq!(guard.frame.sent, [PackType::Ack]);
+        assert!(link.outgoing_ack_empty());
+        // the request is taken once
+        assert!(!link.take_ack_now());
+    }
+
+    #[test]
+    fn test_outgoing_guard_flush_budget() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let mut guard = RecordTransport::default()
+            .handle_outgoing(link, 8, 0, Peer::test(), Role::test_server())
+            .flush_budget(2);
+        for _ in 0..5 {
+            Pin::new(&mut guard)
+                .start_send(Frame::new(
+                    Flags::new(Reliability::Unreliable, false),
+                    Bytes::from(vec![0xfe; 1000]),
+                ))
+                .unwrap();
+        }
+        for sent in [2, 4] {
+            let (waker, test) = TestWaker::pair();
+            let mut cx = Context::from_waker(&waker);
+            assert!(Pin::new(&mut guard).poll_flush(&mut cx).is_pending());
+            // yields after the budget, and wakes itself up to send the rest
+            assert!(test.woken.load(std::sync::atomic::Ordering::Relaxed));
+            assert_eq!(guard.frame.sent.len(), sent);
+        }
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert_eq!(guard.frame.sent, [PackType::FrameSet; 5]);
+    }
+
+    #[test]
+    fn test_outgoing_guard_resend_on_nack() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (mut guard, bodies) = send_reliable_frames(&link);
+        let (waker, _) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert_eq!(
+            guard.frame.sent,
+            vec![
+                (0.into(), vec![bodies[0].clone()]),
+                (1.into(), vec![bodies[1].clone()])
+            ]
+        );
+        assert_eq!(guard.resend.map.len(), 2);
+
+        link.inject_nack([0]);
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        // the lost frame is resent in a new frame set
+        assert_eq!(guard.frame.sent.len(), 3);
+        assert_eq!(guard.frame.sent[2], (2.into(), vec![bodies[0].clone()]));
+        assert_eq!(guard.resend.map.len(), 2);
+        assert_eq!(link.stats().frames_resent(), 1);
+    }
+
+    #[test]
+    fn test_outgoing_guard_resent_stats() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (guard, _) = send_reliable_frames(&link);
+        let rto = Duration::from_millis(20);
+        let mut guard = guard.rto_bounds(rto, rto);
+        let (waker, _) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+
+        link.inject_nack([0]);
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        let stats = link.stats();
+        assert_eq!(stats.frames_resent_by_nack(), 1);
+        assert_eq!(stats.frames_resent_by_timeout(), 0);
+
+        // both frame sets in flight time out
+        std::thread::sleep(rto * 2);
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        let timed_out = link.stats();
+        assert_eq!(timed_out.frames_resent_by_nack(), 1);
+        assert_eq!(timed_out.frames_resent_by_timeout(), 2);
+        assert_eq!(timed_out.frames_resent(), 3);
+    }
+
+    #[test]
+    fn test_outgoing_guard_ack_clears_resend() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (mut guard, _) = send_reliable_frames(&link);
+        let (waker, _) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert_eq!(guard.resend.map.len(), 2);
+
+        link.inject_ack([1]);
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert_eq!(guard.resend.map.len(), 1);
+
+        // nothing is left to wait for when closing
+        link.inject_ack([0]);
+        assert!(matches!(
+            Pin::new(&mut guard).poll_close(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert!(guard.resend.is_empty());
+        assert_eq!(guard.frame.sent.len(), 2);
+        assert_eq!(link.stats().frames_resent(), 0);
+    }
+
+    #[test]
+    fn test_outgoing_guard_resend_backpressure() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (guard, _) = send_reliable_frames(&link);
+        let mut guard = guard.resend_bytes_cap(1500);
+        let (waker, test_waker) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert!(link.stats().resend_bytes() > 2000);
+        // no more frames are accepted until the acks free the room
+        assert!(Pin::new(&mut guard).poll_ready(&mut cx).is_pending());
+
+        link.inject_ack([0]);
+        assert!(test_waker.woken.swap(false, Ordering::Relaxed));
+        assert!(matches!(
+            Pin::new(&mut guard).poll_ready(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        let remain = link.stats().resend_bytes();
+        assert!(remain > 1000 && remain < 1500);
+
+        link.inject_ack([1]);
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert_eq!(link.stats().resend_bytes(), 0);
+    }
+
+    #[test]
+    fn test_outgoing_guard_fair_share() {
+        let share = FairShare::new(1);
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let mut guard = FrameSetTransport::default()
+            .handle_outgoing(link, 8, 0, Peer::test(), Role::test_server())
+            .fair_share(Some(&share));
+        for _ in 0..3 {
+            Pin::new(&mut guard)
+                .start_send(Frame {
+                    flags: Flags::new(Reliability::Unreliable, false),
+                    reliable_frame_index: None,
+                    seq_frame_index: None,
+                    ordered: None,
+                    fragment: None,
+                    priority: Priority::Normal,
+                    coalesce: false,
+                    received: None,
+                    deadline: None,
+                    abandon_at: None,
+                    receipt: None,
+                    retransmits: 0,
+                    body: Bytes::from(vec![0xfe; 1000]),
+                })
+                .unwrap();
+        }
+        // another connection on the socket is backlogged
+        let mut other = share.turn();
+        other.set_backlogged(true);
+
+        let (waker, test_waker) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+        // one frame set in each turn, and it wakes itself up to take the next turn
+        assert!(Pin::new(&mut guard).poll_flush(&mut cx).is_pending());
+        assert!(test_waker.woken.swap(false, Ordering::Relaxed));
+        assert_eq!(guard.frame.sent.len(), 1);
+        assert!(Pin::new(&mut guard).poll_flush(&mut cx).is_pending());
+        assert_eq!(guard.frame.sent.len(), 2);
+
+        // sending alone
+        drop(other);
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert_eq!(guard.frame.sent.len(), 3);
+    }
+
+    #[test]
+    fn test_outgoing_guard_expired_frames() {
+        let expiring = |reliability: Reliability, deadline: Instant| Frame {
+            flags: Flags::new(reliability, false),
+            reliable_frame_index: reliability.is_reliable().then(|| 0.into()),
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            priority: Priority::Normal,
+            coalesce: false,
+            received: None,
+            deadline: Some(deadline),
+            abandon_at: None,
+            receipt: None,
+            retransmits: 0,
+            body: Bytes::from_static(b"\xfe"),
+        };
+        let expired = Instant::now() - Duration::from_millis(10);
+        let (waker, _) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let mut guard = RecordTransport::default().handle_outgoing(
+            link.clone(),
+            8,
+            0,
+            Peer::test(),
+            Role::test_server(),
+        );
+        for frame in [
+            expiring(Reliability::Unreliable, expired),
+            expiring(
+                Reliability::Unreliable,
+                Instant::now() + Duration::from_secs(10),
+            ),
+            expiring(Reliability::Reliable, expired),
+        ] {
+            Pin::new(&mut guard).start_send(frame).unwrap();
+        }
+        assert!(matches!(
+            Pin::new(&mut guard).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        // only the expired unreliable frame is dropped, the reliable one is sent late
+        assert_eq!(link.stats().frames_expired(), 1);
+        assert_eq!(guard.frame.sent, vec![PackType::FrameSet]);
+        assert_eq!(guard.resend.map.len(), 1);
+
+        let strict_link =
+            TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let mut strict = RecordTransport::default()
+            .handle_outgoing(strict_link.clone(), 8, 0, Peer::test(), Role::test_server())
+            .fail_expired_reliable(true);
+        Pin::new(&mut strict)
+            .start_send(expiring(Reliability::Reliable, expired))
+            .unwrap();
+        let Poll::Ready(Err(err)) = Pin::new(&mut strict).poll_flush(&mut cx) else {
+            panic!("expect the expired error");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<FrameExpired>())
+            .is_some_and(|frame_expired| frame_expired.late() >= Duration::from_millis(10)));
+        assert_eq!(strict_link.stats().frames_expired(), 1);
+        assert!(matches!(
+            Pin::new(&mut strict).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert!(strict.frame.sent.is_empty());
+    }
 }
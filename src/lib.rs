@@ -1,5 +1,9 @@
 //! Raknet implementation by rust
+//!
+//! The runtime is built with the default `std` feature. Without it, the crate is `no_std` (with
+//! `alloc`) and only provides the packet codec in [`wire`], e.g. for the embedded parsers.
 
+#![cfg_attr(not(feature = "std"), no_std)]
 #![feature(impl_trait_in_assoc_type)]
 #![feature(ip_bits)]
 #![feature(type_changing_struct_update)]
@@ -9,38 +13,53 @@
 #![feature(context_ext)]
 #![feature(local_waker)]
 #![feature(option_get_or_insert_default)]
+// the runtime using most of the codec internals is not built without `std`
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
+
+extern crate alloc;
 
 /// Protocol codec
+#[cfg(feature = "std")]
 mod codec;
 
 /// Errors
-mod errors;
+pub mod errors;
 
 /// Protocol packet
 mod packet;
 
+/// The `no_std` packet codec
+pub mod wire;
+
 /// Utils
 mod utils;
 
 /// Outgoing guard
+#[cfg(feature = "std")]
 mod guard;
 
 /// Sink & Stream state
+#[cfg(feature = "std")]
 mod state;
 
 /// Transfer link
+#[cfg(feature = "std")]
 mod link;
 
 /// Estimators
+#[cfg(feature = "std")]
 mod estimator;
 
 /// Raknet server
+#[cfg(feature = "std")]
 pub mod server;
 
 /// Raknet client
+#[cfg(feature = "std")]
 pub mod client;
 
 /// Connection optional settings
+#[cfg(feature = "std")]
 pub mod opts;
 
 #[cfg(feature = "micro-bench")]
@@ -48,13 +67,35 @@ pub mod micro_bench {
     pub mod codec {
         pub use crate::codec::micro_bench::*;
     }
+
+    pub mod packet {
+        pub use crate::packet::connected::micro_bench::*;
+    }
+
+    pub mod guard {
+        pub use crate::guard::micro_bench::*;
+    }
+
+    #[cfg(feature = "tokio-rt")]
+    pub mod socket {
+        pub use crate::codec::tokio::micro_bench::*;
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing {
+    /// Feed the bytes to the parsers of the connected packets, it should never panic
+    pub fn parse_connected(data: &[u8]) {
+        crate::packet::connected::parse_all(data);
+    }
 }
 
 /// Unit tests
 #[cfg(test)]
 mod tests;
 
-use std::net::SocketAddr;
+use core::net::SocketAddr;
+use core::time::Duration;
 
 use bytes::Bytes;
 
@@ -81,8 +122,8 @@ impl Role {
     }
 }
 
-impl std::fmt::Display for Role {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Role {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Role::Client { guid } => write!(f, "client({guid})"),
             Role::Server { guid } => write!(f, "server({guid})"),
@@ -90,6 +131,21 @@ impl std::fmt::Display for Role {
     }
 }
 
+/// The reason why a connection was closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The connection was closed by the 4-ways handshake, initiated by either side
+    Graceful,
+    /// The closing initiated locally timed out, as the peer did not acknowledge it within
+    /// `close_timeout` of the configs. A connection is never evicted for being idle, so this is
+    /// the only timeout recorded.
+    Timeout,
+    /// The connection was dropped locally without being closed
+    Dropped,
+    /// The connection was aborted locally without waiting for the peer
+    Aborted,
+}
+
 #[derive(Debug, Clone, Copy)]
 struct Peer {
     guid: u64,
@@ -108,12 +164,58 @@ impl Peer {
     }
 }
 
-impl std::fmt::Display for Peer {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Peer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{guid}@{addr}", guid = self.guid, addr = self.addr,)
     }
 }
 
+/// A distinct identifier for a connection, shared by the send and receive halves of it.
+/// The remote address is mixed in, so that connections from the same remote guid will not collide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnId {
+    local_guid: u64,
+    remote_guid: u64,
+    remote_addr: SocketAddr,
+}
+
+impl ConnId {
+    pub(crate) fn new(local_guid: u64, remote_guid: u64, remote_addr: SocketAddr) -> Self {
+        Self {
+            local_guid,
+            remote_guid,
+            remote_addr,
+        }
+    }
+
+    /// Get the guid of the local side
+    pub fn local_guid(&self) -> u64 {
+        self.local_guid
+    }
+
+    /// Get the guid of the remote peer
+    pub fn remote_guid(&self) -> u64 {
+        self.remote_guid
+    }
+
+    /// Get the address of the remote peer
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
+
+impl core::fmt::Display for ConnId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{local}-{remote}@{addr}",
+            local = self.local_guid,
+            remote = self.remote_guid,
+            addr = self.remote_addr
+        )
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Copy)]
 #[repr(u8)]
 pub enum Reliability {
@@ -166,6 +268,16 @@ impl Reliability {
         )
     }
 
+    /// The sender is notified once the message is acknowledged, or lost if it is unreliable
+    pub(crate) fn with_ack_receipt(&self) -> bool {
+        matches!(
+            self,
+            Reliability::UnreliableWithAckReceipt
+                | Reliability::ReliableWithAckReceipt
+                | Reliability::ReliableOrderedWithAckReceipt
+        )
+    }
+
     /// Sequenced or Ordered ensures that packets should be received in order at their
     /// `order_channels` as they are sent.
     pub(crate) fn is_sequenced_or_ordered(&self) -> bool {
@@ -178,7 +290,8 @@ impl Reliability {
         )
     }
 
-    /// TODO: implement sequenced
+    /// Sequenced ensures that the older packets are dropped at their `order_channels` once a newer
+    /// one is received.
     pub(crate) fn is_sequenced(&self) -> bool {
         matches!(
             self,
@@ -186,6 +299,14 @@ impl Reliability {
         )
     }
 
+    /// Ordered ensures that all packets are received in order at their `order_channels`.
+    pub(crate) fn is_ordered(&self) -> bool {
+        matches!(
+            self,
+            Reliability::ReliableOrdered | Reliability::ReliableOrderedWithAckReceipt
+        )
+    }
+
     /// The header size (without fragment part) implied from reliability
     pub(crate) fn size(&self) -> usize {
         // flag(1B) + length(2B)
@@ -203,11 +324,56 @@ impl Reliability {
     }
 }
 
+impl TryFrom<u8> for Reliability {
+    type Error = errors::CodecError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0b000 => Ok(Reliability::Unreliable),
+            0b001 => Ok(Reliability::UnreliableSequenced),
+            0b010 => Ok(Reliability::Reliable),
+            0b011 => Ok(Reliability::ReliableOrdered),
+            0b100 => Ok(Reliability::ReliableSequenced),
+            0b101 => Ok(Reliability::UnreliableWithAckReceipt),
+            0b110 => Ok(Reliability::ReliableWithAckReceipt),
+            0b111 => Ok(Reliability::ReliableOrderedWithAckReceipt),
+            _ => Err(errors::CodecError::InvalidReliability(value)),
+        }
+    }
+}
+
+/// The priority of a message. When the link is congested, messages with higher priority will be
+/// packed into frame sets first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    /// The number of priority levels
+    pub(crate) const LEVELS: usize = 3;
+
+    /// The index of the priority, 0 is the highest
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+}
+
 /// Raknet message
 #[derive(Debug, Clone)]
 pub struct Message {
     reliability: Reliability,
     order_channel: u8,
+    priority: Priority,
+    coalesce: bool,
+    ttl: Option<Duration>,
+    abandon_after: Option<Duration>,
+    raw_ordered_index: Option<u32>,
+    raw_reliable_index: Option<u32>,
+    receipt: Option<u32>,
     data: Bytes,
 }
 
@@ -216,10 +382,99 @@ impl Message {
         Self {
             reliability,
             order_channel,
+            priority: Priority::Normal,
+            coalesce: false,
+            ttl: None,
+            abandon_after: None,
+            raw_ordered_index: None,
+            raw_reliable_index: None,
+            receipt: None,
             data,
         }
     }
 
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    pub fn get_priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Drop the older messages of the same channel which are still waiting in the send buffer
+    /// when this message is queued. It only takes effect on [`Reliability::UnreliableSequenced`]
+    /// messages that fit in a single frame, and only supersedes the messages sent with it as well.
+    /// It is useful for high-frequency states (e.g. position) where only the newest one matters.
+    pub fn set_coalesce(&mut self, coalesce: bool) {
+        self.coalesce = coalesce;
+    }
+
+    pub fn get_coalesce(&self) -> bool {
+        self.coalesce
+    }
+
+    /// Drop the message if it is still waiting in the send buffer after `ttl` since it was
+    /// queued, e.g. for voice or position data where late delivery is worse than no delivery.
+    /// The expired unreliable frames are dropped silently. The expired reliable frames are sent
+    /// late by default, or fail the flushing if the config asks to. The frames which have been
+    /// sent are resent regardless of it.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = Some(ttl);
+    }
+
+    pub fn get_ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    /// Stop resending the reliable message if it is still not acknowledged after `after` since
+    /// it was queued, e.g. for a texture which is useless once the scene changes. The abandoned
+    /// frames are removed from the resend buffer, and an ordered one is replaced by a marker so
+    /// that the receiver does not wait for it in the ordering. The marker is only understood by
+    /// the peers running this crate, do not abandon the ordered messages to the other peers.
+    pub fn set_abandon_after(&mut self, after: Duration) {
+        self.abandon_after = Some(after);
+    }
+
+    pub fn get_abandon_after(&self) -> Option<Duration> {
+        self.abandon_after
+    }
+
+    /// Send the message with the ordered frame index and the reliable frame index chosen by the
+    /// caller, instead of the ones assigned by the encoder. The parts of a fragmented message take
+    /// the consecutive reliable indices starting from `reliable_index`. An index only takes effect
+    /// if the reliability carries it, and it must be less than 2^24. The automatic indices are not
+    /// advanced by the overridden ones.
+    ///
+    /// It is an escape hatch for the interop with the nonstandard senders and for reconstructing
+    /// the exact frames in tests. A misuse breaks the ordering and the deduplication of the
+    /// receiver, e.g. a reused reliable index is dropped as a duplicate, and a skipped ordered
+    /// index stalls the channel forever.
+    pub fn set_raw_indices(&mut self, ordered_index: Option<u32>, reliable_index: Option<u32>) {
+        self.raw_ordered_index = ordered_index;
+        self.raw_reliable_index = reliable_index;
+    }
+
+    /// Get the ordered frame index and the reliable frame index overridden by
+    /// [`Message::set_raw_indices`]
+    pub fn get_raw_indices(&self) -> (Option<u32>, Option<u32>) {
+        (self.raw_ordered_index, self.raw_reliable_index)
+    }
+
+    /// Tag the message with a receipt number, it is passed to the `on_ack_receipt` hook of the
+    /// configs once the message is acknowledged or lost. An unreliable message is lost if its
+    /// frame set is reported lost by a NACK or not acknowledged in time. Any message is lost if it
+    /// expires before it is sent, is superseded by a coalescing one, is abandoned, or the
+    /// connection is closed before it is acknowledged. It only takes effect with the
+    /// `*WithAckReceipt` reliabilities and the hook set.
+    pub fn set_receipt(&mut self, receipt: u32) {
+        self.receipt = Some(receipt);
+    }
+
+    /// Get the receipt number tagged by [`Message::set_receipt`]
+    pub fn get_receipt(&self) -> Option<u32> {
+        self.receipt
+    }
+
     pub fn set_reliability(&mut self, reliability: Reliability) {
         self.reliability = reliability;
     }
@@ -243,4 +498,8 @@ impl Message {
     pub fn into_data(self) -> Bytes {
         self.data
     }
+
+    pub(crate) fn map_data(&mut self, f: impl FnOnce(Bytes) -> Bytes) {
+        self.data = f(core::mem::take(&mut self.data));
+    }
 }
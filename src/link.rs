@@ -1,22 +1,35 @@
 use std::cmp::Reverse;
 use std::collections::{BTreeSet, BinaryHeap};
-use std::sync::atomic::{self, AtomicBool};
+use std::net::SocketAddr;
+use std::sync::atomic::{self, AtomicBool, AtomicU16, AtomicU64, AtomicUsize};
 use std::sync::Arc;
-use std::time::Instant;
+use std::task::Waker;
+use std::time::{Duration, Instant};
 
-use async_channel::Sender;
-use concurrent_queue::ConcurrentQueue;
+use async_channel::{Sender, TrySendError};
+use concurrent_queue::{ConcurrentQueue, PushError};
+use futures::task::AtomicWaker;
 use futures::Stream;
 use log::{debug, warn};
 
-use crate::packet::connected::{self, AckOrNack, FrameBody, FrameSet, FramesMut};
+use crate::estimator::LossEstimator;
+use crate::opts::{FrameMeta, Stats};
+use crate::packet::connected::{
+    self, AckOrNack, FrameBody, FrameSet, FramesMut, Received, DEFAULT_MAX_ACK_COUNT,
+};
 use crate::packet::unconnected;
-use crate::utils::{u24, ConnId, Reactor};
-use crate::{Peer, Role};
+use crate::utils::{timestamp, u24, Reactor, TimerKind};
+use crate::{ConnId, DisconnectReason, Peer, Role};
+
+/// The default capacity of each of the incoming and outgoing ACK buffers of a link
+pub(crate) const DEFAULT_ACK_BUFFER: usize = 1024;
 
 /// Shared link between stream and sink
 pub(crate) type SharedLink = Arc<TransferLink>;
 
+/// The abort of a dropped sink, run once the stream of the connection is dropped as well
+pub(crate) type DeferredAbort = Box<dyn FnOnce() + Send>;
+
 /// The `TransferLink` is an visitor structure that temporarily holds various types of transfer
 /// link data, such as received `AckOrNack`, sequences of pending response sequence numbers, and
 /// packets ready to send like `unconnected::Packet` and `FrameBody`.
@@ -32,6 +45,13 @@ pub(crate) struct TransferLink {
     /// In this state, the close operation will sleep until an ACK is received to wake it,
     /// after which the flag will be reset to `false`.
     forward_waking: AtomicBool,
+    /// the flag is set when the server is shutting down, the connection is closed on its next
+    /// flush
+    shutdown: AtomicBool,
+    /// the flag is set when the application asks to send the pending ACKs on the next flush
+    ack_now: AtomicBool,
+    /// the background driver of the outgoing guard, it is woken when there is something to send
+    driver: AtomicWaker,
 
     /// pending ACK packets to be sent.
     outgoing_ack: parking_lot::Mutex<BinaryHeap<Reverse<u24>>>,
@@ -43,8 +63,81 @@ pub(crate) struct TransferLink {
     /// data for the frame body that is yet to be handled.
     frame_body: ConcurrentQueue<FrameBody>,
 
+    /// the reason recorded when the connection is closing, first one wins
+    close_reason: parking_lot::Mutex<Option<DisconnectReason>>,
+    /// the guid the client sent in `ConnectionRequest`, only recorded on the server side
+    client_guid: parking_lot::Mutex<Option<u64>>,
+    /// the round trip of the handshake, taken by the outgoing guard to seed its RTT estimation
+    handshake_rtt: parking_lot::Mutex<Option<Duration>>,
+    /// the metadata of the frame of the last decoded user packet
+    last_frame_meta: parking_lot::Mutex<Option<FrameMeta>>,
+    /// the number of the dropped halves (the stream and the sink) of the connection, along with
+    /// the abort parked by the sink until the other half is dropped
+    dropped_halves: parking_lot::Mutex<(usize, Option<DeferredAbort>)>,
+
+    /// the frames sent and resent
+    outbound_stats: parking_lot::Mutex<LossStats>,
+    /// the frame sets received and the gaps reported by NACK
+    inbound_stats: parking_lot::Mutex<LossStats>,
+    /// the maximum number of frames buffered in an ordering channel
+    reorder_high_water: AtomicUsize,
+    /// the frames to be resent as the peer reported them lost by NACK
+    frames_resent_by_nack: AtomicU64,
+    /// the frames to be resent as they were not acknowledged in time
+    frames_resent_by_timeout: AtomicU64,
+    /// the frames dropped as they expired before sending
+    frames_expired: AtomicU64,
+    /// the reliable frames abandoned as they were not acknowledged in time
+    frames_abandoned: AtomicU64,
+    /// the total size of the frames waiting for acknowledgement
+    resend_bytes: AtomicUsize,
+    /// the sequence numbers acknowledged by the peer before they were sent
+    unsent_acks: AtomicU64,
+    /// the frames recovered by the forward error correction
+    fec_recovered: AtomicU64,
+    /// the pacing rate of the frame sets in bytes per second, 0 if pacing is disabled
+    pacing_rate: AtomicU64,
+
     role: Role,
     peer: Peer,
+    /// the address the packets are sent to, it is changed when the connection migrates
+    peer_addr: parking_lot::Mutex<SocketAddr>,
+    /// the effective MTU, it starts at the negotiated one and is reduced if the path drops the
+    /// large frame sets
+    mtu: AtomicU16,
+    /// the capacity of the outgoing buffer in frames, it is set by the outgoing guard and could
+    /// be adjusted at runtime
+    send_buf_cap: AtomicUsize,
+    /// the frames requested to be reserved in the outgoing buffer, taken by the outgoing guard
+    reserve_frames: AtomicUsize,
+    /// the number of frames in the outgoing buffer, published by the outgoing guard
+    buffered_frames: AtomicUsize,
+    /// the flag is set by the outgoing guard when the buffered bytes or the bytes waiting for
+    /// acknowledgement reached their limits
+    send_blocked: AtomicBool,
+}
+
+/// The counters and the loss estimation of one direction
+struct LossStats {
+    total: u64,
+    lost: u64,
+    estimator: LossEstimator,
+}
+
+impl LossStats {
+    fn new(window: Duration) -> Self {
+        Self {
+            total: 0,
+            lost: 0,
+            estimator: LossEstimator::new(window),
+        }
+    }
+
+    fn record(&mut self, total: usize, lost: usize) {
+        self.total += total as u64;
+        self.lost += lost as u64;
+        self.estimator.record(total, lost, Instant::now());
+    }
 }
 
 /// Pop priority queue while holding the lock
@@ -67,23 +160,234 @@ impl<'a, T: Ord> Iterator for BatchRecv<'a, T> {
 }
 
 impl TransferLink {
-    pub(crate) fn new_arc(role: Role, peer: Peer) -> SharedLink {
-        // avoiding ack flood, the overwhelming ack will be dropped and new ack will be displaced
-        const MAX_ACK_BUFFER: usize = 1024;
+    /// `loss_window` is the window of the loss rate estimation in [`Stats`]
+    #[cfg(any(test, feature = "micro-bench"))]
+    pub(crate) fn new_arc(role: Role, peer: Peer, loss_window: Duration) -> SharedLink {
+        Self::with_ack_buffer(role, peer, loss_window, DEFAULT_ACK_BUFFER)
+    }
 
+    /// `loss_window` is the window of the loss rate estimation in [`Stats`], `ack_buffer` is the
+    /// capacity of the ACK buffers. Avoiding ack flood, the overwhelming incoming ack will be
+    /// merged into the oldest one
+    ///
+    /// # Panics
+    /// Panics if `ack_buffer` is zero.
+    pub(crate) fn with_ack_buffer(
+        role: Role,
+        peer: Peer,
+        loss_window: Duration,
+        ack_buffer: usize,
+    ) -> SharedLink {
+        assert!(ack_buffer > 0, "the ack buffer must be greater than zero");
         Arc::new(Self {
-            incoming_ack: ConcurrentQueue::bounded(MAX_ACK_BUFFER),
-            incoming_nack: ConcurrentQueue::bounded(MAX_ACK_BUFFER),
+            incoming_ack: ConcurrentQueue::bounded(ack_buffer),
+            incoming_nack: ConcurrentQueue::bounded(ack_buffer),
             forward_waking: AtomicBool::new(false),
-            outgoing_ack: parking_lot::Mutex::new(BinaryHeap::with_capacity(MAX_ACK_BUFFER)),
+            shutdown: AtomicBool::new(false),
+            ack_now: AtomicBool::new(false),
+            driver: AtomicWaker::new(),
+            outgoing_ack: parking_lot::Mutex::new(BinaryHeap::with_capacity(ack_buffer)),
             outgoing_nack: parking_lot::Mutex::new(BTreeSet::new()),
             unconnected: ConcurrentQueue::unbounded(),
             frame_body: ConcurrentQueue::unbounded(),
+            close_reason: parking_lot::Mutex::new(None),
+            client_guid: parking_lot::Mutex::new(None),
+            handshake_rtt: parking_lot::Mutex::new(None),
+            last_frame_meta: parking_lot::Mutex::new(None),
+            dropped_halves: parking_lot::Mutex::new((0, None)),
+            outbound_stats: parking_lot::Mutex::new(LossStats::new(loss_window)),
+            inbound_stats: parking_lot::Mutex::new(LossStats::new(loss_window)),
+            reorder_high_water: AtomicUsize::new(0),
+            frames_resent_by_nack: AtomicU64::new(0),
+            frames_resent_by_timeout: AtomicU64::new(0),
+            frames_expired: AtomicU64::new(0),
+            frames_abandoned: AtomicU64::new(0),
+            resend_bytes: AtomicUsize::new(0),
+            unsent_acks: AtomicU64::new(0),
+            fec_recovered: AtomicU64::new(0),
+            pacing_rate: AtomicU64::new(0),
             role,
             peer,
+            peer_addr: parking_lot::Mutex::new(peer.addr),
+            mtu: AtomicU16::new(peer.mtu),
+            send_buf_cap: AtomicUsize::new(0),
+            reserve_frames: AtomicUsize::new(0),
+            buffered_frames: AtomicUsize::new(0),
+            send_blocked: AtomicBool::new(false),
         })
     }
 
+    /// Record the metadata of the frame of a decoded user packet
+    pub(crate) fn set_last_frame_meta(&self, meta: FrameMeta) {
+        *self.last_frame_meta.lock() = Some(meta);
+    }
+
+    pub(crate) fn last_frame_meta(&self) -> Option<FrameMeta> {
+        *self.last_frame_meta.lock()
+    }
+
+    /// Called when a half of the connection is dropped, the sink passes its `abort` if it should
+    /// be aborted on drop. The abort runs once both halves are dropped, so that the connection
+    /// is still read after its sink is dropped.
+    pub(crate) fn on_half_dropped(&self, abort: Option<DeferredAbort>) {
+        let mut halves = self.dropped_halves.lock();
+        halves.0 += 1;
+        if abort.is_some() {
+            halves.1 = abort;
+        }
+        if halves.0 < 2 {
+            return;
+        }
+        let parked = halves.1.take();
+        drop(halves);
+        if let Some(run) = parked {
+            run();
+        }
+    }
+
+    /// Record the reason of closing, only the first reason will be kept
+    pub(crate) fn set_close_reason(&self, reason: DisconnectReason) {
+        self.close_reason.lock().get_or_insert(reason);
+    }
+
+    pub(crate) fn close_reason(&self) -> Option<DisconnectReason> {
+        *self.close_reason.lock()
+    }
+
+    /// Record the guid the client sent in `ConnectionRequest`
+    pub(crate) fn record_client_guid(&self, guid: u64) {
+        *self.client_guid.lock() = Some(guid);
+    }
+
+    pub(crate) fn client_guid(&self) -> Option<u64> {
+        *self.client_guid.lock()
+    }
+
+    /// Record the round trip of the handshake from the timestamp we sent and the peer echoed,
+    /// the timestamp in the future is ignored
+    pub(crate) fn record_handshake_rtt(&self, echoed_timestamp: i64) {
+        if let Ok(millis) = u64::try_from(timestamp() - echoed_timestamp) {
+            *self.handshake_rtt.lock() = Some(Duration::from_millis(millis));
+        }
+    }
+
+    /// Take the round trip of the handshake, it is taken only once
+    pub(crate) fn take_handshake_rtt(&self) -> Option<Duration> {
+        self.handshake_rtt.lock().take()
+    }
+
+    /// The effective MTU of the frame sets sent on the link
+    pub(crate) fn mtu(&self) -> u16 {
+        self.mtu.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Reduce the effective MTU, it never grows back. Returns false if it is not smaller than the
+    /// current one.
+    pub(crate) fn reduce_mtu(&self, mtu: u16) -> bool {
+        let prev = self.mtu.fetch_min(mtu, atomic::Ordering::Relaxed);
+        if mtu < prev {
+            warn!(
+                role:% = self.role,
+                peer:% = self.peer,
+                mtu = mtu,
+                prev_mtu = prev;
+                "reduce the MTU, large frame sets are not getting through"
+            );
+            return true;
+        }
+        false
+    }
+
+    /// The capacity of the outgoing buffer in frames
+    pub(crate) fn send_buf_cap(&self) -> usize {
+        self.send_buf_cap.load(atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_send_buf_cap(&self, cap: usize) {
+        self.send_buf_cap.store(cap, atomic::Ordering::Relaxed);
+    }
+
+    /// Record the frames in the outgoing buffer and whether the bytes reached the limits, as of
+    /// the last time the outgoing guard was used
+    pub(crate) fn record_buffered(&self, frames: usize, blocked: bool) {
+        self.buffered_frames
+            .store(frames, atomic::Ordering::Relaxed);
+        self.send_blocked.store(blocked, atomic::Ordering::Relaxed);
+    }
+
+    /// Whether the outgoing buffer has the room for more frames, it is the same check as the
+    /// outgoing guard is ready, but made without polling it. There is no room while the internal
+    /// frame bodies are waiting to be encoded, as the frames would be sent ahead of them.
+    pub(crate) fn has_send_room(&self) -> bool {
+        self.frame_body_empty()
+            && !self.send_blocked.load(atomic::Ordering::Relaxed)
+            && self.buffered_frames.load(atomic::Ordering::Relaxed) < self.send_buf_cap()
+    }
+
+    /// Request to reserve the room of `frames` in the outgoing buffer, the largest request before
+    /// the guard takes it wins
+    pub(crate) fn request_reserve(&self, frames: usize) {
+        self.reserve_frames
+            .fetch_max(frames, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn take_reserve(&self) -> usize {
+        self.reserve_frames.swap(0, atomic::Ordering::Relaxed)
+    }
+
+    /// The current address of the peer
+    pub(crate) fn peer_addr(&self) -> SocketAddr {
+        *self.peer_addr.lock()
+    }
+
+    /// Ask the connection to close gracefully as the server is shutting down
+    pub(crate) fn request_shutdown(&self) {
+        self.shutdown.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn shutdown_requested(&self) -> bool {
+        self.shutdown.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Ask the outgoing guard to send the pending ACKs on the next flush regardless of the flush
+    /// strategy
+    pub(crate) fn request_ack_now(&self) {
+        self.ack_now.store(true, atomic::Ordering::Relaxed);
+        self.wake_driver();
+    }
+
+    /// Take the request of sending the pending ACKs, `restore_ack_now` puts it back if they are
+    /// not sent
+    pub(crate) fn take_ack_now(&self) -> bool {
+        self.ack_now.swap(false, atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn restore_ack_now(&self) {
+        self.ack_now.store(true, atomic::Ordering::Relaxed);
+    }
+
+    /// Register the background driver, it is woken when the link receives something to handle or
+    /// to respond
+    pub(crate) fn register_driver(&self, waker: &Waker) {
+        self.driver.register(waker);
+    }
+
+    fn wake_driver(&self) {
+        self.driver.wake();
+    }
+
+    /// Whether the outgoing guard has something to do without the application, i.e. the frames
+    /// waiting for acknowledgement, the received ACK/NACK and the pending responses
+    pub(crate) fn needs_driving(&self) -> bool {
+        self.resend_bytes.load(atomic::Ordering::Relaxed) > 0
+            || !self.incoming_ack.is_empty()
+            || !self.incoming_nack.is_empty()
+            || !self.outgoing_ack_empty()
+            || !self.outgoing_nack_empty()
+            || !self.unconnected_empty()
+            || !self.frame_body_empty()
+    }
+
     pub(crate) fn turn_on_waking(&self) {
         self.forward_waking.store(true, atomic::Ordering::Relaxed);
     }
@@ -96,52 +400,157 @@ impl TransferLink {
         self.forward_waking.store(false, atomic::Ordering::Relaxed);
     }
 
+    /// Record the frames sent, and the frames to be resent as they were lost
+    pub(crate) fn record_outgoing(&self, sent: usize, resent: usize) {
+        if sent == 0 && resent == 0 {
+            return;
+        }
+        self.outbound_stats.lock().record(sent, resent);
+    }
+
+    /// Record the frames to be resent, by what they were reported lost
+    pub(crate) fn record_resent(&self, by_nack: usize, by_timeout: usize) {
+        self.frames_resent_by_nack
+            .fetch_add(by_nack as u64, atomic::Ordering::Relaxed);
+        self.frames_resent_by_timeout
+            .fetch_add(by_timeout as u64, atomic::Ordering::Relaxed);
+        self.record_outgoing(0, by_nack + by_timeout);
+    }
+
+    /// Record the frame sets received, and the gaps of the sequence numbers found before them
+    pub(crate) fn record_incoming(&self, received: usize, lost: usize) {
+        self.inbound_stats.lock().record(received + lost, lost);
+    }
+
+    /// Record the total size of the frames waiting for acknowledgement
+    pub(crate) fn record_resend_bytes(&self, bytes: usize) {
+        self.resend_bytes.store(bytes, atomic::Ordering::Relaxed);
+    }
+
+    /// Record the pacing rate of the frame sets
+    pub(crate) fn record_pacing_rate(&self, rate: u64) {
+        self.pacing_rate.store(rate, atomic::Ordering::Relaxed);
+    }
+
+    /// Record a frame dropped as it expired before sending
+    pub(crate) fn record_expired(&self) {
+        self.frames_expired.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    /// Record the reliable frames abandoned as they were not acknowledged in time
+    pub(crate) fn record_abandoned(&self, frames: usize) {
+        self.frames_abandoned
+            .fetch_add(frames as u64, atomic::Ordering::Relaxed);
+    }
+
+    /// Record the sequence numbers acknowledged by the peer before they were sent
+    pub(crate) fn record_unsent_acks(&self, acks: usize) {
+        self.unsent_acks
+            .fetch_add(acks as u64, atomic::Ordering::Relaxed);
+    }
+
+    /// Record a frame recovered by the forward error correction
+    #[cfg(feature = "fec")]
+    pub(crate) fn record_fec_recovered(&self) {
+        self.fec_recovered.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    /// Record the number of frames buffered in an ordering channel
+    pub(crate) fn record_reorder_depth(&self, depth: usize) {
+        self.reorder_high_water
+            .fetch_max(depth, atomic::Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the statistics
+    pub(crate) fn stats(&self) -> Stats {
+        let now = Instant::now();
+        let mut outbound = self.outbound_stats.lock();
+        let mut stats = Stats {
+            frames_sent: outbound.total,
+            frames_resent: outbound.lost,
+            frames_resent_by_nack: self.frames_resent_by_nack.load(atomic::Ordering::Relaxed),
+            frames_resent_by_timeout: self
+                .frames_resent_by_timeout
+                .load(atomic::Ordering::Relaxed),
+            outbound_loss: outbound.estimator.get(now),
+            reorder_high_water: self.reorder_high_water.load(atomic::Ordering::Relaxed),
+            frames_expired: self.frames_expired.load(atomic::Ordering::Relaxed),
+            frames_abandoned: self.frames_abandoned.load(atomic::Ordering::Relaxed),
+            resend_bytes: self.resend_bytes.load(atomic::Ordering::Relaxed),
+            pacing_rate: self.pacing_rate.load(atomic::Ordering::Relaxed),
+            unsent_acks: self.unsent_acks.load(atomic::Ordering::Relaxed),
+            fec_recovered: self.fec_recovered.load(atomic::Ordering::Relaxed),
+            ..Stats::default()
+        };
+        drop(outbound);
+        let mut inbound = self.inbound_stats.lock();
+        stats.frame_sets_received = inbound.total - inbound.lost;
+        stats.frame_sets_lost = inbound.lost;
+        stats.inbound_loss = inbound.estimator.get(now);
+        stats
+    }
+
     pub(crate) fn incoming_ack(&self, records: AckOrNack) {
-        if let Some((dropped, _)) = self
-            .incoming_ack
-            .force_push((records, Instant::now()))
-            .unwrap()
-        {
-            warn!(
-                "[{}] discard received ack {dropped:?} from {}, total count: {}",
-                self.role,
-                self.peer,
-                dropped.total_cnt()
-            );
+        let mut entry = (records, Instant::now());
+        while let Err(PushError::Full(full)) = self.incoming_ack.push(entry) {
+            entry = full;
+            // merge the oldest ack instead of dropping it, otherwise the acknowledged packets
+            // will be resent. The newest timestamp is kept to avoid underestimating the RTT.
+            if let Ok((oldest, _)) = self.incoming_ack.pop() {
+                debug!(
+                    role:% = self.role,
+                    peer:% = self.peer,
+                    records:? = oldest,
+                    total = oldest.total_cnt();
+                    "ack buffer is full, merge the received ack"
+                );
+                entry.0.merge(oldest);
+            }
         }
         // wake up after receiving an ack
         if self.should_waking() {
-            let c_id = ConnId::new(self.role.guid(), self.peer.guid);
+            let c_id = ConnId::new(self.role.guid(), self.peer.guid, self.peer.addr);
             let mut cnt = 0;
-            for waker in Reactor::get().cancel_all_timers(c_id) {
+            for waker in Reactor::get().cancel_timers(c_id, TimerKind::Retransmit) {
                 // safe to panic
                 waker.wake();
                 cnt += 1;
             }
             debug!(
-                "[{}] wake up {cnt} wakers after receives ack on connection: {c_id:?}",
-                self.role
+                role:% = self.role,
+                peer:% = self.peer,
+                wakers = cnt;
+                "wake up the wakers after receiving ack"
             );
         }
+        self.wake_driver();
     }
 
-    pub(crate) fn incoming_nack(&self, records: AckOrNack) {
-        if let Some(dropped) = self.incoming_nack.force_push(records).unwrap() {
-            warn!(
-                "[{}] discard received nack {dropped:?} from {}, total count: {}",
-                self.role,
-                self.peer,
-                dropped.total_cnt()
-            );
+    pub(crate) fn incoming_nack(&self, mut records: AckOrNack) {
+        while let Err(PushError::Full(full)) = self.incoming_nack.push(records) {
+            records = full;
+            if let Ok(oldest) = self.incoming_nack.pop() {
+                debug!(
+                    role:% = self.role,
+                    peer:% = self.peer,
+                    records:? = oldest,
+                    total = oldest.total_cnt();
+                    "nack buffer is full, merge the received nack"
+                );
+                records.merge(oldest);
+            }
         }
+        self.wake_driver();
     }
 
     pub(crate) fn send_unconnected(&self, packet: unconnected::Packet) {
         self.unconnected.push(packet).unwrap();
+        self.wake_driver();
     }
 
     pub(crate) fn send_frame_body(&self, body: FrameBody) {
         self.frame_body.push(body).unwrap();
+        self.wake_driver();
     }
 
     pub(crate) fn process_ack(&self) -> impl Iterator<Item = (AckOrNack, Instant)> + '_ {
@@ -156,8 +565,11 @@ impl TransferLink {
         AckOrNack::extend_from(BatchRecv::new(self.outgoing_ack.lock()), mtu)
     }
 
+    /// Take the lost sequence numbers in ascending order. Each one is reported only once, the peer
+    /// will resend it by timeout if the retransmission is lost again.
     pub(crate) fn process_outgoing_nack(&self, mtu: u16) -> Option<AckOrNack> {
-        AckOrNack::extend_from(self.outgoing_nack.lock().iter().map(|v| v.0), mtu)
+        let mut nack = self.outgoing_nack.lock();
+        AckOrNack::extend_from(std::iter::from_fn(|| nack.pop_last().map(|v| v.0)), mtu)
     }
 
     pub(crate) fn process_unconnected(&self) -> impl Iterator<Item = unconnected::Packet> + '_ {
@@ -186,6 +598,78 @@ impl TransferLink {
     }
 }
 
+/// The number of the latest frame sets remembered by [`SeenWindow`]
+const SEEN_WINDOW: u32 = 1024;
+
+const U24_MASK: u32 = 0xff_ffff;
+
+/// The maximum number of sequence numbers waiting to be reported lost, the peer never accepts a
+/// NACK reporting more than [`DEFAULT_MAX_ACK_COUNT`] of them
+const MAX_OUTGOING_NACK: usize = DEFAULT_MAX_ACK_COUNT;
+
+/// Remembers the sequence numbers of the latest delivered frame sets in a bitmap sliding with the
+/// highest one. A resent frame set always carries a new sequence number, so a seen one is a
+/// datagram duplicated on the network (or replayed). The sequence numbers are compared in the
+/// wrapping space of u24, the ones older than the window are never reported as seen, so they are
+/// delivered and deduplicated by the reliable frame index later.
+#[derive(Debug, Default)]
+struct SeenWindow {
+    highest: Option<u32>,
+    bits: [u64; (SEEN_WINDOW / 64) as usize],
+}
+
+impl SeenWindow {
+    fn bit(seq_num: u32) -> (usize, u64) {
+        let index = seq_num % SEEN_WINDOW;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    /// The distance of `seq_num` behind the highest one, `None` if it is ahead
+    fn behind(&self, seq_num: u32) -> Option<u32> {
+        let highest = self.highest?;
+        let ahead = seq_num.wrapping_sub(highest) & U24_MASK;
+        if ahead != 0 && ahead < 1 << 23 {
+            return None;
+        }
+        Some(highest.wrapping_sub(seq_num) & U24_MASK)
+    }
+
+    fn contains(&self, seq_num: u24) -> bool {
+        let seq_num = seq_num.to_u32();
+        match self.behind(seq_num) {
+            Some(behind) if behind < SEEN_WINDOW => {
+                let (word, mask) = Self::bit(seq_num);
+                self.bits[word] & mask != 0
+            }
+            _ => false,
+        }
+    }
+
+    fn insert(&mut self, seq_num: u24) {
+        let seq_num = seq_num.to_u32();
+        match (self.highest, self.behind(seq_num)) {
+            (Some(_), Some(behind)) if behind >= SEEN_WINDOW => return,
+            (Some(highest), None) => {
+                // slide the window, forget the ones falling out of it
+                let ahead = seq_num.wrapping_sub(highest) & U24_MASK;
+                if ahead >= SEEN_WINDOW {
+                    self.bits = Default::default();
+                } else {
+                    for step in 1..=ahead {
+                        let (word, mask) = Self::bit(highest.wrapping_add(step) & U24_MASK);
+                        self.bits[word] &= !mask;
+                    }
+                }
+                self.highest = Some(seq_num);
+            }
+            (None, _) => self.highest = Some(seq_num),
+            _ => {}
+        }
+        let (word, mask) = Self::bit(seq_num);
+        self.bits[word] |= mask;
+    }
+}
+
 /// `Route` is an intermediary structure that wraps a `TransferLink`, providing the functionality to
 /// `deliver` different types of data frames.
 pub(crate) struct Route {
@@ -194,53 +678,470 @@ pub(crate) struct Route {
     /// of the   asynchronous channel.
     router_tx: Sender<FrameSet<FramesMut>>,
     link: SharedLink,
-    // the next expected sequence number for incoming frames on this route
-    seq_read: u24,
+    // the next expected sequence number for incoming frames on this route, it is learned from the
+    // first frame set since the peer might randomize its initial sequence number
+    seq_read: Option<u24>,
+    // only acknowledge the frame sets carrying reliable frames
+    ack_reliable_only: bool,
+    // the frame sets delivered lately, the duplicated ones are acked again but not delivered
+    seen: SeenWindow,
 }
 
 impl Route {
-    pub(crate) fn new(link: SharedLink) -> (Self, impl Stream<Item = FrameSet<FramesMut>>) {
-        let (router_tx, router_rx) = async_channel::unbounded();
+    /// Create a route buffering at most `cap` frame sets that have not been read
+    pub(crate) fn new(
+        link: SharedLink,
+        cap: usize,
+    ) -> (Self, impl Stream<Item = FrameSet<FramesMut>>) {
+        let (router_tx, router_rx) = async_channel::bounded(cap);
         (
             Self {
                 router_tx,
                 link,
-                seq_read: 0.into(),
+                seq_read: None,
+                ack_reliable_only: false,
+                seen: SeenWindow::default(),
             },
             router_rx,
         )
     }
 
-    /// Deliver the packet to the corresponding route. Return false if the connection was dropped.
+    /// Acknowledge only the frame sets carrying at least one reliable frame
+    pub(crate) fn set_ack_reliable_only(&mut self, enabled: bool) {
+        self.ack_reliable_only = enabled;
+    }
+
+    pub(crate) fn link(&self) -> &SharedLink {
+        &self.link
+    }
+
+    /// Check whether the frame set received from another address continues the sequence of this
+    /// route, which means the peer might have changed its address. Its sequence number must be
+    /// within a small window from the next expected one.
+    pub(crate) fn continues(&self, pack: &connected::Packet<FramesMut>) -> bool {
+        const MIGRATION_WINDOW: u32 = 256;
+
+        let connected::Packet::FrameSet(frames) = pack else {
+            return false;
+        };
+        if self.router_tx.is_closed() {
+            return false;
+        }
+        self.seq_read
+            .is_some_and(|read| frames.seq_num.distance_from(read) < MIGRATION_WINDOW)
+    }
+
+    /// Migrate the peer to the new address, the following packets are sent to it
+    pub(crate) fn migrate(&self, addr: SocketAddr) {
+        *self.link.peer_addr.lock() = addr;
+    }
+
+    /// Deliver the packet to the corresponding route. Return false if the connection was dropped,
+    /// a frame set dropped for the full receive buffer is not acked but keeps the connection.
     pub(crate) fn deliver(&mut self, pack: connected::Packet<FramesMut>) -> bool {
         if self.router_tx.is_closed() {
             return false;
         }
         match pack {
-            connected::Packet::FrameSet(frames) => {
+            connected::Packet::FrameSet(mut frames) => {
+                let seq_num = frames.seq_num;
+                let ack = !self.ack_reliable_only
+                    || frames
+                        .set
+                        .iter()
+                        .any(|frame| frame.flags.reliability.is_reliable());
+                // the network duplicated it, a resend would carry a new sequence number. Ack it
+                // again in case the first ack was lost, without delivering it twice
+                if self.seen.contains(seq_num) {
+                    debug!(
+                        role:% = self.link.role,
+                        peer:% = self.link.peer,
+                        seq_num = seq_num.to_u32();
+                        "receive duplicated frame set"
+                    );
+                    let pending = self
+                        .link
+                        .outgoing_ack
+                        .lock()
+                        .iter()
+                        .any(|acked| acked.0 == seq_num);
+                    if ack && !pending {
+                        self.ack(seq_num);
+                    }
+                    return true;
+                }
+
                 // TODO: use lock free concurrent queue to buffer the outgoing ack/nack to avoid
                 // locking the mutex
-
-                self.link.outgoing_ack.lock().push(Reverse(frames.seq_num));
-
                 {
                     let mut nack = self.link.outgoing_nack.lock();
-                    let seq_num = frames.seq_num;
                     nack.remove(&Reverse(seq_num));
-                    let pre_read = self.seq_read;
-                    if pre_read <= seq_num {
-                        self.seq_read = seq_num + 1;
-                        for n in pre_read.to_u32()..seq_num.to_u32() {
-                            nack.insert(Reverse(n.into()));
+                    let pre_read = self.seq_read.unwrap_or(seq_num);
+                    // the sequence numbers wrap around, the half of the space from the next
+                    // expected one is considered ahead of it
+                    let gap = seq_num.distance_from(pre_read);
+                    let lost = if gap < 1 << 23 {
+                        self.seq_read = Some(seq_num.wrapping_add(1));
+                        // only the latest ones of a large (maybe forged) gap are NACKed
+                        let room = MAX_OUTGOING_NACK.saturating_sub(nack.len()) as u32;
+                        for n in gap.saturating_sub(room)..gap {
+                            nack.insert(Reverse(pre_read.wrapping_add(n)));
                         }
-                    }
+                        gap as usize
+                    } else {
+                        0
+                    };
+                    drop(nack);
+                    self.link.record_incoming(1, lost);
                 }
 
-                return self.router_tx.try_send(frames).is_ok();
+                let received = Some(Received {
+                    at: Instant::now(),
+                    fragment: None,
+                });
+                for frame in &mut frames.set {
+                    frame.received = received;
+                }
+                match self.router_tx.try_send(frames) {
+                    Ok(()) => self.seen.insert(seq_num),
+                    // The application reads slowly, drop the frame set without acking it, so the
+                    // peer resends it after the timeout and slows down. The connection is kept.
+                    Err(TrySendError::Full(_)) => {
+                        debug!(
+                            role:% = self.link.role,
+                            peer:% = self.link.peer,
+                            seq_num = seq_num.to_u32();
+                            "receive buffer is full, drop the frame set"
+                        );
+                        return true;
+                    }
+                    Err(TrySendError::Closed(_)) => return false,
+                }
+                if ack {
+                    self.ack(seq_num);
+                }
             }
             connected::Packet::Ack(ack) => self.link.incoming_ack(ack),
             connected::Packet::Nack(nack) => self.link.incoming_nack(nack),
         };
         true
     }
+
+    fn ack(&self, seq_num: u24) {
+        self.link.outgoing_ack.lock().push(Reverse(seq_num));
+        self.link.wake_driver();
+    }
+}
+
+/// Helpers to drive the link in tests without a transport
+#[cfg(test)]
+impl TransferLink {
+    /// Inject an ACK of the sorted sequence numbers as if it was received from the peer
+    pub(crate) fn inject_ack(&self, seq_nums: impl IntoIterator<Item = u32>) {
+        let ack = AckOrNack::extend_from(seq_nums.into_iter().map(u24::from), u16::MAX)
+            .expect("no sequence number to ack");
+        self.incoming_ack(ack);
+    }
+
+    /// Inject a NACK of the sorted sequence numbers as if it was received from the peer
+    pub(crate) fn inject_nack(&self, seq_nums: impl IntoIterator<Item = u32>) {
+        let nack = AckOrNack::extend_from(seq_nums.into_iter().map(u24::from), u16::MAX)
+            .expect("no sequence number to nack");
+        self.incoming_nack(nack);
+    }
+
+    /// The sequence numbers waiting to be acknowledged to the peer in ascending order, they are
+    /// not consumed
+    pub(crate) fn pending_outgoing_ack(&self) -> Vec<u32> {
+        let mut seq_nums: Vec<_> = self
+            .outgoing_ack
+            .lock()
+            .iter()
+            .map(|v| v.0.to_u32())
+            .collect();
+        seq_nums.sort_unstable();
+        seq_nums
+    }
+
+    /// The sequence numbers waiting to be reported lost to the peer in ascending order, they are
+    /// not consumed
+    pub(crate) fn pending_outgoing_nack(&self) -> Vec<u32> {
+        self.outgoing_nack
+            .lock()
+            .iter()
+            .rev()
+            .map(|v| v.0.to_u32())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+    use futures::StreamExt;
+
+    use super::{Route, SeenWindow, TransferLink, MAX_OUTGOING_NACK};
+    use crate::packet::connected::{self, Flags, Frame, FrameSet};
+    use crate::{Peer, Reliability, Role};
+
+    #[test]
+    fn test_link_stats() {
+        let window = Duration::from_millis(10);
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), window);
+        let (mut route, _rx) = Route::new(link.clone(), 16);
+        // the first one is learned as the initial sequence number, 3 and 4 are missing
+        for seq_num in [1, 2, 5, 6, 7] {
+            route.deliver(connected::Packet::FrameSet(FrameSet {
+                seq_num: seq_num.into(),
+                set: vec![],
+            }));
+        }
+        link.record_outgoing(8, 0);
+        link.record_outgoing(0, 2);
+
+        let stats = link.stats();
+        assert_eq!(stats.frame_sets_received(), 5);
+        assert_eq!(stats.frame_sets_lost(), 2);
+        assert_eq!(stats.frames_sent(), 8);
+        assert_eq!(stats.frames_resent(), 2);
+
+        // estimated after the window is over
+        std::thread::sleep(window);
+        let estimated = link.stats();
+        assert!((estimated.inbound_loss() - 2.0 / 7.0).abs() < f64::EPSILON);
+        assert!((estimated.outbound_loss() - 2.0 / 8.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_route_learns_initial_seq_num() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (mut route, _rx) = Route::new(link.clone(), 16);
+        for seq_num in [5_000_000, 5_000_002, 4_999_999] {
+            route.deliver(connected::Packet::FrameSet(FrameSet {
+                seq_num: seq_num.into(),
+                set: vec![],
+            }));
+        }
+        assert_eq!(link.outgoing_nack.lock().len(), 1);
+        assert_eq!(link.stats().frame_sets_lost(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_route_seq_num_wrap() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (mut route, rx) = Route::new(link.clone(), 16);
+        tokio::pin!(rx);
+        // 0 is missing
+        for seq_num in [0xff_fffe, 0xff_ffff, 1, 2] {
+            assert!(route.deliver(connected::Packet::FrameSet(FrameSet {
+                seq_num: seq_num.into(),
+                set: vec![],
+            })));
+        }
+        assert_eq!(link.pending_outgoing_nack(), vec![0]);
+        assert_eq!(link.stats().frame_sets_lost(), 1);
+        assert!(route.continues(&connected::Packet::FrameSet(FrameSet {
+            seq_num: 3.into(),
+            set: vec![],
+        })));
+        // the late one is delivered without a gap
+        assert!(route.deliver(connected::Packet::FrameSet(FrameSet {
+            seq_num: 0.into(),
+            set: vec![],
+        })));
+        assert!(link.pending_outgoing_nack().is_empty());
+        assert_eq!(link.stats().frame_sets_lost(), 1);
+        drop(route);
+        let delivered: Vec<_> = rx
+            .map(|delivered| delivered.seq_num.to_u32())
+            .collect()
+            .await;
+        assert_eq!(delivered, vec![0xff_fffe, 0xff_ffff, 1, 2, 0]);
+    }
+
+    #[test]
+    fn test_route_nack_gap_capped() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (mut route, _rx) = Route::new(link.clone(), 16);
+        // a forged jump almost half of the sequence number space ahead, twice
+        for seq_num in [0, 0x7f_0000, 0xff_0000] {
+            route.deliver(connected::Packet::FrameSet(FrameSet {
+                seq_num: seq_num.into(),
+                set: vec![],
+            }));
+        }
+        let nack = link.pending_outgoing_nack();
+        assert_eq!(nack.len(), MAX_OUTGOING_NACK);
+        // the latest ones of the first gap
+        assert_eq!(nack.first(), Some(&(0x7f_0000 - MAX_OUTGOING_NACK as u32)));
+        assert_eq!(nack.last(), Some(&0x7e_ffff));
+    }
+
+    #[tokio::test]
+    async fn test_route_backpressure() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (mut route, rx) = Route::new(link.clone(), 2);
+        tokio::pin!(rx);
+        for seq_num in 0..3 {
+            assert!(route.deliver(connected::Packet::FrameSet(FrameSet {
+                seq_num: seq_num.into(),
+                set: vec![],
+            })));
+        }
+        // the third one is neither acked nor nacked, the peer resends it after the timeout
+        assert_eq!(link.outgoing_ack.lock().len(), 2);
+        assert!(link.outgoing_nack.lock().is_empty());
+
+        assert_eq!(rx.next().await.unwrap().seq_num, 0.into());
+        route.deliver(connected::Packet::FrameSet(FrameSet {
+            seq_num: 3.into(),
+            set: vec![],
+        }));
+        assert_eq!(link.outgoing_ack.lock().len(), 3);
+        assert_eq!(rx.next().await.unwrap().seq_num, 1.into());
+        assert_eq!(rx.next().await.unwrap().seq_num, 3.into());
+    }
+
+    #[test]
+    fn test_route_closed() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (mut route, rx) = Route::new(link.clone(), 1);
+        let frame_set = |seq_num: u32| {
+            connected::Packet::FrameSet(FrameSet {
+                seq_num: seq_num.into(),
+                set: vec![],
+            })
+        };
+        assert!(route.deliver(frame_set(0)));
+        // the full buffer keeps the connection
+        assert!(route.deliver(frame_set(1)));
+        assert_eq!(link.pending_outgoing_ack(), vec![0]);
+
+        // the closed one drops the connection
+        drop(rx);
+        assert!(!route.deliver(frame_set(2)));
+        assert_eq!(link.pending_outgoing_ack(), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn test_route_duplicated_frame_set() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (mut route, rx) = Route::new(link.clone(), 16);
+        tokio::pin!(rx);
+        let frame_set = |seq_num: u32| {
+            connected::Packet::FrameSet(FrameSet {
+                seq_num: seq_num.into(),
+                set: vec![],
+            })
+        };
+        for seq_num in [0, 1, 0] {
+            assert!(route.deliver(frame_set(seq_num)));
+        }
+        // the ack is still pending
+        assert_eq!(link.pending_outgoing_ack(), vec![0, 1]);
+        assert!(link.process_outgoing_ack(1500).is_some());
+        // acked again but delivered once
+        assert!(route.deliver(frame_set(1)));
+        assert_eq!(link.pending_outgoing_ack(), vec![1]);
+        assert_eq!(link.stats().frame_sets_received(), 2);
+        drop(route);
+        let delivered: Vec<_> = rx
+            .map(|delivered| delivered.seq_num.to_u32())
+            .collect()
+            .await;
+        assert_eq!(delivered, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_seen_window_wrap() {
+        let mut window = SeenWindow::default();
+        window.insert(0xff_fffe.into());
+        window.insert(0.into());
+        assert!(window.contains(0xff_fffe.into()));
+        assert!(!window.contains(0xff_ffff.into()));
+        assert!(window.contains(0.into()));
+        // late but still in the window
+        window.insert(0xff_ffff.into());
+        assert!(window.contains(0xff_ffff.into()));
+
+        // slide far ahead, the old ones are forgotten
+        window.insert(2000.into());
+        assert!(!window.contains(0.into()));
+        assert!(!window.contains(0xff_fffe.into()));
+        assert!(window.contains(2000.into()));
+        // older than the window, never seen
+        window.insert(500.into());
+        assert!(!window.contains(500.into()));
+        assert!(!window.contains(3000.into()));
+    }
+
+    #[test]
+    fn test_route_migration() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (mut route, _rx) = Route::new(link.clone(), 16);
+        let frame_set = |seq_num: u32| {
+            connected::Packet::FrameSet(FrameSet {
+                seq_num: seq_num.into(),
+                set: vec![],
+            })
+        };
+        // the sequence is not learned yet
+        assert!(!route.continues(&frame_set(0)));
+        route.deliver(frame_set(10));
+        assert!(route.continues(&frame_set(11)));
+        assert!(route.continues(&frame_set(20)));
+        assert!(!route.continues(&frame_set(10)));
+        assert!(!route.continues(&frame_set(1000)));
+
+        let addr = "0.0.0.0:2".parse().unwrap();
+        route.migrate(addr);
+        assert_eq!(link.peer_addr(), addr);
+    }
+
+    #[test]
+    fn test_link_pending_records() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (mut route, _rx) = Route::new(link.clone(), 8);
+        for seq_num in [0, 3, 1] {
+            assert!(route.deliver(connected::Packet::FrameSet(FrameSet {
+                seq_num: seq_num.into(),
+                set: vec![],
+            })));
+        }
+        assert_eq!(link.pending_outgoing_ack(), vec![0, 1, 3]);
+        assert_eq!(link.pending_outgoing_nack(), vec![2]);
+        // not consumed
+        assert_eq!(link.pending_outgoing_nack(), vec![2]);
+        assert!(link.process_outgoing_nack(1500).is_some());
+        assert!(link.pending_outgoing_nack().is_empty());
+
+        link.inject_ack([0, 1]);
+        link.inject_nack([3]);
+        assert_eq!(link.process_ack().count(), 1);
+        assert_eq!(link.process_nack().count(), 1);
+    }
+
+    #[test]
+    fn test_route_ack_reliable_only() {
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let (mut route, _rx) = Route::new(link.clone(), 8);
+        route.set_ack_reliable_only(true);
+        let frame_set = |seq_num: u32, reliability| {
+            connected::Packet::FrameSet(FrameSet {
+                seq_num: seq_num.into(),
+                set: vec![
+                    Frame::new(Flags::new(Reliability::Unreliable, false), BytesMut::new()),
+                    Frame::new(Flags::new(reliability, false), BytesMut::new()),
+                ],
+            })
+        };
+        route.deliver(frame_set(0, Reliability::Unreliable));
+        route.deliver(frame_set(1, Reliability::ReliableOrdered));
+        route.deliver(frame_set(3, Reliability::UnreliableSequenced));
+        assert_eq!(link.pending_outgoing_ack(), vec![1]);
+        // the gap is still reported
+        assert_eq!(link.pending_outgoing_nack(), vec![2]);
+    }
 }
@@ -1,20 +1,771 @@
-use std::collections::VecDeque;
-use std::future::Future;
+use std::collections::HashMap;
+use std::future::{poll_fn, Future};
 use std::io;
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::task::{Context, ContextBuilder, Poll};
+use std::time::{Duration, Instant};
 
+use bytes::{Bytes, BytesMut};
 use fastrace::collector::TraceId;
-use futures::{Sink, SinkExt};
+use futures::{Sink, SinkExt, Stream};
 
+use crate::errors::{ConfigError, RecvTimedOut};
+use crate::guard::OutgoingBuf;
 use crate::link::SharedLink;
-use crate::packet::connected::{Frame, FrameBody};
-use crate::utils::timestamp;
+use crate::packet::connected::{Fragment, Frame, FrameBody, Ordered, MAX_ACK_COUNT_LIMIT};
+use crate::state::Aborting;
+pub use crate::utils::batch::{
+    BatchSinkExt, Batched, UnbatchStreamExt, Unbatched, BATCH_PACKET_ID,
+};
+pub use crate::utils::meta::{FrameMetaStreamExt, WithFrameMeta};
+pub use crate::utils::partition::{PartitionStreamExt, Partitioned};
+pub use crate::utils::peek::{PeekStreamExt, Peeking};
+use crate::utils::{timestamp, u24, FlushHandle, Reactor, TimerHandle, TimerKind};
+use crate::{ConnId, DisconnectReason, Message, Reliability};
 
-/// Trace info extension for server
+/// Trace info extension for the connections of the server and the client
 pub trait TraceInfo {
     fn last_trace_id(&self) -> Option<TraceId>;
 }
 
+/// Statistics extension for server incoming
+pub trait IncomingStats {
+    /// The number of connections which finished the offline handshake and have not been closed.
+    fn connection_count(&self) -> usize;
+}
+
+/// Connections extension for server incoming
+pub trait IncomingConnections {
+    /// Get the handle listing the active connections of the incoming
+    fn connections(&self) -> Connections;
+}
+
+/// Graceful shutdown extension for server incoming
+pub trait IncomingShutdown {
+    /// Shut down the server gracefully. It stops accepting new connections, asks the active
+    /// connections to close and waits at most `timeout` for them to finish, then the incoming
+    /// yields `None`.
+    ///
+    /// Each connection sends the `DisconnectNotification` and waits for its unacknowledged frames
+    /// to be acknowledged on the next flush of its sink, e.g. by the `flush_interval` of the
+    /// config or a ticker of the application, after which its sink is closed. The connections
+    /// that do not finish in time are dropped, and their streams end.
+    fn shutdown(self: Pin<&mut Self>, timeout: Duration) -> impl Future<Output = ()> + Send;
+}
+
+type ConnectionMap =
+    HashMap<(usize, SocketAddr), (ConnectionInfo, SharedLink, Weak<dyn FlushHandle>)>;
+
+/// A cloneable handle listing the active connections of the incoming, the connections are added
+/// and removed as the incoming is polled. It could be used from other tasks, e.g. by an admin
+/// endpoint.
+#[derive(Clone, Default)]
+pub struct Connections {
+    // keyed by the socket index and the peer address like the router of the incoming
+    inner: Arc<parking_lot::RwLock<ConnectionMap>>,
+}
+
+impl std::fmt::Debug for Connections {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connections")
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Connections {
+    pub(crate) fn insert(
+        &self,
+        index: usize,
+        info: ConnectionInfo,
+        link: SharedLink,
+        flush: Weak<dyn FlushHandle>,
+    ) {
+        self.inner
+            .write()
+            .insert((index, info.remote_addr()), (info, link, flush));
+    }
+
+    pub(crate) fn migrate(&self, index: usize, from: SocketAddr, to: SocketAddr) {
+        let mut inner = self.inner.write();
+        if let Some(entry) = inner.remove(&(index, from)) {
+            inner.insert((index, to), entry);
+        }
+    }
+
+    pub(crate) fn remove(&self, index: usize, addr: SocketAddr) {
+        self.inner.write().remove(&(index, addr));
+    }
+
+    /// The number of active connections
+    pub fn len(&self) -> usize {
+        self.inner.read().len()
+    }
+
+    /// Whether there is no active connection
+    pub fn is_empty(&self) -> bool {
+        self.inner.read().is_empty()
+    }
+
+    /// Take the snapshots of the active connections, the statistics are taken at the time of
+    /// calling
+    pub fn snapshot(&self) -> Vec<ConnectionSnapshot> {
+        self.inner
+            .read()
+            .iter()
+            .map(|(&(_, addr), (info, link, _))| ConnectionSnapshot {
+                addr,
+                info: info
+                    .with_client_guid(link.client_guid())
+                    .with_close_reason(link.close_reason()),
+                stats: link.stats(),
+            })
+            .collect()
+    }
+
+    /// Flush the sinks of the active connections and wait at most `timeout` for them, e.g. at
+    /// the end of a simulation tick of a tick-driven server. The connections still pending at the
+    /// deadline, e.g. whose sockets are backed up, are left to their next flush. The connections
+    /// accepted after calling are not flushed.
+    ///
+    /// The sinks are flushed by the calling task, a flush of the application pending on the same
+    /// sink at the same time is still woken by the sink.
+    pub async fn flush_all(&self, timeout: Duration) -> FlushSummary {
+        let deadline = Instant::now() + timeout;
+        let mut handles: Vec<_> = self
+            .inner
+            .read()
+            .values()
+            .map(|(info, _, flush)| (info.conn_id(), Weak::clone(flush)))
+            .collect();
+        let mut summary = FlushSummary::default();
+        let mut timer: Option<TimerHandle> = None;
+        poll_fn(|cx| {
+            handles.retain(|(_, flush)| {
+                // the sink was dropped, nothing to flush
+                let Some(flush) = flush.upgrade() else {
+                    return false;
+                };
+                match flush.poll_flush(cx) {
+                    Poll::Ready(Ok(())) => summary.flushed += 1,
+                    Poll::Ready(Err(_)) => summary.failed += 1,
+                    Poll::Pending => return true,
+                }
+                false
+            });
+            let Some(&(c_id, _)) = handles.first() else {
+                return Poll::Ready(());
+            };
+            if Instant::now() >= deadline {
+                summary.pending = handles.len();
+                return Poll::Ready(());
+            }
+            match &mut timer {
+                Some(timer) => Reactor::get().reset_timer(timer, deadline, cx.waker()),
+                None => {
+                    timer = Some(Reactor::get().insert_timer(
+                        c_id,
+                        TimerKind::FlushAll,
+                        deadline,
+                        cx.waker(),
+                    ));
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        if let Some(timer) = timer {
+            Reactor::get().cancel_timer(timer);
+        }
+        summary
+    }
+}
+
+/// The result of [`Connections::flush_all`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlushSummary {
+    flushed: usize,
+    failed: usize,
+    pending: usize,
+}
+
+impl FlushSummary {
+    /// The number of connections flushed completely
+    pub fn flushed(&self) -> usize {
+        self.flushed
+    }
+
+    /// The number of connections whose sinks failed when flushing
+    pub fn failed(&self) -> usize {
+        self.failed
+    }
+
+    /// The number of connections still pending at the deadline
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+}
+
+/// The snapshot of an active connection taken by [`Connections::snapshot`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionSnapshot {
+    addr: SocketAddr,
+    info: ConnectionInfo,
+    stats: Stats,
+}
+
+impl ConnectionSnapshot {
+    /// Get the current address of the peer, which differs from the remote address of the
+    /// connection info after the connection migrated
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Get the negotiated information of the connection
+    pub fn info(&self) -> ConnectionInfo {
+        self.info
+    }
+
+    /// Get the statistics of the connection
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+/// Connectionless extension for server incoming
+pub trait SendUnconnected {
+    /// Send a datagram as it is to `addr` out of the socket of the incoming, without creating a
+    /// connection, e.g. to reply to a custom discovery or query protocol. It is sent out of the
+    /// first socket serving the address family of `addr`. The returned future does not borrow
+    /// the incoming, so it could be spawned while the incoming is polled.
+    fn send_unconnected(
+        &self,
+        addr: SocketAddr,
+        datagram: Bytes,
+    ) -> impl Future<Output = Result<(), io::Error>> + Send + 'static;
+}
+
+/// The negotiated information of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    conn_id: ConnId,
+    mtu: u16,
+    effective_mtu: u16,
+    protocol_version: u8,
+    external_addr: Option<SocketAddr>,
+    client_guid: Option<u64>,
+    close_reason: Option<DisconnectReason>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(conn_id: ConnId, mtu: u16, protocol_version: u8) -> Self {
+        Self {
+            conn_id,
+            mtu,
+            effective_mtu: mtu,
+            protocol_version,
+            external_addr: None,
+            client_guid: None,
+            close_reason: None,
+        }
+    }
+
+    pub(crate) fn set_external_addr(&mut self, addr: SocketAddr) {
+        self.external_addr = Some(addr);
+    }
+
+    pub(crate) fn with_effective_mtu(mut self, mtu: u16) -> Self {
+        self.effective_mtu = mtu;
+        self
+    }
+
+    pub(crate) fn with_client_guid(mut self, guid: Option<u64>) -> Self {
+        self.client_guid = guid;
+        self
+    }
+
+    pub(crate) fn with_close_reason(mut self, reason: Option<DisconnectReason>) -> Self {
+        self.close_reason = reason;
+        self
+    }
+
+    /// Get the identifier of the connection, which could be used to correlate logs and traces
+    pub fn conn_id(&self) -> ConnId {
+        self.conn_id
+    }
+
+    /// Get the mtu negotiated in the offline handshake
+    pub fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// Get the MTU the frame sets are currently sized to, it starts with the negotiated one and
+    /// may be reduced when the large frame sets are lost on the path (see `mtu_blackhole_retries`
+    /// of the configs)
+    pub fn effective_mtu(&self) -> u16 {
+        self.effective_mtu
+    }
+
+    /// Get the guid of the remote peer
+    pub fn remote_guid(&self) -> u64 {
+        self.conn_id.remote_guid()
+    }
+
+    /// Get the address of the remote peer
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.conn_id.remote_addr()
+    }
+
+    /// Get the `RakNet` protocol version used by the connection
+    pub fn protocol_version(&self) -> u8 {
+        self.protocol_version
+    }
+
+    /// Get the address of this side seen by the remote peer (e.g. the address after NAT), which
+    /// is reported in `ConnectionRequestAccepted`. It is `None` before the handshake completes.
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        self.external_addr
+    }
+
+    /// Get the guid the client sent in `ConnectionRequest` on the server side, which may differ
+    /// from [`remote_guid`](Self::remote_guid) sent in the offline handshake. It is `None` before
+    /// the request is received, and always `None` on the client side.
+    pub fn client_guid(&self) -> Option<u64> {
+        self.client_guid
+    }
+
+    /// Get why the connection was closed, e.g. by the peer or by the timeout, it is `None` while
+    /// the connection is open. The stream of the connection ends without telling the reason, so
+    /// check it after the stream yields `None`.
+    pub fn close_reason(&self) -> Option<DisconnectReason> {
+        self.close_reason
+    }
+}
+
+/// Connection info extension for the connections of both sides
+pub trait ConnInfo {
+    fn connection_info(&self) -> ConnectionInfo;
+}
+
+/// The statistics snapshot of a connection. The loss rates are estimated in windows (1 second by
+/// default, set by `loss_window` of the configs), the loss ratio of each window is smoothed into
+/// the estimation by EWMA with a gain of 1/8, and the windows without any traffic are skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Stats {
+    pub(crate) frames_sent: u64,
+    pub(crate) frames_resent: u64,
+    pub(crate) frames_resent_by_nack: u64,
+    pub(crate) frames_resent_by_timeout: u64,
+    pub(crate) frame_sets_received: u64,
+    pub(crate) frame_sets_lost: u64,
+    pub(crate) outbound_loss: f64,
+    pub(crate) inbound_loss: f64,
+    pub(crate) reorder_high_water: usize,
+    pub(crate) frames_expired: u64,
+    pub(crate) frames_abandoned: u64,
+    pub(crate) resend_bytes: usize,
+    pub(crate) pacing_rate: u64,
+    pub(crate) unsent_acks: u64,
+    pub(crate) fec_recovered: u64,
+}
+
+impl Stats {
+    /// Get the number of frames sent, including the resent ones
+    pub fn frames_sent(&self) -> u64 {
+        self.frames_sent
+    }
+
+    /// Get the number of frames to be resent because they were reported by NACK or timed out
+    pub fn frames_resent(&self) -> u64 {
+        self.frames_resent
+    }
+
+    /// Get the number of frames to be resent because the peer reported them lost by NACK, a part
+    /// of [`Stats::frames_resent`]
+    pub fn frames_resent_by_nack(&self) -> u64 {
+        self.frames_resent_by_nack
+    }
+
+    /// Get the number of frames to be resent because they were not acknowledged within the
+    /// retransmission timeout, a part of [`Stats::frames_resent`]
+    pub fn frames_resent_by_timeout(&self) -> u64 {
+        self.frames_resent_by_timeout
+    }
+
+    /// Get the number of frame sets received, including the duplicated ones
+    pub fn frame_sets_received(&self) -> u64 {
+        self.frame_sets_received
+    }
+
+    /// Get the number of missing frame sets detected by the gaps of the sequence numbers, which
+    /// are reported by NACK
+    pub fn frame_sets_lost(&self) -> u64 {
+        self.frame_sets_lost
+    }
+
+    /// Get the estimated loss rate of the outbound direction in `[0, 1]`, measured by the ratio
+    /// of resent frames to sent frames
+    pub fn outbound_loss(&self) -> f64 {
+        self.outbound_loss
+    }
+
+    /// Get the estimated loss rate of the inbound direction in `[0, 1]`, measured by the ratio
+    /// of missing frame sets to expected frame sets
+    pub fn inbound_loss(&self) -> f64 {
+        self.inbound_loss
+    }
+
+    /// Get the maximum number of frames ever buffered in an ordering channel until the missing
+    /// ones arrived, which is limited by `max_reorder_depth` of the configs
+    pub fn reorder_high_water(&self) -> usize {
+        self.reorder_high_water
+    }
+
+    /// Get the number of frames dropped because their messages expired before sending, see
+    /// [`Message::set_ttl`]
+    pub fn frames_expired(&self) -> u64 {
+        self.frames_expired
+    }
+
+    /// Get the number of reliable frames abandoned because they were not acknowledged in time,
+    /// see [`Message::set_abandon_after`]
+    pub fn frames_abandoned(&self) -> u64 {
+        self.frames_abandoned
+    }
+
+    /// Get the total size in bytes of the sent reliable frames waiting for acknowledgement, which
+    /// is limited by `resend_buf_bytes` of the configs
+    pub fn resend_bytes(&self) -> usize {
+        self.resend_bytes
+    }
+
+    /// Get the rate in bytes per second the frame sets are spaced out by, which is 0 unless
+    /// `pacing` of the configs is enabled
+    pub fn pacing_rate(&self) -> u64 {
+        self.pacing_rate
+    }
+
+    /// Get the number of sequence numbers acknowledged by the peer before the frame sets were sent,
+    /// they are ignored as the ACKs may be spoofed by an off-path attacker guessing them
+    pub fn unsent_acks(&self) -> u64 {
+        self.unsent_acks
+    }
+
+    /// Get the number of lost frames recovered from the parity frames, which is 0 unless `fec` of
+    /// the configs is set
+    pub fn fec_recovered(&self) -> u64 {
+        self.fec_recovered
+    }
+}
+
+/// Statistics extension for connections
+pub trait ConnStats {
+    fn stats(&self) -> Stats;
+}
+
+/// Frame metadata extension for the streams of connections, see
+/// [`FrameMetaStreamExt::with_frame_meta`]
+pub trait LastFrameMeta {
+    /// Get the metadata of the frame carrying the last payload yielded by the stream, it is `None`
+    /// before any payload is yielded
+    fn last_frame_meta(&self) -> Option<FrameMeta>;
+}
+
+/// Send buffer extension for connections, e.g. to raise the capacity before a large transfer and
+/// shrink it afterward
+pub trait SendBufCap {
+    /// Get the current capacity of the send buffer in frames, which starts with `send_buf_cap`
+    /// of the configs
+    fn send_buf_cap(&self) -> usize;
+
+    /// Adjust the capacity of the send buffer in frames, it takes effect on the next send. If it
+    /// is shrunk below the frames buffered, no more frames are accepted until they are drained.
+    ///
+    /// # Panics
+    /// Panics if `cap` is zero.
+    fn set_send_buf_cap(&self, cap: usize);
+
+    /// Reserve the room for at least `frames` more frames in the send buffer and for as many
+    /// frame sets waiting for acknowledgement up front, which avoids the reallocations in the
+    /// middle of a large transfer. The room of the send buffer is reserved for the messages of the
+    /// default priority. It takes effect on the next send, and it is a no-op if the capacity
+    /// already suffices.
+    fn reserve(&self, frames: usize);
+}
+
+/// Acknowledgement extension for connections, a latency-tuning escape hatch complementing the
+/// delayed acks of [`FlushStrategy`]
+pub trait AckNow {
+    /// Send the pending acknowledgements on the next flush of the sink, even if the flush strategy
+    /// skips or defers them, e.g. after processing a request the peer is waiting on. It only sets
+    /// a flag and wakes the background driver, so it is cheap to call frequently.
+    fn ack_now(&self);
+}
+
+/// What triggered a retransmission
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetransmitCause {
+    /// The peer reported the frame set lost by a NACK
+    Nack,
+    /// The frame set was not acknowledged within the retransmission timeout
+    Timeout,
+}
+
+/// A frame set whose frames are queued to be resent, passed to the `on_retransmit` hook of the
+/// configs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retransmission {
+    pub(crate) addr: SocketAddr,
+    pub(crate) seq_num: u32,
+    pub(crate) retransmits: u32,
+    pub(crate) cause: RetransmitCause,
+}
+
+impl Retransmission {
+    /// Get the address of the peer
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Get the sequence number of the lost frame set, the frames are resent in new frame sets
+    pub fn seq_num(&self) -> u32 {
+        self.seq_num
+    }
+
+    /// Get the retransmit count of the frame set, i.e. how many times its most resent frame has
+    /// been retransmitted including this time. It is 1 for the first retransmission of the frames.
+    pub fn retransmits(&self) -> u32 {
+        self.retransmits
+    }
+
+    /// Get what triggered the retransmission
+    pub fn cause(&self) -> RetransmitCause {
+        self.cause
+    }
+}
+
+type RetransmitFn = dyn Fn(Retransmission) + Send + Sync;
+
+/// The hook receives the retransmissions of a connection
+#[derive(Clone)]
+pub(crate) struct RetransmitHook(Arc<RetransmitFn>);
+
+impl std::fmt::Debug for RetransmitHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RetransmitHook(..)")
+    }
+}
+
+impl RetransmitHook {
+    pub(crate) fn new(hook: impl Fn(Retransmission) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+
+    pub(crate) fn call(&self, retransmission: Retransmission) {
+        (self.0)(retransmission);
+    }
+}
+
+/// The delivery of a message tagged by [`Message::set_receipt`], passed to the `on_ack_receipt`
+/// hook of the configs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AckReceipt {
+    pub(crate) addr: SocketAddr,
+    pub(crate) receipt: u32,
+    pub(crate) acked: bool,
+}
+
+impl AckReceipt {
+    /// Get the address of the peer
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Get the receipt number of the message
+    pub fn receipt(&self) -> u32 {
+        self.receipt
+    }
+
+    /// Whether the message is acknowledged, otherwise it is lost. A reliable message is resent
+    /// until it is acknowledged, so only an unreliable one could be lost.
+    pub fn acked(&self) -> bool {
+        self.acked
+    }
+}
+
+type ReceiptFn = dyn Fn(AckReceipt) + Send + Sync;
+
+/// The hook receives the ack receipts of a connection
+#[derive(Clone)]
+pub(crate) struct ReceiptHook(Arc<ReceiptFn>);
+
+impl std::fmt::Debug for ReceiptHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ReceiptHook(..)")
+    }
+}
+
+impl ReceiptHook {
+    pub(crate) fn new(hook: impl Fn(AckReceipt) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+
+    pub(crate) fn call(&self, receipt: AckReceipt) {
+        (self.0)(receipt);
+    }
+}
+
+type PacketFn = dyn Fn(ConnId, Bytes) + Send + Sync;
+
+/// The handlers of the user packets registered by the packet ids, the packets of the ids without
+/// a handler are passed through to the stream of the connection
+#[derive(Clone, Default)]
+pub(crate) struct PacketHandlers(Arc<HashMap<u8, Arc<PacketFn>>>);
+
+impl std::fmt::Debug for PacketHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PacketHandlers")
+            .field(&self.0.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl PacketHandlers {
+    pub(crate) fn insert(
+        &mut self,
+        id: u8,
+        handler: impl Fn(ConnId, Bytes) + Send + Sync + 'static,
+    ) {
+        Arc::make_mut(&mut self.0).insert(id, Arc::new(handler));
+    }
+
+    /// Pass the packet to its handler, or return it back if there is no handler for its id
+    pub(crate) fn dispatch(&self, conn_id: ConnId, data: Bytes) -> Option<Bytes> {
+        let Some(handler) = data.first().and_then(|id| self.0.get(id)) else {
+            return Some(data);
+        };
+        handler(conn_id, data);
+        None
+    }
+}
+
+/// The maximum channels of a connection, the ordered decoder takes fewer channels than
+/// `u8::MAX`
+pub(crate) const MAX_CHANNELS: usize = 254;
+
+/// Validate the options of the connections shared by the server and the client configs, returns
+/// the first invalid one
+pub(crate) fn validate_link(
+    send_buf_cap: usize,
+    max_channels: usize,
+    max_ack_count: usize,
+    ack_buffer: usize,
+    (min_rto, max_rto): (Duration, Duration),
+) -> Result<(), ConfigError> {
+    if send_buf_cap == 0 {
+        return Err(ConfigError::new(
+            "send_buf_cap",
+            "it must be greater than zero",
+        ));
+    }
+    if max_channels == 0 || max_channels > MAX_CHANNELS {
+        return Err(ConfigError::new("max_channels", "it must be in 1..=254"));
+    }
+    if max_ack_count == 0 || max_ack_count > MAX_ACK_COUNT_LIMIT {
+        return Err(ConfigError::new("max_ack_count", "it must be in 1..=65536"));
+    }
+    if ack_buffer == 0 {
+        return Err(ConfigError::new(
+            "ack_buffer",
+            "it must be greater than zero",
+        ));
+    }
+    if min_rto.is_zero() || min_rto > max_rto {
+        return Err(ConfigError::new(
+            "rto_bounds",
+            "the min RTO must be greater than zero and not greater than the max RTO",
+        ));
+    }
+    Ok(())
+}
+
+/// The metadata of a received frame, which is stripped from the payload by the decoder. The
+/// connection streams yield it alongside the payloads with
+/// [`FrameMetaStreamExt::with_frame_meta`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameMeta {
+    pub(crate) reliability: Reliability,
+    pub(crate) reliable_frame_index: Option<u24>,
+    pub(crate) seq_frame_index: Option<u24>,
+    pub(crate) ordered: Option<Ordered>,
+    pub(crate) fragment: Option<Fragment>,
+    pub(crate) received_at: Instant,
+}
+
+impl FrameMeta {
+    /// Take the metadata of the decoded frame, the frames that were not received from the route
+    /// are stamped now.
+    pub(crate) fn new<B>(frame: &Frame<B>) -> Self {
+        let (received_at, fragment) = frame.received.map_or((Instant::now(), None), |received| {
+            (received.at, received.fragment)
+        });
+        Self {
+            reliability: frame.flags.reliability,
+            reliable_frame_index: frame.reliable_frame_index,
+            seq_frame_index: frame.seq_frame_index,
+            ordered: frame.ordered,
+            fragment,
+            received_at,
+        }
+    }
+
+    /// Get the reliability of the frame
+    pub fn reliability(&self) -> Reliability {
+        self.reliability
+    }
+
+    /// Get the reliable frame index, it is `None` if the frame is unreliable
+    pub fn reliable_index(&self) -> Option<u32> {
+        self.reliable_frame_index.map(u24::to_u32)
+    }
+
+    /// Get the sequence index, it is `None` if the frame is not sequenced
+    pub fn sequence_index(&self) -> Option<u32> {
+        self.seq_frame_index.map(u24::to_u32)
+    }
+
+    /// Get the ordering channel, it is `None` if the frame is neither ordered nor sequenced
+    pub fn order_channel(&self) -> Option<u8> {
+        self.ordered.map(|ordered| ordered.channel)
+    }
+
+    /// Get the ordering index in the channel, it is `None` if the frame is neither ordered nor
+    /// sequenced
+    pub fn order_index(&self) -> Option<u32> {
+        self.ordered.map(|ordered| ordered.frame_index.to_u32())
+    }
+
+    /// Get the number of the fragments the frame was reassembled from, it is `None` if the frame
+    /// was not fragmented
+    pub fn parted_size(&self) -> Option<u32> {
+        self.fragment.map(|fragment| fragment.parted_size)
+    }
+
+    /// Get the id shared by the fragments the frame was reassembled from, it is `None` if the
+    /// frame was not fragmented
+    pub fn parted_id(&self) -> Option<u16> {
+        self.fragment.map(|fragment| fragment.parted_id)
+    }
+
+    /// Get the instant the frame was received, it is the receive time of the last fragment if the
+    /// frame was reassembled
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+}
+
 /// Ping extension for client, experimental
 pub trait Ping {
     fn ping(self: Pin<&mut Self>) -> impl Future<Output = Result<(), io::Error>> + Send;
@@ -32,6 +783,347 @@ where
     }
 }
 
+/// Reliable flush extension
+pub trait FlushReliable {
+    /// Flush the buffers and wait until all reliable frames sent so far are acknowledged by the
+    /// peer, without closing the connection like [`Sink::poll_close`] does.
+    ///
+    /// Notice that the acknowledgements of a client connection are received by polling its
+    /// stream, so keep polling the stream while waiting.
+    ///
+    /// # Errors
+    /// Returns the error of the underlying sink.
+    fn flush_reliable(self: Pin<&mut Self>) -> impl Future<Output = Result<(), io::Error>> + Send;
+}
+
+impl<S> FlushReliable for S
+where
+    S: Sink<Message, Error = io::Error> + Send,
+{
+    async fn flush_reliable(mut self: Pin<&mut Self>) -> Result<(), io::Error> {
+        let mut strategy = FlushStrategy::default().wait_acked();
+        poll_fn(|cx| {
+            let mut cx = ContextBuilder::from(cx).ext(&mut strategy).build();
+            self.as_mut().poll_flush(&mut cx)
+        })
+        .await
+    }
+}
+
+/// Abort extension for the sink of a connection
+pub trait Abort {
+    /// Abort the connection immediately. Unlike the graceful close, it sends a best-effort
+    /// `DisconnectNotification` once, drops the buffered and the unacked frames without waiting
+    /// for them to be acknowledged, and frees the state of the connection. It does nothing if the
+    /// connection was closed.
+    fn abort(self: Pin<&mut Self>) -> impl Future<Output = Result<(), io::Error>> + Send;
+}
+
+impl<S> Abort for S
+where
+    S: Sink<Message, Error = io::Error> + Send,
+{
+    async fn abort(mut self: Pin<&mut Self>) -> Result<(), io::Error> {
+        let mut aborting = Aborting;
+        poll_fn(|cx| {
+            let mut cx = ContextBuilder::from(cx).ext(&mut aborting).build();
+            self.as_mut().poll_close(&mut cx)
+        })
+        .await
+    }
+}
+
+/// Receive deadline extension for the stream of a connection
+pub trait RecvTimeout {
+    /// Receive the next data, or fail if nothing is received before the `deadline`. It is useful
+    /// to detect a silent peer before the connection is torn down. The timer is driven by the
+    /// internal reactor, no timer of the runtime is needed.
+    ///
+    /// # Errors
+    /// Returns a `TimedOut` error carrying [`RecvTimedOut`] if the deadline is reached, the
+    /// stream is still usable after that. `Ok(None)` is returned if the stream ended.
+    fn recv_deadline(
+        self: Pin<&mut Self>,
+        deadline: Instant,
+    ) -> impl Future<Output = Result<Option<Bytes>, io::Error>> + Send;
+
+    /// Same as [`RecvTimeout::recv_deadline`] with a deadline `timeout` after now.
+    ///
+    /// # Errors
+    /// Returns a `TimedOut` error carrying [`RecvTimedOut`] if the timeout is reached.
+    fn recv_timeout(
+        self: Pin<&mut Self>,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Option<Bytes>, io::Error>> + Send {
+        self.recv_deadline(Instant::now() + timeout)
+    }
+}
+
+impl<S> RecvTimeout for S
+where
+    S: Stream<Item = Bytes> + ConnInfo + Send,
+{
+    async fn recv_deadline(
+        mut self: Pin<&mut Self>,
+        deadline: Instant,
+    ) -> Result<Option<Bytes>, io::Error> {
+        let started = Instant::now();
+        let c_id = self.connection_info().conn_id();
+        let mut timer: Option<TimerHandle> = None;
+        let res = poll_fn(|cx| {
+            if let Poll::Ready(data) = self.as_mut().poll_next(cx) {
+                return Poll::Ready(Ok(data));
+            }
+            if Instant::now() >= deadline {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    RecvTimedOut::new(started.elapsed()),
+                )));
+            }
+            match &mut timer {
+                Some(timer) => Reactor::get().reset_timer(timer, deadline, cx.waker()),
+                None => {
+                    timer = Some(Reactor::get().insert_timer(
+                        c_id,
+                        TimerKind::Recv,
+                        deadline,
+                        cx.waker(),
+                    ));
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        if let Some(timer) = timer {
+            Reactor::get().cancel_timer(timer);
+        }
+        res
+    }
+}
+
+/// The error returned by [`TrySend::try_send`]
+#[derive(thiserror::Error, Debug)]
+pub enum TrySendError {
+    /// The send buffer is full, the message is given back
+    #[error("send buffer is full")]
+    Full(Message),
+    /// The underlying sink failed
+    #[error("io error {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Non-blocking send extension
+pub trait TrySend {
+    /// Try to send a message without suspending. It returns [`TrySendError::Full`] immediately if
+    /// the send buffer has reached its capacity, so the caller could drop or downgrade the message.
+    ///
+    /// The capacity is checked against the buffer as of the last time the sink was polled, nothing
+    /// is flushed by it. Keep flushing the sink (or enable `flush_interval` of the configs) to
+    /// drain the buffer. The buffer is also full while the internal packets like the handshake are
+    /// waiting to be sent, so flush the sink once after connecting.
+    ///
+    /// # Errors
+    /// Returns [`TrySendError::Full`] if the buffer is full, or [`TrySendError::Io`] if the
+    /// underlying sink failed.
+    fn try_send(self: Pin<&mut Self>, msg: Message) -> Result<(), TrySendError>;
+}
+
+/// Compression of the frame bodies, installed by the `compression` option of the server and the
+/// client configs. The messages are compressed before fragmentation, so that the compressed bodies
+/// are split by the MTU, and they are decompressed after reassembly. The bodies are compressed
+/// before being encrypted if [`FrameCrypto`] is installed as well.
+///
+/// It is not negotiated by the handshake, both sides have to be configured with the same
+/// compression. All frame bodies of the connection are compressed, including the internal packets
+/// like `ConnectedPing`. The frame bodies are not compressed by default.
+pub trait FrameCompression: Send + Sync + 'static {
+    /// Compress the body of a message
+    fn compress(&self, body: Bytes) -> Bytes;
+
+    /// Decompress the reassembled body of a message.
+    ///
+    /// # Errors
+    /// Returns an error if the body is malformed, it is reported as a decode error.
+    fn decompress(&self, body: Bytes) -> io::Result<Bytes>;
+}
+
+impl std::fmt::Debug for dyn FrameCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FrameCompression(..)")
+    }
+}
+
+/// The [`FrameCompression`] leaving the bodies as they are, concrete codecs are left to the
+/// application
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoCompression;
+
+impl FrameCompression for NoCompression {
+    fn compress(&self, body: Bytes) -> Bytes {
+        body
+    }
+
+    fn decompress(&self, body: Bytes) -> io::Result<Bytes> {
+        Ok(body)
+    }
+}
+
+/// The forward error correction (FEC) of the unreliable frames, set by `fec` of the server and the
+/// client configs with the `fec` feature. After each group of `group_size` protected frames, a
+/// parity frame carrying the XOR of them is sent, so that the receiver could recover one lost
+/// frame of each group before reassembling, without waiting for a round trip. The overhead is
+/// about `1 / group_size` of the protected traffic, plus 4 bytes per frame.
+///
+/// The frames of the configured reliability (`Unreliable` by default), and of the configured
+/// channel if any, are protected. A lost frame is only recoverable if the other frames of its
+/// group are carried by other frame sets, e.g. when each real-time update is flushed on its own.
+/// The protected frames are NOT compatible with the peers without the same options, so both sides
+/// have to agree on it, e.g. by the protocol version.
+#[cfg(feature = "fec")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FecOptions {
+    pub(crate) group_size: u8,
+    pub(crate) reliability: Reliability,
+    pub(crate) channel: Option<u8>,
+}
+
+#[cfg(feature = "fec")]
+impl FecOptions {
+    /// Protect the `Unreliable` frames by a parity frame every `group_size` frames
+    ///
+    /// # Panics
+    /// Panics if `group_size` is zero.
+    pub fn new(group_size: u8) -> Self {
+        assert!(group_size > 0, "the group size must be greater than zero");
+        Self {
+            group_size,
+            reliability: Reliability::Unreliable,
+            channel: None,
+        }
+    }
+
+    /// Protect the frames of `reliability` instead
+    ///
+    /// # Panics
+    /// Panics if `reliability` is reliable, the reliable frames are resent instead.
+    pub fn reliability(mut self, reliability: Reliability) -> Self {
+        assert!(
+            !reliability.is_reliable(),
+            "only the unreliable frames could be protected"
+        );
+        self.reliability = reliability;
+        self
+    }
+
+    /// Only protect the frames on the ordering `channel`, which are the sequenced ones
+    pub fn channel(mut self, channel: u8) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    pub(crate) fn protects<B>(&self, frame: &Frame<B>) -> bool {
+        frame.flags.reliability == self.reliability
+            && self.channel.map_or(true, |channel| {
+                frame.ordered.map(|ordered| ordered.channel) == Some(channel)
+            })
+    }
+}
+
+/// The options applied to the UDP sockets by `setsockopt` before they are used, set by
+/// `socket_options` of the server and the client configs. The options not set are left as the
+/// system defaults.
+///
+/// The kernel may clamp the buffer sizes, e.g. Linux doubles the requested size for its
+/// bookkeeping and caps it by `net.core.rmem_max`/`net.core.wmem_max`. The DSCP marking is set by
+/// `IP_TOS` on the IPv4 sockets and `IPV6_TCLASS` on the IPv6 ones, the latter is not available
+/// on Windows, and some versions of Windows ignore `IP_TOS` as well.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub(crate) recv_buf_size: Option<usize>,
+    pub(crate) send_buf_size: Option<usize>,
+    pub(crate) dscp: Option<u8>,
+}
+
+impl SocketOptions {
+    /// Create the options leaving the system defaults
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the size of the kernel receive buffer (`SO_RCVBUF`) in bytes
+    pub fn recv_buf_size(mut self, size: usize) -> Self {
+        self.recv_buf_size = Some(size);
+        self
+    }
+
+    /// Set the size of the kernel send buffer (`SO_SNDBUF`) in bytes
+    pub fn send_buf_size(mut self, size: usize) -> Self {
+        self.send_buf_size = Some(size);
+        self
+    }
+
+    /// Mark the outgoing datagrams with the DSCP code point, e.g. 46 for expedited forwarding
+    ///
+    /// # Panics
+    /// Panics if `dscp` does not fit in 6 bits.
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        assert!(dscp < 64, "the DSCP must be less than 64");
+        self.dscp = Some(dscp);
+        self
+    }
+}
+
+/// Encryption of the frame bodies, installed by the `crypto` option of the server and the client
+/// configs. The bodies are encrypted after fragmentation and decrypted before reassembly, so each
+/// fragment is encrypted separately. The offline handshake and ACK/NACK packets are not encrypted.
+///
+/// The server tells the client by `encryption_enabled` in the open connection reply 2, and the
+/// client fails to connect with [`ConnectError::EncryptionMismatch`](crate::errors::ConnectError)
+/// if it does not match its own setting, before any encrypted frame is sent. The connections are
+/// not encrypted by default.
+pub trait FrameCrypto: Send + Sync + 'static {
+    /// Encrypt the frame body in place, the body should grow no more than
+    /// [`FrameCrypto::overhead`] bytes.
+    fn encrypt(&self, body: &mut BytesMut);
+
+    /// Decrypt the frame body in place. If an error is returned, the whole frame set carrying the
+    /// frame fails to decode and all of its frames are dropped.
+    ///
+    /// # Errors
+    /// Returns an error if the body could not be decrypted or authenticated.
+    fn decrypt(&self, body: &mut BytesMut) -> io::Result<()>;
+
+    /// The maximum number of bytes added to a frame body by [`FrameCrypto::encrypt`], which is
+    /// reserved when splitting the messages. The default value is 0.
+    fn overhead(&self) -> usize {
+        0
+    }
+}
+
+impl std::fmt::Debug for dyn FrameCrypto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FrameCrypto(..)")
+    }
+}
+
+/// The tap to observe the raw datagrams of a socket, e.g. for packet capture or metrics. The
+/// incoming datagrams are passed before decoding, including the ones failed to decode, and the
+/// outgoing datagrams are passed after encoding, before they are sent. The buffers are borrowed, so
+/// copy them if they are needed later. Both methods do nothing by default.
+pub trait DatagramTap: Send + Sync + 'static {
+    /// Observe an incoming datagram received from `addr`
+    fn on_raw_in(&self, _datagram: &[u8], _addr: SocketAddr) {}
+
+    /// Observe an outgoing datagram to be sent to `addr`
+    fn on_raw_out(&self, _datagram: &[u8], _addr: SocketAddr) {}
+}
+
+impl std::fmt::Debug for dyn DatagramTap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DatagramTap(..)")
+    }
+}
+
 /// Flush strategy can be used as ext data of [`std::task::Context`] to guide how
 /// [`Sink::poll_flush`] perform flush. And the results after flush will be stored here.
 /// The default strategy will flush all buffers.
@@ -47,7 +1139,8 @@ where
 /// packets.
 ///
 /// After the flush is completed, the strategy will store the number of frames that have been
-/// flushed. You can use this number to determine when to take the next flush.
+/// flushed. You can use this number to determine when to take the next flush. It also stores the
+/// number of packets retransmitted by NACKs (fast retransmit) and by timeouts separately.
 ///
 /// Note that it can only be used in [`Sink::poll_flush`].
 #[derive(Debug, Default, Clone, Copy)]
@@ -55,6 +1148,11 @@ pub struct FlushStrategy {
     ack_tag: isize,
     nack_tag: isize,
     pack_tag: isize,
+    nack_resent: usize,
+    stale_resent: usize,
+    wait_acked: bool,
+    // the (ack, nack, pack) flushed by the last flush call
+    last_flushed: (usize, usize, usize),
 }
 
 impl FlushStrategy {
@@ -64,9 +1162,24 @@ impl FlushStrategy {
             ack_tag: if ack { 0 } else { -1 },
             nack_tag: if nack { 0 } else { -1 },
             pack_tag: if pack { 0 } else { -1 },
+            nack_resent: 0,
+            stale_resent: 0,
+            wait_acked: false,
+            last_flushed: (0, 0, 0),
         }
     }
 
+    /// Wait until all reliable frames are acknowledged after flushing, used by
+    /// [`FlushReliable::flush_reliable`]
+    pub(crate) fn wait_acked(mut self) -> Self {
+        self.wait_acked = true;
+        self
+    }
+
+    pub(crate) fn should_wait_acked(&self) -> bool {
+        self.wait_acked
+    }
+
     /// Get how many ack frames have been flushed.
     ///
     /// # Panics
@@ -103,7 +1216,25 @@ impl FlushStrategy {
         self.pack_tag as usize
     }
 
-    pub(crate) fn check_flushed(&self, link: &SharedLink, buf: &VecDeque<Frame>) -> bool {
+    /// Get how many `(ack, nack, pack)` frames have been flushed by the last
+    /// [`Sink::poll_flush`] call with this strategy. Unlike the accumulated counts above, they are
+    /// reset at the start of each call, so a flushing driver could tell whether another flush
+    /// pass is worthwhile. The disabled kinds are always 0.
+    pub fn last_flushed(&self) -> (usize, usize, usize) {
+        self.last_flushed
+    }
+
+    /// Get how many packets have been retransmitted because the peer reported them lost by NACK.
+    pub fn resent_by_nack(&self) -> usize {
+        self.nack_resent
+    }
+
+    /// Get how many packets have been retransmitted because they were not acknowledged in time.
+    pub fn resent_by_timeout(&self) -> usize {
+        self.stale_resent
+    }
+
+    pub(crate) fn check_flushed(&self, link: &SharedLink, buf: &OutgoingBuf) -> bool {
         let mut ret = true;
         if self.ack_tag != -1 {
             ret &= link.outgoing_ack_empty();
@@ -129,11 +1260,16 @@ impl FlushStrategy {
         self.pack_tag != -1
     }
 
+    pub(crate) fn reset_last_flushed(&mut self) {
+        self.last_flushed = (0, 0, 0);
+    }
+
     pub(crate) fn mark_flushed_ack(&mut self, cnt: usize) {
         if self.ack_tag == -1 {
             return;
         }
         self.ack_tag += cnt as isize;
+        self.last_flushed.0 += cnt;
     }
 
     pub(crate) fn mark_flushed_nack(&mut self, cnt: usize) {
@@ -141,6 +1277,22 @@ impl FlushStrategy {
             return;
         }
         self.nack_tag += cnt as isize;
+        self.last_flushed.1 += cnt;
+    }
+
+    pub(crate) fn mark_resent(&mut self, nack: usize, stale: usize) {
+        self.nack_resent += nack;
+        self.stale_resent += stale;
+    }
+
+    /// Mark the resent packets on the strategy of the context if any, returns a copy of it or the
+    /// default strategy
+    pub(crate) fn mark_resent_in(cx: &mut Context<'_>, nack: usize, stale: usize) -> Self {
+        let Some(strategy) = cx.ext().downcast_mut::<FlushStrategy>() else {
+            return Self::default();
+        };
+        strategy.mark_resent(nack, stale);
+        *strategy
     }
 
     pub(crate) fn mark_flushed_pack(&mut self, cnt: usize) {
@@ -148,5 +1300,6 @@ impl FlushStrategy {
             return;
         }
         self.pack_tag += cnt as isize;
+        self.last_flushed.2 += cnt;
     }
 }
@@ -1,16 +1,32 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
 use bytes::{Buf, BufMut, BytesMut};
 
 use crate::errors::CodecError;
 use crate::packet::read_buf;
 use crate::utils::{u24, BufExt, BufMutExt};
 
+/// The default maximum number of packets acknowledged by a single ACK or NACK, a larger one is
+/// rejected by [`CodecError::AckCountExceed`]
+pub(crate) const DEFAULT_MAX_ACK_COUNT: usize = 8192;
+
+/// The hard upper bound of the maximum number of packets acknowledged by a single ACK or NACK, it
+/// keeps a peer from making the decoder allocate and walk arbitrarily large records
+pub(crate) const MAX_ACK_COUNT_LIMIT: usize = 65536;
+
+/// The maximum number of records kept by [`AckOrNack::merge`]
+const MAX_MERGED_RECORDS: usize = 4096;
+
+/// The records of the acknowledged (or lost) sequence numbers
 #[derive(PartialEq, Clone)]
-pub(crate) struct AckOrNack {
-    pub(crate) records: Vec<Record>,
+pub struct AckOrNack {
+    pub records: Vec<Record>,
 }
 
-impl std::fmt::Debug for AckOrNack {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for AckOrNack {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let mut records = String::new();
         for record in &self.records {
             match record {
@@ -59,7 +75,8 @@ impl AckOrNack {
             let Some(seq_num) = sorted_seq_nums.next() else {
                 break;
             };
-            if seq_num == last + 1 {
+            // the sequence numbers are sorted by value, a range never wraps around
+            if seq_num.to_u32() == last.to_u32() + 1 {
                 if upgrade_flag {
                     mtu -= 3;
                     upgrade_flag = false;
@@ -87,16 +104,23 @@ impl AckOrNack {
         Some(Self { records })
     }
 
-    pub(super) fn read(buf: &mut BytesMut) -> Result<Self, CodecError> {
-        const MAX_ACKNOWLEDGEMENT_PACKETS: usize = 8192;
+    /// Read the records acknowledging at most `max_ack_count` packets in total
+    pub(super) fn read(buf: &mut BytesMut, max_ack_count: usize) -> Result<Self, CodecError> {
+        // a single record takes at least 4 bytes
+        const MIN_RECORD_SIZE: usize = 4;
 
         let mut ack_cnt = 0;
-        let record_cnt = buf.get_u16();
-        let mut records = Vec::with_capacity(record_cnt as usize);
+        let record_cnt = read_buf!(buf, 2, buf.get_u16());
+        // record_cnt is controlled by the peer, validate it before allocating
+        if record_cnt as usize * MIN_RECORD_SIZE > buf.remaining() {
+            return Err(CodecError::InvalidPacketLength("ack records"));
+        }
+        // every record acknowledges at least one packet
+        let mut records = Vec::with_capacity((record_cnt as usize).min(max_ack_count));
         for _ in 0..record_cnt {
             let record = Record::read(buf)?;
             ack_cnt += record.ack_cnt();
-            if ack_cnt > MAX_ACKNOWLEDGEMENT_PACKETS {
+            if ack_cnt > max_ack_count {
                 return Err(CodecError::AckCountExceed);
             }
             records.push(record);
@@ -115,6 +139,41 @@ impl AckOrNack {
         }
     }
 
+    /// Merge the records of another `AckOrNack` into this one, it should only be used for
+    /// processing received acks. The records are coalesced into the sorted union of their ranges,
+    /// at most [`MAX_MERGED_RECORDS`] of them are kept and the higher sequence numbers are dropped,
+    /// so a flood of acks never grows it unbounded.
+    pub(crate) fn merge(&mut self, other: AckOrNack) {
+        let mut bounds: Vec<(u24, u24)> = self
+            .records
+            .iter()
+            .chain(&other.records)
+            .map(Record::bounds)
+            .collect();
+        bounds.sort_unstable();
+
+        let mut union: Vec<(u24, u24)> = Vec::with_capacity(bounds.len().min(MAX_MERGED_RECORDS));
+        for (start, end) in bounds {
+            let full = union.len() == MAX_MERGED_RECORDS;
+            match union.last_mut() {
+                // overlapping or adjacent
+                Some((_, last)) if start.to_u32() <= last.to_u32() + 1 => *last = end.max(*last),
+                _ if full => break,
+                _ => union.push((start, end)),
+            }
+        }
+        self.records = union
+            .into_iter()
+            .map(|(start, end)| {
+                if start == end {
+                    Record::Single(start)
+                } else {
+                    Record::Range(start, end)
+                }
+            })
+            .collect();
+    }
+
     pub(crate) fn total_cnt(&self) -> usize {
         self.records.iter().map(|record| record.ack_cnt()).sum()
     }
@@ -123,8 +182,9 @@ impl AckOrNack {
 const RECORD_RANGE: u8 = 0;
 const RECORD_SINGLE: u8 = 1;
 
-#[derive(PartialEq, Clone)]
-pub(crate) enum Record {
+/// A single sequence number or an inclusive range of them
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Record {
     Range(u24, u24),
     Single(u24),
 }
@@ -133,11 +193,15 @@ impl Record {
     fn read(buf: &mut BytesMut) -> Result<Self, CodecError> {
         let record_type = read_buf!(buf, 1, buf.get_u8());
         match record_type {
-            RECORD_RANGE => read_buf!(
-                buf,
-                6,
-                Ok(Record::Range(buf.get_u24_le(), buf.get_u24_le()))
-            ),
+            RECORD_RANGE => {
+                let (start, end) = read_buf!(buf, 6, (buf.get_u24_le(), buf.get_u24_le()));
+                // the ranges are built from the sorted sequence numbers, so a valid one is always
+                // ascending even though the sequence numbers wrap around
+                if start > end {
+                    return Err(CodecError::InvalidRecordRange(start.to_u32(), end.to_u32()));
+                }
+                Ok(Record::Range(start, end))
+            }
             RECORD_SINGLE => read_buf!(buf, 3, Ok(Record::Single(buf.get_u24_le()))),
             _ => Err(CodecError::InvalidRecordType(record_type)),
         }
@@ -157,9 +221,18 @@ impl Record {
         }
     }
 
+    /// The inclusive bounds of the record
+    fn bounds(&self) -> (u24, u24) {
+        match *self {
+            Record::Range(start, end) => (start, end),
+            Record::Single(idx) => (idx, idx),
+        }
+    }
+
     fn ack_cnt(&self) -> usize {
         match self {
-            Record::Range(start, end) => usize::from(end - start + 1),
+            // do not add on u24, the range might cover all sequence numbers
+            Record::Range(start, end) => end.to_usize() - start.to_usize() + 1,
             Record::Single(_) => 1,
         }
     }
@@ -169,6 +242,123 @@ impl Record {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_ack_record_cnt_exceed_remaining() {
+        // claims 65535 records but contains none, or 2 records but contains only one
+        for (record_cnt, singles) in [(u16::MAX, 0), (2, 1)] {
+            let mut buf = BytesMut::new();
+            buf.put_u16(record_cnt);
+            for _ in 0..singles {
+                buf.put_u8(RECORD_SINGLE);
+                buf.put_u24_le(0.into());
+            }
+            let err = AckOrNack::read(&mut buf, DEFAULT_MAX_ACK_COUNT).unwrap_err();
+            assert!(matches!(
+                err,
+                CodecError::InvalidPacketLength("ack records")
+            ));
+        }
+        assert!(AckOrNack::read(&mut BytesMut::new(), DEFAULT_MAX_ACK_COUNT).is_err());
+    }
+
+    #[test]
+    fn test_ack_max_count() {
+        // 0-9999 acknowledges 10000 packets
+        let ack = AckOrNack {
+            records: vec![Record::Range(0.into(), 9999.into())],
+        };
+        let mut buf = BytesMut::new();
+        ack.clone().write(&mut buf);
+        let err = AckOrNack::read(&mut buf.clone(), DEFAULT_MAX_ACK_COUNT).unwrap_err();
+        assert!(matches!(err, CodecError::AckCountExceed));
+        assert_eq!(AckOrNack::read(&mut buf, 10000).unwrap(), ack);
+    }
+
+    #[test]
+    fn test_ack_reference_bytes() {
+        #[rustfmt::skip]
+        let reference: &[u8] = &[
+            // record count (u16 BE)
+            0x00, 0x02,
+            // range 0x0100ff-0x010101 (u24 LE)
+            0x00, 0xff, 0x00, 0x01, 0x01, 0x01, 0x01,
+            // single 0x010002 (u24 LE)
+            0x01, 0x02, 0x00, 0x01,
+        ];
+        let ack = AckOrNack {
+            records: vec![
+                Record::Range(0x01_00ff.into(), 0x01_0101.into()),
+                Record::Single(0x01_0002.into()),
+            ],
+        };
+        let mut buf = BytesMut::new();
+        ack.clone().write(&mut buf);
+        assert_eq!(&buf[..], reference);
+        assert_eq!(
+            AckOrNack::read(&mut buf, DEFAULT_MAX_ACK_COUNT).unwrap(),
+            ack
+        );
+    }
+
+    #[test]
+    fn test_ack_merge_coalesced() {
+        let mut ack = AckOrNack {
+            records: vec![Record::Range(5.into(), 9.into()), Record::Single(20.into())],
+        };
+        ack.merge(AckOrNack {
+            records: vec![
+                Record::Single(10.into()),
+                Record::Range(1.into(), 6.into()),
+                Record::Single(20.into()),
+                Record::Single(30.into()),
+            ],
+        });
+        assert_eq!(
+            ack.records,
+            vec![
+                Record::Range(1.into(), 10.into()),
+                Record::Single(20.into()),
+                Record::Single(30.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ack_merge_bounded() {
+        // a flood of the same acks does not grow the merged one
+        let flood = AckOrNack {
+            records: (0..1000).map(|n| Record::Single((n * 2).into())).collect(),
+        };
+        let mut ack = flood.clone();
+        for _ in 0..100 {
+            ack.merge(flood.clone());
+        }
+        assert_eq!(ack, flood);
+
+        // nor a flood of distinct ones, the higher sequence numbers are dropped
+        for round in 1..100 {
+            ack.merge(AckOrNack {
+                records: (0..1000)
+                    .map(|n| Record::Single((round * 2000 + n * 2).into()))
+                    .collect(),
+            });
+            assert!(ack.records.len() <= MAX_MERGED_RECORDS);
+        }
+        assert_eq!(ack.records.len(), MAX_MERGED_RECORDS);
+        assert_eq!(ack.records[0], Record::Single(0.into()));
+    }
+
+    #[test]
+    fn test_ack_reversed_range() {
+        let mut buf = BytesMut::new();
+        buf.put_u16(1);
+        buf.put_u8(RECORD_RANGE);
+        buf.put_u24_le(5.into());
+        buf.put_u24_le(2.into());
+        let err = AckOrNack::read(&mut buf, DEFAULT_MAX_ACK_COUNT).unwrap_err();
+        assert!(matches!(err, CodecError::InvalidRecordRange(5, 2)));
+    }
+
     #[test]
     fn test_ack_should_not_overflow_mtu() {
         let mtu: u16 = 21;
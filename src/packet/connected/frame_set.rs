@@ -1,33 +1,51 @@
-use std::net::SocketAddr;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+#[cfg(feature = "std")]
+use std::time::Instant;
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use crate::errors::CodecError;
 use crate::packet::{
-    read_buf, PackType, SocketAddrRead, SocketAddrWrite, FRAGMENT_PART_SIZE, NEEDS_B_AND_AS_FLAG,
-    PARTED_FLAG,
+    read_buf, PackType, SocketAddrRead, SocketAddrWrite, FRAGMENT_PART_SIZE,
+    FRAME_SET_CHECKSUM_SIZE, NEEDS_B_AND_AS_FLAG, PARTED_FLAG,
 };
-use crate::utils::{u24, BufExt, BufMutExt};
-use crate::Reliability;
+use crate::utils::{crc32, u24, BufExt, BufMutExt};
+use crate::{Priority, Reliability};
 
-pub(crate) type Frames<B = Bytes> = Vec<Frame<B>>;
+pub type Frames<B = Bytes> = Vec<Frame<B>>;
 
-// Cheap slice of a frames vector to reduce heap allocation
-pub(crate) type FramesRef<'a, B = Bytes> = &'a [Frame<B>];
+/// Cheap slice of a frames vector to reduce heap allocation
+pub type FramesRef<'a, B = Bytes> = &'a [Frame<B>];
 
-pub(crate) type FramesMut = Frames<BytesMut>;
+pub type FramesMut = Frames<BytesMut>;
 
 pub(crate) type FrameMut = Frame<BytesMut>;
 
+/// A frame set carrying the frames, it is acknowledged by its sequence number
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) struct FrameSet<S> {
-    pub(crate) seq_num: u24,
-    pub(crate) set: S,
+pub struct FrameSet<S> {
+    pub seq_num: u24,
+    pub set: S,
 }
 
 impl FrameSet<FramesMut> {
-    pub(super) fn read(buf: &mut BytesMut) -> Result<Self, CodecError> {
-        let seq_num = read_buf!(buf, 3, buf.get_u24_le());
+    /// Read the frame set, the trailing CRC-32 is validated and stripped if `checksum` is
+    /// enabled. The frame set failing the checksum is dropped as it is lost, so that it will be
+    /// reported by NACK and resent.
+    pub(super) fn read(buf: &mut BytesMut, checksum: bool) -> Result<Self, CodecError> {
+        if checksum {
+            let len = read_buf!(buf, FRAME_SET_CHECKSUM_SIZE, buf.len());
+            let expected = (&buf[len - FRAME_SET_CHECKSUM_SIZE..]).get_u32_le();
+            buf.truncate(len - FRAME_SET_CHECKSUM_SIZE);
+            let actual = crc32(buf);
+            if actual != expected {
+                return Err(CodecError::ChecksumMismatch(expected, actual));
+            }
+        }
+        let seq_num = read_buf!(buf, 3, "frame set header", buf.get_u24_le());
         let mut frames = vec![];
         while buf.has_remaining() {
             frames.push(Frame::read(buf)?);
@@ -43,26 +61,67 @@ impl FrameSet<FramesMut> {
 }
 
 impl<'a, B: Buf + Clone> FrameSet<FramesRef<'a, B>> {
-    pub(super) fn write(self, buf: &mut BytesMut) {
+    pub(super) fn write(self, buf: &mut BytesMut, checksum: bool) {
+        let start = buf.len();
         buf.put_u24_le(self.seq_num);
         for frame in self.set {
             frame.write_ref(buf);
         }
+        if checksum {
+            let crc = crc32(&buf[start..]);
+            buf.put_u32_le(crc);
+        }
     }
 }
 
+/// A frame carrying a message or a fragment of it
 #[derive(PartialEq, Clone)]
-pub(crate) struct Frame<B = Bytes> {
-    pub(crate) flags: Flags,
-    pub(crate) reliable_frame_index: Option<u24>,
-    pub(crate) seq_frame_index: Option<u24>,
-    pub(crate) ordered: Option<Ordered>,
+pub struct Frame<B = Bytes> {
+    pub flags: Flags,
+    pub reliable_frame_index: Option<u24>,
+    pub seq_frame_index: Option<u24>,
+    pub ordered: Option<Ordered>,
+    pub fragment: Option<Fragment>,
+    /// The local sending priority, it is not transferred over the wire
+    pub(crate) priority: Priority,
+    /// Whether the frame supersedes the older coalescing frames of its channel in the send
+    /// buffer, it is not transferred over the wire
+    pub(crate) coalesce: bool,
+    /// The local receive information, it is not transferred over the wire
+    #[cfg(feature = "std")]
+    pub(crate) received: Option<Received>,
+    /// The frame is expired if it is still waiting in the send buffer after it, it is not
+    /// transferred over the wire
+    #[cfg(feature = "std")]
+    pub(crate) deadline: Option<Instant>,
+    /// The reliable frame is abandoned instead of resent if it is not acknowledged after it, it
+    /// is not transferred over the wire
+    #[cfg(feature = "std")]
+    pub(crate) abandon_at: Option<Instant>,
+    /// The receipt number reported once the frame is acknowledged, only a frame with an ack
+    /// receipt reliability carries it. It is not transferred over the wire
+    #[cfg(feature = "std")]
+    pub(crate) receipt: Option<u32>,
+    /// The number of times the frame has been queued to be resent, it is not transferred over the
+    /// wire
+    #[cfg(feature = "std")]
+    pub(crate) retransmits: u32,
+    pub body: B,
+}
+
+/// The local receive information of a frame
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Received {
+    /// When the frame set carrying the frame was received, it is the last received part if the
+    /// frame is reassembled
+    pub(crate) at: Instant,
+    /// The fragment the frame is reassembled from
     pub(crate) fragment: Option<Fragment>,
-    pub(crate) body: B,
 }
 
-impl<B: Buf> std::fmt::Debug for Frame<B> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<B: Buf> core::fmt::Debug for Frame<B> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         // better for debug printing
         fn to_hex_string(bytes: &[u8]) -> String {
             let mut s = String::with_capacity(bytes.len() * 2 + 2);
@@ -103,9 +162,9 @@ impl FrameMut {
         self
     }
 
-    fn read(buf: &mut BytesMut) -> Result<Self, CodecError> {
-        let (flags, length) = read_buf!(buf, 3, {
-            let flags = Flags::read(buf);
+    pub(crate) fn read(buf: &mut BytesMut) -> Result<Self, CodecError> {
+        let (flags, length) = read_buf!(buf, 3, "frame header", {
+            let flags = Flags::read(buf)?;
             // length in bytes
             let length = buf.get_u16() >> 3;
             if length == 0 {
@@ -121,29 +180,57 @@ impl FrameMut {
         let mut fragment = None;
 
         if reliability.is_reliable() {
-            reliable_frame_index = read_buf!(buf, 3, Some(buf.get_u24_le()));
+            reliable_frame_index =
+                read_buf!(buf, 3, "reliable frame index", Some(buf.get_u24_le()));
         }
         if reliability.is_sequenced() {
-            seq_frame_index = read_buf!(buf, 3, Some(buf.get_u24_le()));
+            seq_frame_index = read_buf!(buf, 3, "sequenced frame index", Some(buf.get_u24_le()));
         }
         if reliability.is_sequenced_or_ordered() {
-            ordered = read_buf!(buf, 4, Some(Ordered::read(buf)));
+            ordered = read_buf!(buf, 4, "ordered frame index", Some(Ordered::read(buf)));
         }
         if flags.parted {
-            fragment = read_buf!(buf, 10, Some(Fragment::read(buf)));
+            fragment = Some(Fragment::read(buf)?);
         }
-        let body = read_buf!(buf, length, buf.split_to(length));
+        // zero copy, the body shares the allocation of the datagram
+        let body = read_buf!(buf, length, "frame body", buf.split_to(length));
         Ok(Frame {
-            flags,
             reliable_frame_index,
             seq_frame_index,
             ordered,
             fragment,
-            body,
+            ..Frame::new(flags, body)
         })
     }
 }
 
+impl<B> Frame<B> {
+    /// Create a frame without the indices, they should be set as the reliability requires before
+    /// encoding
+    pub fn new(flags: Flags, body: B) -> Self {
+        Self {
+            flags,
+            reliable_frame_index: None,
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            priority: Priority::Normal,
+            coalesce: false,
+            #[cfg(feature = "std")]
+            received: None,
+            #[cfg(feature = "std")]
+            deadline: None,
+            #[cfg(feature = "std")]
+            abandon_at: None,
+            #[cfg(feature = "std")]
+            receipt: None,
+            #[cfg(feature = "std")]
+            retransmits: 0,
+            body,
+        }
+    }
+}
+
 impl<B: Buf> Frame<B> {
     /// Get the total size of this frame
     pub(crate) fn size(&self) -> usize {
@@ -157,7 +244,7 @@ impl<B: Buf> Frame<B> {
 }
 
 impl<B: Buf + Clone> Frame<B> {
-    fn write_ref(&self, buf: &mut BytesMut) {
+    pub(crate) fn write_ref(&self, buf: &mut BytesMut) {
         self.flags.write(buf);
         // length in bits
         // self.body will be split up so cast to u16 should not overflow here
@@ -184,8 +271,8 @@ impl<B: Buf + Clone> Frame<B> {
 
 /// Top 3 bits are reliability type, fourth bit is 1 when the frame is fragmented and part of a
 /// compound.
-#[derive(Debug, Clone, PartialEq)]
-pub(crate) struct Flags {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Flags {
     raw: u8,
     pub(crate) reliability: Reliability,
     pub(crate) parted: bool,
@@ -193,9 +280,9 @@ pub(crate) struct Flags {
 }
 
 impl Flags {
-    pub(crate) fn new(reliability: Reliability, parted: bool) -> Self {
+    pub fn new(reliability: Reliability, parted: bool) -> Self {
+        // the reference RakNet only writes the reliability and the parted bit
         let mut raw = (reliability as u8) << 5;
-        raw |= NEEDS_B_AND_AS_FLAG;
         if parted {
             raw |= PARTED_FLAG;
         }
@@ -203,11 +290,11 @@ impl Flags {
             raw,
             reliability,
             parted,
-            needs_bas: true,
+            needs_bas: false,
         }
     }
 
-    fn read(buf: &mut BytesMut) -> Self {
+    fn read(buf: &mut BytesMut) -> Result<Self, CodecError> {
         let raw = buf.get_u8();
         Self::parse(raw)
     }
@@ -216,33 +303,47 @@ impl Flags {
         buf.put_u8(self.raw);
     }
 
-    pub(crate) fn parse(raw: u8) -> Self {
-        let r = raw >> 5;
-        // Safety:
-        // It is checked before transmute
-        Self {
+    pub(crate) fn parse(raw: u8) -> Result<Self, CodecError> {
+        Ok(Self {
             raw,
-            reliability: unsafe { std::mem::transmute::<u8, Reliability>(r) },
+            reliability: Reliability::try_from(raw >> 5)?,
             parted: raw & PARTED_FLAG != 0,
             needs_bas: raw & NEEDS_B_AND_AS_FLAG != 0,
-        }
+        })
+    }
+
+    /// Get the reliability of the frame
+    pub fn reliability(&self) -> Reliability {
+        self.reliability
+    }
+
+    /// Whether the frame is a fragment of a larger one
+    pub fn parted(&self) -> bool {
+        self.parted
     }
 }
 
+/// The fragment part of a parted frame
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub(crate) struct Fragment {
-    pub(crate) parted_size: u32,
-    pub(crate) parted_id: u16,
-    pub(crate) parted_index: u32,
+pub struct Fragment {
+    pub parted_size: u32,
+    pub parted_id: u16,
+    pub parted_index: u32,
 }
 
 impl Fragment {
-    fn read(buf: &mut BytesMut) -> Self {
-        Self {
-            parted_size: buf.get_u32(),
-            parted_id: buf.get_u16(),
-            parted_index: buf.get_u32(),
-        }
+    fn read(buf: &mut BytesMut) -> Result<Self, CodecError> {
+        // the parted flag is given by the peer, do not trust it
+        Ok(read_buf!(
+            buf,
+            FRAGMENT_PART_SIZE,
+            "fragment",
+            Self {
+                parted_size: buf.get_u32(),
+                parted_id: buf.get_u16(),
+                parted_index: buf.get_u32(),
+            }
+        ))
     }
 
     fn write(self, buf: &mut BytesMut) {
@@ -252,10 +353,11 @@ impl Fragment {
     }
 }
 
+/// The ordering part of an ordered or sequenced frame
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub(crate) struct Ordered {
-    pub(crate) frame_index: u24,
-    pub(crate) channel: u8,
+pub struct Ordered {
+    pub frame_index: u24,
+    pub channel: u8,
 }
 
 impl Ordered {
@@ -272,8 +374,13 @@ impl Ordered {
     }
 }
 
-// The max number of addresses from a peer, constant here to avoid alloc heap memory
-const MAX_SYSTEM_ADDRESSES_ENDPOINTS: usize = 20;
+// The max number of addresses from a peer, constant here to avoid alloc heap memory. The reference
+// RakNet sends 10 addresses while the newer protocols send 20, both of them are accepted.
+pub(crate) const MAX_SYSTEM_ADDRESSES_ENDPOINTS: usize = 20;
+
+/// The dummy address padding the unused entries of the system addresses
+pub(crate) const DUMMY_SYSTEM_ADDRESS: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::BROADCAST, 0));
 
 #[derive(Clone)]
 pub(crate) enum FrameBody {
@@ -290,26 +397,29 @@ pub(crate) enum FrameBody {
         use_encryption: bool,
     },
     ConnectionRequestAccepted {
-        client_address: std::net::SocketAddr,
+        client_address: SocketAddr,
         system_index: u16,
-        system_addresses: [std::net::SocketAddr; MAX_SYSTEM_ADDRESSES_ENDPOINTS],
+        system_addresses: [SocketAddr; MAX_SYSTEM_ADDRESSES_ENDPOINTS],
         request_timestamp: i64,
         accepted_timestamp: i64,
     },
     NewIncomingConnection {
-        server_address: std::net::SocketAddr,
-        system_addresses: [std::net::SocketAddr; MAX_SYSTEM_ADDRESSES_ENDPOINTS],
+        server_address: SocketAddr,
+        system_addresses: [SocketAddr; MAX_SYSTEM_ADDRESSES_ENDPOINTS],
         request_timestamp: i64,
         accepted_timestamp: i64,
     },
     DisconnectNotification,
     DetectLostConnections,
+    /// Stands in for an abandoned reliable frame, so that the ordering of the receiver does not
+    /// wait for it
+    Abandoned,
     // User Packet
     User(Bytes),
 }
 
-impl std::fmt::Debug for FrameBody {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for FrameBody {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::ConnectedPing { .. } => write!(f, "ConnectedPing"),
             Self::ConnectedPong { .. } => write!(f, "ConnectedPong"),
@@ -318,6 +428,7 @@ impl std::fmt::Debug for FrameBody {
             Self::NewIncomingConnection { .. } => write!(f, "NewIncomingConnection"),
             Self::DisconnectNotification => write!(f, "Disconnect"),
             Self::DetectLostConnections => write!(f, "DetectLostConnections"),
+            Self::Abandoned => write!(f, "Abandoned"),
             Self::User(data) => write!(f, "User(size:{})", data.len()),
         }
     }
@@ -326,7 +437,8 @@ impl std::fmt::Debug for FrameBody {
 impl FrameBody {
     pub(crate) fn read(mut buf: Bytes) -> Result<Self, CodecError> {
         fn parse_system_addresses(buf: &mut Bytes) -> Result<[SocketAddr; 20], CodecError> {
-            let mut addresses = [buf.get_socket_addr()?; MAX_SYSTEM_ADDRESSES_ENDPOINTS];
+            let mut addresses = [DUMMY_SYSTEM_ADDRESS; MAX_SYSTEM_ADDRESSES_ENDPOINTS];
+            addresses[0] = buf.get_socket_addr()?;
             #[allow(clippy::needless_range_loop)] // do not tech me
             for i in 1..MAX_SYSTEM_ADDRESSES_ENDPOINTS {
                 if buf.remaining() > 16 {
@@ -339,12 +451,15 @@ impl FrameBody {
             if buf.remaining() < 16 {
                 return Err(CodecError::InvalidPacketLength("frame body"));
             }
-            debug_assert!(buf.remaining() == 16);
+            // the trailing bytes after 20 addresses are ignored
             Ok(addresses)
         }
 
-        // checked in FrameSet, length is always greater than 0
-        let Ok(id) = PackType::from_u8(buf.chunk()[0]) else {
+        // the length is checked in FrameSet, but the body might be emptied by the decryption
+        let Some(&first) = buf.first() else {
+            return Err(CodecError::InvalidPacketLength("frame body"));
+        };
+        let Ok(id) = PackType::from_u8(first) else {
             // if we cannot recognize the packet type, it should be a user packet
             return Ok(Self::User(buf));
         };
@@ -392,6 +507,8 @@ impl FrameBody {
             }),
             PackType::DisconnectNotification => Ok(Self::DisconnectNotification),
             PackType::DetectLostConnections => Ok(Self::DetectLostConnections),
+            // only the exact marker, the user packets starting with it are kept
+            PackType::SndReceiptLoss if buf.len() == 1 => Ok(Self::Abandoned),
             // TODO: more raknet features
             _ => Ok(Self::User(buf)), /* we rely on the user to handle this even it is not a user
                                        * packet */
@@ -458,9 +575,328 @@ impl FrameBody {
             FrameBody::DetectLostConnections => {
                 buf.put_u8(PackType::DetectLostConnections as u8);
             }
+            FrameBody::Abandoned => {
+                buf.put_u8(PackType::SndReceiptLoss as u8);
+            }
             FrameBody::User(data) => {
                 buf.put(data);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use bytes::{BufMut, Bytes, BytesMut};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::{
+        Flags, Fragment, Frame, FrameBody, FrameMut, FrameSet, Ordered, DUMMY_SYSTEM_ADDRESS,
+    };
+    use crate::errors::CodecError;
+    use crate::packet::{PackType, SocketAddrWrite};
+    use crate::utils::BufMutExt;
+    use crate::{Priority, Reliability};
+
+    #[test]
+    fn test_reliability_conversion() {
+        for r in 0..8 {
+            let reliability = Reliability::try_from(r).unwrap();
+            assert_eq!(reliability as u8, r);
+            let flags = Flags::parse(Flags::new(reliability, false).raw).unwrap();
+            assert_eq!(flags.reliability, reliability);
+        }
+        assert!(matches!(
+            Reliability::try_from(8),
+            Err(CodecError::InvalidReliability(8))
+        ));
+    }
+
+    #[test]
+    fn test_frame_reliability_round_trip() {
+        for r in 0..8 {
+            let reliability = Reliability::try_from(r).unwrap();
+            let mut frame = Frame::new(
+                Flags::new(reliability, false),
+                Bytes::from_static(b"\xfehello"),
+            );
+            if reliability.is_reliable() {
+                frame.reliable_frame_index = Some(1.into());
+            }
+            if reliability.is_sequenced() {
+                frame.seq_frame_index = Some(2.into());
+            }
+            if reliability.is_sequenced_or_ordered() {
+                frame.ordered = Some(Ordered {
+                    frame_index: 3.into(),
+                    channel: 1,
+                });
+            }
+            let mut buf = BytesMut::new();
+            frame.write_ref(&mut buf);
+            assert_eq!(buf.len(), frame.size(), "{reliability:?}");
+
+            let read = FrameMut::read(&mut buf).unwrap();
+            assert_eq!(read.flags.reliability, reliability);
+            assert_eq!(read.reliable_frame_index, frame.reliable_frame_index);
+            assert_eq!(read.seq_frame_index, frame.seq_frame_index);
+            assert_eq!(read.ordered, frame.ordered);
+            assert_eq!(&read.body[..], b"\xfehello");
+        }
+    }
+
+    #[test]
+    fn test_frame_set_checksum() {
+        let frame = Frame {
+            flags: Flags::new(Reliability::Reliable, false),
+            reliable_frame_index: Some(1.into()),
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            priority: Priority::Normal,
+            coalesce: false,
+            received: None,
+            deadline: None,
+            abandon_at: None,
+            receipt: None,
+            retransmits: 0,
+            body: Bytes::from_static(b"\xfehello"),
+        };
+        let mut buf = BytesMut::new();
+        FrameSet {
+            seq_num: 7.into(),
+            set: &[frame.clone()][..],
+        }
+        .write(&mut buf, true);
+
+        let frame_set = FrameSet::read(&mut buf.clone(), true).unwrap();
+        assert_eq!(frame_set.seq_num, 7.into());
+        assert_eq!(frame_set.set.len(), 1);
+        assert_eq!(&frame_set.set[0].body[..], &frame.body[..]);
+
+        // flip a bit of the body
+        let pos = buf.len() - 8;
+        buf[pos] ^= 0b0000_0100;
+        assert!(matches!(
+            FrameSet::read(&mut buf, true),
+            Err(CodecError::ChecksumMismatch(..))
+        ));
+    }
+
+    #[test]
+    fn test_frame_set_truncated() {
+        let frame = Frame {
+            flags: Flags::new(Reliability::ReliableSequenced, true),
+            reliable_frame_index: Some(1.into()),
+            seq_frame_index: Some(2.into()),
+            ordered: Some(Ordered {
+                frame_index: 3.into(),
+                channel: 0,
+            }),
+            fragment: Some(Fragment {
+                parted_size: 2,
+                parted_id: 4,
+                parted_index: 0,
+            }),
+            priority: Priority::Normal,
+            coalesce: false,
+            received: None,
+            deadline: None,
+            abandon_at: None,
+            receipt: None,
+            retransmits: 0,
+            body: Bytes::from_static(b"\xfehello"),
+        };
+        for checksum in [false, true] {
+            let mut buf = BytesMut::new();
+            FrameSet {
+                seq_num: 7.into(),
+                set: &[frame.clone()][..],
+            }
+            .write(&mut buf, checksum);
+            assert!(FrameSet::read(&mut buf.clone(), checksum).is_ok());
+            // truncated at each read offset
+            for len in 0..buf.len() {
+                let mut truncated = BytesMut::from(&buf[..len]);
+                let err = FrameSet::read(&mut truncated, checksum).unwrap_err();
+                assert!(
+                    matches!(
+                        err,
+                        CodecError::InvalidPacketLength(_) | CodecError::ChecksumMismatch(..)
+                    ),
+                    "unexpected {err:?} at {len}"
+                );
+            }
+        }
+
+        // the length field exceeds the remaining buffer
+        let mut buf = BytesMut::new();
+        buf.put_u24_le(0.into());
+        buf.put_u8(Flags::new(Reliability::Unreliable, false).raw);
+        buf.put_u16(u16::MAX);
+        buf.put_u8(0xfe);
+        assert!(matches!(
+            FrameSet::read(&mut buf, false),
+            Err(CodecError::InvalidPacketLength("frame body"))
+        ));
+    }
+
+    #[test]
+    fn test_frame_set_read_fuzz() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let frame = Frame {
+            flags: Flags::new(Reliability::ReliableOrdered, true),
+            reliable_frame_index: Some(1.into()),
+            seq_frame_index: None,
+            ordered: Some(Ordered {
+                frame_index: 3.into(),
+                channel: 0,
+            }),
+            fragment: Some(Fragment {
+                parted_size: 2,
+                parted_id: 4,
+                parted_index: 1,
+            }),
+            priority: Priority::Normal,
+            coalesce: false,
+            received: None,
+            deadline: None,
+            abandon_at: None,
+            receipt: None,
+            retransmits: 0,
+            body: Bytes::from_static(b"\xfehello"),
+        };
+        let mut valid = BytesMut::new();
+        FrameSet {
+            seq_num: 7.into(),
+            set: &[frame.clone(), frame][..],
+        }
+        .write(&mut valid, false);
+
+        for _ in 0..10000 {
+            // random bytes
+            let mut random = BytesMut::zeroed(rng.gen_range(0..64));
+            rng.fill(&mut random[..]);
+            let _ = FrameSet::read(&mut random, rng.gen_bool(0.5));
+
+            // mutated valid frame sets
+            let mut mutated = valid.clone();
+            for _ in 0..rng.gen_range(1..4) {
+                let pos = rng.gen_range(0..mutated.len());
+                mutated[pos] = rng.gen_range(0..=u8::MAX);
+            }
+            mutated.truncate(rng.gen_range(0..=mutated.len()));
+            let _ = FrameSet::read(&mut mutated, false);
+        }
+    }
+
+    #[test]
+    fn test_frame_set_reference_bytes() {
+        #[rustfmt::skip]
+        let reference: &[u8] = &[
+            // sequence number 0x010203 (u24 LE)
+            0x03, 0x02, 0x01,
+            // reliable ordered and parted, length 3 bytes in bits (u16 BE)
+            0x70, 0x00, 0x18,
+            // reliable frame index 0x0a0b0c (u24 LE)
+            0x0c, 0x0b, 0x0a,
+            // ordering frame index 5 (u24 LE), channel 2
+            0x05, 0x00, 0x00, 0x02,
+            // parted size 2 (u32 BE), parted id 0x1234 (u16 BE), parted index 1 (u32 BE)
+            0x00, 0x00, 0x00, 0x02, 0x12, 0x34, 0x00, 0x00, 0x00, 0x01,
+            // body
+            0xfe, 0x01, 0x02,
+            // unreliable sequenced, length 1 byte in bits
+            0x20, 0x00, 0x08,
+            // sequenced frame index 0x000100 (u24 LE)
+            0x00, 0x01, 0x00,
+            // ordering frame index 0x020000 (u24 LE), channel 0
+            0x00, 0x00, 0x02, 0x00,
+            // body
+            0xfe,
+        ];
+        let frames = [
+            Frame {
+                reliable_frame_index: Some(0x0a0b0c.into()),
+                ordered: Some(Ordered {
+                    frame_index: 5.into(),
+                    channel: 2,
+                }),
+                fragment: Some(Fragment {
+                    parted_size: 2,
+                    parted_id: 0x1234,
+                    parted_index: 1,
+                }),
+                ..Frame::new(
+                    Flags::new(Reliability::ReliableOrdered, true),
+                    Bytes::from_static(b"\xfe\x01\x02"),
+                )
+            },
+            Frame {
+                seq_frame_index: Some(0x100.into()),
+                ordered: Some(Ordered {
+                    frame_index: 0x2_0000.into(),
+                    channel: 0,
+                }),
+                ..Frame::new(
+                    Flags::new(Reliability::UnreliableSequenced, false),
+                    Bytes::from_static(b"\xfe"),
+                )
+            },
+        ];
+        let mut buf = BytesMut::new();
+        FrameSet {
+            seq_num: 0x010203.into(),
+            set: &frames[..],
+        }
+        .write(&mut buf, false);
+        assert_eq!(&buf[..], reference);
+
+        let frame_set = FrameSet::read(&mut buf, false).unwrap();
+        assert_eq!(frame_set.seq_num, 0x010203.into());
+        let decoded: Vec<_> = frame_set
+            .set
+            .into_iter()
+            .map(|frame| frame.freeze())
+            .collect();
+        assert_eq!(decoded, frames);
+    }
+
+    #[test]
+    fn test_connection_request_accepted_ten_addresses() {
+        // sent by the reference RakNet with 10 system addresses
+        let client: SocketAddr = "192.168.1.2:19132".parse().unwrap();
+        let system: SocketAddr = "10.0.0.1:19132".parse().unwrap();
+        let mut buf = BytesMut::new();
+        buf.put_u8(PackType::ConnectionRequestAccepted as u8);
+        buf.put_socket_addr(client);
+        buf.put_u16(0);
+        buf.put_socket_addr(system);
+        for _ in 1..10 {
+            buf.put_socket_addr(DUMMY_SYSTEM_ADDRESS);
+        }
+        buf.put_i64(114);
+        buf.put_i64(514);
+
+        let FrameBody::ConnectionRequestAccepted {
+            client_address,
+            system_index,
+            system_addresses,
+            request_timestamp,
+            accepted_timestamp,
+        } = FrameBody::read(buf.freeze()).unwrap()
+        else {
+            panic!("expect ConnectionRequestAccepted");
+        };
+        assert_eq!(client_address, client);
+        assert_eq!(system_index, 0);
+        assert_eq!(system_addresses[0], system);
+        assert!(system_addresses[1..]
+            .iter()
+            .all(|&addr| addr == DUMMY_SYSTEM_ADDRESS));
+        assert_eq!((request_timestamp, accepted_timestamp), (114, 514));
+    }
+}
@@ -6,14 +6,14 @@ use crate::packet::PackType;
 mod ack;
 mod frame_set;
 
-pub(crate) use ack::*;
-pub(crate) use frame_set::*;
+pub use ack::*;
+pub use frame_set::*;
 
 use super::{ACK_FLAG, CONTINUOUS_SEND_FLAG, NACK_FLAG, NEEDS_B_AND_AS_FLAG, VALID_FLAG};
 
-// Packet when RakNet has established a connection
+/// Packet when `RakNet` has established a connection
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) enum Packet<S> {
+pub enum Packet<S> {
     FrameSet(FrameSet<S>),
     Ack(AckOrNack),
     Nack(AckOrNack),
@@ -28,18 +28,18 @@ impl<S> Packet<S> {
         }
     }
 
-    pub(super) fn read_ack(buf: &mut BytesMut) -> Result<Self, CodecError> {
-        Ok(Packet::Ack(AckOrNack::read(buf)?))
+    pub(crate) fn read_ack(buf: &mut BytesMut, max_ack_count: usize) -> Result<Self, CodecError> {
+        Ok(Packet::Ack(AckOrNack::read(buf, max_ack_count)?))
     }
 
-    pub(super) fn read_nack(buf: &mut BytesMut) -> Result<Self, CodecError> {
-        Ok(Packet::Nack(AckOrNack::read(buf)?))
+    pub(crate) fn read_nack(buf: &mut BytesMut, max_ack_count: usize) -> Result<Self, CodecError> {
+        Ok(Packet::Nack(AckOrNack::read(buf, max_ack_count)?))
     }
 }
 
 /// For cheap buffers cloning (i.e. `bytes::Bytes`)
 impl<'a, B: Buf + Clone> Packet<FramesRef<'a, B>> {
-    pub(crate) fn write(self, buf: &mut BytesMut) {
+    pub(crate) fn write(self, buf: &mut BytesMut, checksum: bool) {
         match self {
             Packet::FrameSet(frame) => {
                 let mut flag = VALID_FLAG | NEEDS_B_AND_AS_FLAG;
@@ -47,7 +47,7 @@ impl<'a, B: Buf + Clone> Packet<FramesRef<'a, B>> {
                     flag |= CONTINUOUS_SEND_FLAG;
                 }
                 buf.put_u8(flag);
-                frame.write(buf);
+                frame.write(buf, checksum);
             }
             Packet::Ack(ack) => {
                 buf.put_u8(ACK_FLAG);
@@ -62,7 +62,317 @@ impl<'a, B: Buf + Clone> Packet<FramesRef<'a, B>> {
 }
 
 impl Packet<FramesMut> {
-    pub(super) fn read_frame_set(buf: &mut BytesMut) -> Result<Self, CodecError> {
-        Ok(Packet::FrameSet(FrameSet::read(buf)?))
+    pub(crate) fn read_frame_set(buf: &mut BytesMut, checksum: bool) -> Result<Self, CodecError> {
+        Ok(Packet::FrameSet(FrameSet::read(buf, checksum)?))
+    }
+}
+
+/// Parse the bytes by all the connected packet parsers, including the bodies of the decoded frame
+/// sets. It never panics on any input, the invalid ones are rejected by `CodecError`.
+#[cfg(any(test, feature = "fuzzing"))]
+pub(crate) fn parse_all(data: &[u8]) {
+    for checksum in [false, true] {
+        if let Ok(Packet::FrameSet(frame_set)) =
+            Packet::read_frame_set(&mut BytesMut::from(data), checksum)
+        {
+            for frame in frame_set.set {
+                let _ = FrameBody::read(frame.body.freeze());
+            }
+        }
+    }
+    let _ = Packet::<FramesMut>::read_ack(&mut BytesMut::from(data), DEFAULT_MAX_ACK_COUNT);
+    let _ = Packet::<FramesMut>::read_nack(&mut BytesMut::from(data), DEFAULT_MAX_ACK_COUNT);
+}
+
+/// Micro bench helper
+#[cfg(feature = "micro-bench")]
+pub mod micro_bench {
+    use bytes::{Bytes, BytesMut};
+
+    use super::{AckOrNack, Flags, Fragment, Frame, FrameSet, Frames, FramesMut, Packet};
+    use crate::packet::FRAME_SET_HEADER_SIZE;
+    use crate::utils::u24;
+    use crate::Reliability;
+
+    /// The size of the body of a tiny frame
+    const TINY_BODY_SIZE: usize = 16;
+
+    /// The representative mixes of the frames
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Mix {
+        /// Tiny unreliable frames, e.g. the movements in a game
+        TinyUnreliable,
+        /// The parts of large fragmented reliable ordered messages, each fills a frame set
+        FragmentedReliable,
+        /// Tiny unreliable frames interleaved with the fragmented reliable ones
+        Mixed,
+    }
+
+    impl Mix {
+        /// Generate `cnt` frames of the mix, each one fits in a frame set of `mtu`
+        #[allow(clippy::missing_panics_doc)]
+        pub fn frames(self, cnt: usize, mtu: usize) -> Frames {
+            let tiny = || {
+                Frame::new(
+                    Flags::new(Reliability::Unreliable, false),
+                    Bytes::from_static(&[0xfe; TINY_BODY_SIZE]),
+                )
+            };
+            let mut frame_index = 0_u32;
+            let mut fragmented = || {
+                let mut frame =
+                    Frame::new(Flags::new(Reliability::ReliableOrdered, true), Bytes::new());
+                frame.reliable_frame_index = Some(frame_index.into());
+                frame.ordered = Some(super::Ordered {
+                    frame_index: (frame_index / 4).into(),
+                    channel: 0,
+                });
+                frame.fragment = Some(Fragment {
+                    parted_size: 4,
+                    parted_id: (frame_index / 4) as u16,
+                    parted_index: frame_index % 4,
+                });
+                frame.body = Bytes::from(vec![0xfe; mtu - FRAME_SET_HEADER_SIZE - frame.size()]);
+                frame_index += 1;
+                frame
+            };
+            (0..cnt)
+                .map(|i| match self {
+                    Mix::TinyUnreliable => tiny(),
+                    Mix::FragmentedReliable => fragmented(),
+                    Mix::Mixed if i % 2 == 0 => tiny(),
+                    Mix::Mixed => fragmented(),
+                })
+                .collect()
+        }
+    }
+
+    /// The frame sets packed from a mix of frames and the datagrams encoded from them
+    #[derive(Debug, Clone)]
+    pub struct PacketOpts {
+        sets: Vec<Frames>,
+        datagrams: Vec<BytesMut>,
+        checksum: bool,
+    }
+
+    impl PacketOpts {
+        /// Pack `cnt` frames of the mix into the frame sets of `mtu`
+        pub fn new(mix: Mix, cnt: usize, mtu: usize, checksum: bool) -> Self {
+            let mut sets: Vec<Frames> = vec![];
+            let mut remain = 0;
+            for frame in mix.frames(cnt, mtu) {
+                let size = frame.size();
+                match sets.last_mut() {
+                    Some(set) if remain >= size => {
+                        remain -= size;
+                        set.push(frame);
+                    }
+                    _ => {
+                        remain = (mtu - FRAME_SET_HEADER_SIZE).saturating_sub(size);
+                        sets.push(vec![frame]);
+                    }
+                }
+            }
+            let mut opts = Self {
+                sets,
+                datagrams: vec![],
+                checksum,
+            };
+            opts.datagrams = (0..opts.sets.len())
+                .map(|i| {
+                    let mut buf = BytesMut::new();
+                    opts.encode_one(i, &mut buf);
+                    buf
+                })
+                .collect();
+            opts
+        }
+
+        fn encode_one(&self, i: usize, buf: &mut BytesMut) {
+            Packet::FrameSet(FrameSet {
+                seq_num: i.into(),
+                set: &self.sets[i][..],
+            })
+            .write(buf, self.checksum);
+        }
+
+        /// Encode all the frame sets into `buf` one by one, returns the total encoded bytes
+        pub fn encode(&self, buf: &mut BytesMut) -> usize {
+            let mut total = 0;
+            for i in 0..self.sets.len() {
+                buf.clear();
+                self.encode_one(i, buf);
+                total += buf.len();
+            }
+            total
+        }
+
+        /// The encoded datagrams to be decoded
+        pub fn datagrams(&self) -> Vec<BytesMut> {
+            self.datagrams.clone()
+        }
+
+        /// Decode the datagrams, returns the number of decoded frames
+        ///
+        /// # Panics
+        /// Panics if a datagram is not a valid frame set.
+        pub fn decode(&self, datagrams: Vec<BytesMut>) -> usize {
+            datagrams
+                .into_iter()
+                .map(|mut datagram| {
+                    // skip the flag
+                    let _ = datagram.split_to(1);
+                    match Packet::<FramesMut>::read_frame_set(&mut datagram, self.checksum) {
+                        Ok(Packet::FrameSet(frame_set)) => frame_set.set.len(),
+                        _ => panic!("invalid frame set"),
+                    }
+                })
+                .sum()
+        }
+
+        pub fn bytes(&self) -> u64 {
+            self.datagrams.iter().map(|b| b.len() as u64).sum()
+        }
+
+        pub fn elements(&self) -> u64 {
+            self.sets.iter().map(|set| set.len() as u64).sum()
+        }
+    }
+
+    /// The sequence numbers to be acknowledged and the ACKs encoded from them
+    #[derive(Debug, Clone)]
+    pub struct AckOpts {
+        seq_nums: Vec<u24>,
+        mtu: u16,
+        datagrams: Vec<BytesMut>,
+    }
+
+    impl AckOpts {
+        /// `cnt` sequence numbers with a gap after every `run` consecutive ones, which compresses
+        /// into ranges (`run` > 1) or singles (`run` = 1)
+        ///
+        /// # Panics
+        /// Panics if `run` is 0.
+        pub fn new(cnt: usize, run: usize, mtu: u16) -> Self {
+            assert!(run > 0, "run must be larger than 0");
+            let seq_nums = (0..cnt).map(|i| (i + i / run).into()).collect();
+            let mut opts = Self {
+                seq_nums,
+                mtu,
+                datagrams: vec![],
+            };
+            opts.datagrams = opts
+                .extend()
+                .into_iter()
+                .map(|ack| {
+                    let mut buf = BytesMut::new();
+                    ack.write(&mut buf);
+                    buf
+                })
+                .collect();
+            opts
+        }
+
+        /// Compress all the sequence numbers into the ACKs sized to the MTU
+        pub fn extend(&self) -> Vec<AckOrNack> {
+            let mut seq_nums = self.seq_nums.iter().copied().peekable();
+            let mut acks = vec![];
+            while seq_nums.peek().is_some() {
+                acks.extend(AckOrNack::extend_from(&mut seq_nums, self.mtu));
+            }
+            acks
+        }
+
+        /// The encoded ACKs to be read
+        pub fn datagrams(&self) -> Vec<BytesMut> {
+            self.datagrams.clone()
+        }
+
+        /// Read the encoded ACKs, returns the number of acknowledged sequence numbers
+        ///
+        /// # Panics
+        /// Panics if a datagram is not a valid ACK.
+        pub fn read(datagrams: Vec<BytesMut>) -> usize {
+            datagrams
+                .into_iter()
+                .map(|mut datagram| {
+                    AckOrNack::read(&mut datagram, super::DEFAULT_MAX_ACK_COUNT)
+                        .unwrap()
+                        .total_cnt()
+                })
+                .sum()
+        }
+
+        pub fn elements(&self) -> u64 {
+            self.seq_nums.len() as u64
+        }
+    }
+
+    #[cfg(test)]
+    #[test]
+    fn test_bench() {
+        for mix in [Mix::TinyUnreliable, Mix::FragmentedReliable, Mix::Mixed] {
+            let opts = PacketOpts::new(mix, 100, 1400, true);
+            assert_eq!(opts.elements(), 100);
+            let mut buf = BytesMut::new();
+            assert_eq!(opts.encode(&mut buf) as u64, opts.bytes());
+            assert_eq!(opts.decode(opts.datagrams()), 100);
+        }
+        let opts = AckOpts::new(1000, 3, 1400);
+        assert_eq!(AckOpts::read(opts.datagrams()) as u64, opts.elements());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{BufMut, BytesMut};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::parse_all;
+    use crate::packet::PackType;
+    use crate::utils::BufMutExt;
+
+    #[test]
+    fn test_parse_connected_fuzz() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        // a frame set of a connection request accepted with trailing bytes, and a full range ack
+        let mut seeds = vec![];
+        let mut body = BytesMut::new();
+        body.put_u8(PackType::ConnectionRequestAccepted as u8);
+        for _ in 0..21 {
+            body.put_u8(4);
+            body.put_slice(&[127, 0, 0, 1, 0x4a, 0xbc]);
+        }
+        body.put_slice(&[0; 20]);
+        let mut frame_set = BytesMut::new();
+        frame_set.put_u24_le(0.into());
+        frame_set.put_u8(0);
+        frame_set.put_u16((body.len() << 3) as u16);
+        frame_set.put(body);
+        seeds.push(frame_set);
+        let mut ack = BytesMut::new();
+        ack.put_u16(1);
+        ack.put_u8(0);
+        ack.put_u24_le(0.into());
+        ack.put_u24_le(0xff_ffff.into());
+        seeds.push(ack);
+
+        for seed in &seeds {
+            parse_all(seed);
+        }
+        for _ in 0..10000 {
+            let mut random = BytesMut::zeroed(rng.gen_range(0..256));
+            rng.fill(&mut random[..]);
+            parse_all(&random);
+
+            let mut mutated = seeds[rng.gen_range(0..seeds.len())].clone();
+            for _ in 0..rng.gen_range(1..4) {
+                let pos = rng.gen_range(0..mutated.len());
+                mutated[pos] = rng.gen_range(0..=u8::MAX);
+            }
+            mutated.truncate(rng.gen_range(0..=mutated.len()));
+            parse_all(&mutated);
+        }
     }
 }
@@ -1,7 +1,7 @@
 pub(crate) mod connected;
 pub(crate) mod unconnected;
 
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use core::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use bytes::{Buf, BufMut, BytesMut};
 use connected::{FramesMut, FramesRef};
@@ -9,15 +9,18 @@ use connected::{FramesMut, FramesRef};
 use crate::errors::CodecError;
 
 macro_rules! read_buf {
-    ($buf:expr, $len:expr, $exp:expr) => {{
+    ($buf:expr, $len:expr, $exp:expr) => {
+        read_buf!($buf, $len, "certain sized pack", $exp)
+    };
+    ($buf:expr, $len:expr, $name:literal, $exp:expr) => {{
         if $buf.remaining() < $len {
-            return Err(CodecError::InvalidPacketLength("certain sized pack"));
+            return Err(CodecError::InvalidPacketLength($name));
         }
         $exp
     }};
 }
 
-pub(in crate::packet) use read_buf;
+pub(crate) use read_buf;
 
 const VALID_FLAG: u8 = 0b1000_0000; // A valid user frame
 const ACK_FLAG: u8 = 0b1100_0000; // A valid user frame which contains an ACK frame
@@ -30,6 +33,9 @@ const NEEDS_B_AND_AS_FLAG: u8 = 0b0000_0100;
 // 1B ID + 3B seq num
 pub(crate) const FRAME_SET_HEADER_SIZE: usize = 4;
 
+// CRC-32 appended to the frame set when the checksum is enabled
+pub(crate) const FRAME_SET_CHECKSUM_SIZE: usize = 4;
+
 // u32 + u16 + u32
 pub(crate) const FRAGMENT_PART_SIZE: usize = 10;
 
@@ -147,13 +153,13 @@ pub(crate) enum Packet<S> {
 }
 
 impl<'a, B: Buf + Clone> Packet<FramesRef<'a, B>> {
-    pub(crate) fn write(self, buf: &mut BytesMut) {
+    pub(crate) fn write(self, buf: &mut BytesMut, magic: &Magic, checksum: bool) {
         match self {
             Packet::Unconnected(packet) => {
-                packet.write(buf);
+                packet.write(buf, magic);
             }
             Packet::Connected(packet) => {
-                packet.write(buf);
+                packet.write(buf, checksum);
             }
         }
     }
@@ -167,60 +173,94 @@ impl Packet<FramesMut> {
         }
     }
 
-    pub(crate) fn read(buf: &mut BytesMut) -> Result<Self, CodecError> {
+    pub(crate) fn read(
+        buf: &mut BytesMut,
+        magic: &Magic,
+        checksum: bool,
+        max_ack_count: usize,
+    ) -> Result<Self, CodecError> {
         let pack_type: PackType = read_buf!(buf, 1, PackType::from_u8(buf.get_u8())?);
         if pack_type.is_frame_set() {
-            return Ok(Self::Connected(connected::Packet::read_frame_set(buf)?));
+            return Ok(Self::Connected(connected::Packet::read_frame_set(
+                buf, checksum,
+            )?));
         }
         if pack_type.is_ack() {
-            return Ok(Self::Connected(connected::Packet::read_ack(buf)?));
+            return Ok(Self::Connected(connected::Packet::read_ack(
+                buf,
+                max_ack_count,
+            )?));
         }
         if pack_type.is_nack() {
-            return Ok(Self::Connected(connected::Packet::read_nack(buf)?));
+            return Ok(Self::Connected(connected::Packet::read_nack(
+                buf,
+                max_ack_count,
+            )?));
         }
         // unconnected packets
         match pack_type {
             PackType::UnconnectedPing1 | PackType::UnconnectedPing2 => {
-                read_buf!(buf, 32, unconnected::Packet::read_unconnected_ping(buf))
+                read_buf!(
+                    buf,
+                    32,
+                    unconnected::Packet::read_unconnected_ping(buf, magic)
+                )
             }
             PackType::UnconnectedPong => {
-                read_buf!(buf, 34, unconnected::Packet::read_unconnected_pong(buf))
+                read_buf!(
+                    buf,
+                    34,
+                    unconnected::Packet::read_unconnected_pong(buf, magic)
+                )
             }
             PackType::OpenConnectionRequest1 => {
                 read_buf!(
                     buf,
-                    19,
-                    unconnected::Packet::read_open_connection_request1(buf)
+                    17,
+                    unconnected::Packet::read_open_connection_request1(buf, magic)
                 )
             }
             PackType::OpenConnectionReply1 => {
                 read_buf!(
                     buf,
                     27,
-                    unconnected::Packet::read_open_connection_reply1(buf)
+                    unconnected::Packet::read_open_connection_reply1(buf, magic)
                 )
             }
             PackType::IncompatibleProtocolVersion => {
                 read_buf!(
                     buf,
                     25,
-                    unconnected::Packet::read_incompatible_protocol(buf)
+                    unconnected::Packet::read_incompatible_protocol(buf, magic)
                 )
             }
             PackType::AlreadyConnected => {
-                read_buf!(buf, 24, unconnected::Packet::read_already_connected(buf))
+                read_buf!(
+                    buf,
+                    24,
+                    unconnected::Packet::read_already_connected(buf, magic)
+                )
             }
             PackType::ConnectionRequestFailed => {
                 read_buf!(
                     buf,
                     24,
-                    unconnected::Packet::read_connection_request_failed(buf)
+                    unconnected::Packet::read_connection_request_failed(buf, magic)
+                )
+            }
+            PackType::NoFreeIncomingConnections => {
+                read_buf!(
+                    buf,
+                    24,
+                    unconnected::Packet::read_no_free_incoming_connections(buf, magic)
                 )
             }
             PackType::OpenConnectionRequest2 => {
-                unconnected::Packet::read_open_connection_request2(buf)
+                unconnected::Packet::read_open_connection_request2(buf, magic)
+            }
+            PackType::OpenConnectionReply2 => {
+                unconnected::Packet::read_open_connection_reply2(buf, magic)
             }
-            PackType::OpenConnectionReply2 => unconnected::Packet::read_open_connection_reply2(buf),
             _ => Err(CodecError::InvalidPacketType(pack_type as u8)),
         }
         .map(Self::Unconnected)
@@ -228,37 +268,40 @@ impl Packet<FramesMut> {
 }
 
 /// Magic sequence is a sequence of bytes which is found in every unconnected message sent in Raknet
-pub(crate) const MAGIC: [u8; 16] = [
+pub(crate) type Magic = [u8; 16];
+
+/// The default magic sequence used by Raknet
+pub(crate) const MAGIC: Magic = [
     0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78,
 ];
 
 pub(crate) trait MagicRead {
-    /// Get the raknet magic and return a bool if it matches the magic
-    fn get_checked_magic(&mut self) -> Result<(), CodecError>;
+    /// Get the raknet magic and return an error if it does not match the given magic
+    fn get_checked_magic(&mut self, magic: &Magic) -> Result<(), CodecError>;
 }
 
 pub(crate) trait MagicWrite {
-    /// Put the raknet magic
-    fn put_magic(&mut self);
+    /// Put the given raknet magic
+    fn put_magic(&mut self, magic: &Magic);
 }
 
 impl<B: Buf> MagicRead for B {
     #![allow(clippy::needless_range_loop)]
-    fn get_checked_magic(&mut self) -> Result<(), CodecError> {
-        for i in 0..MAGIC.len() {
+    fn get_checked_magic(&mut self, magic: &Magic) -> Result<(), CodecError> {
+        for i in 0..magic.len() {
             let byte = self.chunk()[i];
-            if byte != MAGIC[i] {
+            if byte != magic[i] {
                 return Err(CodecError::MagicNotMatched(i, byte));
             }
         }
-        self.advance(MAGIC.len());
+        self.advance(magic.len());
         Ok(())
     }
 }
 
 impl<B: BufMut> MagicWrite for B {
-    fn put_magic(&mut self) {
-        self.put_slice(&MAGIC);
+    fn put_magic(&mut self, magic: &Magic) {
+        self.put_slice(magic);
     }
 }
 
@@ -276,15 +319,16 @@ impl<B: Buf> SocketAddrRead for B {
         match ver {
             4 => {
                 read_buf!(self, 6, {
-                    let ip = Ipv4Addr::from_bits(self.get_u32());
+                    // the octets are inverted by the reference RakNet
+                    let ip = Ipv4Addr::from_bits(!self.get_u32());
                     let port = self.get_u16();
                     Ok(SocketAddr::V4(SocketAddrV4::new(ip, port)))
                 })
             }
             6 => {
-                // TODO: to be determined
+                // the raw `sockaddr_in6`, the family is in little endian (`AF_INET6` on Windows)
                 read_buf!(self, 28, {
-                    let family = self.get_u16();
+                    let family = self.get_u16_le();
                     if family != 0x17 {
                         return Err(CodecError::InvalidIPV6Family(family));
                     }
@@ -307,12 +351,12 @@ impl<B: BufMut> SocketAddrWrite for B {
         match addr {
             SocketAddr::V4(v4) => {
                 self.put_u8(4);
-                self.put_slice(&v4.ip().octets());
+                self.put_u32(!v4.ip().to_bits());
                 self.put_u16(v4.port());
             }
             SocketAddr::V6(v6) => {
                 self.put_u8(6);
-                self.put_u16(0x17);
+                self.put_u16_le(0x17);
                 self.put_u16(v6.port());
                 self.put_u32(v6.flowinfo());
                 self.put_slice(&v6.ip().octets());
@@ -321,3 +365,129 @@ impl<B: BufMut> SocketAddrWrite for B {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packet::connected::DEFAULT_MAX_ACK_COUNT;
+
+    #[test]
+    fn test_custom_magic() {
+        let mut custom = MAGIC;
+        custom.reverse();
+
+        let mut buf = BytesMut::new();
+        unconnected::Packet::UnconnectedPing {
+            send_timestamp: 0,
+            magic: (),
+            client_guid: 114514,
+        }
+        .write(&mut buf, &custom);
+
+        let pack = Packet::read(&mut buf.clone(), &custom, false, DEFAULT_MAX_ACK_COUNT).unwrap();
+        assert_eq!(pack.pack_type(), PackType::UnconnectedPing1);
+
+        let err = Packet::read(&mut buf, &MAGIC, false, DEFAULT_MAX_ACK_COUNT).unwrap_err();
+        assert!(matches!(err, CodecError::MagicNotMatched(0, 0x78)));
+    }
+
+    #[test]
+    fn test_socket_addr_reference_bytes() {
+        let cases: [(SocketAddr, &[u8]); 2] = [
+            (
+                "127.0.0.1:19132".parse().unwrap(),
+                &[0x04, 0x80, 0xff, 0xff, 0xfe, 0x4a, 0xbc],
+            ),
+            (
+                "[::1]:19133".parse().unwrap(),
+                &[
+                    0x06, 0x17, 0x00, 0x4a, 0xbd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+            ),
+        ];
+        for (addr, reference) in cases {
+            let mut buf = BytesMut::new();
+            buf.put_socket_addr(addr);
+            assert_eq!(&buf[..], reference);
+            assert_eq!(buf.get_socket_addr().unwrap(), addr);
+        }
+    }
+
+    #[test]
+    fn test_unconnected_reference_bytes() {
+        let mut ping = BytesMut::new();
+        ping.put_u8(0x01);
+        ping.put_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xe2, 0x40]);
+        ping.put_slice(&MAGIC);
+        ping.put_slice(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+        let mut request2 = BytesMut::new();
+        request2.put_u8(0x07);
+        request2.put_slice(&MAGIC);
+        request2.put_slice(&[0x04, 0x3f, 0x57, 0xfe, 0xfd, 0x4a, 0xbc]);
+        request2.put_slice(&[0x05, 0x78]);
+        request2.put_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00]);
+
+        let cases = [
+            (
+                ping,
+                unconnected::Packet::UnconnectedPing {
+                    send_timestamp: 123_456,
+                    magic: (),
+                    client_guid: 0x0102_0304_0506_0708,
+                },
+            ),
+            (
+                request2,
+                unconnected::Packet::OpenConnectionRequest2 {
+                    magic: (),
+                    cookie: None,
+                    server_address: "192.168.1.2:19132".parse().unwrap(),
+                    mtu: 1400,
+                    client_guid: 256,
+                },
+            ),
+        ];
+        for (reference, packet) in cases {
+            let mut buf = BytesMut::new();
+            packet.clone().write(&mut buf, &MAGIC);
+            assert_eq!(buf, reference);
+            assert_eq!(
+                Packet::read(&mut buf, &MAGIC, false, DEFAULT_MAX_ACK_COUNT).unwrap(),
+                Packet::Unconnected(packet)
+            );
+        }
+    }
+
+    #[test]
+    fn test_handshake_cookie_round_trip() {
+        for addr in ["192.168.1.2:19132", "[::1]:19133"] {
+            for cookie in [None, Some(0x0102_0304)] {
+                let packets = [
+                    unconnected::Packet::OpenConnectionReply1 {
+                        magic: (),
+                        server_guid: 1,
+                        cookie,
+                        mtu: 1400,
+                    },
+                    unconnected::Packet::OpenConnectionRequest2 {
+                        magic: (),
+                        cookie,
+                        server_address: addr.parse().unwrap(),
+                        mtu: 1400,
+                        client_guid: 256,
+                    },
+                ];
+                for packet in packets {
+                    let mut buf = BytesMut::new();
+                    packet.clone().write(&mut buf, &MAGIC);
+                    assert_eq!(
+                        Packet::read(&mut buf, &MAGIC, false, DEFAULT_MAX_ACK_COUNT).unwrap(),
+                        Packet::Unconnected(packet)
+                    );
+                }
+            }
+        }
+    }
+}
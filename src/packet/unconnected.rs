@@ -1,9 +1,26 @@
-use std::net::SocketAddr;
+use core::net::SocketAddr;
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use crate::errors::CodecError;
-use crate::packet::{read_buf, MagicRead, MagicWrite, PackType, SocketAddrRead, SocketAddrWrite};
+use crate::packet::{
+    read_buf, Magic, MagicRead, MagicWrite, PackType, SocketAddrRead, SocketAddrWrite,
+};
+
+/// The size of `OpenConnectionRequest2` after the magic without the cookie, with the IPv4 and
+/// IPv6 server address
+const OPEN_CONNECTION_REQUEST2_SIZES: [usize; 2] = [7 + 10, 29 + 10];
+
+/// The cookie (4) and the flag whether the client wrote the challenge (1)
+const COOKIE_SIZE: usize = 5;
+
+/// The size of the IP and the UDP headers, the reference `RakNet` counts them in the MTU told by
+/// the size of `OpenConnectionRequest1`
+const IP_UDP_HEADER_SIZE: usize = 28;
+
+/// The size of `OpenConnectionRequest1` without the padding: the id (1), the magic (16) and the
+/// protocol version (1)
+const OPEN_CONNECTION_REQUEST1_SIZE: usize = 18;
 
 /// Request sent before establishing a connection
 #[derive(Debug, PartialEq, Clone)]
@@ -27,11 +44,14 @@ pub(crate) enum Packet {
     OpenConnectionReply1 {
         magic: (),
         server_guid: u64,
-        use_encryption: bool,
+        // the handshake cookie, the security flag is set if it is present
+        cookie: Option<u32>,
         mtu: u16,
     },
     OpenConnectionRequest2 {
         magic: (),
+        // echoes the cookie of `OpenConnectionReply1`
+        cookie: Option<u32>,
         server_address: SocketAddr,
         mtu: u16,
         client_guid: u64,
@@ -56,6 +76,10 @@ pub(crate) enum Packet {
         magic: (),
         server_guid: u64,
     },
+    NoFreeIncomingConnections {
+        magic: (),
+        server_guid: u64,
+    },
 }
 
 impl Packet {
@@ -75,22 +99,29 @@ impl Packet {
             Packet::IncompatibleProtocol { .. } => PackType::IncompatibleProtocolVersion,
             Packet::AlreadyConnected { .. } => PackType::AlreadyConnected,
             Packet::ConnectionRequestFailed { .. } => PackType::ConnectionRequestFailed,
+            Packet::NoFreeIncomingConnections { .. } => PackType::NoFreeIncomingConnections,
         }
     }
 
-    pub(super) fn read_unconnected_ping(buf: &mut BytesMut) -> Result<Self, CodecError> {
+    pub(super) fn read_unconnected_ping(
+        buf: &mut BytesMut,
+        magic: &Magic,
+    ) -> Result<Self, CodecError> {
         Ok(Packet::UnconnectedPing {
-            send_timestamp: buf.get_i64(),   // 8
-            magic: buf.get_checked_magic()?, // 16
-            client_guid: buf.get_u64(),      // 8
+            send_timestamp: buf.get_i64(),        // 8
+            magic: buf.get_checked_magic(magic)?, // 16
+            client_guid: buf.get_u64(),           // 8
         })
     }
 
-    pub(super) fn read_unconnected_pong(buf: &mut BytesMut) -> Result<Self, CodecError> {
+    pub(super) fn read_unconnected_pong(
+        buf: &mut BytesMut,
+        magic: &Magic,
+    ) -> Result<Self, CodecError> {
         Ok(Packet::UnconnectedPong {
-            send_timestamp: buf.get_i64(),   // 8
-            server_guid: buf.get_u64(),      // 8
-            magic: buf.get_checked_magic()?, // 16
+            send_timestamp: buf.get_i64(),        // 8
+            server_guid: buf.get_u64(),           // 8
+            magic: buf.get_checked_magic(magic)?, // 16
             data: {
                 let len = buf.get_u16() as usize;
                 read_buf!(buf, len, {
@@ -102,35 +133,72 @@ impl Packet {
         })
     }
 
-    pub(super) fn read_open_connection_request1(buf: &mut BytesMut) -> Result<Self, CodecError> {
+    pub(super) fn read_open_connection_request1(
+        buf: &mut BytesMut,
+        magic: &Magic,
+    ) -> Result<Self, CodecError> {
+        // the request is padded to the MTU, the id is read already
+        let size = 1 + buf.remaining() + IP_UDP_HEADER_SIZE;
+        buf.get_checked_magic(magic)?; // 16
+        let protocol_version = buf.get_u8(); // 1
+        buf.advance(buf.remaining());
         Ok(Packet::OpenConnectionRequest1 {
-            magic: buf.get_checked_magic()?, // 16
-            protocol_version: buf.get_u8(),  // 1
-            mtu: buf.get_u16(),              // 2
+            magic: (),
+            protocol_version,
+            mtu: u16::try_from(size).unwrap_or(u16::MAX),
         })
     }
 
-    pub(super) fn read_open_connection_reply1(buf: &mut BytesMut) -> Result<Self, CodecError> {
+    pub(super) fn read_open_connection_reply1(
+        buf: &mut BytesMut,
+        magic: &Magic,
+    ) -> Result<Self, CodecError> {
+        buf.get_checked_magic(magic)?; // 16
+        let server_guid = buf.get_u64(); // 8
+                                         // the security flag (1), followed by the cookie (4) if set
+        let cookie = if buf.get_u8() != 0 {
+            Some(read_buf!(buf, 4 + 2, buf.get_u32()))
+        } else {
+            None
+        };
         Ok(Packet::OpenConnectionReply1 {
-            magic: buf.get_checked_magic()?,   // 16
-            server_guid: buf.get_u64(),        // 8
-            use_encryption: buf.get_u8() != 0, // 1
-            mtu: buf.get_u16(),                // 2
+            magic: (),
+            server_guid,
+            cookie,
+            mtu: buf.get_u16(), // 2
         })
     }
 
-    pub(super) fn read_open_connection_request2(buf: &mut BytesMut) -> Result<Self, CodecError> {
+    pub(super) fn read_open_connection_request2(
+        buf: &mut BytesMut,
+        magic: &Magic,
+    ) -> Result<Self, CodecError> {
+        read_buf!(buf, 16, buf.get_checked_magic(magic))?;
+        // the cookie is present only if the server sent one, tell it by the size
+        let cookie = OPEN_CONNECTION_REQUEST2_SIZES
+            .iter()
+            .any(|size| buf.remaining() == size + COOKIE_SIZE)
+            .then(|| {
+                let cookie = buf.get_u32();
+                // the challenge is never written, no key exchange is supported
+                buf.advance(1);
+                cookie
+            });
         Ok(Packet::OpenConnectionRequest2 {
-            magic: read_buf!(buf, 16, buf.get_checked_magic())?,
+            magic: (),
+            cookie,
             server_address: buf.get_socket_addr()?,
             mtu: read_buf!(buf, 2, buf.get_u16()),
             client_guid: read_buf!(buf, 8, buf.get_u64()),
         })
     }
 
-    pub(super) fn read_open_connection_reply2(buf: &mut BytesMut) -> Result<Self, CodecError> {
+    pub(super) fn read_open_connection_reply2(
+        buf: &mut BytesMut,
+        magic: &Magic,
+    ) -> Result<Self, CodecError> {
         Ok(Packet::OpenConnectionReply2 {
-            magic: read_buf!(buf, 16, buf.get_checked_magic())?,
+            magic: read_buf!(buf, 16, buf.get_checked_magic(magic))?,
             server_guid: read_buf!(buf, 8, buf.get_u64()),
             client_address: buf.get_socket_addr()?,
             mtu: read_buf!(buf, 2, buf.get_u16()),
@@ -138,29 +206,48 @@ impl Packet {
         })
     }
 
-    pub(super) fn read_incompatible_protocol(buf: &mut BytesMut) -> Result<Self, CodecError> {
+    pub(super) fn read_incompatible_protocol(
+        buf: &mut BytesMut,
+        magic: &Magic,
+    ) -> Result<Self, CodecError> {
         Ok(Packet::IncompatibleProtocol {
-            server_protocol: buf.get_u8(),   // 1
-            magic: buf.get_checked_magic()?, // 16
-            server_guid: buf.get_u64(),      // 8
+            server_protocol: buf.get_u8(),        // 1
+            magic: buf.get_checked_magic(magic)?, // 16
+            server_guid: buf.get_u64(),           // 8
         })
     }
 
-    pub(super) fn read_already_connected(buf: &mut BytesMut) -> Result<Self, CodecError> {
+    pub(super) fn read_already_connected(
+        buf: &mut BytesMut,
+        magic: &Magic,
+    ) -> Result<Self, CodecError> {
         Ok(Packet::AlreadyConnected {
-            magic: buf.get_checked_magic()?, // 16
-            server_guid: buf.get_u64(),      // 8
+            magic: buf.get_checked_magic(magic)?, // 16
+            server_guid: buf.get_u64(),           // 8
         })
     }
 
-    pub(super) fn read_connection_request_failed(buf: &mut BytesMut) -> Result<Self, CodecError> {
+    pub(super) fn read_connection_request_failed(
+        buf: &mut BytesMut,
+        magic: &Magic,
+    ) -> Result<Self, CodecError> {
         Ok(Packet::ConnectionRequestFailed {
-            magic: buf.get_checked_magic()?, // 16
-            server_guid: buf.get_u64(),      // 8
+            magic: buf.get_checked_magic(magic)?, // 16
+            server_guid: buf.get_u64(),           // 8
+        })
+    }
+
+    pub(super) fn read_no_free_incoming_connections(
+        buf: &mut BytesMut,
+        magic: &Magic,
+    ) -> Result<Self, CodecError> {
+        Ok(Packet::NoFreeIncomingConnections {
+            magic: buf.get_checked_magic(magic)?, // 16
+            server_guid: buf.get_u64(),           // 8
         })
     }
 
-    pub(crate) fn write(self, buf: &mut BytesMut) {
+    pub(crate) fn write(self, buf: &mut BytesMut, magic: &Magic) {
         // Fixed id (type)
         buf.put_u8(self.pack_type() as u8);
         match self {
@@ -170,7 +257,7 @@ impl Packet {
                 client_guid,
             } => {
                 buf.put_i64(send_timestamp);
-                buf.put_magic();
+                buf.put_magic(magic);
                 buf.put_u64(client_guid);
             }
             Packet::UnconnectedPong {
@@ -181,7 +268,7 @@ impl Packet {
             } => {
                 buf.put_i64(send_timestamp);
                 buf.put_u64(server_guid);
-                buf.put_magic();
+                buf.put_magic(magic);
                 buf.put_u16(data.len() as u16);
                 buf.put(data);
             }
@@ -190,28 +277,40 @@ impl Packet {
                 protocol_version,
                 mtu,
             } => {
-                buf.put_magic();
+                buf.put_magic(magic);
                 buf.put_u8(protocol_version);
-                buf.put_u16(mtu);
+                // the datagram is padded with zeros to tell the MTU, it is dropped on the way if
+                // the MTU is too large for the path
+                let padding = (mtu as usize)
+                    .saturating_sub(IP_UDP_HEADER_SIZE + OPEN_CONNECTION_REQUEST1_SIZE);
+                buf.put_bytes(0, padding);
             }
             Packet::OpenConnectionReply1 {
                 magic: _magic,
                 server_guid,
-                use_encryption: _use_encryption,
+                cookie,
                 mtu,
             } => {
-                buf.put_magic();
+                buf.put_magic(magic);
                 buf.put_u64(server_guid);
-                buf.put_u8(0);
+                buf.put_u8(u8::from(cookie.is_some()));
+                if let Some(cookie) = cookie {
+                    buf.put_u32(cookie);
+                }
                 buf.put_u16(mtu);
             }
             Packet::OpenConnectionRequest2 {
                 magic: _magic,
+                cookie,
                 server_address,
                 mtu,
                 client_guid,
             } => {
-                buf.put_magic();
+                buf.put_magic(magic);
+                if let Some(cookie) = cookie {
+                    buf.put_u32(cookie);
+                    buf.put_u8(0);
+                }
                 buf.put_socket_addr(server_address);
                 buf.put_u16(mtu);
                 buf.put_u64(client_guid);
@@ -221,13 +320,13 @@ impl Packet {
                 server_guid,
                 client_address,
                 mtu,
-                encryption_enabled: _encryption_enabled,
+                encryption_enabled,
             } => {
-                buf.put_magic();
+                buf.put_magic(magic);
                 buf.put_u64(server_guid);
                 buf.put_socket_addr(client_address);
                 buf.put_u16(mtu);
-                buf.put_u8(0);
+                buf.put_u8(u8::from(encryption_enabled));
             }
             Packet::IncompatibleProtocol {
                 server_protocol,
@@ -235,21 +334,28 @@ impl Packet {
                 server_guid,
             } => {
                 buf.put_u8(server_protocol);
-                buf.put_magic();
+                buf.put_magic(magic);
                 buf.put_u64(server_guid);
             }
             Packet::AlreadyConnected {
                 magic: _magic,
                 server_guid,
             } => {
-                buf.put_magic();
+                buf.put_magic(magic);
                 buf.put_u64(server_guid);
             }
             Packet::ConnectionRequestFailed {
                 magic: _magic,
                 server_guid,
             } => {
-                buf.put_magic();
+                buf.put_magic(magic);
+                buf.put_u64(server_guid);
+            }
+            Packet::NoFreeIncomingConnections {
+                magic: _magic,
+                server_guid,
+            } => {
+                buf.put_magic(magic);
                 buf.put_u64(server_guid);
             }
         }
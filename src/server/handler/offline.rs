@@ -1,9 +1,12 @@
 use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
 use std::io;
 use std::net::SocketAddr;
 use std::num::NonZeroUsize;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 use fastrace::collector::SpanContext;
@@ -16,15 +19,138 @@ use crate::packet::connected::{self, FramesMut};
 use crate::packet::{unconnected, Packet};
 use crate::{Peer, Role};
 
+/// The advertisement replied in `UnconnectedPong`
+#[derive(Clone)]
+pub(crate) enum Advertisement {
+    Static(Bytes),
+    Dynamic(Arc<dyn Fn() -> Bytes + Send + Sync>),
+}
+
+impl std::fmt::Debug for Advertisement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Advertisement::Static(data) => f.debug_tuple("Static").field(data).finish(),
+            Advertisement::Dynamic(_) => f.write_str("Dynamic(..)"),
+        }
+    }
+}
+
+impl Advertisement {
+    pub(crate) fn get(&self) -> Bytes {
+        match self {
+            Advertisement::Static(data) => data.clone(),
+            Advertisement::Dynamic(make) => make(),
+        }
+    }
+}
+
+/// Decide whether the protocol version requested by the client is accepted
+#[derive(Clone)]
+pub(crate) struct VersionFilter(Arc<dyn Fn(SocketAddr, u8) -> bool + Send + Sync>);
+
+impl std::fmt::Debug for VersionFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("VersionFilter(..)")
+    }
+}
+
+impl VersionFilter {
+    pub(crate) fn new(filter: impl Fn(SocketAddr, u8) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(filter))
+    }
+
+    pub(crate) fn accepts(&self, addr: SocketAddr, version: u8) -> bool {
+        (self.0)(addr, version)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct Config {
-    pub(crate) sever_guid: u64,
-    pub(crate) advertisement: Bytes,
+    pub(crate) server_guid: u64,
+    pub(crate) advertisement: Advertisement,
     pub(crate) min_mtu: u16,
     pub(crate) max_mtu: u16,
     // Supported raknet versions, sorted
     pub(crate) support_version: Vec<u8>,
+    // Checked after the supported versions
+    pub(crate) version_filter: Option<VersionFilter>,
     pub(crate) max_pending: usize,
+    // The maximum established connections, new handshakes are rejected once reached
+    pub(crate) max_connections: usize,
+    // Whether the frame sets from unknown addresses are yielded as they might come from the
+    // migrated connections, instead of being rejected
+    pub(crate) migration: bool,
+    // The interval to rotate the secret of the handshake cookies, `None` means the cookies are
+    // disabled and the pending handshakes are kept instead
+    pub(crate) cookie_rotation: Option<Duration>,
+    // Whether the frame bodies are encrypted, told to the clients by the open connection reply 2
+    pub(crate) encryption: bool,
+}
+
+/// Issues the stateless cookies of the offline handshake like the SYN cookies. A cookie is derived
+/// from the address and the protocol version of the client with a secret rotated on an interval,
+/// the cookies derived from the current and the previous secret are accepted.
+#[derive(Debug)]
+struct Cookies {
+    rotation: Duration,
+    rotated_at: Instant,
+    current: RandomState,
+    previous: RandomState,
+}
+
+impl Cookies {
+    fn new(rotation: Duration) -> Self {
+        Self {
+            rotation,
+            rotated_at: Instant::now(),
+            current: RandomState::new(),
+            previous: RandomState::new(),
+        }
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.rotated_at);
+        if elapsed < self.rotation {
+            return;
+        }
+        self.previous = if elapsed < self.rotation * 2 {
+            std::mem::replace(&mut self.current, RandomState::new())
+        } else {
+            // both secrets are expired
+            self.current = RandomState::new();
+            RandomState::new()
+        };
+        self.rotated_at = now;
+    }
+
+    fn derive(secret: &RandomState, addr: SocketAddr, version: u8) -> u32 {
+        secret.hash_one((addr, version)) as u32
+    }
+
+    fn issue(&mut self, addr: SocketAddr, version: u8) -> u32 {
+        self.rotate(Instant::now());
+        Self::derive(&self.current, addr, version)
+    }
+
+    /// Validate the cookie echoed by the client, returns the protocol version it was issued for
+    fn validate(&mut self, addr: SocketAddr, cookie: u32, versions: &[u8]) -> Option<u8> {
+        self.rotate(Instant::now());
+        versions.iter().copied().find(|version| {
+            [&self.current, &self.previous]
+                .into_iter()
+                .any(|secret| Self::derive(secret, addr, *version) == cookie)
+        })
+    }
+}
+
+/// The source of a connected packet yielded by the [`OfflineHandler`]
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Source {
+    /// The peer which has finished the offline handshake
+    Connected(Peer),
+    /// An address without connection, the frame set might come from a connected peer that
+    /// changed its address. It should be migrated or rejected by the caller.
+    Unknown(SocketAddr),
 }
 
 /// Implements a simple `OfflineHandler` state machine to process sink requests.
@@ -59,11 +185,13 @@ pin_project! {
         // and it will be popped out during the OpenConnectionRequest2
         // or when the connection is disconnected.
         pending: lru::LruCache<SocketAddr, u8>,
-        // A `HashMap<SocketAddr, Peer>` that caches connections
+        // A `HashMap<SocketAddr, (Peer, u8)>` that caches connections with their protocol versions
         // in the OpenConnectionRequest2 stage and is cleaned up on disconnection.
         // The `connected` map is used to check if a `Peer` has completed the connection
         // from the socket.
-        connected: HashMap<SocketAddr, Peer>,
+        connected: HashMap<SocketAddr, (Peer, u8)>,
+        // Issues the handshake cookies instead of keeping the pending handshakes if enabled
+        cookies: Option<Cookies>,
         state: OfflineState,
         role: Role,
         read_span: Option<Span>,
@@ -82,8 +210,9 @@ where
                 NonZeroUsize::new(config.max_pending).expect("max_pending > 0"),
             ),
             role: Role::Server {
-                guid: config.sever_guid,
+                guid: config.server_guid,
             },
+            cookies: config.cookie_rotation.map(Cookies::new),
             config,
             connected: HashMap::new(),
             state: OfflineState::Listening,
@@ -97,25 +226,162 @@ where
         this.connected.remove(addr);
     }
 
+    /// Move the connected peer to the new address
+    pub(crate) fn migrate(self: Pin<&mut Self>, from: &SocketAddr, to: SocketAddr) {
+        let this = self.project();
+        if let Some((peer, version)) = this.connected.remove(from) {
+            this.connected
+                .insert(to, (Peer { addr: to, ..peer }, version));
+        }
+    }
+
+    /// Reject the packet from the unknown address which is not migrated
+    pub(crate) fn reject(self: Pin<&mut Self>, addr: SocketAddr) {
+        let this = self.project();
+        // the pending reply is always sent before yielding the packet
+        debug_assert!(matches!(this.state, OfflineState::Listening));
+        *this.state = OfflineState::SendingPrepare(Some((
+            Self::make_connection_request_failed(this.config),
+            addr,
+        )));
+    }
+
+    /// Stop accepting new connections, the following handshakes are rejected as if the
+    /// connections reached the limit
+    pub(crate) fn stop_accepting(self: Pin<&mut Self>) {
+        let this = self.project();
+        this.config.max_connections = 0;
+        this.pending.clear();
+    }
+
+    /// The number of connections that have finished the offline handshake
+    pub(crate) fn connected_count(&self) -> usize {
+        self.connected.len()
+    }
+
+    /// The protocol version requested by the connected peer
+    pub(crate) fn protocol_version(&self, addr: &SocketAddr) -> Option<u8> {
+        self.connected.get(addr).map(|(_, version)| *version)
+    }
+
+    /// Check whether the protocol version requested by the client is accepted. The client must
+    /// not change its version during the handshake.
+    fn check_version(
+        config: &Config,
+        pending: &mut lru::LruCache<SocketAddr, u8>,
+        role: &Role,
+        addr: SocketAddr,
+        version: u8,
+    ) -> bool {
+        if config.support_version.binary_search(&version).is_err() {
+            debug!("[{role}] received incompatible version({version}) from {addr}");
+            return false;
+        }
+        if let Some(pending_version) = pending.peek(&addr)
+            && *pending_version != version
+        {
+            debug!("[{role}] received mixed versions({pending_version} and {version}) from {addr}");
+            pending.pop(&addr);
+            return false;
+        }
+        if let Some(filter) = &config.version_filter
+            && !filter.accepts(addr, version)
+        {
+            debug!("[{role}] version({version}) from {addr} is rejected by the filter");
+            return false;
+        }
+        true
+    }
+
+    /// Keep the half-opened connection, or issue the cookie for it if the cookies are enabled
+    fn open_pending(
+        cookies: &mut Option<Cookies>,
+        pending: &mut lru::LruCache<SocketAddr, u8>,
+        role: &Role,
+        addr: SocketAddr,
+        version: u8,
+    ) -> Option<u32> {
+        if let Some(cookies) = cookies {
+            trace!("[{role}] received open connection request 1 from {addr}, reply with a cookie");
+            return Some(cookies.issue(addr, version));
+        }
+        if pending.put(addr, version).is_some() {
+            debug!("[{role}] received duplicate open connection request 1 from {addr}");
+        } else {
+            trace!("[{role}] received open connection request 1 from {addr}");
+        }
+        None
+    }
+
+    /// Take the protocol version of the half-opened connection, or the one of the valid cookie if
+    /// the cookies are enabled
+    fn close_pending(
+        config: &Config,
+        cookies: &mut Option<Cookies>,
+        pending: &mut lru::LruCache<SocketAddr, u8>,
+        addr: SocketAddr,
+        cookie: Option<u32>,
+    ) -> Option<u8> {
+        match cookies {
+            Some(cookies) => cookies.validate(addr, cookie?, &config.support_version),
+            None => pending.pop(&addr),
+        }
+    }
+
+    /// Check whether the client could be connected, return the rejection if not
+    fn check_open_connection_request2(
+        config: &Config,
+        connected: &HashMap<SocketAddr, (Peer, u8)>,
+        role: &Role,
+        addr: SocketAddr,
+        mtu: u16,
+        guid: u64,
+    ) -> Option<unconnected::Packet> {
+        // client should adjust the mtu
+        if mtu < config.min_mtu || mtu > config.max_mtu || connected.contains_key(&addr) {
+            debug!("[{role}] received unexpected mtu({mtu}) from {addr}");
+            return Some(Self::make_already_connected(config));
+        }
+        if guid == config.server_guid {
+            debug!("[{role}] reject client {addr} since its guid({guid}) collides with the server");
+            return Some(Self::make_already_connected(config));
+        }
+        if connected.len() >= config.max_connections {
+            debug!(
+                "[{role}] reject client {addr} since the connections reached the limit({})",
+                config.max_connections
+            );
+            return Some(Self::make_no_free_incoming_connections(config));
+        }
+        None
+    }
+
     fn make_incompatible_version(config: &Config) -> unconnected::Packet {
         unconnected::Packet::IncompatibleProtocol {
             server_protocol: *config.support_version.last().unwrap(),
             magic: (),
-            server_guid: config.sever_guid,
+            server_guid: config.server_guid,
         }
     }
 
     fn make_already_connected(config: &Config) -> unconnected::Packet {
         unconnected::Packet::AlreadyConnected {
             magic: (),
-            server_guid: config.sever_guid,
+            server_guid: config.server_guid,
+        }
+    }
+
+    fn make_no_free_incoming_connections(config: &Config) -> unconnected::Packet {
+        unconnected::Packet::NoFreeIncomingConnections {
+            magic: (),
+            server_guid: config.server_guid,
         }
     }
 
     fn make_connection_request_failed(config: &Config) -> unconnected::Packet {
         unconnected::Packet::ConnectionRequestFailed {
             magic: (),
-            server_guid: config.sever_guid,
+            server_guid: config.server_guid,
         }
     }
 }
@@ -125,7 +391,7 @@ where
     F: Stream<Item = (Packet<FramesMut>, SocketAddr)>
         + Sink<(unconnected::Packet, SocketAddr), Error = io::Error>,
 {
-    type Item = (connected::Packet<FramesMut>, Peer);
+    type Item = (connected::Packet<FramesMut>, Source);
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
@@ -173,10 +439,15 @@ where
             let pack = match packet {
                 Packet::Unconnected(pack) => pack,
                 Packet::Connected(pack) => {
-                    if let Some(peer) = this.connected.get(&addr) {
+                    if let Some((peer, _)) = this.connected.get(&addr) {
                         drop(guard);
                         this.read_span.take();
-                        return Poll::Ready(Some((pack, *peer)));
+                        return Poll::Ready(Some((pack, Source::Connected(*peer))));
+                    }
+                    if this.config.migration && matches!(pack, connected::Packet::FrameSet(_)) {
+                        drop(guard);
+                        this.read_span.take();
+                        return Poll::Ready(Some((pack, Source::Unknown(addr))));
                     }
                     debug!(
                         "[{}] ignore packet {:?} from unconnected client {addr}",
@@ -193,9 +464,9 @@ where
                 unconnected::Packet::UnconnectedPing { send_timestamp, .. } => {
                     unconnected::Packet::UnconnectedPong {
                         send_timestamp,
-                        server_guid: this.config.sever_guid,
+                        server_guid: this.config.server_guid,
                         magic: (),
-                        data: this.config.advertisement.clone(),
+                        data: this.config.advertisement.get(),
                     }
                 }
                 unconnected::Packet::OpenConnectionRequest1 {
@@ -203,79 +474,75 @@ where
                     mtu,
                     ..
                 } => {
-                    if this
-                        .config
-                        .support_version
-                        .binary_search(&protocol_version)
-                        .is_err()
-                    {
-                        debug!(
-                            "[{}] received incompatible version({protocol_version}) from {addr}",
-                            this.role
-                        );
+                    if !Self::check_version(
+                        this.config,
+                        this.pending,
+                        this.role,
+                        addr,
+                        protocol_version,
+                    ) {
                         *this.state = OfflineState::SendingPrepare(Some((
                             Self::make_incompatible_version(this.config),
                             addr,
                         )));
                         continue;
                     }
-                    if this.pending.put(addr, protocol_version).is_some() {
-                        debug!(
-                            "[{}] received duplicate open connection request 1 from {addr}",
-                            this.role
-                        );
-                    } else {
-                        trace!(
-                            "[{}] received open connection request 1 from {addr}",
-                            this.role,
-                        );
-                    }
+                    let cookie = Self::open_pending(
+                        this.cookies,
+                        this.pending,
+                        this.role,
+                        addr,
+                        protocol_version,
+                    );
                     // max_mtu >= final_mtu >= min_mtu
                     let final_mtu = mtu.clamp(this.config.min_mtu, this.config.max_mtu);
                     unconnected::Packet::OpenConnectionReply1 {
                         magic: (),
-                        server_guid: this.config.sever_guid,
-                        use_encryption: false, // must set to false first
+                        server_guid: this.config.server_guid,
+                        cookie,
                         mtu: final_mtu,
                     }
                 }
                 unconnected::Packet::OpenConnectionRequest2 {
+                    cookie,
                     mtu,
                     client_guid: guid,
                     ..
                 } => {
-                    if this.pending.pop(&addr).is_none() {
-                        debug!("[{}] received open connection request 2 from {addr} without open connection request 1", this.role);
+                    let Some(version) =
+                        Self::close_pending(this.config, this.cookies, this.pending, addr, cookie)
+                    else {
+                        debug!("[{}] received open connection request 2 from {addr} without open connection request 1 or a valid cookie", this.role);
                         *this.state = OfflineState::SendingPrepare(Some((
                             Self::make_incompatible_version(this.config),
                             addr,
                         )));
                         continue;
-                    }
+                    };
                     trace!(
                         "[{}] received open connection request 2 from {addr}",
                         this.role
                     );
-                    // client should adjust the mtu
-                    if mtu < this.config.min_mtu
-                        || mtu > this.config.max_mtu
-                        || this.connected.contains_key(&addr)
-                    {
-                        debug!("[{}] received unexpected mtu({mtu}) from {addr}", this.role);
-                        *this.state = OfflineState::SendingPrepare(Some((
-                            Self::make_already_connected(this.config),
-                            addr,
-                        )));
+                    if let Some(reject) = Self::check_open_connection_request2(
+                        this.config,
+                        this.connected,
+                        this.role,
+                        addr,
+                        mtu,
+                        guid,
+                    ) {
+                        *this.state = OfflineState::SendingPrepare(Some((reject, addr)));
                         continue;
                     }
                     debug!("[{}] client {addr} connected with mtu {mtu}", this.role);
-                    this.connected.insert(addr, Peer { addr, mtu, guid });
+                    this.connected
+                        .insert(addr, (Peer { addr, mtu, guid }, version));
                     unconnected::Packet::OpenConnectionReply2 {
                         magic: (),
-                        server_guid: this.config.sever_guid,
+                        server_guid: this.config.server_guid,
                         client_address: addr,
                         mtu,
-                        encryption_enabled: false, // must set to false
+                        encryption_enabled: this.config.encryption,
                     }
                 }
                 _ => {
@@ -372,6 +639,7 @@ mod test {
                 },
                 unconnected::Packet::OpenConnectionRequest2 {
                     magic: (),
+                    cookie: None,
                     server_address: "0.0.0.0:1".parse().unwrap(),
                     mtu: 1000,
                     client_guid: 114514,
@@ -392,12 +660,17 @@ mod test {
         let handler = OfflineHandler::new(
             test_case,
             Config {
-                sever_guid: 1919810,
-                advertisement: Bytes::from_static(b"hello"),
+                server_guid: 1919810,
+                advertisement: Advertisement::Static(Bytes::from_static(b"hello")),
                 min_mtu: 800,
                 max_mtu: 1400,
                 support_version: vec![8, 11, 12],
+                version_filter: None,
                 max_pending: 10,
+                max_connections: 10,
+                migration: false,
+                cookie_rotation: None,
+                encryption: false,
             },
         );
         tokio::pin!(handler);
@@ -420,9 +693,99 @@ mod test {
                 unconnected::Packet::OpenConnectionReply1 {
                     magic: (),
                     server_guid: 1919810,
-                    use_encryption: false,
+                    cookie: None,
+                    mtu: 1000
+                },
+                unconnected::Packet::OpenConnectionReply2 {
+                    magic: (),
+                    server_guid: 1919810,
+                    client_address: client_addr,
+                    mtu: 1000,
+                    encryption_enabled: false
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_handshake_cookie() {
+        let _guard = test_trace_log_setup();
+
+        let client_addr = "0.0.0.1:1".parse().unwrap();
+        let request2 = |cookie| {
+            Packet::Unconnected(unconnected::Packet::OpenConnectionRequest2 {
+                magic: (),
+                cookie,
+                server_address: "0.0.0.0:1".parse().unwrap(),
+                mtu: 1000,
+                client_guid: 114514,
+            })
+        };
+        let test_case = TestCase {
+            addr: client_addr,
+            source: VecDeque::from([
+                Packet::Unconnected(unconnected::Packet::OpenConnectionRequest1 {
+                    magic: (),
+                    protocol_version: 11,
+                    mtu: 1000,
+                }),
+                // the replayed open connection request 2 without the cookie
+                request2(None),
+            ]),
+            dst: vec![],
+        };
+        let handler = OfflineHandler::new(
+            test_case,
+            Config {
+                server_guid: 1919810,
+                advertisement: Advertisement::Static(Bytes::from_static(b"hello")),
+                min_mtu: 800,
+                max_mtu: 1400,
+                support_version: vec![8, 11, 12],
+                version_filter: None,
+                max_pending: 10,
+                max_connections: 10,
+                migration: false,
+                cookie_rotation: Some(Duration::from_secs(10)),
+                encryption: false,
+            },
+        );
+        tokio::pin!(handler);
+        let cookie = handler
+            .as_mut()
+            .project()
+            .cookies
+            .as_mut()
+            .unwrap()
+            .issue(client_addr, 11);
+        handler.as_mut().project().frame.source.extend([
+            request2(Some(cookie.wrapping_add(1))),
+            request2(Some(cookie)),
+        ]);
+        assert!(handler.next().await.is_none());
+        assert_eq!(handler.connected_count(), 1);
+        assert_eq!(handler.protocol_version(&client_addr), Some(11));
+        // no state is kept for the half-opened connections
+        assert_eq!(handler.pending.len(), 0);
+        assert_eq!(
+            handler.project().frame.dst,
+            vec![
+                unconnected::Packet::OpenConnectionReply1 {
+                    magic: (),
+                    server_guid: 1919810,
+                    cookie: Some(cookie),
                     mtu: 1000
                 },
+                unconnected::Packet::IncompatibleProtocol {
+                    server_protocol: 12,
+                    magic: (),
+                    server_guid: 1919810,
+                },
+                unconnected::Packet::IncompatibleProtocol {
+                    server_protocol: 12,
+                    magic: (),
+                    server_guid: 1919810,
+                },
                 unconnected::Packet::OpenConnectionReply2 {
                     magic: (),
                     server_guid: 1919810,
@@ -434,6 +797,32 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_cookies_rotation() {
+        let addr = "0.0.0.1:1".parse().unwrap();
+        let mut cookies = Cookies::new(Duration::from_secs(10));
+        let cookie = cookies.issue(addr, 11);
+        assert_eq!(cookies.validate(addr, cookie, &[8, 11]), Some(11));
+        assert_eq!(cookies.validate(addr, cookie, &[8]), None);
+        assert_eq!(
+            cookies.validate("0.0.0.2:1".parse().unwrap(), cookie, &[11]),
+            None
+        );
+
+        // the cookie of the previous secret is still accepted
+        let start = cookies.rotated_at;
+        cookies.rotate(start + Duration::from_secs(10));
+        assert_eq!(cookies.validate(addr, cookie, &[11]), Some(11));
+        cookies.rotate(start + Duration::from_secs(20));
+        assert_eq!(cookies.validate(addr, cookie, &[11]), None);
+
+        // both secrets are replaced after a long idle
+        let fresh = cookies.issue(addr, 11);
+        let rotated_at = cookies.rotated_at;
+        cookies.rotate(rotated_at + Duration::from_secs(25));
+        assert_eq!(cookies.validate(addr, fresh, &[11]), None);
+    }
+
     #[tokio::test]
     async fn test_offline_reject_unconnected_packet() {
         let _guard = test_trace_log_setup();
@@ -451,12 +840,17 @@ mod test {
         let handler = OfflineHandler::new(
             test_case,
             Config {
-                sever_guid: 1919810,
-                advertisement: Bytes::from_static(b"hello"),
+                server_guid: 1919810,
+                advertisement: Advertisement::Static(Bytes::from_static(b"hello")),
                 min_mtu: 800,
                 max_mtu: 1400,
                 support_version: vec![8, 11, 12],
+                version_filter: None,
                 max_pending: 10,
+                max_connections: 10,
+                migration: false,
+                cookie_rotation: None,
+                encryption: false,
             },
         );
         tokio::pin!(handler);
@@ -470,6 +864,60 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn test_offline_yield_unknown_frame_set() {
+        let _guard = test_trace_log_setup();
+
+        let addr: SocketAddr = "0.0.0.2:2".parse().unwrap();
+        let test_case = TestCase {
+            addr,
+            source: vec![
+                Packet::Connected(connected::Packet::FrameSet(FrameSet {
+                    seq_num: 0.into(),
+                    set: Frames::new(),
+                })),
+                Packet::Connected(connected::Packet::Ack(
+                    connected::AckOrNack::extend_from([0.into()].into_iter(), 1500).unwrap(),
+                )),
+            ]
+            .into_iter()
+            .collect(),
+            dst: vec![],
+        };
+        let handler = OfflineHandler::new(
+            test_case,
+            Config {
+                server_guid: 1919810,
+                advertisement: Advertisement::Static(Bytes::from_static(b"hello")),
+                min_mtu: 800,
+                max_mtu: 1400,
+                support_version: vec![8, 11, 12],
+                version_filter: None,
+                max_pending: 10,
+                max_connections: 10,
+                migration: true,
+                cookie_rotation: None,
+                encryption: false,
+            },
+        );
+        tokio::pin!(handler);
+        let (_, source) = handler.next().await.unwrap();
+        assert!(matches!(source, Source::Unknown(unknown) if unknown == addr));
+        handler.as_mut().reject(addr);
+        // only the frame sets are yielded, the ack is rejected as well
+        assert!(handler.next().await.is_none());
+        assert_eq!(
+            handler.project().frame.dst,
+            vec![
+                unconnected::Packet::ConnectionRequestFailed {
+                    magic: (),
+                    server_guid: 1919810,
+                };
+                2
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn test_offline_reject_wrong_connect_flow() {
         let _guard = test_trace_log_setup();
@@ -482,6 +930,7 @@ mod test {
                     source: vec![
                         unconnected::Packet::OpenConnectionRequest2 {
                             magic: (),
+                            cookie: None,
                             server_address: "0.0.0.0:1".parse().unwrap(),
                             mtu: 1000,
                             client_guid: 114514,
@@ -506,7 +955,7 @@ mod test {
                     unconnected::Packet::OpenConnectionReply1 {
                         magic: (),
                         server_guid: 1919810,
-                        use_encryption: false,
+                        cookie: None,
                         mtu: 1000,
                     },
                 ],
@@ -543,6 +992,7 @@ mod test {
                         },
                         unconnected::Packet::OpenConnectionRequest2 {
                             magic: (),
+                            cookie: None,
                             server_address: "0.0.0.0:1".parse().unwrap(),
                             mtu: 1000,
                             client_guid: 114514,
@@ -554,6 +1004,7 @@ mod test {
                         },
                         unconnected::Packet::OpenConnectionRequest2 {
                             magic: (),
+                            cookie: None,
                             server_address: "0.0.0.0:1".parse().unwrap(),
                             mtu: 1000,
                             client_guid: 114514,
@@ -568,7 +1019,7 @@ mod test {
                     unconnected::Packet::OpenConnectionReply1 {
                         magic: (),
                         server_guid: 1919810,
-                        use_encryption: false,
+                        cookie: None,
                         mtu: 1000,
                     },
                     unconnected::Packet::OpenConnectionReply2 {
@@ -581,7 +1032,7 @@ mod test {
                     unconnected::Packet::OpenConnectionReply1 {
                         magic: (),
                         server_guid: 1919810,
-                        use_encryption: false,
+                        cookie: None,
                         mtu: 1000,
                     },
                     unconnected::Packet::AlreadyConnected {
@@ -602,6 +1053,7 @@ mod test {
                         },
                         unconnected::Packet::OpenConnectionRequest2 {
                             magic: (),
+                            cookie: None,
                             server_address: "0.0.0.0:1".parse().unwrap(),
                             mtu: 10,
                             client_guid: 114514,
@@ -616,7 +1068,7 @@ mod test {
                     unconnected::Packet::OpenConnectionReply1 {
                         magic: (),
                         server_guid: 1919810,
-                        use_encryption: false,
+                        cookie: None,
                         mtu: 800,
                     },
                     unconnected::Packet::AlreadyConnected {
@@ -625,18 +1077,59 @@ mod test {
                     },
                 ],
             ),
+            (
+                TestCase {
+                    addr: "0.0.0.8:1".parse().unwrap(),
+                    // guid collides with the server
+                    source: vec![
+                        unconnected::Packet::OpenConnectionRequest1 {
+                            magic: (),
+                            protocol_version: 11,
+                            mtu: 1000,
+                        },
+                        unconnected::Packet::OpenConnectionRequest2 {
+                            magic: (),
+                            cookie: None,
+                            server_address: "0.0.0.0:1".parse().unwrap(),
+                            mtu: 1000,
+                            client_guid: 1919810,
+                        },
+                    ]
+                    .into_iter()
+                    .map(Packet::Unconnected)
+                    .collect(),
+                    dst: vec![],
+                },
+                vec![
+                    unconnected::Packet::OpenConnectionReply1 {
+                        magic: (),
+                        server_guid: 1919810,
+                        cookie: None,
+                        mtu: 1000,
+                    },
+                    unconnected::Packet::AlreadyConnected {
+                        magic: (),
+                        server_guid: 1919810,
+                    },
+                ],
+            ),
         ];
 
         for (case, expect) in test_cases {
             let handler = OfflineHandler::new(
                 case,
                 Config {
-                    sever_guid: 1919810,
-                    advertisement: Bytes::from_static(b"hello"),
+                    server_guid: 1919810,
+                    advertisement: Advertisement::Static(Bytes::from_static(b"hello")),
                     min_mtu: 800,
                     max_mtu: 1400,
                     support_version: vec![8, 11, 12],
+                    version_filter: None,
                     max_pending: 10,
+                    max_connections: 10,
+                    migration: false,
+                    cookie_rotation: None,
+                    encryption: false,
                 },
             );
             tokio::pin!(handler);
@@ -644,4 +1137,181 @@ mod test {
             assert_eq!(handler.project().frame.dst, expect);
         }
     }
+
+    #[tokio::test]
+    async fn test_offline_reject_when_connections_full() {
+        let _guard = test_trace_log_setup();
+
+        let test_case = TestCase {
+            addr: "0.0.0.7:1".parse().unwrap(),
+            source: vec![
+                unconnected::Packet::OpenConnectionRequest1 {
+                    magic: (),
+                    protocol_version: 11,
+                    mtu: 1000,
+                },
+                unconnected::Packet::OpenConnectionRequest2 {
+                    magic: (),
+                    cookie: None,
+                    server_address: "0.0.0.0:1".parse().unwrap(),
+                    mtu: 1000,
+                    client_guid: 114514,
+                },
+            ]
+            .into_iter()
+            .map(Packet::Unconnected)
+            .collect(),
+            dst: vec![],
+        };
+        let handler = OfflineHandler::new(
+            test_case,
+            Config {
+                server_guid: 1919810,
+                advertisement: Advertisement::Static(Bytes::from_static(b"hello")),
+                min_mtu: 800,
+                max_mtu: 1400,
+                support_version: vec![8, 11, 12],
+                version_filter: None,
+                max_pending: 10,
+                max_connections: 0,
+                migration: false,
+                cookie_rotation: None,
+                encryption: false,
+            },
+        );
+        tokio::pin!(handler);
+        assert!(handler.next().await.is_none());
+        assert_eq!(handler.connected_count(), 0);
+        assert_eq!(
+            handler.project().frame.dst,
+            vec![
+                unconnected::Packet::OpenConnectionReply1 {
+                    magic: (),
+                    server_guid: 1919810,
+                    cookie: None,
+                    mtu: 1000,
+                },
+                unconnected::Packet::NoFreeIncomingConnections {
+                    magic: (),
+                    server_guid: 1919810,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_reject_filtered_and_mixed_versions() {
+        let _guard = test_trace_log_setup();
+
+        let test_case = TestCase {
+            addr: "0.0.0.9:1".parse().unwrap(),
+            source: [11, 12, 8]
+                .into_iter()
+                .map(|protocol_version| {
+                    Packet::Unconnected(unconnected::Packet::OpenConnectionRequest1 {
+                        magic: (),
+                        protocol_version,
+                        mtu: 1000,
+                    })
+                })
+                .collect(),
+            dst: vec![],
+        };
+        let handler = OfflineHandler::new(
+            test_case,
+            Config {
+                server_guid: 1919810,
+                advertisement: Advertisement::Static(Bytes::from_static(b"hello")),
+                min_mtu: 800,
+                max_mtu: 1400,
+                support_version: vec![8, 11, 12],
+                version_filter: Some(VersionFilter::new(|_, version| version != 8)),
+                max_pending: 10,
+                max_connections: 10,
+                migration: false,
+                cookie_rotation: None,
+                encryption: false,
+            },
+        );
+        tokio::pin!(handler);
+        assert!(handler.next().await.is_none());
+        let incompatible = unconnected::Packet::IncompatibleProtocol {
+            server_protocol: 12,
+            magic: (),
+            server_guid: 1919810,
+        };
+        assert_eq!(
+            handler.project().frame.dst,
+            vec![
+                unconnected::Packet::OpenConnectionReply1 {
+                    magic: (),
+                    server_guid: 1919810,
+                    cookie: None,
+                    mtu: 1000,
+                },
+                // the client changed its version during the handshake
+                incompatible.clone(),
+                // rejected by the filter
+                incompatible,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_dynamic_advertisement() {
+        let _guard = test_trace_log_setup();
+
+        let test_case = TestCase {
+            addr: "0.0.0.8:1".parse().unwrap(),
+            source: (0..2)
+                .map(|send_timestamp| {
+                    Packet::Unconnected(unconnected::Packet::UnconnectedPing {
+                        send_timestamp,
+                        magic: (),
+                        client_guid: 114514,
+                    })
+                })
+                .collect(),
+            dst: vec![],
+        };
+        let players = std::sync::atomic::AtomicUsize::new(0);
+        let handler = OfflineHandler::new(
+            test_case,
+            Config {
+                server_guid: 1919810,
+                advertisement: Advertisement::Dynamic(Arc::new(move || {
+                    let online = players.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    Bytes::from(format!("MCPE;hello;{online}"))
+                })),
+                min_mtu: 800,
+                max_mtu: 1400,
+                support_version: vec![8, 11, 12],
+                version_filter: None,
+                max_pending: 10,
+                max_connections: 10,
+                migration: false,
+                cookie_rotation: None,
+                encryption: false,
+            },
+        );
+        tokio::pin!(handler);
+        assert!(handler.next().await.is_none());
+        assert_eq!(
+            handler.project().frame.dst,
+            vec![
+                unconnected::Packet::UnconnectedPong {
+                    send_timestamp: 0,
+                    server_guid: 1919810,
+                    magic: (),
+                    data: Bytes::from_static(b"MCPE;hello;0")
+                },
+                unconnected::Packet::UnconnectedPong {
+                    send_timestamp: 1,
+                    server_guid: 1919810,
+                    magic: (),
+                    data: Bytes::from_static(b"MCPE;hello;1")
+                },
+            ]
+        );
+    }
 }
@@ -8,26 +8,53 @@ use log::{debug, trace};
 use pin_project_lite::pin_project;
 
 use crate::link::SharedLink;
-use crate::packet::connected::FrameBody;
+use crate::opts::{
+    AckNow, ConnInfo, ConnStats, ConnectionInfo, FrameMeta, LastFrameMeta, PacketHandlers,
+    SendBufCap, Stats,
+};
+use crate::packet::connected::{FrameBody, DUMMY_SYSTEM_ADDRESS, MAX_SYSTEM_ADDRESSES_ENDPOINTS};
 use crate::packet::unconnected;
 use crate::utils::timestamp;
 use crate::{Peer, Role};
 
 pub(crate) trait HandleOnline: Sized {
-    fn handle_online(self, role: Role, peer: Peer, link: SharedLink) -> OnlineHandler<Self>;
+    fn handle_online(
+        self,
+        role: Role,
+        peer: Peer,
+        info: ConnectionInfo,
+        use_encryption: bool,
+        link: SharedLink,
+        on_connected: Option<OnConnected>,
+    ) -> OnlineHandler<Self>;
 }
 
+/// Called with the guid the client sent once its `ConnectionRequest` is accepted
+pub(crate) type OnConnected = Box<dyn FnOnce(u64) + Send + Sync>;
+
 impl<F> HandleOnline for F
 where
     F: Stream<Item = FrameBody>,
 {
-    fn handle_online(self, role: Role, peer: Peer, link: SharedLink) -> OnlineHandler<Self> {
+    fn handle_online(
+        self,
+        role: Role,
+        peer: Peer,
+        info: ConnectionInfo,
+        use_encryption: bool,
+        link: SharedLink,
+        on_connected: Option<OnConnected>,
+    ) -> OnlineHandler<Self> {
         OnlineHandler {
             frame: self,
             role,
             peer,
+            info,
+            use_encryption,
             state: HandshakeState::WaitConnRequest,
             link,
+            on_connected,
+            handlers: PacketHandlers::default(),
         }
     }
 }
@@ -38,8 +65,21 @@ pin_project! {
         frame: F,
         role: Role,
         peer: Peer,
+        // the negotiated information of the connection
+        info: ConnectionInfo,
+        // whether the frame bodies are encrypted, the connection request should agree with it
+        use_encryption: bool,
         state: HandshakeState,
         link: SharedLink,
+        on_connected: Option<OnConnected>,
+        handlers: PacketHandlers,
+    }
+
+    impl<F> PinnedDrop for OnlineHandler<F> {
+        fn drop(this: Pin<&mut Self>) {
+            // the sink of the connection is aborted on drop once the stream is dropped as well
+            this.link.on_half_dropped(None);
+        }
     }
 }
 
@@ -64,12 +104,20 @@ where
                         return Poll::Ready(None);
                     };
                     if let FrameBody::ConnectionRequest {
+                        client_guid,
                         request_timestamp,
                         use_encryption,
-                        ..
                     } = body
                     {
-                        if use_encryption {
+                        // the encryption is negotiated by the open connection reply 2, the clients
+                        // of this crate never disagree here. It rejects the
+                        // clients requesting the security of the reference
+                        // RakNet which sends the request in the clear.
+                        if use_encryption != *this.use_encryption {
+                            debug!(
+                                "[{}] reject connection request with use_encryption {use_encryption}",
+                                this.role
+                            );
                             this.link.send_unconnected(
                                 unconnected::Packet::ConnectionRequestFailed {
                                     magic: (),
@@ -86,14 +134,21 @@ where
                         } else {
                             SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)
                         };
+                        let mut system_addresses =
+                            [DUMMY_SYSTEM_ADDRESS; MAX_SYSTEM_ADDRESSES_ENDPOINTS];
+                        system_addresses[0] = system_addr;
                         this.link
                             .send_frame_body(FrameBody::ConnectionRequestAccepted {
                                 client_address: this.peer.addr,
                                 system_index: 0,
-                                system_addresses: [system_addr; 20],
+                                system_addresses,
                                 request_timestamp,
                                 accepted_timestamp: timestamp(),
                             });
+                        this.link.record_client_guid(client_guid);
+                        if let Some(on_connected) = this.on_connected.take() {
+                            on_connected(client_guid);
+                        }
                         *this.state = HandshakeState::WaitNewIncomingConn;
                         continue;
                     }
@@ -103,7 +158,12 @@ where
                     let Some(body) = ready!(this.frame.as_mut().poll_next(cx)) else {
                         return Poll::Ready(None);
                     };
-                    if let FrameBody::NewIncomingConnection { .. } = body {
+                    if let FrameBody::NewIncomingConnection {
+                        accepted_timestamp, ..
+                    } = body
+                    {
+                        // the accepted timestamp is ours echoed by the client
+                        this.link.record_handshake_rtt(accepted_timestamp);
                         debug!("[{}] accept new incoming connection", this.role);
                         *this.state = HandshakeState::Connected;
                         continue;
@@ -111,7 +171,11 @@ where
                     // FIXME: it is wrong, client should finish handshake before sending user data
                     // currently, the client's handshake is lazy, so user data may be sent before
                     match body {
-                        FrameBody::User(data) => return Poll::Ready(Some(data)),
+                        FrameBody::User(data) => {
+                            if let Some(data) = this.handlers.dispatch(this.info.conn_id(), data) {
+                                return Poll::Ready(Some(data));
+                            }
+                        }
                         _ => {
                             debug!(
                                 "[{}] ignore packet {body:?} on WaitNewIncomingConn",
@@ -136,7 +200,11 @@ where
                                 server_timestamp: timestamp(),
                             });
                         }
-                        FrameBody::User(data) => return Poll::Ready(Some(data)),
+                        FrameBody::User(data) => {
+                            if let Some(data) = this.handlers.dispatch(this.info.conn_id(), data) {
+                                return Poll::Ready(Some(data));
+                            }
+                        }
                         _ => {
                             debug!("[{}] ignore packet {body:?} on Connected", this.role);
                         }
@@ -146,3 +214,53 @@ where
         }
     }
 }
+
+impl<F> OnlineHandler<F> {
+    /// Pass the user packets of the registered ids to their handlers instead of yielding them
+    pub(crate) fn packet_handlers(mut self, handlers: PacketHandlers) -> Self {
+        self.handlers = handlers;
+        self
+    }
+}
+
+impl<F> ConnInfo for OnlineHandler<F> {
+    fn connection_info(&self) -> ConnectionInfo {
+        self.info
+            .with_effective_mtu(self.link.mtu())
+            .with_client_guid(self.link.client_guid())
+            .with_close_reason(self.link.close_reason())
+    }
+}
+
+impl<F> ConnStats for OnlineHandler<F> {
+    fn stats(&self) -> Stats {
+        self.link.stats()
+    }
+}
+
+impl<F> LastFrameMeta for OnlineHandler<F> {
+    fn last_frame_meta(&self) -> Option<FrameMeta> {
+        self.link.last_frame_meta()
+    }
+}
+
+impl<F> SendBufCap for OnlineHandler<F> {
+    fn send_buf_cap(&self) -> usize {
+        self.link.send_buf_cap()
+    }
+
+    fn set_send_buf_cap(&self, cap: usize) {
+        assert!(cap > 0, "cap must larger than 0");
+        self.link.set_send_buf_cap(cap);
+    }
+
+    fn reserve(&self, frames: usize) {
+        self.link.request_reserve(frames);
+    }
+}
+
+impl<F> AckNow for OnlineHandler<F> {
+    fn ack_now(&self) {
+        self.link.request_ack_now();
+    }
+}
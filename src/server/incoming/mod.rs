@@ -1,33 +1,121 @@
 use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::{Buf, Bytes};
 use futures::{Sink, Stream};
+use log::warn;
 
 use super::handler::offline;
-use crate::opts::TraceInfo;
-use crate::{codec, Message, Role};
+use crate::errors::{CodecError, ConfigError, DecodeErrorHook};
+#[cfg(feature = "fec")]
+use crate::opts::FecOptions;
+use crate::opts::{
+    AckNow, AckReceipt, ConnInfo, ConnStats, DatagramTap, FrameCompression, FrameCrypto,
+    IncomingConnections, IncomingShutdown, IncomingStats, LastFrameMeta, PacketHandlers,
+    ReceiptHook, Retransmission, RetransmitHook, SendBufCap, SendUnconnected, SocketOptions,
+    TraceInfo, TrySend,
+};
+use crate::{codec, estimator, link, opts, packet, ConnId, DisconnectReason, Message, Role};
 
 /// Incoming implementation by using tokio's UDP framework
 #[cfg(feature = "tokio-rt")]
 mod tokio;
 
+/// The lifecycle events of the connections accepted by the incoming
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// A new connection is established, it is emitted once the `ConnectionRequest` of the client
+    /// is accepted while polling the source of the connection
+    Connected {
+        addr: SocketAddr,
+        /// The guid sent in the offline handshake
+        guid: u64,
+        /// The guid sent in `ConnectionRequest`
+        client_guid: u64,
+        mtu: u16,
+    },
+    /// The connection is migrated to a new address of the peer
+    Migrated { from: SocketAddr, to: SocketAddr },
+    /// The connection is closed and removed from the incoming, it is emitted only for the
+    /// connections that emitted `Connected`, not for the ones that ended in the handshake
+    Disconnected {
+        addr: SocketAddr,
+        reason: DisconnectReason,
+    },
+}
+
+type ConnectionEventFn = dyn Fn(ConnectionEvent) + Send + Sync;
+
+/// The hook receives the connection lifecycle events
+#[derive(Clone)]
+struct ConnectionEventHook(Arc<ConnectionEventFn>);
+
+impl std::fmt::Debug for ConnectionEventHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ConnectionEventHook(..)")
+    }
+}
+
+impl ConnectionEventHook {
+    fn call(&self, event: ConnectionEvent) {
+        (self.0)(event);
+    }
+}
+
 /// Incoming config
 #[derive(Debug, Clone)]
 pub struct Config {
     /// The send buffer of each IO polled by the incoming
     send_buf_cap: usize,
+    /// The maximum number of received frame sets waiting to be read
+    recv_buf_cap: usize,
+    /// The send buffer size in bytes of each IO polled by the incoming, 0 means no limit
+    send_buf_bytes: usize,
+    /// The number of packets a backlogged connection sends before yielding the socket to others
+    send_quantum: usize,
+    /// The interval to flush the IO in background, `None` means disabled
+    flush_interval: Option<Duration>,
+    /// Whether the IO resends and acknowledges in background without the application
+    self_driving: bool,
+    /// The maximum time to wait for the unacked frames when closing, `None` means no limit
+    close_timeout: Option<Duration>,
+    /// Whether the flushing fails when a reliable frame expired before it was sent
+    fail_expired_reliable: bool,
+    /// The maximum size in bytes of the frames waiting for acknowledgement, 0 means no limit
+    resend_buf_bytes: usize,
+    /// The window of the loss rate estimation
+    loss_window: Duration,
+    /// The lower bound of the retransmission timeout
+    min_rto: Duration,
+    /// The upper bound of the retransmission timeout
+    max_rto: Duration,
+    /// The maximum number of packets acknowledged by an incoming ACK or NACK
+    max_ack_count: usize,
+    /// The capacity of each of the ACK buffers of a connection
+    ack_buffer: usize,
+    /// The maximum number of datagrams sent in one batch, 1 means disabled
+    send_batch_size: usize,
+    /// The maximum number of datagrams received in one batch, 1 means disabled
+    recv_batch_size: usize,
     /// The server guid, used to identify the server, initialized by random
-    sever_guid: u64,
+    server_guid: u64,
     /// The advertisement, sent to the client when the client pings the server
-    advertisement: Bytes,
+    advertisement: offline::Advertisement,
     /// The minimum mtu, the default value is 510
     min_mtu: u16,
     /// The maximum mtu, the default value is 1500
     max_mtu: u16,
     /// Supported raknet versions, sorted
     support_version: Vec<u8>,
+    /// Decide whether the version requested by a client is accepted after the supported versions
+    version_filter: Option<offline::VersionFilter>,
     /// The maximum pending(aka. half-opened connections)
     max_pending: usize,
+    /// The maximum established connections, new handshakes will receive
+    /// `NoFreeIncomingConnections` once it is reached
+    max_connections: usize,
     /// Limit the max size of a parted frames set, 0 means no limit
     /// It will abort the split frame if the `parted_size` reaches limit.
     /// Enable it to avoid `DoS` attack.
@@ -40,6 +128,52 @@ pub struct Config {
     max_parted_count: usize,
     /// Maximum ordered channel, the value should be less than 256
     max_channels: usize,
+    /// Limit the max frames buffered in each ordering channel, 0 means no limit
+    max_reorder_depth: usize,
+    /// The magic sequence carried by the offline packets, the default value is the standard
+    /// `RakNet` magic
+    magic: [u8; 16],
+    /// The hook receives the decode errors of the packets from peers
+    decode_err_hook: Option<DecodeErrorHook>,
+    /// The hook receives the retransmissions of the connections
+    retransmit_hook: Option<RetransmitHook>,
+    /// The hook receives the fates of the messages tagged with a receipt
+    receipt_hook: Option<ReceiptHook>,
+    /// The handlers of the user packets by their ids
+    packet_handlers: PacketHandlers,
+    /// The hook receives the connection lifecycle events
+    conn_event_hook: Option<ConnectionEventHook>,
+    /// Encrypt/decrypt the frame bodies, `None` means disabled
+    crypto: Option<Arc<dyn FrameCrypto>>,
+    /// Compress/decompress the frame bodies, `None` means disabled
+    compression: Option<Arc<dyn FrameCompression>>,
+    /// Observe the raw datagrams, `None` means disabled
+    tap: Option<Arc<dyn DatagramTap>>,
+    /// Whether the CRC-32 is appended to each frame set and validated
+    checksum: bool,
+    /// Whether the sequence numbers of the frame sets start at a random number
+    random_initial_seq_num: bool,
+    /// Whether the connections are migrated when the peers change their addresses
+    connection_migration: bool,
+    /// Whether only the frame sets carrying reliable frames are acknowledged
+    ack_reliable_only: bool,
+    /// The number of consecutive timeouts of the large frame sets before reducing the MTU, 0
+    /// means disabled
+    mtu_blackhole_retries: usize,
+    /// Whether the frame sets are spaced out by the pacing rate
+    pacing: bool,
+    /// Whether a buffered frame set is sent before the acks
+    data_first: bool,
+    /// The maximum number of packets sent in one poll of flushing, 0 means no limit
+    flush_budget: usize,
+    /// Whether a connection dropped without being closed is aborted
+    disconnect_on_drop: bool,
+    socket_options: SocketOptions,
+    /// The interval to rotate the secret of the handshake cookies, `None` means disabled
+    handshake_cookie: Option<Duration>,
+    /// Protect the unreliable frames by the parity frames, `None` means disabled
+    #[cfg(feature = "fec")]
+    fec: Option<FecOptions>,
 }
 
 impl Default for Config {
@@ -52,18 +186,126 @@ impl Config {
     pub fn new() -> Self {
         Self {
             send_buf_cap: 1024,
-            sever_guid: rand::random(),
-            advertisement: Bytes::new(),
+            recv_buf_cap: 1024,
+            send_buf_bytes: 0,
+            send_quantum: 32,
+            flush_interval: None,
+            self_driving: false,
+            close_timeout: None,
+            fail_expired_reliable: false,
+            resend_buf_bytes: 0,
+            loss_window: Duration::from_secs(1),
+            min_rto: estimator::DEFAULT_MIN_RTO,
+            max_rto: estimator::DEFAULT_MAX_RTO,
+            max_ack_count: packet::connected::DEFAULT_MAX_ACK_COUNT,
+            ack_buffer: link::DEFAULT_ACK_BUFFER,
+            send_batch_size: 1,
+            recv_batch_size: 1,
+            server_guid: rand::random(),
+            advertisement: offline::Advertisement::Static(Bytes::new()),
             min_mtu: 510,
             max_mtu: 1500,
             support_version: vec![9, 11, 13],
+            version_filter: None,
             max_pending: 1024,
+            max_connections: 1024,
             max_parted_size: 256,
             max_parted_count: 256,
             max_channels: 1,
+            max_reorder_depth: 0,
+            magic: packet::MAGIC,
+            decode_err_hook: None,
+            retransmit_hook: None,
+            receipt_hook: None,
+            packet_handlers: PacketHandlers::default(),
+            conn_event_hook: None,
+            crypto: None,
+            compression: None,
+            tap: None,
+            checksum: false,
+            random_initial_seq_num: false,
+            connection_migration: false,
+            ack_reliable_only: false,
+            mtu_blackhole_retries: 0,
+            pacing: false,
+            data_first: false,
+            flush_budget: 0,
+            disconnect_on_drop: true,
+            socket_options: SocketOptions::default(),
+            handshake_cookie: None,
+            #[cfg(feature = "fec")]
+            fec: None,
         }
     }
 
+    /// Start building a config from the default values, it is the same as [`Config::new`]. The
+    /// options are set by the methods below, and validated by [`Config::build`] at last.
+    pub fn builder() -> Self {
+        Self::new()
+    }
+
+    /// Validate the options which depend on each other or would fail the server later, so that an
+    /// invalid config is reported up front instead of panicking in the connections.
+    ///
+    /// # Errors
+    /// Returns [`ConfigError`] telling the first invalid option.
+    pub fn build(self) -> Result<Self, ConfigError> {
+        self.validate()?;
+        Ok(self)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        opts::validate_link(
+            self.send_buf_cap,
+            self.max_channels,
+            self.max_ack_count,
+            self.ack_buffer,
+            (self.min_rto, self.max_rto),
+        )?;
+        if self.min_mtu > self.max_mtu {
+            return Err(ConfigError::new(
+                "min_mtu",
+                "it must not be greater than max_mtu",
+            ));
+        }
+        if self.support_version.is_empty() {
+            return Err(ConfigError::new(
+                "support_version",
+                "at least one version is required",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Clamp the invalid options to the nearest valid values, each one is logged. It is used by
+    /// `make_incoming`, which cannot fail, so that an unbuilt config does not panic in the
+    /// connections later.
+    pub(crate) fn clamped(mut self) -> Self {
+        while let Err(err) = self.validate() {
+            warn!("[{}] {err}, it is clamped", self.server_role());
+            match err.option() {
+                "send_buf_cap" => self.send_buf_cap = 1,
+                "max_channels" => {
+                    self.max_channels = self.max_channels.clamp(1, opts::MAX_CHANNELS);
+                }
+                "max_ack_count" => {
+                    self.max_ack_count = self
+                        .max_ack_count
+                        .clamp(1, packet::connected::MAX_ACK_COUNT_LIMIT);
+                }
+                "ack_buffer" => self.ack_buffer = 1,
+                "rto_bounds" => {
+                    self.min_rto = self.min_rto.max(Duration::from_millis(1));
+                    self.max_rto = self.max_rto.max(self.min_rto);
+                }
+                "min_mtu" => self.min_mtu = self.max_mtu,
+                "support_version" => self.support_version = Config::new().support_version,
+                option => unreachable!("the option {option} is not clamped"),
+            }
+        }
+        self
+    }
+
     /// Set the send buffer of each IO polled by the incoming
     /// The default value is 1024
     pub fn send_buf_cap(mut self, cap: usize) -> Self {
@@ -71,17 +313,179 @@ impl Config {
         self
     }
 
-    /// Set the server guid
+    /// Set the send buffer size in bytes of each IO polled by the incoming, it is accounted by the
+    /// total size of buffered frames and works along with `send_buf_cap`
+    /// The default value is 0, which means no limit
+    pub fn send_buf_bytes(mut self, bytes: usize) -> Self {
+        self.send_buf_bytes = bytes;
+        self
+    }
+
+    /// Set the number of packets a connection sends in a turn when other connections on the same
+    /// socket have buffered frames too, after which it yields the socket to them, so that a
+    /// backed-up connection could not starve the others. The connections take turns in a
+    /// round-robin way, a connection sending alone is never throttled.
+    /// The default value is 32, and 0 disables the fair sharing
+    pub fn send_quantum(mut self, quantum: usize) -> Self {
+        self.send_quantum = quantum;
+        self
+    }
+
+    /// Set the maximum number of received frame sets waiting to be read by each connection. When it
+    /// is full, the new frame sets are dropped without acking, so the peer resends them later
+    /// and slows down instead of buffering them without limit.
+    /// The default value is 1024
+    ///
+    /// # Panics
+    /// Panics if `cap` is zero.
+    pub fn recv_buf_cap(mut self, cap: usize) -> Self {
+        assert!(
+            cap > 0,
+            "the receive buffer capacity must be greater than zero"
+        );
+        self.recv_buf_cap = cap;
+        self
+    }
+
+    /// Set the interval to flush each IO polled by the incoming in background, so the buffered
+    /// frames will not linger if there is no explicit flush
+    /// The default value is None, which means disabled
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = Some(interval);
+        self
+    }
+
+    /// Set whether each IO polled by the incoming drives itself in background, i.e. it resends the
+    /// unacked frames and sends the ACKs even if the application stops polling it. It flushes at
+    /// the `flush_interval`, or every 10ms if that is not set
+    /// The default value is false
+    pub fn self_driving(mut self, enabled: bool) -> Self {
+        self.self_driving = enabled;
+        self
+    }
+
+    /// Set the maximum time to wait for the unacked frames when closing each IO polled by the
+    /// incoming, the unacked frames will be abandoned and the closing returns a `TimedOut` error
+    /// carrying [`CloseTimedOut`](crate::errors::CloseTimedOut) after that
+    /// The default value is None, which means no limit
+    pub fn close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail the flushing of each IO polled by the incoming with a `TimedOut` error carrying
+    /// [`FrameExpired`](crate::errors::FrameExpired) when a reliable frame expired before it was
+    /// sent, see [`Message::set_ttl`](crate::Message::set_ttl). The expired reliable frames are
+    /// sent late otherwise.
+    /// The default value is false
+    pub fn fail_expired_reliable(mut self, enabled: bool) -> Self {
+        self.fail_expired_reliable = enabled;
+        self
+    }
+
+    /// Set the maximum total size in bytes of the sent reliable frames waiting for
+    /// acknowledgement. Once it is reached, no more messages are accepted by the sink until the
+    /// acknowledgements free the room, which bounds the memory of huge reliable transfers. The
+    /// frames to be resent are still sent.
+    /// The default value is 0, which means no limit
+    pub fn resend_buf_bytes(mut self, bytes: usize) -> Self {
+        self.resend_buf_bytes = bytes;
+        self
+    }
+
+    /// Set the window of the loss rate estimation in [`Stats`](crate::opts::Stats) of each IO
+    /// polled by the incoming, the loss ratio of each window is smoothed into the estimation.
+    /// The default value is 1 second
+    /// # Panics
+    /// Panics if the window is zero
+    pub fn loss_window(mut self, window: Duration) -> Self {
+        assert!(!window.is_zero(), "loss_window should be larger than 0");
+        self.loss_window = window;
+        self
+    }
+
+    /// Set the bounds of the retransmission timeout of all connections. It is estimated from the
+    /// round trips of the acknowledged frame sets as `SRTT + 4 * RTTVAR` (RFC6298), then
+    /// clamped to `[min, max]`. A lower bound below the default suits the low latency links, at
+    /// the cost of the spurious resends when the peer delays its acks.
+    /// The default value is 1 second to 60 seconds, `min` must be larger than 0 and not larger
+    /// than `max`
+    pub fn rto_bounds(mut self, min: Duration, max: Duration) -> Self {
+        self.min_rto = min;
+        self.max_rto = max;
+        self
+    }
+
+    /// Set the maximum number of packets acknowledged by a single incoming ACK or NACK, a larger
+    /// one is dropped as a decode error. Raising it lets the peers acknowledge more packets at
+    /// once on the links with huge windows, at the cost of the memory and the time spent on
+    /// decoding each ACK sent by a malicious peer.
+    /// The default value is 8192, it must be in `1..=65536`
+    pub fn max_ack_count(mut self, count: usize) -> Self {
+        self.max_ack_count = count;
+        self
+    }
+
+    /// Set the capacity of the incoming ACK and NACK buffers of each connection accepted by the
+    /// incoming, the overwhelming ones are merged into the oldest one when a buffer is
+    /// full. The outgoing ACK buffer is preallocated with it as well. A larger buffer keeps more
+    /// ACKs apart under a burst, while every connection preallocates about 100 bytes per slot.
+    /// The default value is 1024, it must be larger than 0
+    pub fn ack_buffer(mut self, size: usize) -> Self {
+        self.ack_buffer = size;
+        self
+    }
+
+    /// Set the maximum number of datagrams sent in one batch by each IO polled by the incoming,
+    /// which is submitted by one `sendmmsg` syscall on Linux. The datagrams are buffered until the
+    /// batch is full or the IO is flushed.
+    /// The default value is 1, which means disabled
+    /// # Panics
+    /// Panics if the size is 0
+    pub fn send_batch_size(mut self, size: usize) -> Self {
+        assert!(size > 0, "send_batch_size should be larger than 0");
+        self.send_batch_size = size;
+        self
+    }
+
+    /// Set the maximum number of datagrams received in one batch by the incoming, which is
+    /// received by one `recvmmsg` syscall on Linux.
+    /// The default value is 1, which means disabled
+    /// # Panics
+    /// Panics if the size is 0
+    pub fn recv_batch_size(mut self, size: usize) -> Self {
+        assert!(size > 0, "recv_batch_size should be larger than 0");
+        self.recv_batch_size = size;
+        self
+    }
+
+    /// Set the server guid, it should be unique in a cluster of servers. The clients with the
+    /// same guid will be rejected.
     /// The default value is random
-    pub fn sever_guid(mut self, guid: u64) -> Self {
-        self.sever_guid = guid;
+    pub fn server_guid(mut self, guid: u64) -> Self {
+        self.server_guid = guid;
         self
     }
 
+    /// Set the server guid
+    #[deprecated(note = "use `server_guid` instead")]
+    pub fn sever_guid(self, guid: u64) -> Self {
+        self.server_guid(guid)
+    }
+
     /// Set the advertisement
     /// The default value is empty
     pub fn advertisement(mut self, advertisement: impl Buf) -> Self {
-        self.advertisement = Bytes::copy_from_slice(advertisement.chunk());
+        self.advertisement =
+            offline::Advertisement::Static(Bytes::copy_from_slice(advertisement.chunk()));
+        self
+    }
+
+    /// Set the advertisement maker, it is invoked every time the server receives an
+    /// unconnected ping, so the advertisement (e.g. MOTD) could be generated dynamically
+    /// It replaces the advertisement set by [`Config::advertisement`]
+    pub fn advertisement_fn(mut self, make: impl Fn() -> Bytes + Send + Sync + 'static) -> Self {
+        self.advertisement = offline::Advertisement::Dynamic(Arc::new(make));
         self
     }
 
@@ -107,6 +511,34 @@ impl Config {
         self
     }
 
+    /// Set the filter deciding whether the raknet version requested by a client is accepted, it
+    /// is called with the address of the client after the version is checked against the
+    /// supported ones. The rejected clients receive `IncompatibleProtocolVersion`, the filter
+    /// could also be used to flag the clients of a legacy version before accepting them. The
+    /// negotiated version is exposed by the `ConnectionInfo` of the connection.
+    /// The default value is None
+    pub fn version_filter(
+        mut self,
+        filter: impl Fn(SocketAddr, u8) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.version_filter = Some(offline::VersionFilter::new(filter));
+        self
+    }
+
+    /// Reply the open connection request 1 with a stateless cookie, which must be echoed by the
+    /// open connection request 2, instead of keeping the half-opened connection until then. It
+    /// defends against the spoofed or replayed handshakes filling up the pending ones, like the
+    /// SYN cookies of TCP. The cookie is derived from the address and the protocol version of the
+    /// client with a secret rotated every `rotation`, so it expires in at most twice of it.
+    ///
+    /// The cookie is sent by the security flag of the open connection reply 1, the clients which
+    /// do not echo it are rejected. The clients of this crate always echo it.
+    /// The default value is None, which means disabled
+    pub fn handshake_cookie(mut self, rotation: Duration) -> Self {
+        self.handshake_cookie = Some(rotation);
+        self
+    }
+
     /// Set the maximum pending(aka. half-opened connections)
     /// The default value is 1024
     pub fn max_pending(mut self, pending: usize) -> Self {
@@ -114,6 +546,13 @@ impl Config {
         self
     }
 
+    /// Set the maximum established connections
+    /// The default value is 1024
+    pub fn max_connections(mut self, connections: usize) -> Self {
+        self.max_connections = connections;
+        self
+    }
+
     /// Set the maximum parted size
     /// The default value is 256
     /// The maximum number of inflight parted frames is `max_parted_size`*`max_parted_count`nt
@@ -132,7 +571,7 @@ impl Config {
 
     /// Set the maximum channels
     /// The default value is 1
-    /// The maximum value should be less than 256
+    /// The maximum value is 254, the greater ones are rejected by `build`
     /// # Panics
     /// Panics if the channels is greater than 256
     pub fn max_channels(mut self, channels: usize) -> Self {
@@ -141,14 +580,256 @@ impl Config {
         self
     }
 
+    /// Set the maximum number of frames buffered in each ordering channel until the missing ones
+    /// arrive, which bounds the memory a peer withholding one frame could take. When it is
+    /// exceeded by a sequenced frame, the missing frames are skipped and the buffered ones are
+    /// read with a gap. When it is exceeded by an ordered frame, the connection cannot continue in
+    /// order, so the decode error is reported and the received stream ends.
+    /// The default value is 0, which means no limit
+    pub fn max_reorder_depth(mut self, depth: usize) -> Self {
+        self.max_reorder_depth = depth;
+        self
+    }
+
+    /// Set the magic sequence carried by the offline packets
+    /// The default value is the standard `RakNet` magic
+    /// Offline packets with other magic will be rejected
+    pub fn magic(mut self, magic: [u8; 16]) -> Self {
+        self.magic = magic;
+        self
+    }
+
+    /// Set the hook to receive the decode errors with the peer address, so the malformed traffic
+    /// could be inspected without enabling logs. The malformed packets are still dropped.
+    /// The default value is None
+    pub fn on_decode_error(
+        mut self,
+        hook: impl Fn(SocketAddr, &CodecError) + Send + Sync + 'static,
+    ) -> Self {
+        self.decode_err_hook = Some(DecodeErrorHook::new(hook));
+        self
+    }
+
+    /// Set the hook to receive the retransmissions of all connections, it is invoked with the
+    /// sequence number of each lost frame set whose frames are queued to be resent, either
+    /// reported by a NACK or timed out, e.g. to adapt the content quality to the loss. It is
+    /// invoked on the sending path, so it should be cheap and not block.
+    /// The default value is None
+    pub fn on_retransmit(mut self, hook: impl Fn(Retransmission) + Send + Sync + 'static) -> Self {
+        self.retransmit_hook = Some(RetransmitHook::new(hook));
+        self
+    }
+
+    /// Set the hook to receive the fates of the messages tagged with a receipt by
+    /// [`Message::set_receipt`](crate::Message::set_receipt) on all connections, it is invoked
+    /// once such a message is acknowledged or lost, see `Message::set_receipt` for when it is
+    /// lost. Only the messages sent with the `*WithAckReceipt` reliabilities are tracked. It is
+    /// invoked on the sending path, so it should be cheap and not block.
+    /// The default value is None
+    pub fn on_ack_receipt(mut self, hook: impl Fn(AckReceipt) + Send + Sync + 'static) -> Self {
+        self.receipt_hook = Some(ReceiptHook::new(hook));
+        self
+    }
+
+    /// Register the handler of the user packets with the packet id `id`, which is the first byte
+    /// of the payload, e.g. to intercept the application's own control packets. The handler is
+    /// invoked with the id of the connection and the whole payload when any connection receives
+    /// such a packet, which is not passed to the stream then. The packets of the ids without a
+    /// handler are passed through to the stream as before, and registering the same id again
+    /// replaces the handler. The ids of the `RakNet` internal packets (e.g. `0x00` of
+    /// `ConnectedPing`) are handled by the connection and never reach the handlers. It is
+    /// invoked on the receiving path, so it should be cheap and not block.
+    /// The default value has no handler
+    pub fn packet_handler(
+        mut self,
+        id: u8,
+        handler: impl Fn(ConnId, Bytes) + Send + Sync + 'static,
+    ) -> Self {
+        self.packet_handlers.insert(id, handler);
+        self
+    }
+
+    /// Set the hook to receive the connection lifecycle events, it is invoked when a connection
+    /// is established or closed, and should not block.
+    /// The default value is None
+    pub fn on_connection_event(
+        mut self,
+        hook: impl Fn(ConnectionEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.conn_event_hook = Some(ConnectionEventHook(Arc::new(hook)));
+        self
+    }
+
+    /// Set the crypto to encrypt/decrypt the frame bodies of all connections, the clients must
+    /// request the connection with the same encryption, or they will be rejected.
+    /// The default value is None, which means the connections are not encrypted
+    pub fn crypto(mut self, crypto: impl FrameCrypto) -> Self {
+        self.crypto = Some(Arc::new(crypto));
+        self
+    }
+
+    /// Set the compression to compress/decompress the frame bodies of all connections, the
+    /// clients must be configured with the same compression as it is not negotiated by the
+    /// handshake. The default value is None, which means the frame bodies are not compressed
+    pub fn compression(mut self, compression: impl FrameCompression) -> Self {
+        self.compression = Some(Arc::new(compression));
+        self
+    }
+
+    /// Set the tap to observe the raw datagrams of all sockets of the server, the incoming ones
+    /// before decoding and the outgoing ones after encoding. The default value is None
+    pub fn tap(mut self, tap: impl DatagramTap) -> Self {
+        self.tap = Some(Arc::new(tap));
+        self
+    }
+
+    fn emit(&self, event: ConnectionEvent) {
+        if let Some(hook) = &self.conn_event_hook {
+            hook.call(event);
+        }
+    }
+
+    /// Append a CRC-32 to each frame set and validate it on receiving, the frame sets failing
+    /// the checksum are dropped and resent as lost. It is not wire compatible, the clients must
+    /// enable it as well.
+    /// The default value is false
+    pub fn checksum(mut self, enabled: bool) -> Self {
+        self.checksum = enabled;
+        self
+    }
+
+    /// Start the sequence numbers of the frame sets sent by each IO polled by the incoming at a
+    /// random number instead of 0, which makes the off-path injection harder. The peer learns
+    /// it from the first frame set, so it is wire compatible.
+    /// The default value is false
+    pub fn random_initial_seq_num(mut self, enabled: bool) -> Self {
+        self.random_initial_seq_num = enabled;
+        self
+    }
+
+    /// Migrate the connection when its peer changes the address, e.g. a mobile client roaming
+    /// between networks. A frame set from an unknown address is taken as the continuation of the
+    /// connection on the same socket whose next expected sequence number is right before it, and
+    /// the connection is moved to that address with its state kept if there is exactly one such
+    /// connection. Otherwise the frame set is rejected as before.
+    ///
+    /// If `crypto` is installed, the frame set has to be decrypted by it before the connection is
+    /// moved, so only the peer holding the key could migrate it. Otherwise the new address is not
+    /// validated at all, anyone guessing the sequence number (e.g. observing the traffic) could
+    /// take over the connection. Randomizing the initial sequence numbers only makes the blind
+    /// guessing harder, install `crypto` if the connections should not be hijacked.
+    /// The default value is false
+    pub fn connection_migration(mut self, enabled: bool) -> Self {
+        self.connection_migration = enabled;
+        self
+    }
+
+    /// Acknowledge only the frame sets carrying at least one reliable frame, which saves the ACKs
+    /// of the unreliable traffic since the peer never resends it. The gaps in the sequence are
+    /// still reported by NACK as the receiver cannot tell whether a lost frame set was reliable,
+    /// the peer ignores the ones it does not track.
+    /// The default value is false
+    pub fn ack_reliable_only(mut self, enabled: bool) -> Self {
+        self.ack_reliable_only = enabled;
+        self
+    }
+
+    /// Reduce the effective MTU of the connections when the frame sets larger than any acknowledged
+    /// one are lost (by a NACK or a timeout) `retries` times in a row while the smaller ones still
+    /// get through, which is the sign of a path MTU blackhole (e.g. a tunnel dropping the large
+    /// datagrams silently). The MTU is reduced to fit the largest acknowledged frame set (at least
+    /// 576), and never grows back. The messages sent afterwards are split and packed by the
+    /// reduced MTU. The frames already split for the old one could not be split again, so they are
+    /// abandoned like [`Message::set_abandon_after`](crate::Message::set_abandon_after) and
+    /// counted in [`Stats::frames_abandoned`](crate::opts::Stats::frames_abandoned): the ordered
+    /// ones are skipped by the receiver, and the others are never delivered.
+    /// The effective MTU is
+    /// exposed by
+    /// [`ConnectionInfo::effective_mtu`](crate::opts::ConnectionInfo::effective_mtu).
+    /// The default value is 0, which means disabled
+    pub fn mtu_blackhole_retries(mut self, retries: usize) -> Self {
+        self.mtu_blackhole_retries = retries;
+        self
+    }
+
+    /// Space out the frame sets of the connections by a pacing rate instead of sending them in a
+    /// burst, which avoids the burst loss at the start of a large transfer. The rate starts
+    /// conservatively from an initial window of 10 frame sets and ramps up on the
+    /// acknowledgements by the slow start until the first loss, after which it grows slowly.
+    /// The pacing rate is exposed by [`Stats::pacing_rate`](crate::opts::Stats::pacing_rate).
+    /// The default value is false
+    pub fn pacing(mut self, enabled: bool) -> Self {
+        self.pacing = enabled;
+        self
+    }
+
+    /// Send a buffered frame set of the connections before the acks instead of after them when
+    /// flushing, which cuts the latency of the data when the buffer is data-heavy. The acks are
+    /// deferred by at most one frame set. A datagram carries only one packet, so the acks could
+    /// not be combined with the frame sets anyway.
+    /// The default value is false, the acks are sent first to free the resend buffer of the peer
+    pub fn data_first(mut self, enabled: bool) -> Self {
+        self.data_first = enabled;
+        self
+    }
+
+    /// Limit the number of packets sent in one poll of flushing each connection, the flush yields
+    /// after sending `budget` packets and wakes itself up to send the rest. It keeps a large
+    /// buffer from starving other tasks on a busy single-threaded runtime, at the cost of more
+    /// polls to flush it.
+    /// The default value is 0, which means no limit
+    pub fn flush_budget(mut self, budget: usize) -> Self {
+        self.flush_budget = budget;
+        self
+    }
+
+    /// Abort the connections whose sinks are dropped without being closed, a best-effort
+    /// `DisconnectNotification` is sent on a detached task so that the peer learns it promptly
+    /// instead of waiting for the timeout. It happens once the stream is dropped as well, a
+    /// connection whose stream is still read stays open. The drop never blocks. Disable it to drop
+    /// the connections silently.
+    /// The default value is true
+    pub fn disconnect_on_drop(mut self, enabled: bool) -> Self {
+        self.disconnect_on_drop = enabled;
+        self
+    }
+
+    /// Set the options applied to the sockets of the server by `setsockopt` before it is used, such
+    /// as the kernel buffer sizes and the DSCP marking, see [`SocketOptions`] for the platform
+    /// limitations. The failures are logged and the sockets are used as they are.
+    /// The default value leaves the system defaults
+    pub fn socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    /// Protect the unreliable frames of the connections by the forward error correction, a parity
+    /// frame is sent after each group of the protected frames so that the receiver could
+    /// recover one lost frame of the group without waiting for a resend. It costs one frame per
+    /// group, so the overhead ratio is `1 / group_size`, see [`FecOptions`] for which frames are
+    /// protected. It is not wire compatible, the peer must enable it with the same options.
+    /// The number of recovered frames is exposed by
+    /// [`Stats::fec_recovered`](crate::opts::Stats::fec_recovered).
+    /// The default value is disabled
+    #[cfg(feature = "fec")]
+    pub fn fec(mut self, options: FecOptions) -> Self {
+        self.fec = Some(options);
+        self
+    }
+
     fn offline_config(&self) -> offline::Config {
         offline::Config {
-            sever_guid: self.sever_guid,
+            server_guid: self.server_guid,
             advertisement: self.advertisement.clone(),
             min_mtu: self.min_mtu,
             max_mtu: self.max_mtu,
             support_version: self.support_version.clone(),
+            version_filter: self.version_filter.clone(),
             max_pending: self.max_pending,
+            max_connections: self.max_connections,
+            migration: self.connection_migration,
+            cookie_rotation: self.handshake_cookie,
+            encryption: self.crypto.is_some(),
         }
     }
 
@@ -157,24 +838,48 @@ impl Config {
             max_parted_count: self.max_parted_count,
             max_parted_size: self.max_parted_size,
             max_channels: self.max_channels,
+            max_reorder_depth: self.max_reorder_depth,
+            crypto: self.crypto.clone(),
+            compression: self.compression.clone(),
+            checksum: self.checksum,
+            #[cfg(feature = "fec")]
+            fec: self.fec,
         }
     }
 
     fn server_role(&self) -> Role {
         Role::Server {
-            guid: self.sever_guid,
+            guid: self.server_guid,
         }
     }
 }
 
+/// Make the incoming stream of connections from the sockets.
+///
+/// A connection is identified by the socket it arrived on and the address of the peer. The flow
+/// info of an IPv6 address is ignored, and the scope id is only taken into account for the
+/// link-local addresses, so the peers with the same link-local address on different interfaces
+/// are different connections.
+///
+/// The config is not required to be built by [`Config::build`], the invalid options are logged and
+/// clamped to the nearest valid values.
 pub trait MakeIncoming: Sized {
     fn make_incoming(
         self,
         config: Config,
     ) -> impl Stream<
         Item = (
-            impl Stream<Item = Bytes> + TraceInfo,
-            impl Sink<Message, Error = io::Error>,
+            impl Stream<Item = Bytes>
+                + TraceInfo
+                + ConnInfo
+                + ConnStats
+                + SendBufCap
+                + AckNow
+                + LastFrameMeta,
+            impl Sink<Message, Error = io::Error> + TrySend,
         ),
-    >;
+    > + IncomingStats
+           + IncomingConnections
+           + IncomingShutdown
+           + SendUnconnected;
 }
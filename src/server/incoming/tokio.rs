@@ -1,126 +1,561 @@
 use std::collections::HashMap;
+use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 use concurrent_queue::ConcurrentQueue;
-use fastrace::collector::SpanContext;
-use fastrace::Span;
-use futures::{Sink, Stream};
-use log::{debug, error, trace};
+use futures::task::AtomicWaker;
+use futures::{Sink, Stream, StreamExt};
+use log::{debug, error, trace, warn};
 use pin_project_lite::pin_project;
 use tokio::net::UdpSocket as TokioUdpSocket;
 
-use super::{Config, MakeIncoming};
+use super::{Config, ConnectionEvent, MakeIncoming};
 use crate::codec::frame::Framed;
 use crate::codec::{Decoded, Encoded};
 use crate::guard::HandleOutgoing;
 use crate::link::{Route, TransferLink};
-use crate::opts::TraceInfo;
-use crate::server::handler::offline::OfflineHandler;
-use crate::server::handler::online::HandleOnline;
+use crate::opts::{
+    AckNow, ConnInfo, ConnStats, ConnectionInfo, Connections, FrameCrypto, IncomingConnections,
+    IncomingShutdown, IncomingStats, LastFrameMeta, SendBufCap, SendUnconnected, TraceInfo,
+    TrySend,
+};
+use crate::packet::connected::{self, FramesMut};
+use crate::server::handler::offline::{OfflineHandler, Source};
+use crate::server::handler::online::{HandleOnline, OnConnected};
 use crate::state::{CloseOnDrop, IncomingStateManage, OutgoingStateManage};
-use crate::utils::{Logged, TraceStreamExt};
-use crate::Message;
+use crate::utils::{
+    online_span, AutoFlushed, DisconnectOnDrop, FairShare, Logged, Reactor, TimerHandle, TimerKind,
+    TraceStreamExt, DEFAULT_DRIVE_INTERVAL,
+};
+use crate::{ConnId, DisconnectReason, Message};
 
 pin_project! {
+    #[project = IncomingProj]
     struct Incoming {
-        #[pin]
-        offline: OfflineHandler<Framed<Arc<TokioUdpSocket>>>,
+        // the offline handler of each socket, the replies are sent out the socket the packet
+        // arrived on
+        offlines: Vec<OfflineHandler<Framed<Arc<TokioUdpSocket>>>>,
         config: Config,
-        socket: Arc<TokioUdpSocket>,
-        router: HashMap<SocketAddr, Route>,
-        close_events: Arc<ConcurrentQueue<SocketAddr>>,
+        sockets: Vec<Arc<TokioUdpSocket>>,
+        // the send capacity of each socket shared by its connections, None if it is disabled
+        fair_shares: Vec<Option<Arc<FairShare>>>,
+        // keyed by the socket index and the peer address, which is normalized when it is received
+        // so that the link-local peers on different interfaces do not collide
+        router: HashMap<(usize, SocketAddr), Route>,
+        close_events: Arc<ConcurrentQueue<(usize, SocketAddr)>>,
+        close_waker: Arc<AtomicWaker>,
+        // the current addresses of the migrated connections keyed by their first addresses, which
+        // are reported by the close events
+        migrated: HashMap<(usize, SocketAddr), SocketAddr>,
+        connections: Connections,
+        // the socket polled first, rotated so that a busy socket does not starve the others
+        next_socket: usize,
+        // the deadline of the graceful shutdown, no connection is accepted after it was started
+        shutdown: Option<Instant>,
+        // the timer waking up at the shutdown deadline
+        shutdown_timer: Option<TimerHandle>,
+        terminated: bool,
     }
 }
 
+impl Incoming {
+    fn new(sockets: Vec<TokioUdpSocket>, config: Config) -> Self {
+        assert!(!sockets.is_empty(), "at least one socket is required");
+        let config = config.clamped();
+        for socket in &sockets {
+            if let Err(err) = config.socket_options.apply(socket) {
+                warn!(
+                    "[{}] failed to apply the socket options: {err}",
+                    config.server_role()
+                );
+            }
+        }
+        let sockets: Vec<_> = sockets.into_iter().map(Arc::new).collect();
+        let offlines = sockets
+            .iter()
+            .map(|socket| {
+                OfflineHandler::new(
+                    Framed::new(Arc::clone(socket), config.max_mtu as usize, config.magic)
+                        .recv_batch(config.recv_batch_size)
+                        .checksum(config.checksum)
+                        .max_ack_count(config.max_ack_count)
+                        .decode_err_hook(config.decode_err_hook.clone())
+                        .tap(config.tap.clone()),
+                    config.offline_config(),
+                )
+            })
+            .collect();
+        let fair_shares = sockets
+            .iter()
+            .map(|_| (config.send_quantum > 0).then(|| FairShare::new(config.send_quantum)))
+            .collect();
+        Incoming {
+            offlines,
+            sockets,
+            fair_shares,
+            config,
+            router: HashMap::new(),
+            close_events: Arc::new(ConcurrentQueue::unbounded()),
+            close_waker: Arc::default(),
+            migrated: HashMap::new(),
+            connections: Connections::default(),
+            next_socket: 0,
+            shutdown: None,
+            shutdown_timer: None,
+            terminated: false,
+        }
+    }
+
+    /// Poll the offline handlers in turn, returns the packet with the index of the socket
+    fn poll_offline(
+        offlines: &mut [OfflineHandler<Framed<Arc<TokioUdpSocket>>>],
+        next_socket: &mut usize,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<(usize, connected::Packet<FramesMut>, Source)>> {
+        let len = offlines.len();
+        let mut ended = 0;
+        for i in 0..len {
+            let index = (*next_socket + i) % len;
+            match Pin::new(&mut offlines[index]).poll_next(cx) {
+                Poll::Ready(Some((pack, source))) => {
+                    *next_socket = (index + 1) % len;
+                    return Poll::Ready(Some((index, pack, source)));
+                }
+                Poll::Ready(None) => ended += 1,
+                Poll::Pending => {}
+            }
+        }
+        if ended == len {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+
+    /// Remove the routes of the closed connections
+    fn process_close_events(this: &mut IncomingProj<'_>) {
+        let role = this.config.server_role();
+        for (index, first) in this.close_events.try_iter() {
+            let ev = this.migrated.remove(&(index, first)).unwrap_or(first);
+            let Some(route) = this.router.remove(&(index, ev)) else {
+                debug!("[{role}] the route of {ev} was removed before");
+                continue;
+            };
+            // TODO: could we keep the connection alive for a while? 0-RTT handshake?
+            Pin::new(&mut this.offlines[index]).disconnect(&ev);
+            this.connections.remove(index, ev);
+            let reason = route
+                .link()
+                .close_reason()
+                .unwrap_or(DisconnectReason::Dropped);
+            debug!("[{role}] connection closed: {ev}, reason: {reason:?}");
+            Self::emit_disconnected(this.config, ev, &route, reason);
+        }
+    }
+
+    /// Emit `Disconnected` for the connection, only if `Connected` was emitted for it, i.e. its
+    /// `ConnectionRequest` was accepted, so that the lifecycle events are balanced
+    fn emit_disconnected(
+        config: &Config,
+        addr: SocketAddr,
+        route: &Route,
+        reason: DisconnectReason,
+    ) {
+        if route.link().client_guid().is_none() {
+            debug!(
+                "[{}] connection {addr} closed before the handshake finished",
+                config.server_role()
+            );
+            return;
+        }
+        config.emit(ConnectionEvent::Disconnected { addr, reason });
+    }
+
+    /// Drop the connections that did not finish closing before the shutdown deadline, their
+    /// streams end as the routes are dropped
+    fn terminate(this: &mut IncomingProj<'_>) {
+        let role = this.config.server_role();
+        for ((index, addr), route) in this.router.drain() {
+            Pin::new(&mut this.offlines[index]).disconnect(&addr);
+            this.connections.remove(index, addr);
+            let reason = route
+                .link()
+                .close_reason()
+                .unwrap_or(DisconnectReason::Dropped);
+            debug!("[{role}] connection dropped on shutdown: {addr}, reason: {reason:?}");
+            Self::emit_disconnected(this.config, addr, &route, reason);
+        }
+        this.migrated.clear();
+        *this.terminated = true;
+    }
+
+    /// Migrate the connection that the frame set from the unknown address continues, return
+    /// false if there is no such connection or it is ambiguous.
+    fn migrate(
+        this: &mut IncomingProj<'_>,
+        index: usize,
+        addr: SocketAddr,
+        pack: connected::Packet<FramesMut>,
+    ) -> bool {
+        let role = this.config.server_role();
+        // the sequence number is guessable, only the frame set authenticated by the crypto proves
+        // that it is sent by the peer
+        if let Some(crypto) = &this.config.crypto
+            && !authenticated(crypto.as_ref(), &pack)
+        {
+            debug!("[{role}] frame set from {addr} is not authenticated, not migrated");
+            return false;
+        }
+        let mut candidates = this
+            .router
+            .iter()
+            .filter(|((socket, _), route)| *socket == index && route.continues(&pack))
+            .map(|((_, from), _)| *from);
+        let (Some(from), None) = (candidates.next(), candidates.next()) else {
+            return false;
+        };
+        let mut route = this.router.remove(&(index, from)).unwrap();
+        route.migrate(addr);
+        Pin::new(&mut this.offlines[index]).migrate(&from, addr);
+        this.connections.migrate(index, from, addr);
+        let first = this
+            .migrated
+            .iter()
+            .find_map(|(first, current)| (first.0 == index && *current == from).then_some(*first))
+            .unwrap_or((index, from));
+        this.migrated.insert(first, addr);
+        debug!("[{role}] connection migrated from {from} to {addr}");
+        this.config
+            .emit(ConnectionEvent::Migrated { from, to: addr });
+        if !route.deliver(pack) {
+            error!("[{role}] connection was dropped before closed");
+        }
+        this.router.insert((index, addr), route);
+        true
+    }
+}
+
+/// Whether all frames of the frame set are decrypted by `crypto`, the frame set itself is left as
+/// it is
+fn authenticated(crypto: &dyn FrameCrypto, pack: &connected::Packet<FramesMut>) -> bool {
+    let connected::Packet::FrameSet(frames) = pack else {
+        return false;
+    };
+    !frames.set.is_empty()
+        && frames
+            .set
+            .iter()
+            .all(|frame| crypto.decrypt(&mut frame.body.clone()).is_ok())
+}
+
 impl MakeIncoming for TokioUdpSocket {
     fn make_incoming(
         self,
         config: Config,
     ) -> impl Stream<
         Item = (
-            impl Stream<Item = Bytes> + TraceInfo,
-            impl Sink<Message, Error = io::Error>,
+            impl Stream<Item = Bytes>
+                + TraceInfo
+                + ConnInfo
+                + ConnStats
+                + SendBufCap
+                + AckNow
+                + LastFrameMeta,
+            impl Sink<Message, Error = io::Error> + TrySend,
         ),
-    > {
-        let socket = Arc::new(self);
-        Incoming {
-            offline: OfflineHandler::new(
-                Framed::new(Arc::clone(&socket), config.max_mtu as usize),
-                config.offline_config(),
-            ),
-            socket,
-            config,
-            router: HashMap::new(),
-            close_events: Arc::new(ConcurrentQueue::unbounded()),
+    > + IncomingStats
+           + IncomingConnections
+           + IncomingShutdown
+           + SendUnconnected {
+        Incoming::new(vec![self], config)
+    }
+}
+
+/// Serve on multiple sockets (e.g. an IPv4 one and an IPv6 one) with one incoming, the
+/// connections from all sockets are merged into it. The limits of the config like
+/// `max_connections` are applied to each socket.
+///
+/// # Panics
+/// Panics if there is no socket.
+impl MakeIncoming for Vec<TokioUdpSocket> {
+    fn make_incoming(
+        self,
+        config: Config,
+    ) -> impl Stream<
+        Item = (
+            impl Stream<Item = Bytes>
+                + TraceInfo
+                + ConnInfo
+                + ConnStats
+                + SendBufCap
+                + AckNow
+                + LastFrameMeta,
+            impl Sink<Message, Error = io::Error> + TrySend,
+        ),
+    > + IncomingStats
+           + IncomingConnections
+           + IncomingShutdown
+           + SendUnconnected {
+        Incoming::new(self, config)
+    }
+}
+
+impl IncomingStats for Incoming {
+    fn connection_count(&self) -> usize {
+        self.offlines
+            .iter()
+            .map(OfflineHandler::connected_count)
+            .sum()
+    }
+}
+
+impl IncomingConnections for Incoming {
+    fn connections(&self) -> Connections {
+        self.connections.clone()
+    }
+}
+
+impl IncomingShutdown for Incoming {
+    async fn shutdown(mut self: Pin<&mut Self>, timeout: Duration) {
+        let this = self.as_mut().project();
+        if this.shutdown.is_none() {
+            for offline in this.offlines.iter_mut() {
+                Pin::new(offline).stop_accepting();
+            }
+            for route in this.router.values() {
+                route.link().request_shutdown();
+            }
+            *this.shutdown = Some(Instant::now() + timeout);
+        }
+        // no connection is yielded during the shutdown
+        while self.next().await.is_some() {}
+    }
+}
+
+impl SendUnconnected for Incoming {
+    fn send_unconnected(
+        &self,
+        addr: SocketAddr,
+        datagram: Bytes,
+    ) -> impl Future<Output = Result<(), io::Error>> + Send + 'static {
+        let socket = self
+            .sockets
+            .iter()
+            .find(|socket| {
+                socket
+                    .local_addr()
+                    .is_ok_and(|local| local.is_ipv4() == addr.is_ipv4())
+            })
+            .unwrap_or(&self.sockets[0]);
+        let socket = Arc::clone(socket);
+        let tap = self.config.tap.clone();
+        async move {
+            if let Some(tap) = tap {
+                tap.on_raw_out(&datagram, addr);
+            }
+            socket.send_to(&datagram, addr).await?;
+            Ok(())
         }
     }
 }
 
 impl Stream for Incoming {
     type Item = (
-        impl Stream<Item = Bytes> + TraceInfo,
-        impl Sink<Message, Error = io::Error>,
+        impl Stream<Item = Bytes>
+            + TraceInfo
+            + ConnInfo
+            + ConnStats
+            + SendBufCap
+            + AckNow
+            + LastFrameMeta,
+        impl Sink<Message, Error = io::Error> + TrySend,
     );
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
 
+        if *this.terminated {
+            return Poll::Ready(None);
+        }
         let role = this.config.server_role();
-        for ev in this.close_events.try_iter() {
-            this.router
-                .remove(&ev)
-                .expect("closed a non-exist connection");
-            // TODO: could we keep the connection alive for a while? 0-RTT handshake?
-            this.offline.as_mut().disconnect(&ev);
-            debug!("[{role}] connection closed: {ev}");
+        this.close_waker.register(cx.waker());
+        Self::process_close_events(&mut this);
+        if let Some(deadline) = *this.shutdown {
+            if this.router.is_empty() || Instant::now() >= deadline {
+                if let Some(timer) = this.shutdown_timer.take() {
+                    Reactor::get().cancel_timer(timer);
+                }
+                Self::terminate(&mut this);
+                return Poll::Ready(None);
+            }
+            match this.shutdown_timer {
+                Some(timer) => Reactor::get().reset_timer(timer, deadline, cx.waker()),
+                None => {
+                    // the timers are keyed by connections, the server itself takes the key of
+                    // its first socket
+                    let addr = this.sockets[0]
+                        .local_addr()
+                        .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+                    let c_id = ConnId::new(role.guid(), role.guid(), addr);
+                    *this.shutdown_timer = Some(Reactor::get().insert_timer(
+                        c_id,
+                        TimerKind::Shutdown,
+                        deadline,
+                        cx.waker(),
+                    ));
+                }
+            }
         }
 
         loop {
-            let Some((pack, peer)) = ready!(this.offline.as_mut().poll_next(cx)) else {
+            let Some((index, pack, source)) =
+                ready!(Self::poll_offline(this.offlines, this.next_socket, cx))
+            else {
                 return Poll::Ready(None);
             };
-            if let Some(entry) = this.router.get_mut(&peer.addr) {
+            let peer = match source {
+                Source::Connected(peer) => peer,
+                Source::Unknown(addr) => {
+                    if !Self::migrate(&mut this, index, addr, pack) {
+                        debug!("[{role}] reject frame set from unknown address {addr}");
+                        Pin::new(&mut this.offlines[index]).reject(addr);
+                    }
+                    continue;
+                }
+            };
+            if let Some(entry) = this.router.get_mut(&(index, peer.addr)) {
                 if !entry.deliver(pack) {
                     error!("[{role}] connection was dropped before closed");
                 }
                 continue;
             }
 
-            let link = TransferLink::new_arc(role, peer);
-            let (mut entry, route) = Route::new(Arc::clone(&link));
+            if this.shutdown.is_some() {
+                debug!(
+                    "[{role}] reject {} as the server is shutting down",
+                    peer.addr
+                );
+                let mut offline = Pin::new(&mut this.offlines[index]);
+                offline.as_mut().disconnect(&peer.addr);
+                offline.reject(peer.addr);
+                continue;
+            }
+
+            let c_id = ConnId::new(role.guid(), peer.guid, peer.addr);
+            let link = TransferLink::with_ack_buffer(
+                role,
+                peer,
+                this.config.loss_window,
+                this.config.ack_buffer,
+            );
+            let version = this.offlines[index]
+                .protocol_version(&peer.addr)
+                .unwrap_or_default();
+            let info = ConnectionInfo::new(c_id, peer.mtu, version);
+            let (mut entry, route) = Route::new(Arc::clone(&link), this.config.recv_buf_cap);
+            entry.set_ack_reliable_only(this.config.ack_reliable_only);
             entry.deliver(pack);
-            this.router.insert(peer.addr, entry);
+            this.router.insert((index, peer.addr), entry);
+            let on_connected = this
+                .config
+                .conn_event_hook
+                .clone()
+                .map(|ev_hook| -> OnConnected {
+                    Box::new(move |client_guid| {
+                        ev_hook.call(ConnectionEvent::Connected {
+                            addr: peer.addr,
+                            guid: peer.guid,
+                            client_guid,
+                            mtu: peer.mtu,
+                        });
+                    })
+                });
 
-            let dst = Framed::new(Arc::clone(this.socket), this.config.max_mtu as usize)
-                .handle_outgoing(Arc::clone(&link), this.config.send_buf_cap, peer, role)
-                .frame_encoded(peer.mtu, this.config.codec_config(), Arc::clone(&link))
-                .manage_outgoing_state(Some(CloseOnDrop::new(
+            let dst = Framed::new(
+                Arc::clone(&this.sockets[index]),
+                this.config.max_mtu as usize,
+                this.config.magic,
+            )
+            .send_batch(this.config.send_batch_size)
+            .checksum(this.config.checksum)
+            .tap(this.config.tap.clone())
+            .handle_outgoing(
+                Arc::clone(&link),
+                this.config.send_buf_cap,
+                this.config.send_buf_bytes,
+                peer,
+                role,
+            )
+            .close_timeout(this.config.close_timeout)
+            .fail_expired_reliable(this.config.fail_expired_reliable)
+            .resend_bytes_cap(this.config.resend_buf_bytes)
+            .rto_bounds(this.config.min_rto, this.config.max_rto)
+            .retransmit_hook(this.config.retransmit_hook.clone())
+            .receipt_hook(this.config.receipt_hook.clone())
+            .mtu_blackhole_retries(this.config.mtu_blackhole_retries)
+            .crypto(this.config.crypto.clone())
+            .compression(this.config.compression.clone())
+            .pacing(this.config.pacing)
+            .data_first(this.config.data_first)
+            .flush_budget(this.config.flush_budget)
+            .fair_share(this.fair_shares[index].as_ref())
+            .random_initial_seq_num(this.config.random_initial_seq_num)
+            .checksum(this.config.checksum)
+            .frame_encoded(peer.mtu, this.config.codec_config(), Arc::clone(&link))
+            .manage_outgoing_state(
+                Some(CloseOnDrop::new(
+                    index,
                     peer.addr,
                     Arc::clone(this.close_events),
-                )));
+                    Arc::clone(this.close_waker),
+                )),
+                Arc::clone(&link),
+            );
+            let flush_interval = this
+                .config
+                .flush_interval
+                .or(this.config.self_driving.then_some(DEFAULT_DRIVE_INTERVAL));
+            let dst = match flush_interval {
+                Some(interval) => {
+                    let driving = this.config.self_driving.then(|| Arc::clone(&link));
+                    let (dst, flusher) = dst.auto_flushed(interval, c_id, driving);
+                    tokio::spawn(flusher);
+                    dst
+                }
+                None => dst.shared(),
+            };
+            this.connections
+                .insert(index, info, Arc::clone(&link), dst.flush_handle());
+            let dst = DisconnectOnDrop::new(dst, Arc::clone(&link), this.config.disconnect_on_drop);
 
+            let hook = this.config.decode_err_hook.clone();
             let src = route
-                .frame_decoded(this.config.codec_config())
+                .frame_decoded(this.config.codec_config(), Arc::clone(&link))
                 .logged(
                     move |frame| trace!("[{role}] received {frame:?} from {peer}"),
-                    move |err| error!("[{role}] decode error: {err} from {peer}"),
+                    move |err| {
+                        error!("[{role}] decode error: {err} from {peer}");
+                        if let Some(hook) = &hook {
+                            hook.call(peer.addr, err);
+                        }
+                    },
                 )
-                .manage_incoming_state()
-                .handle_online(role, peer, Arc::clone(&link))
-                .enter_on_item(move || {
-                    Span::root("online", SpanContext::random()).with_properties(|| {
-                        [
-                            ("peer_guid", peer.guid.to_string()),
-                            ("peer_addr", peer.addr.to_string()),
-                            ("conn_mtu", peer.mtu.to_string()),
-                        ]
-                    })
-                });
+                .manage_incoming_state(Arc::clone(&link))
+                .handle_online(
+                    role,
+                    peer,
+                    info,
+                    this.config.crypto.is_some(),
+                    Arc::clone(&link),
+                    on_connected,
+                )
+                .packet_handlers(this.config.packet_handlers.clone())
+                .enter_on_item(move || online_span(c_id, peer));
 
             return Poll::Ready(Some((src, dst)));
         }
@@ -8,12 +8,14 @@ use std::sync::Arc;
 use std::task::{ready, Context, Poll};
 
 use concurrent_queue::ConcurrentQueue;
+use futures::task::AtomicWaker;
 use futures::{Sink, Stream};
 use log::warn;
 use pin_project_lite::pin_project;
 
+use crate::link::SharedLink;
 use crate::packet::connected::FrameBody;
-use crate::Message;
+use crate::{DisconnectReason, Message};
 
 enum OutgoingState {
     // before sending DisconnectNotification
@@ -40,23 +42,43 @@ impl OutgoingState {
     }
 }
 
+/// The marker set as the ext data of [`std::task::Context`] to abort the connection in
+/// [`Sink::poll_close`], the layers drop their buffers instead of waiting for them to be sent and
+/// acknowledged.
+pub(crate) struct Aborting;
+
 /// Send close event when dropped.
 pub(crate) struct CloseOnDrop {
+    /// The index of the socket serving the connection
+    pub(crate) socket: usize,
     pub(crate) addr: SocketAddr,
-    pub(crate) close_events: Arc<ConcurrentQueue<SocketAddr>>,
+    pub(crate) close_events: Arc<ConcurrentQueue<(usize, SocketAddr)>>,
+    /// Wake the consumer of the close events
+    pub(crate) waker: Arc<AtomicWaker>,
 }
 
 impl Drop for CloseOnDrop {
     fn drop(&mut self) {
         self.close_events
-            .push(self.addr)
+            .push((self.socket, self.addr))
             .expect("closed events queue cannot be closed");
+        self.waker.wake();
     }
 }
 
 impl CloseOnDrop {
-    pub(crate) fn new(addr: SocketAddr, close_events: Arc<ConcurrentQueue<SocketAddr>>) -> Self {
-        Self { addr, close_events }
+    pub(crate) fn new(
+        socket: usize,
+        addr: SocketAddr,
+        close_events: Arc<ConcurrentQueue<(usize, SocketAddr)>>,
+        waker: Arc<AtomicWaker>,
+    ) -> Self {
+        Self {
+            socket,
+            addr,
+            close_events,
+            waker,
+        }
     }
 }
 
@@ -66,6 +88,7 @@ pin_project! {
         frame: F,
         state: S,
         close_on_drop: Option<CloseOnDrop>,
+        link: SharedLink,
     }
 }
 
@@ -74,6 +97,7 @@ pub(crate) trait OutgoingStateManage: Sized {
     fn manage_outgoing_state(
         self,
         close_on_drop: Option<CloseOnDrop>,
+        link: SharedLink,
     ) -> impl Sink<FrameBody, Error = io::Error> + Sink<Message, Error = io::Error>;
 }
 
@@ -84,11 +108,13 @@ where
     fn manage_outgoing_state(
         self,
         close_on_drop: Option<CloseOnDrop>,
+        link: SharedLink,
     ) -> impl Sink<FrameBody, Error = io::Error> + Sink<Message, Error = io::Error> {
         StateManager {
             frame: self,
             state: OutgoingState::Connecting,
             close_on_drop,
+            link,
         }
     }
 }
@@ -98,18 +124,19 @@ pub(crate) trait IncomingStateManage: Sized {
     ///
     /// It will yield None when it receives the `DisconnectNotification`. And will continue to
     /// return None in the following.
-    fn manage_incoming_state(self) -> impl Stream<Item = FrameBody>;
+    fn manage_incoming_state(self, link: SharedLink) -> impl Stream<Item = FrameBody>;
 }
 
 impl<F> IncomingStateManage for F
 where
     F: Stream<Item = FrameBody>,
 {
-    fn manage_incoming_state(self) -> impl Stream<Item = FrameBody> {
+    fn manage_incoming_state(self, link: SharedLink) -> impl Stream<Item = FrameBody> {
         StateManager {
             frame: self,
             state: IncomingState::Connecting,
             close_on_drop: None,
+            link,
         }
     }
 }
@@ -141,11 +168,19 @@ where
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the server is shutting down, close the connection gracefully instead
+        if self.link.shutdown_requested() && !matches!(self.state, OutgoingState::Closed) {
+            return self.poll_close(cx);
+        }
         // flush is allowed after the connection is closed, it will deliver ack.
         self.project().frame.poll_flush(cx)
     }
 
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if cx.ext().downcast_ref::<Aborting>().is_some() {
+            self.as_mut().abort(cx);
+            return Poll::Ready(Ok(()));
+        }
         let mut this = self.project();
         if matches!(this.state, OutgoingState::Closed) {
             return Poll::Ready(Err(io::Error::new(
@@ -160,7 +195,14 @@ where
                 }
                 OutgoingState::FirstCloseWait => {
                     // first wait all stales packets to receive by the peer
-                    ready!(this.frame.as_mut().poll_close(cx)?);
+                    if let Err(err) = ready!(this.frame.as_mut().poll_close(cx)) {
+                        return Poll::Ready(Err(close_failed(
+                            this.state,
+                            this.link,
+                            this.close_on_drop,
+                            err,
+                        )));
+                    }
                     *this.state = OutgoingState::FinWait;
                 }
                 OutgoingState::FinWait => {
@@ -173,11 +215,19 @@ where
                 }
                 OutgoingState::SecondCloseWait => {
                     // second wait the DisconnectNotification to receive by the peer
-                    ready!(this.frame.as_mut().poll_close(cx)?);
+                    if let Err(err) = ready!(this.frame.as_mut().poll_close(cx)) {
+                        return Poll::Ready(Err(close_failed(
+                            this.state,
+                            this.link,
+                            this.close_on_drop,
+                            err,
+                        )));
+                    }
                     *this.state = OutgoingState::Closed;
                 }
                 OutgoingState::Closed => {
                     // send close event
+                    this.link.set_close_reason(DisconnectReason::Graceful);
                     let _ = this.close_on_drop.take();
                     return Poll::Ready(Ok(()));
                 }
@@ -186,6 +236,55 @@ where
     }
 }
 
+impl<F> StateManager<F, OutgoingState>
+where
+    F: Sink<FrameBody, Error = io::Error>,
+{
+    /// Abort the connection, a `DisconnectNotification` is sent once if the connection has not
+    /// sent it, and nothing is waited to be acknowledged. It does nothing if the connection was
+    /// closed.
+    fn abort(self: Pin<&mut Self>, cx: &mut Context<'_>) {
+        let mut this = self.project();
+        if matches!(this.state, OutgoingState::Closed) {
+            return;
+        }
+        // drop the buffered and the unacked frames first, so only the notification is sent
+        let _ = this.frame.as_mut().poll_close(cx);
+        if this.state.before_finish()
+            && let Poll::Ready(Ok(())) = this.frame.as_mut().poll_ready(cx)
+            && this
+                .frame
+                .as_mut()
+                .start_send(FrameBody::DisconnectNotification)
+                .is_ok()
+        {
+            // best effort, it will not be resent
+            let _ = this.frame.as_mut().poll_flush(cx);
+        }
+        let _ = this.frame.as_mut().poll_close(cx);
+        *this.state = OutgoingState::Closed;
+        this.link.set_close_reason(DisconnectReason::Aborted);
+        // send close event
+        let _ = this.close_on_drop.take();
+    }
+}
+
+/// The connection is force closed if closing timed out, other errors are left to the caller
+fn close_failed(
+    state: &mut OutgoingState,
+    link: &SharedLink,
+    close_on_drop: &mut Option<CloseOnDrop>,
+    err: io::Error,
+) -> io::Error {
+    if err.kind() == io::ErrorKind::TimedOut {
+        *state = OutgoingState::Closed;
+        link.set_close_reason(DisconnectReason::Timeout);
+        // send close event
+        let _ = close_on_drop.take();
+    }
+    err
+}
+
 impl<F> Sink<Message> for StateManager<F, OutgoingState>
 where
     F: Sink<FrameBody, Error = io::Error> + Sink<Message, Error = io::Error>,
@@ -197,6 +296,13 @@ where
     }
 
     fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        // sent without polling by `TrySend`
+        if !self.state.before_finish() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "connection was closed before",
+            ));
+        }
         self.project().frame.start_send(item)?;
         Ok(())
     }
@@ -230,6 +336,7 @@ where
         };
         if matches!(body, FrameBody::DisconnectNotification) {
             // The peer no longer sends any data.
+            this.link.set_close_reason(DisconnectReason::Graceful);
             *this.state = IncomingState::Closed;
             return Poll::Ready(None);
         }
@@ -243,13 +350,16 @@ mod test {
     use std::pin::Pin;
     use std::sync::Arc;
     use std::task::{Context, Poll};
+    use std::time::Duration;
 
     use concurrent_queue::ConcurrentQueue;
     use futures::{Sink, SinkExt};
 
+    use crate::link::TransferLink;
+    use crate::opts::Abort;
     use crate::packet::connected::FrameBody;
     use crate::state::CloseOnDrop;
-    use crate::Message;
+    use crate::{DisconnectReason, Message, Peer, Role};
 
     #[derive(Debug, Default)]
     struct DstSink {
@@ -319,10 +429,17 @@ mod test {
     async fn test_goodbye_works() {
         let queue = Arc::new(ConcurrentQueue::unbounded());
         let addr = "0.0.0.0:0".parse().unwrap();
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
         let mut goodbye = super::StateManager {
             frame: DstSink::default(),
             state: crate::state::OutgoingState::Connecting,
-            close_on_drop: Some(CloseOnDrop::new(addr, Arc::clone(&queue))),
+            close_on_drop: Some(CloseOnDrop::new(
+                0,
+                addr,
+                Arc::clone(&queue),
+                Arc::default(),
+            )),
+            link: Arc::clone(&link),
         };
         SinkExt::<FrameBody>::close(&mut goodbye).await.unwrap();
         assert_eq!(goodbye.frame.buf.len(), 1);
@@ -338,11 +455,78 @@ mod test {
         assert_eq!(goodbye.frame.buf.len(), 1);
 
         // close event was pushed
-        assert_eq!(queue.pop().unwrap(), addr);
+        assert_eq!(queue.pop().unwrap(), (0, addr));
         assert!(queue.is_empty());
+        assert_eq!(link.close_reason(), Some(DisconnectReason::Graceful));
 
         std::future::poll_fn(|cx| SinkExt::<FrameBody>::poll_ready_unpin(&mut goodbye, cx))
             .await
             .unwrap_err();
     }
+
+    #[tokio::test]
+    async fn test_abort_works() {
+        let queue = Arc::new(ConcurrentQueue::unbounded());
+        let addr = "0.0.0.0:0".parse().unwrap();
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let mut aborted = Box::pin(super::StateManager {
+            frame: DstSink::default(),
+            state: crate::state::OutgoingState::Connecting,
+            close_on_drop: Some(CloseOnDrop::new(
+                0,
+                addr,
+                Arc::clone(&queue),
+                Arc::default(),
+            )),
+            link: Arc::clone(&link),
+        });
+        for _ in 0..2 {
+            Abort::abort(aborted.as_mut()).await.unwrap();
+        }
+        // only one DisconnectNotification is sent
+        assert_eq!(aborted.frame.buf.len(), 1);
+        assert!(matches!(
+            aborted.frame.buf[0],
+            FrameBody::DisconnectNotification
+        ));
+
+        // close event was pushed once
+        assert_eq!(queue.pop().unwrap(), (0, addr));
+        assert!(queue.is_empty());
+        assert_eq!(link.close_reason(), Some(DisconnectReason::Aborted));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_works() {
+        let queue = Arc::new(ConcurrentQueue::unbounded());
+        let addr = "0.0.0.0:0".parse().unwrap();
+        let link = TransferLink::new_arc(Role::test_server(), Peer::test(), Duration::from_secs(1));
+        let mut goodbye = super::StateManager {
+            frame: DstSink::default(),
+            state: crate::state::OutgoingState::Connecting,
+            close_on_drop: Some(CloseOnDrop::new(
+                0,
+                addr,
+                Arc::clone(&queue),
+                Arc::default(),
+            )),
+            link: Arc::clone(&link),
+        };
+        SinkExt::<FrameBody>::flush(&mut goodbye).await.unwrap();
+        assert!(goodbye.frame.buf.is_empty());
+
+        // the connection is closed on the next flush
+        link.request_shutdown();
+        for _ in 0..2 {
+            SinkExt::<FrameBody>::flush(&mut goodbye).await.unwrap();
+        }
+        assert_eq!(goodbye.frame.buf.len(), 1);
+        assert!(matches!(
+            goodbye.frame.buf[0],
+            FrameBody::DisconnectNotification
+        ));
+        assert_eq!(queue.pop().unwrap(), (0, addr));
+        assert!(queue.is_empty());
+        assert_eq!(link.close_reason(), Some(DisconnectReason::Graceful));
+    }
 }
@@ -1,18 +1,29 @@
-use std::future::poll_fn;
+use std::future::{poll_fn, Future};
+use std::io;
 use std::iter::repeat;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::task::ContextBuilder;
 use std::time::Duration;
 
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
 use log::info;
 use tokio::net::UdpSocket;
 
 use crate::client::{self, ConnectTo};
-use crate::opts::FlushStrategy;
+use crate::errors::{CloseTimedOut, ConnectError, RecvTimedOut};
+use crate::opts::{
+    Abort, BatchSinkExt, ConnInfo, ConnStats, DatagramTap, FlushReliable, FlushStrategy,
+    FrameCompression, FrameCrypto, FrameMetaStreamExt, IncomingConnections, IncomingShutdown,
+    IncomingStats, RecvTimeout, SendUnconnected, TrySend, TrySendError, UnbatchStreamExt,
+};
+use crate::packet::{self, connected, Packet};
 use crate::server::{self, MakeIncoming};
-use crate::utils::tests::test_trace_log_setup;
-use crate::{Message, Reliability};
+use crate::utils::tests::{ipv6_available, test_trace_log_setup};
+use crate::{ConnId, Message, Reliability};
 
 impl From<Bytes> for Message {
     fn from(data: Bytes) -> Self {
@@ -23,7 +34,7 @@ impl From<Bytes> for Message {
 fn make_server_conf() -> server::Config {
     server::Config::new()
         .send_buf_cap(1024)
-        .sever_guid(1919810)
+        .server_guid(1919810)
         .max_channels(64)
         .advertisement(&b"123456"[..])
         .max_mtu(1500)
@@ -80,6 +91,15 @@ async fn test_tokio_udp_works() {
             .await
             .unwrap();
 
+        let info = src.connection_info();
+        assert_eq!(info.mtu(), 1000);
+        assert_eq!(info.remote_guid(), 1919810);
+        assert_eq!(info.remote_addr(), "127.0.0.1:19132".parse().unwrap());
+        assert_eq!(info.protocol_version(), 11);
+        assert_eq!(info.conn_id().local_guid(), 114514);
+        assert_eq!(info.conn_id().to_string(), "114514-1919810@127.0.0.1:19132");
+        assert!(info.external_addr().is_none());
+
         tokio::pin!(src);
         tokio::pin!(dst);
 
@@ -90,6 +110,11 @@ async fn test_tokio_udp_works() {
             src.next().await.unwrap(),
             Bytes::from_iter(repeat(0xfe).take(256))
         );
+        // reported by the server in the handshake
+        assert_eq!(
+            src.connection_info().external_addr().map(|addr| addr.ip()),
+            Some("127.0.0.1".parse().unwrap())
+        );
         dst.send(Bytes::from_iter(repeat(0xfe).take(512)).into())
             .await
             .unwrap();
@@ -118,6 +143,11 @@ async fn test_tokio_udp_works() {
             src.next().await.unwrap(),
             Bytes::from_iter(repeat(0xfe).take(4096))
         );
+
+        let stats = src.stats();
+        assert!(stats.frames_sent() > 0);
+        assert!(stats.frame_sets_received() > 0);
+        assert!(stats.outbound_loss() <= 1.0);
     };
 
     tokio::spawn(client).await.unwrap();
@@ -238,6 +268,19 @@ async fn test_flush_strategy_works() {
                 .await
                 .unwrap();
                 assert_eq!(strategy.flushed_pack(), 1); // flushed the packet feed before
+                assert_eq!(strategy.resent_by_nack(), 0);
+                assert_eq!(strategy.resent_by_timeout(), 0);
+                assert_eq!(strategy.last_flushed(), (0, 0, 1));
+
+                // nothing to flush, the last counts are reset while the accumulated ones are not
+                poll_fn(|cx| {
+                    let mut cx = ContextBuilder::from(cx).ext(&mut strategy).build();
+                    sender.poll_flush_unpin(&mut cx)
+                })
+                .await
+                .unwrap();
+                assert_eq!(strategy.last_flushed(), (0, 0, 0));
+                assert_eq!(strategy.flushed_pack(), 1);
 
                 // not enabled, should panic
                 std::panic::catch_unwind(|| strategy.flushed_ack()).unwrap_err();
@@ -266,3 +309,2422 @@ async fn test_flush_strategy_works() {
         Bytes::from_iter(repeat(0xfe).take(256))
     );
 }
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_max_connections_works() {
+    let _guard = test_trace_log_setup();
+
+    let (closed_tx, mut closed_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    let server = async move {
+        let mut incoming = UdpSocket::bind("0.0.0.0:19135")
+            .await
+            .unwrap()
+            .make_incoming(make_server_conf().max_connections(1));
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            assert_eq!(incoming.connection_count(), 1);
+            // close the connection on server side
+            drop((src, dst));
+            closed_tx.send(()).unwrap();
+        }
+    };
+
+    tokio::spawn(server);
+
+    let client = async move {
+        let (_src1, dst1) = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .unwrap()
+            .connect_to("127.0.0.1:19135", make_client_conf())
+            .await
+            .unwrap();
+        tokio::pin!(dst1);
+
+        let err = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .unwrap()
+            .connect_to("127.0.0.1:19135", make_client_conf())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, ConnectError::ServerFull));
+        assert_eq!(
+            io::Error::from(err).kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+
+        // make the first connection be established on server side
+        dst1.send(Bytes::from_static(b"hello").into())
+            .await
+            .unwrap();
+        closed_rx.recv().await.unwrap();
+
+        // the slot is reclaimed after the first connection is closed
+        let (_src3, _dst3) = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .unwrap()
+            .connect_to("127.0.0.1:19135", make_client_conf())
+            .await
+            .unwrap();
+    };
+
+    tokio::spawn(client).await.unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_flush_interval_works() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = socket.local_addr().unwrap();
+    let mut incoming =
+        socket.make_incoming(make_server_conf().flush_interval(Duration::from_millis(10)));
+    tokio::spawn(async move {
+        loop {
+            let (reader, sender) = incoming.next().await.unwrap();
+            tokio::spawn(async move {
+                tokio::pin!(reader);
+                tokio::pin!(sender);
+                while let Some(data) = reader.next().await {
+                    // never flush explicitly
+                    sender
+                        .feed(Message::new(Reliability::Reliable, 0, data))
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    // the buffered data should be flushed in background
+    run_client(async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(
+                server_addr,
+                make_client_conf().flush_interval(Duration::from_millis(10)),
+            )
+            .await
+            .unwrap();
+
+        tokio::pin!(src);
+        tokio::pin!(dst);
+
+        for size in [16, 1024, 4096] {
+            dst.feed(Bytes::from_iter(repeat(0xfe).take(size)).into())
+                .await
+                .unwrap();
+            assert_eq!(
+                src.next().await.unwrap(),
+                Bytes::from_iter(repeat(0xfe).take(size))
+            );
+        }
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_decode_error_hook_works() {
+    let _guard = test_trace_log_setup();
+
+    let (err_tx, mut err_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    let server = async move {
+        let mut incoming = UdpSocket::bind("0.0.0.0:19137")
+            .await
+            .unwrap()
+            .make_incoming(make_server_conf().on_decode_error(move |addr, err| {
+                err_tx.send((addr, err.to_string())).unwrap();
+            }));
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            tokio::spawn(async move {
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                while let Some(data) = src.next().await {
+                    dst.send(Message::new(Reliability::Reliable, 0, data))
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    };
+
+    tokio::spawn(server);
+
+    let client = async move {
+        // an open connection request 1 with broken magic
+        let garbage = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        garbage
+            .send_to(&[0x05; 32], "127.0.0.1:19137")
+            .await
+            .unwrap();
+        let (addr, err) = err_rx.recv().await.unwrap();
+        assert_eq!(addr, garbage.local_addr().unwrap());
+        assert!(err.contains("magic"), "unexpected error {err}");
+
+        // the listener is still working
+        let (src, dst) = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .unwrap()
+            .connect_to("127.0.0.1:19137", make_client_conf())
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+        dst.send(Bytes::from_static(b"hello").into()).await.unwrap();
+        assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"hello"));
+    };
+
+    tokio::spawn(client).await.unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_connection_event_hook_works() {
+    let _guard = test_trace_log_setup();
+
+    let (ev_tx, mut ev_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    let server = async move {
+        let mut incoming = UdpSocket::bind("0.0.0.0:19138")
+            .await
+            .unwrap()
+            .make_incoming(make_server_conf().on_connection_event(move |ev| {
+                ev_tx.send(ev).unwrap();
+            }));
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            tokio::spawn(async move {
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                let mut ticker = tokio::time::interval(Duration::from_millis(5));
+                loop {
+                    tokio::select! {
+                        res = src.next() => {
+                            let Some(data) = res else {
+                                break;
+                            };
+                            assert_eq!(src.connection_info().client_guid(), Some(114514));
+                            dst.send(Message::new(Reliability::Reliable, 0, data)).await.unwrap();
+                        }
+                        _ = ticker.tick() => {
+                            dst.flush().await.unwrap();
+                        }
+                    };
+                }
+                dst.close().await.unwrap();
+            });
+        }
+    };
+
+    tokio::spawn(server);
+
+    let client = async move {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = socket.local_addr().unwrap();
+        let (src, dst) = socket
+            .connect_to("127.0.0.1:19138", make_client_conf())
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+        dst.send(Bytes::from_static(b"hello").into()).await.unwrap();
+        assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"hello"));
+        assert!(matches!(
+            ev_rx.recv().await.unwrap(),
+            server::ConnectionEvent::Connected { addr, guid: 114514, client_guid: 114514, .. }
+                if addr == client_addr
+        ));
+
+        SinkExt::<Message>::close(&mut dst).await.unwrap();
+        // flush the ack of the DisconnectNotification from server
+        let mut ticker = tokio::time::interval(Duration::from_millis(10));
+        let last_timer = tokio::time::sleep(Duration::from_millis(200));
+        tokio::pin!(last_timer);
+        let mut server_closed = false;
+        loop {
+            tokio::select! {
+                None = src.next(), if !server_closed => server_closed = true,
+                _ = ticker.tick() => {
+                    SinkExt::<Message>::flush(&mut dst).await.unwrap();
+                }
+                _ = &mut last_timer => break,
+            };
+        }
+        assert!(server_closed);
+        assert_eq!(
+            src.connection_info().close_reason(),
+            Some(crate::DisconnectReason::Graceful)
+        );
+
+        // close events are processed when the incoming is polled again
+        let (_src2, _dst2) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to("127.0.0.1:19138", make_client_conf())
+            .await
+            .unwrap();
+        assert_eq!(
+            ev_rx.recv().await.unwrap(),
+            server::ConnectionEvent::Disconnected {
+                addr: client_addr,
+                reason: crate::DisconnectReason::Graceful,
+            }
+        );
+    };
+
+    tokio::time::timeout(Duration::from_secs(10), tokio::spawn(client))
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_close_timeout_works() {
+    let _guard = test_trace_log_setup();
+
+    let (closed_tx, mut closed_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    let server = async move {
+        let mut incoming = UdpSocket::bind("0.0.0.0:19139")
+            .await
+            .unwrap()
+            .make_incoming(make_server_conf());
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            // drop the connection on server side, so the frames sent later will never be acked
+            drop((src, dst));
+            closed_tx.send(()).unwrap();
+        }
+    };
+
+    tokio::spawn(server);
+
+    let client = async move {
+        let (_src, dst) = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .unwrap()
+            .connect_to(
+                "127.0.0.1:19139",
+                make_client_conf().close_timeout(Duration::from_millis(500)),
+            )
+            .await
+            .unwrap();
+        tokio::pin!(dst);
+        dst.send(Bytes::from_static(b"hello").into()).await.unwrap();
+        closed_rx.recv().await.unwrap();
+
+        dst.send(Bytes::from_static(b"lost").into()).await.unwrap();
+        let err = SinkExt::<Message>::close(&mut dst).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        let timed_out = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<CloseTimedOut>())
+            .unwrap();
+        assert!(timed_out.unacked() > 0);
+
+        // closed
+        let closed = SinkExt::<Message>::close(&mut dst).await.unwrap_err();
+        assert_eq!(closed.kind(), std::io::ErrorKind::NotConnected);
+    };
+
+    tokio::time::timeout(Duration::from_secs(10), tokio::spawn(client))
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_batch_io_works() {
+    let _guard = test_trace_log_setup();
+
+    let server_addr =
+        spawn_echo_server(make_server_conf().send_batch_size(16).recv_batch_size(16)).await;
+
+    run_client(async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(
+                server_addr,
+                make_client_conf().send_batch_size(16).recv_batch_size(16),
+            )
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+        // finish the online handshake
+        dst.send(Bytes::from_static(b"hello").into()).await.unwrap();
+        assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"hello"));
+
+        for i in 0..64 {
+            dst.feed(Bytes::from_iter(repeat(0xfe).take(256 + i)).into())
+                .await
+                .unwrap();
+        }
+        dst.flush().await.unwrap();
+        // echoed reliably, not in order
+        let mut echoed = Vec::new();
+        for _ in 0..64 {
+            echoed.push(src.next().await.unwrap());
+        }
+        echoed.sort_by_key(Bytes::len);
+        for (i, data) in echoed.into_iter().enumerate() {
+            assert_eq!(data, Bytes::from_iter(repeat(0xfe).take(256 + i)));
+        }
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_jumbo_message_works() {
+    let _guard = test_trace_log_setup();
+
+    // driven in background to send the ACKs and resend the lost fragments
+    let server_addr = spawn_echo_server(
+        make_server_conf()
+            .max_parted_size(1024)
+            .self_driving(true)
+            .flush_interval(Duration::from_millis(5)),
+    )
+    .await;
+
+    run_client(async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(
+                server_addr,
+                make_client_conf().mtu(1200).max_parted_size(1024),
+            )
+            .await
+            .unwrap();
+        assert_eq!(src.connection_info().mtu(), 1200);
+
+        tokio::pin!(src);
+        tokio::pin!(dst);
+
+        // 1 MB reliable message, far larger than the mtu
+        let jumbo = Bytes::from_iter((0..1024 * 1024).map(|i| (i % 251) as u8 | 0x80));
+        dst.send(Message::new(Reliability::Reliable, 0, jumbo.clone()))
+            .await
+            .unwrap();
+        // keep flushing to resend the lost fragments while waiting for the echo
+        let mut ticker = tokio::time::interval(Duration::from_millis(5));
+        loop {
+            tokio::select! {
+                data = src.next() => {
+                    assert_eq!(data.unwrap(), jumbo);
+                    break;
+                }
+                _ = ticker.tick() => {
+                    dst.flush().await.unwrap();
+                }
+            };
+        }
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_flush_reliable_works() {
+    let _guard = test_trace_log_setup();
+
+    // driven in background to send the ACKs and resend the lost echoes
+    let server_addr = spawn_echo_server(
+        make_server_conf()
+            .self_driving(true)
+            .flush_interval(Duration::from_millis(5)),
+    )
+    .await;
+
+    run_client(async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(server_addr, make_client_conf())
+            .await
+            .unwrap();
+
+        tokio::pin!(src);
+        tokio::pin!(dst);
+
+        for size in [256, 4096] {
+            dst.feed(Bytes::from_iter(repeat(0xfe).take(size)).into())
+                .await
+                .unwrap();
+            // the acks are received by polling the stream
+            let mut echoed = false;
+            loop {
+                tokio::select! {
+                    res = dst.as_mut().flush_reliable() => {
+                        res.unwrap();
+                        break;
+                    }
+                    Some(data) = src.next(), if !echoed => {
+                        assert_eq!(data, Bytes::from_iter(repeat(0xfe).take(size)));
+                        echoed = true;
+                    }
+                };
+            }
+            if !echoed {
+                assert_eq!(
+                    src.next().await.unwrap(),
+                    Bytes::from_iter(repeat(0xfe).take(size))
+                );
+            }
+        }
+        // nothing to wait, and the connection is still open
+        dst.as_mut().flush_reliable().await.unwrap();
+        dst.send(Bytes::from_iter(repeat(0xfe).take(16)).into())
+            .await
+            .unwrap();
+        assert_eq!(
+            src.next().await.unwrap(),
+            Bytes::from_iter(repeat(0xfe).take(16))
+        );
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_frame_crypto_works() {
+    let _guard = test_trace_log_setup();
+
+    // XOR the body with the key and append the key as a tag
+    #[derive(Clone)]
+    struct XorCrypto {
+        key: u8,
+        encrypted: Arc<AtomicUsize>,
+    }
+
+    impl FrameCrypto for XorCrypto {
+        fn encrypt(&self, body: &mut BytesMut) {
+            body.iter_mut().for_each(|b| *b ^= self.key);
+            body.put_u8(self.key);
+            self.encrypted.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn decrypt(&self, body: &mut BytesMut) -> io::Result<()> {
+            if body.last() != Some(&self.key) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "tag not matched",
+                ));
+            }
+            body.truncate(body.len() - 1);
+            body.iter_mut().for_each(|b| *b ^= self.key);
+            Ok(())
+        }
+
+        fn overhead(&self) -> usize {
+            1
+        }
+    }
+
+    let crypto = XorCrypto {
+        key: 0x5a,
+        encrypted: Arc::new(AtomicUsize::new(0)),
+    };
+
+    let server_addr = spawn_echo_server(make_server_conf().crypto(crypto.clone())).await;
+
+    let client_crypto = crypto.clone();
+    run_client(async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(server_addr, make_client_conf().crypto(client_crypto))
+            .await
+            .unwrap();
+
+        tokio::pin!(src);
+        tokio::pin!(dst);
+
+        // the larger one is fragmented
+        for size in [256, 4096] {
+            dst.send(Bytes::from_iter(repeat(0xfe).take(size)).into())
+                .await
+                .unwrap();
+            assert_eq!(
+                src.next().await.unwrap(),
+                Bytes::from_iter(repeat(0xfe).take(size))
+            );
+        }
+    })
+    .await;
+    assert!(crypto.encrypted.load(Ordering::Relaxed) > 0);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_checksum_works() {
+    let _guard = test_trace_log_setup();
+
+    let server_addr = spawn_echo_server(make_server_conf().checksum(true)).await;
+
+    run_client(async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(server_addr, make_client_conf().checksum(true))
+            .await
+            .unwrap();
+
+        tokio::pin!(src);
+        tokio::pin!(dst);
+
+        // the larger one is fragmented into full sized frame sets
+        for size in [256, 4096] {
+            dst.send(Bytes::from_iter(repeat(0xfe).take(size)).into())
+                .await
+                .unwrap();
+            assert_eq!(
+                src.next().await.unwrap(),
+                Bytes::from_iter(repeat(0xfe).take(size))
+            );
+        }
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_multiple_sockets_works() {
+    let _guard = test_trace_log_setup();
+    let ipv6 = ipv6_available();
+
+    // the sockets share a random port
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let mut sockets = vec![socket];
+    if ipv6 {
+        sockets.push(UdpSocket::bind(("::1", port)).await.unwrap());
+    }
+    let mut incoming = sockets.make_incoming(make_server_conf());
+    tokio::spawn(async move {
+        loop {
+            let (reader, sender) = incoming.next().await.unwrap();
+            tokio::spawn(async move {
+                tokio::pin!(reader);
+                tokio::pin!(sender);
+                while let Some(data) = reader.next().await {
+                    sender
+                        .send(Message::new(Reliability::Reliable, 0, data))
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    let pairs = [
+        ("127.0.0.1:0", SocketAddr::from(([127, 0, 0, 1], port))),
+        ("[::1]:0", SocketAddr::from((Ipv6Addr::LOCALHOST, port))),
+    ];
+    for (local, remote) in pairs.into_iter().take(if ipv6 { 2 } else { 1 }) {
+        run_client(async move {
+            let (src, dst) = UdpSocket::bind(local)
+                .await
+                .unwrap()
+                .connect_to(remote, make_client_conf())
+                .await
+                .unwrap();
+
+            tokio::pin!(src);
+            tokio::pin!(dst);
+
+            dst.send(Bytes::from_iter(repeat(0xfe).take(256)).into())
+                .await
+                .unwrap();
+            // replied from the socket that the client connected to
+            assert_eq!(
+                src.next().await.unwrap(),
+                Bytes::from_iter(repeat(0xfe).take(256))
+            );
+        })
+        .await;
+    }
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_random_initial_seq_num_works() {
+    let _guard = test_trace_log_setup();
+
+    // both sides start right before the wrap around, the guards are created on this thread
+    crate::guard::INITIAL_SEQ_NUM.set(Some(0xff_fffd));
+    let server_addr = spawn_echo_server(make_server_conf().random_initial_seq_num(true)).await;
+    run_client(async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(server_addr, make_client_conf().random_initial_seq_num(true))
+            .await
+            .unwrap();
+
+        tokio::pin!(src);
+        tokio::pin!(dst);
+
+        for size in [256, 4096, 256, 4096] {
+            dst.send(Bytes::from_iter(repeat(0xfe).take(size)).into())
+                .await
+                .unwrap();
+            assert_eq!(
+                src.next().await.unwrap(),
+                Bytes::from_iter(repeat(0xfe).take(size))
+            );
+        }
+        // the initial sequence number is learned and the sequence carries across the wrap
+        // around, no gap is reported
+        assert!(src.stats().frame_sets_received() > 3);
+        assert_eq!(src.stats().frame_sets_lost(), 0);
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_datagram_tap_works() {
+    let _guard = test_trace_log_setup();
+
+    // Record the packet ID of each datagram
+    #[derive(Clone, Default)]
+    struct RecordTap {
+        raw_in: Arc<parking_lot::Mutex<Vec<u8>>>,
+        raw_out: Arc<parking_lot::Mutex<Vec<u8>>>,
+    }
+
+    impl DatagramTap for RecordTap {
+        fn on_raw_in(&self, datagram: &[u8], _addr: SocketAddr) {
+            self.raw_in.lock().push(datagram[0]);
+        }
+
+        fn on_raw_out(&self, datagram: &[u8], _addr: SocketAddr) {
+            self.raw_out.lock().push(datagram[0]);
+        }
+    }
+
+    let server_tap = RecordTap::default();
+    let echo_server = {
+        let server_tap = server_tap.clone();
+        async move {
+            let mut incoming = UdpSocket::bind("0.0.0.0:19147")
+                .await
+                .unwrap()
+                .make_incoming(make_server_conf().tap(server_tap));
+            loop {
+                let (reader, sender) = incoming.next().await.unwrap();
+                tokio::spawn(async move {
+                    tokio::pin!(reader);
+                    tokio::pin!(sender);
+                    while let Some(data) = reader.next().await {
+                        sender.send(data.into()).await.unwrap();
+                    }
+                });
+            }
+        }
+    };
+
+    tokio::spawn(echo_server);
+
+    let client_tap = RecordTap::default();
+    let client = {
+        let client_tap = client_tap.clone();
+        async move {
+            let (src, dst) = UdpSocket::bind("0.0.0.0:0")
+                .await
+                .unwrap()
+                .connect_to("127.0.0.1:19147", make_client_conf().tap(client_tap))
+                .await
+                .unwrap();
+
+            tokio::pin!(src);
+            tokio::pin!(dst);
+
+            dst.send(Bytes::from_static(b"\xfehello").into())
+                .await
+                .unwrap();
+            assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"\xfehello"));
+        }
+    };
+
+    tokio::time::timeout(Duration::from_secs(10), tokio::spawn(client))
+        .await
+        .unwrap()
+        .unwrap();
+
+    // the open connection request 1 is the first datagram on both sides
+    assert_eq!(client_tap.raw_out.lock().first(), Some(&0x05));
+    assert_eq!(server_tap.raw_in.lock().first(), Some(&0x05));
+    // the open connection reply 1
+    assert_eq!(client_tap.raw_in.lock().first(), Some(&0x06));
+    assert_eq!(server_tap.raw_out.lock().first(), Some(&0x06));
+    // the frame sets
+    assert!(client_tap.raw_out.lock().iter().any(|id| id & 0x80 != 0));
+    assert!(server_tap.raw_in.lock().iter().any(|id| id & 0x80 != 0));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_abort_works() {
+    let _guard = test_trace_log_setup();
+
+    let (ev_tx, mut ev_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    let server = async move {
+        let mut incoming = UdpSocket::bind("0.0.0.0:19148")
+            .await
+            .unwrap()
+            .make_incoming(make_server_conf().on_connection_event(move |ev| {
+                ev_tx.send(ev).unwrap();
+            }));
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            tokio::spawn(async move {
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                src.next().await.unwrap();
+                // the frames buffered are dropped without being sent
+                dst.feed(Bytes::from_static(b"\xfedropped").into())
+                    .await
+                    .unwrap();
+                dst.as_mut().abort().await.unwrap();
+                // aborting again is a no-op
+                dst.as_mut().abort().await.unwrap();
+            });
+        }
+    };
+
+    tokio::spawn(server);
+
+    let client = async move {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = socket.local_addr().unwrap();
+        let (src, dst) = socket
+            .connect_to("127.0.0.1:19148", make_client_conf())
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+        dst.send(Bytes::from_static(b"\xfehello").into())
+            .await
+            .unwrap();
+        // the DisconnectNotification closes the connection
+        assert!(src.next().await.is_none());
+
+        // close events are processed when the incoming is polled again
+        let (_src2, _dst2) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to("127.0.0.1:19148", make_client_conf())
+            .await
+            .unwrap();
+        loop {
+            if let server::ConnectionEvent::Disconnected { addr, reason } =
+                ev_rx.recv().await.unwrap()
+            {
+                assert_eq!(addr, client_addr);
+                assert_eq!(reason, crate::DisconnectReason::Aborted);
+                break;
+            }
+        }
+    };
+
+    tokio::time::timeout(Duration::from_secs(10), tokio::spawn(client))
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_send_unconnected_works() {
+    let _guard = test_trace_log_setup();
+
+    let incoming = UdpSocket::bind("127.0.0.1:19149")
+        .await
+        .unwrap()
+        .make_incoming(make_server_conf());
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+
+    incoming
+        .send_unconnected(peer_addr, Bytes::from_static(b"\xfediscovery"))
+        .await
+        .unwrap();
+    let mut buf = [0; 64];
+    let (len, from) = tokio::time::timeout(Duration::from_secs(10), peer.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..len], b"\xfediscovery");
+    assert_eq!(from, "127.0.0.1:19149".parse().unwrap());
+    // no connection is created
+    assert_eq!(incoming.connection_count(), 0);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_connection_migration_works() {
+    let _guard = test_trace_log_setup();
+
+    let (ev_tx, mut ev_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut incoming = UdpSocket::bind("127.0.0.1:19150")
+        .await
+        .unwrap()
+        .make_incoming(
+            make_server_conf()
+                .connection_migration(true)
+                .on_connection_event(move |ev| {
+                    if matches!(ev, server::ConnectionEvent::Migrated { .. }) {
+                        ev_tx.send(ev).unwrap();
+                    }
+                }),
+        );
+    let server = async move {
+        while let Some((src, dst)) = incoming.next().await {
+            tokio::spawn(async move {
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                let mut ticker = tokio::time::interval(Duration::from_millis(5));
+                loop {
+                    tokio::select! {
+                        res = src.next() => {
+                            let Some(data) = res else {
+                                break;
+                            };
+                            dst.send(Message::new(Reliability::Reliable, 0, data)).await.unwrap();
+                        }
+                        _ = ticker.tick() => {
+                            SinkExt::<Message>::flush(&mut dst).await.unwrap();
+                        }
+                    };
+                }
+            });
+        }
+    };
+    tokio::spawn(server);
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = socket.local_addr().unwrap();
+    let (src, dst) = socket
+        .connect_to("127.0.0.1:19150", make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(src);
+    tokio::pin!(dst);
+    dst.send(Bytes::from_static(b"\xfehello").into())
+        .await
+        .unwrap();
+    assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"\xfehello"));
+
+    // the client roams to another address, the sequence number continues within the window
+    let roamed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let roamed_addr = roamed.local_addr().unwrap();
+    let frames = [connected::Frame {
+        flags: connected::Flags::new(Reliability::Unreliable, false),
+        reliable_frame_index: None,
+        seq_frame_index: None,
+        ordered: None,
+        fragment: None,
+        priority: crate::Priority::Normal,
+        coalesce: false,
+        received: None,
+        deadline: None,
+        abandon_at: None,
+        receipt: None,
+        retransmits: 0,
+        body: Bytes::from_static(b"\xfemigrated"),
+    }];
+    let mut buf = BytesMut::new();
+    Packet::Connected(connected::Packet::FrameSet(connected::FrameSet {
+        seq_num: 100.into(),
+        set: &frames[..],
+    }))
+    .write(&mut buf, &packet::MAGIC, false);
+    roamed.send_to(&buf, "127.0.0.1:19150").await.unwrap();
+
+    assert_eq!(
+        ev_rx.recv().await.unwrap(),
+        server::ConnectionEvent::Migrated {
+            from: client_addr,
+            to: roamed_addr,
+        }
+    );
+    // the echo is sent to the new address
+    let echo = async {
+        let mut datagram = [0; 1500];
+        loop {
+            let len = roamed.recv(&mut datagram).await.unwrap();
+            if datagram[..len].windows(9).any(|w| w == b"\xfemigrated") {
+                break;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), echo)
+        .await
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_connection_migration_authenticated() {
+    let _guard = test_trace_log_setup();
+
+    struct Tagged;
+
+    impl FrameCrypto for Tagged {
+        fn encrypt(&self, body: &mut BytesMut) {
+            body.put_u8(0xaa);
+        }
+
+        fn decrypt(&self, body: &mut BytesMut) -> io::Result<()> {
+            if body.last() != Some(&0xaa) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "missing tag"));
+            }
+            body.truncate(body.len() - 1);
+            Ok(())
+        }
+
+        fn overhead(&self) -> usize {
+            1
+        }
+    }
+
+    let (ev_tx, mut ev_rx) = tokio::sync::mpsc::unbounded_channel();
+    let server_addr = spawn_echo_server(
+        make_server_conf()
+            .connection_migration(true)
+            .crypto(Tagged)
+            .on_connection_event(move |ev| {
+                if matches!(ev, server::ConnectionEvent::Migrated { .. }) {
+                    ev_tx.send(ev).unwrap();
+                }
+            }),
+    )
+    .await;
+
+    run_client(async move {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = socket.local_addr().unwrap();
+        let (src, dst) = socket
+            .connect_to(server_addr, make_client_conf().crypto(Tagged))
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+        dst.send(Bytes::from_static(b"\xfehello").into())
+            .await
+            .unwrap();
+        assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"\xfehello"));
+
+        let roamed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let roamed_addr = roamed.local_addr().unwrap();
+        let forge = |seq_num: u32, body: &'static [u8]| {
+            let frames = [connected::Frame {
+                flags: connected::Flags::new(Reliability::Unreliable, false),
+                reliable_frame_index: None,
+                seq_frame_index: None,
+                ordered: None,
+                fragment: None,
+                priority: crate::Priority::Normal,
+                coalesce: false,
+                received: None,
+                deadline: None,
+                abandon_at: None,
+                receipt: None,
+                retransmits: 0,
+                body: Bytes::from_static(body),
+            }];
+            let mut buf = BytesMut::new();
+            Packet::Connected(connected::Packet::FrameSet(connected::FrameSet {
+                seq_num: seq_num.into(),
+                set: &frames[..],
+            }))
+            .write(&mut buf, &packet::MAGIC, false);
+            buf
+        };
+
+        // the sequence number continues, but the frame set is not authenticated
+        roamed
+            .send_to(&forge(100, b"\xfehijacked"), server_addr)
+            .await
+            .unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), ev_rx.recv())
+                .await
+                .is_err()
+        );
+
+        roamed
+            .send_to(&forge(101, b"\xfemigrated\xaa"), server_addr)
+            .await
+            .unwrap();
+        assert_eq!(
+            ev_rx.recv().await.unwrap(),
+            server::ConnectionEvent::Migrated {
+                from: client_addr,
+                to: roamed_addr,
+            }
+        );
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_incoming_connections_works() {
+    let _guard = test_trace_log_setup();
+
+    let mut incoming = UdpSocket::bind("127.0.0.1:19151")
+        .await
+        .unwrap()
+        .make_incoming(make_server_conf());
+    let connections = incoming.connections();
+    assert!(connections.is_empty());
+    let server = async move {
+        while let Some((src, dst)) = incoming.next().await {
+            tokio::spawn(async move {
+                // the version negotiated in the offline handshake
+                assert_eq!(src.connection_info().protocol_version(), 11);
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                let mut ticker = tokio::time::interval(Duration::from_millis(5));
+                loop {
+                    tokio::select! {
+                        res = src.next() => {
+                            let Some(data) = res else {
+                                break;
+                            };
+                            dst.send(Message::new(Reliability::Reliable, 0, data)).await.unwrap();
+                        }
+                        _ = ticker.tick() => {
+                            SinkExt::<Message>::flush(&mut dst).await.unwrap();
+                        }
+                    };
+                }
+                SinkExt::<Message>::close(&mut dst).await.unwrap();
+            });
+        }
+    };
+    tokio::spawn(server);
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = socket.local_addr().unwrap();
+    let (src, dst) = socket
+        .connect_to("127.0.0.1:19151", make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(src);
+    tokio::pin!(dst);
+    dst.send(Bytes::from_static(b"\xfehello").into())
+        .await
+        .unwrap();
+    assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"\xfehello"));
+
+    // the handle could be used from another task
+    let snapshot = tokio::spawn({
+        let connections = connections.clone();
+        async move { connections.snapshot() }
+    })
+    .await
+    .unwrap();
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(snapshot[0].addr(), client_addr);
+    assert_eq!(snapshot[0].info().remote_guid(), 114514);
+    assert_eq!(snapshot[0].info().remote_addr(), client_addr);
+    assert_eq!(snapshot[0].info().mtu(), 1000);
+    assert_eq!(snapshot[0].info().protocol_version(), 11);
+    assert!(snapshot[0].stats().frame_sets_received() > 0);
+
+    SinkExt::<Message>::close(&mut dst).await.unwrap();
+    // close events are processed when the incoming is polled again
+    let removed = async {
+        loop {
+            let (_src, _dst) = UdpSocket::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .connect_to("127.0.0.1:19151", make_client_conf())
+                .await
+                .unwrap();
+            if connections
+                .snapshot()
+                .iter()
+                .all(|conn| conn.addr() != client_addr)
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), removed)
+        .await
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_incoming_shutdown_works() {
+    let _guard = test_trace_log_setup();
+
+    let mut incoming = UdpSocket::bind("127.0.0.1:19152")
+        .await
+        .unwrap()
+        .make_incoming(make_server_conf());
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    let mut server = tokio::spawn(async move {
+        tokio::pin!(shutdown_rx);
+        loop {
+            tokio::select! {
+                conn = incoming.next() => {
+                    let (src, dst) = conn.unwrap();
+                    tokio::spawn(async move {
+                        tokio::pin!(src);
+                        tokio::pin!(dst);
+                        let mut ticker = tokio::time::interval(Duration::from_millis(5));
+                        loop {
+                            tokio::select! {
+                                res = src.next() => {
+                                    let Some(data) = res else {
+                                        break;
+                                    };
+                                    // the sink is closed by the shutdown
+                                    let _ = dst.send(Message::new(Reliability::Reliable, 0, data)).await;
+                                }
+                                _ = ticker.tick() => {
+                                    SinkExt::<Message>::flush(&mut dst).await.unwrap();
+                                }
+                            };
+                        }
+                    });
+                }
+                _ = &mut shutdown_rx => break,
+            }
+        }
+        let started = std::time::Instant::now();
+        Pin::new(&mut incoming)
+            .shutdown(Duration::from_secs(10))
+            .await;
+        assert!(incoming.next().await.is_none());
+        assert_eq!(incoming.connection_count(), 0);
+        assert!(incoming.connections().is_empty());
+        started.elapsed()
+    });
+
+    let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to("127.0.0.1:19152", make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(src);
+    tokio::pin!(dst);
+    dst.send(Bytes::from_static(b"\xfehello").into())
+        .await
+        .unwrap();
+    assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"\xfehello"));
+
+    shutdown_tx.send(()).unwrap();
+    // the stream ends when the DisconnectNotification of the server is received
+    let mut ticker = tokio::time::interval(Duration::from_millis(5));
+    loop {
+        tokio::select! {
+            res = src.next() => {
+                if res.is_none() {
+                    break;
+                }
+            }
+            _ = ticker.tick() => {
+                SinkExt::<Message>::flush(&mut dst).await.unwrap();
+            }
+        };
+    }
+    // keep acknowledging until the server finished shutting down
+    let elapsed = loop {
+        tokio::select! {
+            res = &mut server => break res.unwrap(),
+            _ = ticker.tick() => {
+                SinkExt::<Message>::flush(&mut dst).await.unwrap();
+            }
+        }
+    };
+    // the connection closed before the deadline
+    assert!(elapsed < Duration::from_secs(10));
+}
+
+/// Spawn a proxy on `port` (0 for a random one) forwarding the datagrams between the server on
+/// `server_port` and a client, the datagrams from the client are dropped if `lossy` returns true.
+/// Returns the address of the proxy.
+async fn spawn_lossy_proxy(
+    port: u16,
+    server_port: u16,
+    mut lossy: impl FnMut(&[u8]) -> bool + Send + 'static,
+) -> SocketAddr {
+    let proxy = UdpSocket::bind(("127.0.0.1", port)).await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let server_addr = SocketAddr::from(([127, 0, 0, 1], server_port));
+    tokio::spawn(async move {
+        let mut client_addr = None;
+        let mut buf = [0; 1500];
+        loop {
+            let (len, from) = proxy.recv_from(&mut buf).await.unwrap();
+            let datagram = &buf[..len];
+            if from == server_addr {
+                if let Some(addr) = client_addr {
+                    proxy.send_to(datagram, addr).await.unwrap();
+                }
+                continue;
+            }
+            client_addr = Some(from);
+            if !lossy(datagram) {
+                proxy.send_to(datagram, server_addr).await.unwrap();
+            }
+        }
+    });
+    proxy_addr
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_self_driving_works() {
+    let _guard = test_trace_log_setup();
+
+    let mut incoming = UdpSocket::bind("127.0.0.1:19153")
+        .await
+        .unwrap()
+        .make_incoming(make_server_conf());
+    tokio::spawn(async move {
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            tokio::spawn(async move {
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                while let Some(data) = src.next().await {
+                    dst.send(Message::new(Reliability::Reliable, 0, data))
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    // a proxy which drops the first datagram carrying the data from the client
+    let mut dropped = false;
+    spawn_lossy_proxy(19154, 19153, move |datagram| {
+        let drop = !dropped && datagram.windows(7).any(|w| w == b"driving");
+        dropped |= drop;
+        drop
+    })
+    .await;
+
+    let client = async {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to("127.0.0.1:19154", make_client_conf().self_driving(true))
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+        dst.send(Bytes::from_static(b"\xfedriving").into())
+            .await
+            .unwrap();
+        // the dst is never polled again, the lost frame is resent in background
+        assert_eq!(
+            src.next().await.unwrap(),
+            Bytes::from_static(b"\xfedriving")
+        );
+    };
+    tokio::time::timeout(Duration::from_secs(60), tokio::spawn(client))
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_abandon_reliable_works() {
+    let _guard = test_trace_log_setup();
+
+    let mut incoming = UdpSocket::bind("127.0.0.1:19155")
+        .await
+        .unwrap()
+        .make_incoming(make_server_conf());
+    tokio::spawn(async move {
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            tokio::spawn(async move {
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                while let Some(data) = src.next().await {
+                    dst.send(Message::new(Reliability::Reliable, 0, data))
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+    // the message to be abandoned never arrives
+    spawn_lossy_proxy(19156, 19155, |datagram| {
+        datagram.windows(7).any(|w| w == b"abandon")
+    })
+    .await;
+
+    let client = async {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to("127.0.0.1:19156", make_client_conf().self_driving(true))
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+        let mut abandoned = Message::new(
+            Reliability::ReliableOrdered,
+            0,
+            Bytes::from_static(b"\xfeabandon"),
+        );
+        abandoned.set_abandon_after(Duration::ZERO);
+        dst.send(abandoned).await.unwrap();
+        dst.send(Bytes::from_static(b"\xfeafter").into())
+            .await
+            .unwrap();
+        // the ordering of the server does not wait for the abandoned one
+        assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"\xfeafter"));
+        assert_eq!(src.stats().frames_abandoned(), 1);
+    };
+    tokio::time::timeout(Duration::from_secs(60), tokio::spawn(client))
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_handshake_mtu_step_down_works() {
+    let _guard = test_trace_log_setup();
+
+    let server_addr = spawn_echo_server(make_server_conf().max_mtu(1200)).await;
+    // the open connection requests padded to larger MTUs than the server takes never get through,
+    // the MTU counts the IP and UDP headers
+    let requests = Arc::new(parking_lot::Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&requests);
+    let proxy_addr = spawn_lossy_proxy(0, server_addr.port(), move |datagram| {
+        if datagram[0] != 0x05 {
+            return false;
+        }
+        recorded.lock().push(datagram.len() + 28);
+        datagram.len() + 28 > 1200
+    })
+    .await;
+
+    run_client(async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(proxy_addr, make_client_conf().mtu(1500))
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+        assert_eq!(*requests.lock(), [1500, 1492, 1200]);
+        assert_eq!(src.connection_info().effective_mtu(), 1200);
+
+        let mut data = vec![b'd'; 3000];
+        data[0] = 0xfe;
+        let data = Bytes::from(data);
+        dst.send(data.clone().into()).await.unwrap();
+        assert_eq!(src.next().await.unwrap(), data);
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_mtu_blackhole_works() {
+    let _guard = test_trace_log_setup();
+
+    let server_addr = spawn_echo_server(make_server_conf()).await;
+    // a path dropping the frame sets above 700 bytes, after the handshake negotiated 1000
+    let proxy_addr = spawn_lossy_proxy(0, server_addr.port(), |datagram| {
+        datagram.len() > 700 && datagram[0] & 0x80 != 0
+    })
+    .await;
+
+    run_client(async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(
+                proxy_addr,
+                make_client_conf()
+                    .self_driving(true)
+                    .mtu_blackhole_retries(2),
+            )
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+        // split by the negotiated MTU, the parts never get through
+        let mut lost = vec![b'l'; 2000];
+        lost[0] = 0xfe;
+        dst.send(Bytes::from(lost).into()).await.unwrap();
+        // the small frame sets keep getting through until the MTU is reduced
+        while src.connection_info().effective_mtu() == 1000 {
+            dst.send(Message::new(
+                Reliability::Reliable,
+                1,
+                Bytes::from_static(b"\xfeping"),
+            ))
+            .await
+            .unwrap();
+            assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"\xfeping"));
+        }
+        assert_eq!(src.connection_info().effective_mtu(), 576);
+
+        // split by the reduced MTU, and the parts split for the old one are abandoned, so that the
+        // ordering of the server does not wait for them
+        let mut after = vec![b'a'; 2000];
+        after[0] = 0xfe;
+        let after = Bytes::from(after);
+        dst.send(after.clone().into()).await.unwrap();
+        loop {
+            let data = src.next().await.unwrap();
+            if data != Bytes::from_static(b"\xfeping") {
+                assert_eq!(data, after);
+                break;
+            }
+        }
+        assert!(src.stats().frames_abandoned() > 0);
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_recv_timeout_works() {
+    let _guard = test_trace_log_setup();
+
+    let mut incoming = UdpSocket::bind("127.0.0.1:19157")
+        .await
+        .unwrap()
+        .make_incoming(make_server_conf());
+    tokio::spawn(async move {
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            tokio::spawn(async move {
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                // only speak when spoken to
+                while let Some(data) = src.next().await {
+                    dst.send(Message::new(Reliability::Reliable, 0, data))
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    let client = async {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to("127.0.0.1:19157", make_client_conf())
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+        let err = src
+            .as_mut()
+            .recv_timeout(Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let timed_out = err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<RecvTimedOut>())
+            .unwrap();
+        assert!(timed_out.waited() >= Duration::from_millis(100));
+
+        // the stream is still usable
+        dst.send(Bytes::from_static(b"\xfehello").into())
+            .await
+            .unwrap();
+        assert_eq!(
+            src.recv_timeout(Duration::from_secs(10)).await.unwrap(),
+            Some(Bytes::from_static(b"\xfehello"))
+        );
+    };
+    tokio::time::timeout(Duration::from_secs(60), tokio::spawn(client))
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_handshake_cookie_works() {
+    let _guard = test_trace_log_setup();
+
+    let mut incoming = UdpSocket::bind("127.0.0.1:19158")
+        .await
+        .unwrap()
+        .make_incoming(make_server_conf().handshake_cookie(Duration::from_secs(10)));
+    tokio::spawn(async move {
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            tokio::spawn(async move {
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                while let Some(data) = src.next().await {
+                    dst.send(Message::new(Reliability::Reliable, 0, data))
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    let client = async {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to("127.0.0.1:19158", make_client_conf())
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+        dst.send(Bytes::from_static(b"\xfecookie").into())
+            .await
+            .unwrap();
+        assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"\xfecookie"));
+    };
+    tokio::time::timeout(Duration::from_secs(60), tokio::spawn(client))
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_connect_error_incompatible_version() {
+    let _guard = test_trace_log_setup();
+
+    let mut incoming = UdpSocket::bind("127.0.0.1:19159")
+        .await
+        .unwrap()
+        .make_incoming(make_server_conf());
+    tokio::spawn(async move { while incoming.next().await.is_some() {} });
+
+    let client = async {
+        let Err(err) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to("127.0.0.1:19159", make_client_conf().protocol_version(10))
+            .await
+        else {
+            panic!("expect the handshake to fail");
+        };
+        assert!(matches!(
+            err,
+            ConnectError::IncompatibleVersion {
+                server_protocol: 13
+            }
+        ));
+        assert_eq!(
+            io::Error::from(err).kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+    };
+    tokio::time::timeout(Duration::from_secs(60), tokio::spawn(client))
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_flush_all_works() {
+    let _guard = test_trace_log_setup();
+
+    let mut incoming = UdpSocket::bind("127.0.0.1:19160")
+        .await
+        .unwrap()
+        .make_incoming(make_server_conf());
+    let connections = incoming.connections();
+    let (fed_tx, mut fed_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some((src, dst)) = incoming.next().await {
+            let fed_tx = fed_tx.clone();
+            tokio::spawn(async move {
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                while let Some(data) = src.next().await {
+                    // left to the server to flush
+                    dst.feed(Message::new(Reliability::Reliable, 0, data))
+                        .await
+                        .unwrap();
+                    fed_tx.send(()).unwrap();
+                }
+            });
+        }
+    });
+
+    let client = async move {
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let (src, mut dst) = UdpSocket::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .connect_to("127.0.0.1:19160", make_client_conf())
+                .await
+                .unwrap();
+            dst.send(Bytes::from_static(b"\xfetick").into())
+                .await
+                .unwrap();
+            clients.push((Box::pin(src), dst));
+        }
+        for _ in 0..2 {
+            fed_rx.recv().await.unwrap();
+        }
+
+        let summary = connections.flush_all(Duration::from_secs(10)).await;
+        assert_eq!(summary.flushed(), 2);
+        assert_eq!(summary.failed(), 0);
+        assert_eq!(summary.pending(), 0);
+        for (src, _) in &mut clients {
+            assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"\xfetick"));
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(60), tokio::spawn(client))
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_frame_compression_works() {
+    let _guard = test_trace_log_setup();
+
+    // Run-length encodes the bodies in pairs of (count, byte)
+    struct RunLength;
+
+    impl FrameCompression for RunLength {
+        fn compress(&self, body: Bytes) -> Bytes {
+            let mut out = Vec::new();
+            for byte in body {
+                match out.as_mut_slice() {
+                    [.., count, last] if *last == byte && *count < u8::MAX => *count += 1,
+                    _ => out.extend([1, byte]),
+                }
+            }
+            out.into()
+        }
+
+        fn decompress(&self, body: Bytes) -> io::Result<Bytes> {
+            if body.len() % 2 != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "odd length"));
+            }
+            Ok(body
+                .chunks(2)
+                .flat_map(|pair| repeat(pair[1]).take(pair[0] as usize))
+                .collect())
+        }
+    }
+
+    // Records the largest body it encrypts, the tag is a single byte
+    #[derive(Clone, Default)]
+    struct Tagged {
+        largest: Arc<AtomicUsize>,
+    }
+
+    impl FrameCrypto for Tagged {
+        fn encrypt(&self, body: &mut BytesMut) {
+            self.largest.fetch_max(body.len(), Ordering::Relaxed);
+            body.put_u8(0xaa);
+        }
+
+        fn decrypt(&self, body: &mut BytesMut) -> io::Result<()> {
+            if body.last() != Some(&0xaa) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "missing tag"));
+            }
+            body.truncate(body.len() - 1);
+            Ok(())
+        }
+
+        fn overhead(&self) -> usize {
+            1
+        }
+    }
+
+    let mut incoming = UdpSocket::bind("127.0.0.1:19161")
+        .await
+        .unwrap()
+        .make_incoming(
+            make_server_conf()
+                .compression(RunLength)
+                .crypto(Tagged::default()),
+        );
+    tokio::spawn(async move {
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            tokio::spawn(async move {
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                while let Some(data) = src.next().await {
+                    dst.send(Message::new(Reliability::Reliable, 0, data))
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    let crypto = Tagged::default();
+    let client_crypto = crypto.clone();
+    let client = async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(
+                "127.0.0.1:19161",
+                make_client_conf()
+                    .compression(RunLength)
+                    .crypto(client_crypto),
+            )
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+
+        // it would be fragmented without the compression
+        let large = Bytes::from_iter(repeat(0xfe).take(4096));
+        dst.send(large.clone().into()).await.unwrap();
+        assert_eq!(src.next().await.unwrap(), large);
+    };
+    tokio::time::timeout(Duration::from_secs(60), tokio::spawn(client))
+        .await
+        .unwrap()
+        .unwrap();
+    // the bodies are compressed before being encrypted
+    assert!(crypto.largest.load(Ordering::Relaxed) < 200);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_pacing_works() {
+    let _guard = test_trace_log_setup();
+
+    let mut incoming = UdpSocket::bind("127.0.0.1:19162")
+        .await
+        .unwrap()
+        .make_incoming(make_server_conf().pacing(true));
+    tokio::spawn(async move {
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            tokio::spawn(async move {
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                while let Some(data) = src.next().await {
+                    dst.send(Message::new(Reliability::Reliable, 0, data))
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    let client = async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to("127.0.0.1:19162", make_client_conf().pacing(true))
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+
+        // larger than the initial window
+        let large = Bytes::from_iter(repeat(0xfe).take(16 * 1024));
+        dst.send(large.clone().into()).await.unwrap();
+        assert_eq!(src.next().await.unwrap(), large);
+        assert!(src.stats().pacing_rate() > 0);
+    };
+    tokio::time::timeout(Duration::from_secs(60), tokio::spawn(client))
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_config_build_validates() {
+    let err = server::Config::builder()
+        .min_mtu(1000)
+        .max_mtu(900)
+        .build()
+        .unwrap_err();
+    assert_eq!(err.option(), "min_mtu");
+    assert!(server::Config::builder().max_mtu(1400).build().is_ok());
+    assert_eq!(
+        client::Config::builder()
+            .send_buf_cap(0)
+            .build()
+            .unwrap_err()
+            .option(),
+        "send_buf_cap"
+    );
+    assert_eq!(
+        server::Config::builder()
+            .max_ack_count(65537)
+            .build()
+            .unwrap_err()
+            .option(),
+        "max_ack_count"
+    );
+    assert_eq!(
+        client::Config::builder()
+            .ack_buffer(0)
+            .build()
+            .unwrap_err()
+            .option(),
+        "ack_buffer"
+    );
+    // the ordered decoder takes fewer channels than u8::MAX
+    assert_eq!(
+        server::Config::builder()
+            .max_channels(255)
+            .build()
+            .unwrap_err()
+            .option(),
+        "max_channels"
+    );
+    assert_eq!(
+        client::Config::builder()
+            .max_channels(255)
+            .build()
+            .unwrap_err()
+            .option(),
+        "max_channels"
+    );
+    assert!(client::Config::builder().max_channels(254).build().is_ok());
+    for (min, max) in [(0, 1000), (2000, 1000)] {
+        let bounds = (Duration::from_millis(min), Duration::from_millis(max));
+        assert_eq!(
+            server::Config::builder()
+                .rto_bounds(bounds.0, bounds.1)
+                .build()
+                .unwrap_err()
+                .option(),
+            "rto_bounds"
+        );
+        assert_eq!(
+            client::Config::builder()
+                .rto_bounds(bounds.0, bounds.1)
+                .build()
+                .unwrap_err()
+                .option(),
+            "rto_bounds"
+        );
+    }
+
+    // connect_to validates the config before the handshake
+    let connect_err = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to("127.0.0.1:19163", make_client_conf().max_channels(0))
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(connect_err, ConnectError::InvalidConfig(_)));
+    assert_eq!(
+        io::Error::from(connect_err).kind(),
+        io::ErrorKind::InvalidInput
+    );
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_make_incoming_clamps_config() {
+    let _guard = test_trace_log_setup();
+
+    // not built, the invalid options are clamped instead of panicking in the connections
+    let server_addr = spawn_echo_server(
+        make_server_conf()
+            .send_buf_cap(0)
+            .max_channels(255)
+            .ack_buffer(0),
+    )
+    .await;
+
+    run_client(async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(server_addr, make_client_conf())
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+        dst.send(Bytes::from_static(b"clamped").into())
+            .await
+            .unwrap();
+        assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"clamped"));
+    })
+    .await;
+}
+
+/// Bind a server with `config` on a random local port, it echoes the messages of each connection
+/// reliably. Returns the address of the server.
+async fn spawn_echo_server(config: server::Config) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(config);
+    tokio::spawn(async move {
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            tokio::spawn(async move {
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                while let Some(data) = src.next().await {
+                    dst.send(Message::new(Reliability::Reliable, 0, data))
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Bind a server with `config` on a random local port, the messages of each connection are
+/// forwarded to the returned receiver with the connection id, followed by `None` once the
+/// connection ends. The sink of the connection is kept alive until then.
+async fn spawn_forward_server(
+    config: server::Config,
+) -> (
+    SocketAddr,
+    tokio::sync::mpsc::UnboundedReceiver<(ConnId, Option<Bytes>)>,
+) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(config);
+    let (forward_tx, forward_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            let forward_tx = forward_tx.clone();
+            tokio::spawn(async move {
+                let _dst = dst;
+                tokio::pin!(src);
+                let conn_id = src.connection_info().conn_id();
+                while let Some(data) = src.next().await {
+                    forward_tx.send((conn_id, Some(data))).unwrap();
+                }
+                forward_tx.send((conn_id, None)).unwrap();
+            });
+        }
+    });
+    (addr, forward_rx)
+}
+
+/// Run the client in its own task, it fails if the client panics or does not finish in time
+async fn run_client(client: impl Future<Output = ()> + Send + 'static) {
+    tokio::time::timeout(Duration::from_secs(60), tokio::spawn(client))
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_connect_unconnected_socket() {
+    let _guard = test_trace_log_setup();
+
+    let server_addr = spawn_echo_server(make_server_conf()).await;
+    run_client(async move {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let (src, dst) = Arc::clone(&socket)
+            .connect_to(server_addr, make_client_conf())
+            .await
+            .unwrap();
+        tokio::pin!(src);
+        tokio::pin!(dst);
+        // the socket is still able to talk to other peers
+        assert!(socket.peer_addr().is_err());
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket
+            .send_to(b"hello", other.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0; 8];
+        let (len, from) = other.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(from, socket.local_addr().unwrap());
+        // the datagrams from other peers are dropped by the connection
+        other
+            .send_to(b"\x84garbage", socket.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        dst.send(Bytes::from_static(b"ping").into()).await.unwrap();
+        assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"ping"));
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_packet_handler_works() {
+    let _guard = test_trace_log_setup();
+
+    let (handled_tx, mut handled_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (server_addr, mut passed_rx) = spawn_forward_server(make_server_conf().packet_handler(
+        0x86,
+        move |conn_id, data| {
+            handled_tx.send((conn_id, data)).unwrap();
+        },
+    ))
+    .await;
+    run_client(async move {
+        let (_src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(server_addr, make_client_conf())
+            .await
+            .unwrap();
+        tokio::pin!(dst);
+
+        dst.send(Bytes::from_static(b"\x86ping").into())
+            .await
+            .unwrap();
+        dst.send(Bytes::from_static(b"\xfehello").into())
+            .await
+            .unwrap();
+        // only the unregistered one is passed through
+        let (passed_id, passed) = passed_rx.recv().await.unwrap();
+        assert_eq!(passed, Some(Bytes::from_static(b"\xfehello")));
+        let (handled_id, handled) = handled_rx.recv().await.unwrap();
+        assert_eq!(handled, Bytes::from_static(b"\x86ping"));
+        assert_eq!(handled_id, passed_id);
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_disconnect_on_drop_works() {
+    let _guard = test_trace_log_setup();
+
+    let (server_addr, mut ended_rx) = spawn_forward_server(make_server_conf()).await;
+    run_client(async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(server_addr, make_client_conf())
+            .await
+            .unwrap();
+        let mut dst = Box::pin(dst);
+        dst.send(Bytes::from_static(b"\xfehello").into())
+            .await
+            .unwrap();
+        assert_eq!(
+            ended_rx.recv().await.unwrap().1,
+            Some(Bytes::from_static(b"\xfehello"))
+        );
+        // the connection stays open while its stream is still read
+        drop(dst);
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), ended_rx.recv())
+                .await
+                .is_err()
+        );
+        // dropped without closing, the server is notified long before the timeout
+        drop(src);
+        let ended = tokio::time::timeout(Duration::from_secs(5), ended_rx.recv())
+            .await
+            .unwrap();
+        assert_eq!(ended.map(|(_, data)| data), Some(None));
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_frame_meta_works() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf());
+    let (meta_tx, mut meta_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            let meta_tx = meta_tx.clone();
+            tokio::spawn(async move {
+                let _dst = dst;
+                let mut src = Box::pin(src.with_frame_meta());
+                while let Some(item) = src.next().await {
+                    meta_tx.send(item).unwrap();
+                }
+            });
+        }
+    });
+
+    run_client(async move {
+        let (_src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(server_addr, make_client_conf())
+            .await
+            .unwrap();
+        let mut dst = Box::pin(dst);
+        dst.send(Message::new(
+            Reliability::ReliableOrdered,
+            3,
+            Bytes::from_static(b"\xfehello"),
+        ))
+        .await
+        .unwrap();
+        // split into the parts by the MTU of 1000
+        let large = Bytes::from_iter(repeat(0xfe).take(2500));
+        dst.send(Message::new(Reliability::Reliable, 0, large.clone()))
+            .await
+            .unwrap();
+
+        let mut metas = vec![];
+        for _ in 0..2 {
+            metas.push(meta_rx.recv().await.unwrap());
+        }
+        let (small, small_meta) = &metas[0];
+        assert_eq!(small, &Bytes::from_static(b"\xfehello"));
+        assert_eq!(small_meta.reliability(), Reliability::ReliableOrdered);
+        assert_eq!(small_meta.order_channel(), Some(3));
+        assert_eq!(small_meta.order_index(), Some(0));
+        assert_eq!(small_meta.parted_size(), None);
+
+        let (reassembled, reassembled_meta) = &metas[1];
+        assert_eq!(reassembled, &large);
+        assert_eq!(reassembled_meta.reliability(), Reliability::Reliable);
+        assert_eq!(reassembled_meta.order_channel(), None);
+        assert_eq!(reassembled_meta.parted_size(), Some(3));
+        assert!(reassembled_meta.received_at() >= small_meta.received_at());
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_batched_messages_works() {
+    let _guard = test_trace_log_setup();
+
+    let server_addr = spawn_echo_server(make_server_conf()).await;
+    run_client(async move {
+        let (src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(server_addr, make_client_conf())
+            .await
+            .unwrap();
+        let mut src = Box::pin(src.unbatched());
+        let mut dst = Box::pin(dst.batched(256));
+
+        // the length prefix of the first payload is 0x15, the id of the disconnect notification
+        let payloads = [
+            Bytes::from_iter(repeat(0xfe).take(21)),
+            Bytes::new(),
+            Bytes::from_static(b"\x13\x10\x09\x03"),
+        ];
+        for payload in &payloads {
+            dst.feed(Message::new(
+                Reliability::ReliableOrdered,
+                0,
+                payload.clone(),
+            ))
+            .await
+            .unwrap();
+        }
+        dst.flush().await.unwrap();
+        for payload in payloads {
+            assert_eq!(src.next().await.unwrap().unwrap(), payload);
+        }
+
+        // the connection is still alive
+        dst.send(Bytes::from_static(b"ping").into()).await.unwrap();
+        assert_eq!(
+            src.next().await.unwrap().unwrap(),
+            Bytes::from_static(b"ping")
+        );
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_frame_crypto_mismatch() {
+    let _guard = test_trace_log_setup();
+
+    struct Plain;
+
+    impl FrameCrypto for Plain {
+        fn encrypt(&self, _body: &mut BytesMut) {}
+
+        fn decrypt(&self, _body: &mut BytesMut) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let encrypted_addr = spawn_echo_server(make_server_conf().crypto(Plain)).await;
+    let plain_addr = spawn_echo_server(make_server_conf()).await;
+    run_client(async move {
+        for (addr, client_conf, server_encrypted) in [
+            (encrypted_addr, make_client_conf(), true),
+            (plain_addr, make_client_conf().crypto(Plain), false),
+        ] {
+            let err = UdpSocket::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .connect_to(addr, client_conf)
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(
+                err,
+                ConnectError::EncryptionMismatch { server_encryption } if server_encryption == server_encrypted
+            ));
+            assert_eq!(
+                io::Error::from(err).kind(),
+                io::ErrorKind::ConnectionRefused
+            );
+        }
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_connection_event_handshake_abandoned() {
+    let _guard = test_trace_log_setup();
+
+    let (ev_tx, mut ev_rx) = tokio::sync::mpsc::unbounded_channel();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf().on_connection_event(move |ev| {
+        ev_tx.send(ev).unwrap();
+    }));
+    let abandoned = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let abandoned_addr = abandoned.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (src, dst) = incoming.next().await.unwrap();
+            if src.connection_info().remote_addr() == abandoned_addr {
+                // the peer never sends the `ConnectionRequest`
+                continue;
+            }
+            tokio::spawn(async move {
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                while let Some(data) = src.next().await {
+                    dst.send(Message::new(Reliability::Reliable, 0, data))
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    run_client(async move {
+        // stop after the offline handshake
+        let mut datagram = [0; 1500];
+        for request in [
+            packet::unconnected::Packet::OpenConnectionRequest1 {
+                magic: (),
+                protocol_version: 11,
+                mtu: 1000,
+            },
+            packet::unconnected::Packet::OpenConnectionRequest2 {
+                magic: (),
+                cookie: None,
+                server_address: server_addr,
+                mtu: 1000,
+                client_guid: 42,
+            },
+        ] {
+            let mut buf = BytesMut::new();
+            Packet::<&[connected::Frame]>::Unconnected(request).write(
+                &mut buf,
+                &packet::MAGIC,
+                false,
+            );
+            abandoned.send_to(&buf, server_addr).await.unwrap();
+            abandoned.recv(&mut datagram).await.unwrap();
+        }
+        // the connection is yielded on its first frame set, which is not a `ConnectionRequest`
+        let frames = [connected::Frame::new(
+            connected::Flags::new(Reliability::Unreliable, false),
+            Bytes::from_static(b"\xfehello"),
+        )];
+        let mut buf = BytesMut::new();
+        Packet::Connected(connected::Packet::FrameSet(connected::FrameSet {
+            seq_num: 0.into(),
+            set: &frames[..],
+        }))
+        .write(&mut buf, &packet::MAGIC, false);
+        abandoned.send_to(&buf, server_addr).await.unwrap();
+
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client_socket.local_addr().unwrap();
+        let (src, dst) = client_socket
+            .connect_to(server_addr, make_client_conf())
+            .await
+            .unwrap();
+        let mut src = Box::pin(src);
+        let mut dst = Box::pin(dst);
+        dst.send(Bytes::from_static(b"hello").into()).await.unwrap();
+        assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"hello"));
+        // the server is notified by the abort on drop
+        drop(dst);
+        drop(src);
+
+        // the close events are processed when the incoming is polled again
+        let (_src2, _dst2) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(server_addr, make_client_conf())
+            .await
+            .unwrap();
+        assert!(matches!(
+            ev_rx.recv().await.unwrap(),
+            server::ConnectionEvent::Connected { addr, .. } if addr == client_addr
+        ));
+        // the abandoned handshake is never reported
+        assert!(matches!(
+            ev_rx.recv().await.unwrap(),
+            server::ConnectionEvent::Disconnected { addr, .. } if addr == client_addr
+        ));
+    })
+    .await;
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_try_send_works() {
+    let _guard = test_trace_log_setup();
+
+    let (server_addr, mut forward_rx) = spawn_forward_server(make_server_conf()).await;
+    run_client(async move {
+        let (_src, dst) = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(server_addr, make_client_conf().send_buf_cap(2))
+            .await
+            .unwrap();
+        tokio::pin!(dst);
+        let msg = || Message::new(Reliability::Reliable, 0, Bytes::from_static(b"1"));
+
+        // the handshake is waiting to be sent ahead of the messages
+        assert!(matches!(
+            dst.as_mut().try_send(msg()),
+            Err(TrySendError::Full(_))
+        ));
+        dst.flush().await.unwrap();
+
+        dst.as_mut().try_send(msg()).unwrap();
+        dst.as_mut().try_send(msg()).unwrap();
+        assert!(matches!(
+            dst.as_mut().try_send(msg()),
+            Err(TrySendError::Full(_))
+        ));
+        // nothing is flushed by trying
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), forward_rx.recv())
+                .await
+                .is_err()
+        );
+
+        // the room is back once the buffer is flushed
+        dst.flush().await.unwrap();
+        dst.as_mut().try_send(msg()).unwrap();
+        dst.flush().await.unwrap();
+        for _ in 0..3 {
+            let (_, data) = forward_rx.recv().await.unwrap();
+            assert_eq!(data, Some(Bytes::from_static(b"1")));
+        }
+    })
+    .await;
+}
@@ -0,0 +1,51 @@
+use std::net::{SocketAddr, SocketAddrV6};
+
+/// Normalize the peer address into the identity of a connection. The flow info of an IPv6
+/// address is dropped since it may vary between the datagrams of a peer. The scope id is kept for
+/// the link-local addresses, so the peers on different interfaces with the same link-local address
+/// are different connections, and it is dropped for the other addresses where it is meaningless.
+pub(crate) fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => addr,
+        SocketAddr::V6(v6) => {
+            // fe80::/10, `Ipv6Addr::is_unicast_link_local` is unstable
+            let scope_id = if v6.ip().segments()[0] & 0xffc0 == 0xfe80 {
+                v6.scope_id()
+            } else {
+                0
+            };
+            SocketAddr::V6(SocketAddrV6::new(*v6.ip(), v6.port(), 0, scope_id))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+
+    use super::normalize_addr;
+
+    #[test]
+    fn test_normalize_addr_scope_id() {
+        let link_local = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+        let eth0 = SocketAddr::V6(SocketAddrV6::new(link_local, 19132, 0, 1));
+        let eth1 = SocketAddr::V6(SocketAddrV6::new(link_local, 19132, 0, 2));
+        let keys: HashSet<_> = [eth0, eth1].into_iter().map(normalize_addr).collect();
+        assert_eq!(keys.len(), 2);
+
+        // the flow info does not make a new connection
+        let flow = SocketAddr::V6(SocketAddrV6::new(link_local, 19132, 0x12345, 1));
+        assert_eq!(normalize_addr(flow), normalize_addr(eth0));
+
+        // the scope id of a global address is meaningless
+        let global = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        assert_eq!(
+            normalize_addr(SocketAddr::V6(SocketAddrV6::new(global, 19132, 0, 3))),
+            SocketAddr::V6(SocketAddrV6::new(global, 19132, 0, 0))
+        );
+
+        let v4 = SocketAddr::from(([127, 0, 0, 1], 19132));
+        assert_eq!(normalize_addr(v4), v4);
+    }
+}
@@ -0,0 +1,354 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{Sink, Stream};
+use pin_project_lite::pin_project;
+
+use crate::{Message, Priority, Reliability};
+
+/// The packet ID prefixing every batch. It is in the user packet range (from 0x86), so the
+/// receiver never mistakes a batch for a system packet, e.g. a first payload of 21 bytes would
+/// otherwise start with 0x15, which is read as a disconnect notification. It differs from the
+/// game packet ID 0xfe of Bedrock.
+pub const BATCH_PACKET_ID: u8 = 0xfd;
+
+/// Batch extension for the sinks of messages
+pub trait BatchSinkExt: Sink<Message> + Sized {
+    /// Concatenate the consecutive small messages into one message of at most `max_size` bytes,
+    /// which starts with [`BATCH_PACKET_ID`] followed by the payloads, each prefixed by its length
+    /// in LEB128. It saves the framing overhead of each
+    /// message for chatty protocols, the receiver splits them back by
+    /// [`UnbatchStreamExt::unbatched`].
+    ///
+    /// The messages are batched only if they have the same reliability, ordering channel,
+    /// priority, coalescing and TTL, otherwise a new batch is started. A message larger than
+    /// `max_size` is sent as a batch of its own. The pending batch is sent when it is full, or
+    /// when the sink is flushed or closed.
+    ///
+    /// It is opt-in and the batches are NOT compatible with the peers that do not unbatch them, so
+    /// both sides have to agree on it, e.g. by the protocol version.
+    fn batched(self, max_size: usize) -> Batched<Self> {
+        Batched {
+            sink: self,
+            max_size,
+            pending: None,
+            staged: None,
+        }
+    }
+}
+
+impl<S: Sink<Message>> BatchSinkExt for S {}
+
+/// Unbatch extension for the streams of received data
+pub trait UnbatchStreamExt: Stream<Item = Bytes> + Sized {
+    /// Split the data batched by [`BatchSinkExt::batched`] back into the payloads. The data not
+    /// starting with [`BATCH_PACKET_ID`] or a malformed batch yields an
+    /// [`io::ErrorKind::InvalidData`] error, and the rest of it is discarded.
+    fn unbatched(self) -> Unbatched<Self> {
+        Unbatched {
+            stream: self,
+            current: Bytes::new(),
+        }
+    }
+}
+
+impl<S: Stream<Item = Bytes>> UnbatchStreamExt for S {}
+
+// The settings shared by the messages of a batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BatchKey {
+    reliability: Reliability,
+    order_channel: u8,
+    priority: Priority,
+    coalesce: bool,
+    ttl: Option<Duration>,
+}
+
+impl BatchKey {
+    fn of(msg: &Message) -> Self {
+        Self {
+            reliability: msg.get_reliability(),
+            order_channel: msg.get_order_channel(),
+            priority: msg.get_priority(),
+            coalesce: msg.get_coalesce(),
+            ttl: msg.get_ttl(),
+        }
+    }
+
+    fn into_message(self, data: Bytes) -> Message {
+        let mut msg = Message::new(self.reliability, self.order_channel, data);
+        msg.set_priority(self.priority);
+        msg.set_coalesce(self.coalesce);
+        if let Some(ttl) = self.ttl {
+            msg.set_ttl(ttl);
+        }
+        msg
+    }
+}
+
+fn varint_len(mut value: usize) -> usize {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+fn put_varint(buf: &mut BytesMut, mut value: usize) {
+    while value >= 0x80 {
+        buf.put_u8((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn get_varint(buf: &mut Bytes) -> Option<usize> {
+    let mut value = 0_usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        if !buf.has_remaining() {
+            return None;
+        }
+        let byte = buf.get_u8();
+        value |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+pin_project! {
+    /// A sink returned by [`BatchSinkExt::batched`]
+    #[derive(Debug)]
+    pub struct Batched<S> {
+        #[pin]
+        sink: S,
+        max_size: usize,
+        // the batch being filled
+        pending: Option<(BatchKey, BytesMut)>,
+        // the batch waiting for the inner sink to be ready
+        staged: Option<Message>,
+    }
+}
+
+impl<S> Batched<S> {
+    /// Get the inner sink
+    pub fn get_ref(&self) -> &S {
+        &self.sink
+    }
+
+    /// Get the inner sink mutably
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+impl<S: Sink<Message>> Batched<S> {
+    fn poll_staged(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        let mut this = self.project();
+        if this.staged.is_some() {
+            ready!(this.sink.as_mut().poll_ready(cx))?;
+            this.sink.start_send(this.staged.take().unwrap())?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_pending(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        ready!(self.as_mut().poll_staged(cx))?;
+        let this = self.as_mut().project();
+        if let Some((key, buf)) = this.pending.take() {
+            *this.staged = Some(key.into_message(buf.freeze()));
+            ready!(self.poll_staged(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: Sink<Message>> Sink<Message> for Batched<S> {
+    type Error = S::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_staged(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: Message) -> Result<(), Self::Error> {
+        let this = self.project();
+        debug_assert!(this.staged.is_none(), "poll_ready must be called first");
+        let key = BatchKey::of(&msg);
+        let data = msg.into_data();
+        let size = varint_len(data.len()) + data.len();
+        if let Some((pending_key, buf)) = this.pending
+            && *pending_key == key
+            && buf.len() + size <= *this.max_size
+        {
+            put_varint(buf, data.len());
+            buf.put_slice(&data);
+            return Ok(());
+        }
+        // the pending batch is sent on the next poll_ready
+        if let Some((pending_key, buf)) = this.pending.take() {
+            *this.staged = Some(pending_key.into_message(buf.freeze()));
+        }
+        let mut buf = BytesMut::with_capacity((1 + size).max(*this.max_size));
+        buf.put_u8(BATCH_PACKET_ID);
+        put_varint(&mut buf, data.len());
+        buf.put_slice(&data);
+        *this.pending = Some((key, buf));
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_pending(cx))?;
+        self.project().sink.poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_pending(cx))?;
+        self.project().sink.poll_close(cx)
+    }
+}
+
+pin_project! {
+    /// A stream returned by [`UnbatchStreamExt::unbatched`]
+    #[derive(Debug)]
+    pub struct Unbatched<S> {
+        #[pin]
+        stream: S,
+        // the rest of the batch being split
+        current: Bytes,
+    }
+}
+
+impl<S: Stream<Item = Bytes>> Stream for Unbatched<S> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        while this.current.is_empty() {
+            let Some(batch) = ready!(this.stream.as_mut().poll_next(cx)) else {
+                return Poll::Ready(None);
+            };
+            if batch.first() != Some(&BATCH_PACKET_ID) {
+                return Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a batch",
+                ))));
+            }
+            *this.current = batch.slice(1..);
+        }
+        let Some(len) = get_varint(this.current).filter(|len| *len <= this.current.len()) else {
+            this.current.clear();
+            return Poll::Ready(Some(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed batch",
+            ))));
+        };
+        Poll::Ready(Some(Ok(this.current.split_to(len))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+
+    use super::{BatchSinkExt, UnbatchStreamExt, BATCH_PACKET_ID};
+    use crate::{Message, Reliability};
+
+    fn msg(reliability: Reliability, data: &'static [u8]) -> Message {
+        Message::new(reliability, 0, Bytes::from_static(data))
+    }
+
+    #[tokio::test]
+    async fn test_batched_works() {
+        let mut batched = Vec::new().batched(8);
+        batched
+            .feed(msg(Reliability::Reliable, b"ab"))
+            .await
+            .unwrap();
+        batched
+            .feed(msg(Reliability::Reliable, b"c"))
+            .await
+            .unwrap();
+        // exceeds the max size
+        batched
+            .feed(msg(Reliability::Reliable, b"defg"))
+            .await
+            .unwrap();
+        // different reliability
+        batched
+            .feed(msg(Reliability::Unreliable, b"h"))
+            .await
+            .unwrap();
+        // larger than the max size
+        let large = [0x42; 200];
+        batched
+            .feed(Message::new(
+                Reliability::Unreliable,
+                0,
+                Bytes::copy_from_slice(&large),
+            ))
+            .await
+            .unwrap();
+        // the last two batches are still buffered
+        assert_eq!(batched.get_ref().len(), 2);
+        batched.flush().await.unwrap();
+
+        let sent = std::mem::take(batched.get_mut());
+        assert_eq!(sent.len(), 4);
+        assert_eq!(sent[0].get_data().as_ref(), b"\xfd\x02ab\x01c");
+        assert_eq!(sent[1].get_data().as_ref(), b"\xfd\x04defg");
+        assert_eq!(sent[2].get_reliability(), Reliability::Unreliable);
+        assert_eq!(sent[3].get_data().len(), 203);
+        assert!(sent
+            .iter()
+            .all(|msg| msg.get_data().first() == Some(&BATCH_PACKET_ID)));
+
+        let payloads: Vec<_> = futures::stream::iter(sent.into_iter().map(Message::into_data))
+            .unbatched()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(
+            payloads,
+            vec![
+                Bytes::from_static(b"ab"),
+                Bytes::from_static(b"c"),
+                Bytes::from_static(b"defg"),
+                Bytes::from_static(b"h"),
+                Bytes::copy_from_slice(&large),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unbatched_malformed() {
+        let mut unbatched = futures::stream::iter([
+            Bytes::from_static(b"\xfd\x05ab"),
+            Bytes::from_static(b"\xfd"),
+            // not a batch
+            Bytes::from_static(b"\x01c"),
+            Bytes::from_static(b"\xfd\x01c"),
+        ])
+        .unbatched();
+        assert_eq!(
+            unbatched.next().await.unwrap().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            unbatched.next().await.unwrap().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            unbatched.next().await.unwrap().unwrap(),
+            Bytes::from_static(b"c")
+        );
+        assert!(unbatched.next().await.is_none());
+    }
+}
@@ -0,0 +1,42 @@
+// CRC-32 (IEEE 802.3) lookup table, generated at compile time
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Calculate the CRC-32 (IEEE 802.3) checksum of the data
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        (crc >> 8) ^ CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize]
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::crc32;
+
+    #[test]
+    fn test_crc32_works() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Sink;
+
+use crate::link::{DeferredAbort, SharedLink};
+use crate::opts::{Abort, TrySend, TrySendError};
+
+/// Aborts the wrapped sink of a connection once both halves of it are dropped without being
+/// closed, so that the peer is notified by a best-effort `DisconnectNotification` instead of
+/// waiting for the timeout. A sink dropped while its stream is still read is parked on the link
+/// until the stream is dropped as well. The abort is run on a detached task of the runtime, or
+/// polled once in place if there is no runtime, it never blocks the drop. Aborting a closed
+/// connection does nothing.
+pub(crate) struct DisconnectOnDrop<S> {
+    sink: Option<Pin<Box<S>>>,
+    link: SharedLink,
+    // set if the sink is aborted on drop, it is resolved where the bounds of `S` are known
+    abort: Option<DeferAbort<S>>,
+}
+
+type DeferAbort<S> = fn(Pin<Box<S>>) -> DeferredAbort;
+
+impl<S> DisconnectOnDrop<S>
+where
+    S: Sink<crate::Message, Error = io::Error> + Send + 'static,
+{
+    pub(crate) fn new(sink: S, link: SharedLink, enabled: bool) -> Self {
+        Self {
+            sink: Some(Box::pin(sink)),
+            link,
+            abort: enabled.then_some(defer_abort::<S> as DeferAbort<S>),
+        }
+    }
+}
+
+fn defer_abort<S>(sink: Pin<Box<S>>) -> DeferredAbort
+where
+    S: Sink<crate::Message, Error = io::Error> + Send + 'static,
+{
+    Box::new(move || spawn_abort(sink))
+}
+
+fn spawn_abort<S>(mut sink: Pin<Box<S>>)
+where
+    S: Sink<crate::Message, Error = io::Error> + Send + 'static,
+{
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let _ = std::pin::pin!(sink.as_mut().abort()).poll(&mut cx);
+        return;
+    };
+    runtime.spawn(async move {
+        let _ = sink.as_mut().abort().await;
+    });
+}
+
+impl<S> DisconnectOnDrop<S> {
+    fn sink(&mut self) -> Pin<&mut S> {
+        self.sink
+            .as_mut()
+            .expect("the sink is taken only on drop")
+            .as_mut()
+    }
+}
+
+impl<S> Drop for DisconnectOnDrop<S> {
+    fn drop(&mut self) {
+        let abort = self
+            .sink
+            .take()
+            .zip(self.abort)
+            .map(|(sink, abort)| abort(sink));
+        self.link.on_half_dropped(abort);
+    }
+}
+
+impl<S> TrySend for DisconnectOnDrop<S>
+where
+    S: Sink<crate::Message, Error = io::Error>,
+{
+    fn try_send(self: Pin<&mut Self>, msg: crate::Message) -> Result<(), TrySendError> {
+        let this = self.get_mut();
+        // the room published by the outgoing guard, it is not polled here so that neither the
+        // buffer is flushed nor the waker of the application is replaced
+        if !this.link.has_send_room() {
+            return Err(TrySendError::Full(msg));
+        }
+        Ok(this.sink().start_send(msg)?)
+    }
+}
+
+impl<S, T> Sink<T> for DisconnectOnDrop<S>
+where
+    S: Sink<T, Error = io::Error>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().sink().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        self.get_mut().sink().start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().sink().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().sink().poll_close(cx)
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Shares the send capacity of a socket among the connections on it. A connection with buffered
+/// frames takes a turn of sending at most `quantum` packets, then yields to other connections if
+/// any of them is backlogged too, so that a backed-up connection could not monopolize the socket.
+#[derive(Debug)]
+pub(crate) struct FairShare {
+    quantum: usize,
+    // the number of connections which have buffered frames to send
+    backlogged: AtomicUsize,
+}
+
+impl FairShare {
+    /// # Panics
+    /// Panics if `quantum` is zero.
+    pub(crate) fn new(quantum: usize) -> Arc<Self> {
+        assert!(quantum > 0, "the quantum must be greater than zero");
+        Arc::new(Self {
+            quantum,
+            backlogged: AtomicUsize::new(0),
+        })
+    }
+
+    /// Create the turn of a connection on the socket
+    pub(crate) fn turn(self: &Arc<Self>) -> FairTurn {
+        FairTurn {
+            share: Arc::clone(self),
+            backlogged: false,
+        }
+    }
+
+    fn backlogged(&self) -> usize {
+        self.backlogged.load(Ordering::Relaxed)
+    }
+}
+
+/// The state of a connection in the [`FairShare`], it leaves the backlog when dropped.
+#[derive(Debug)]
+pub(crate) struct FairTurn {
+    share: Arc<FairShare>,
+    backlogged: bool,
+}
+
+impl FairTurn {
+    /// Mark whether the connection has buffered frames to send
+    pub(crate) fn set_backlogged(&mut self, backlogged: bool) {
+        if self.backlogged == backlogged {
+            return;
+        }
+        self.backlogged = backlogged;
+        if backlogged {
+            self.share.backlogged.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.share.backlogged.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the connection should yield after sending `sent` packets in this turn
+    pub(crate) fn should_yield(&self, sent: usize) -> bool {
+        sent >= self.share.quantum && self.share.backlogged() > 1
+    }
+}
+
+impl Drop for FairTurn {
+    fn drop(&mut self) {
+        self.set_backlogged(false);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FairShare;
+
+    #[test]
+    fn test_fair_share_works() {
+        let share = FairShare::new(2);
+        let mut first = share.turn();
+        let mut second = share.turn();
+        first.set_backlogged(true);
+        first.set_backlogged(true);
+        // the only backlogged connection never yields
+        assert!(!first.should_yield(100));
+
+        second.set_backlogged(true);
+        assert!(!first.should_yield(1));
+        assert!(first.should_yield(2));
+        assert!(second.should_yield(2));
+
+        drop(second);
+        assert!(!first.should_yield(2));
+        first.set_backlogged(false);
+        assert_eq!(share.backlogged(), 0);
+    }
+}
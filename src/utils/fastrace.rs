@@ -2,17 +2,46 @@ use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use fastrace::collector::{SpanContext, TraceId};
-use fastrace::Span;
+use fastrace::{Event, Span};
 use futures::Stream;
 use pin_project_lite::pin_project;
 
-use crate::opts::TraceInfo;
+use crate::opts::{
+    AckNow, ConnInfo, ConnStats, ConnectionInfo, FrameMeta, LastFrameMeta, SendBufCap, Stats,
+    TraceInfo,
+};
+use crate::{ConnId, Peer};
+
+/// Add an event to the local parent span, the properties are only evaluated if the span is
+/// sampled, so it is cheap when the tracing is disabled.
+pub(crate) fn add_event<const N: usize>(
+    name: &'static str,
+    properties: impl FnOnce() -> [(&'static str, String); N],
+) {
+    Event::add_to_local_parent(name, || {
+        properties().map(|(key, value)| (key.into(), value.into()))
+    });
+}
+
+/// The root span of each packet received on a connection, the server and the client share it so
+/// that their traces are correlated by the same properties
+pub(crate) fn online_span(c_id: ConnId, peer: Peer) -> Span {
+    Span::root("online", SpanContext::random()).with_properties(|| {
+        [
+            ("conn_id", c_id.to_string()),
+            ("peer_guid", peer.guid.to_string()),
+            ("peer_addr", peer.addr.to_string()),
+            ("conn_mtu", peer.mtu.to_string()),
+        ]
+    })
+}
 
 pub(crate) trait TraceStreamExt: Stream + Sized {
     /// It starts a span at every time an item is generating from the stream, and the span will end
     /// when an option yield from the stream. So it could be used to track the span from last
     /// reception to the current reception of each packet .
     ///
+    /// ```text
     ///                [--------------**SPAN**-------------------]
     ///                v                                         v
     /// [---packet1---]           [-----------packet2-----------]
@@ -20,6 +49,7 @@ pub(crate) trait TraceStreamExt: Stream + Sized {
     ///                           [----codec children spans----]
     ///
     /// ------------------------- timeline ------------------------------>>>
+    /// ```
     fn enter_on_item<O: Fn() -> Span>(self, span_fn: O) -> EnterOnItem<Self, O> {
         EnterOnItem {
             inner: self,
@@ -67,6 +97,44 @@ where
     }
 }
 
+impl<T: ConnStats, O> ConnStats for EnterOnItem<T, O> {
+    fn stats(&self) -> Stats {
+        self.inner.stats()
+    }
+}
+
+impl<T: LastFrameMeta, O> LastFrameMeta for EnterOnItem<T, O> {
+    fn last_frame_meta(&self) -> Option<FrameMeta> {
+        self.inner.last_frame_meta()
+    }
+}
+
+impl<T: SendBufCap, O> SendBufCap for EnterOnItem<T, O> {
+    fn send_buf_cap(&self) -> usize {
+        self.inner.send_buf_cap()
+    }
+
+    fn set_send_buf_cap(&self, cap: usize) {
+        self.inner.set_send_buf_cap(cap);
+    }
+
+    fn reserve(&self, frames: usize) {
+        self.inner.reserve(frames);
+    }
+}
+
+impl<T: AckNow, O> AckNow for EnterOnItem<T, O> {
+    fn ack_now(&self) {
+        self.inner.ack_now();
+    }
+}
+
+impl<T: ConnInfo, O> ConnInfo for EnterOnItem<T, O> {
+    fn connection_info(&self) -> ConnectionInfo {
+        self.inner.connection_info()
+    }
+}
+
 impl<T, O> TraceInfo for EnterOnItem<T, O> {
     fn last_trace_id(&self) -> Option<TraceId> {
         self.last_trace_id
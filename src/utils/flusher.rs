@@ -0,0 +1,322 @@
+use std::future::{poll_fn, Future};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, ContextBuilder, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+
+use futures::task::AtomicWaker;
+use futures::Sink;
+use parking_lot::Mutex;
+
+use super::{Reactor, TimerKind};
+use crate::link::SharedLink;
+use crate::{ConnId, Message};
+
+/// The interval of the self-driving flusher if the flush interval is not set
+pub(crate) const DEFAULT_DRIVE_INTERVAL: Duration = Duration::from_millis(10);
+
+struct Shared<S> {
+    sink: Mutex<Pin<Box<S>>>,
+    // set when there are frames sent but not flushed yet
+    dirty: AtomicBool,
+    // waker of the flusher which is waiting for the sink to become dirty
+    waker: AtomicWaker,
+    // the sink is polled by the application and by the flush handle, it is always polled with
+    // this waker waking both of them, so that neither loses its waker to the other
+    polled: Arc<PolledBy>,
+    polled_waker: Waker,
+}
+
+/// The tasks waiting on the shared sink
+#[derive(Default)]
+struct PolledBy {
+    application: AtomicWaker,
+    handle: AtomicWaker,
+}
+
+impl Wake for PolledBy {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.application.wake();
+        self.handle.wake();
+    }
+}
+
+impl<S> Shared<S> {
+    fn new(sink: S) -> Self {
+        let polled = Arc::new(PolledBy::default());
+        Self {
+            sink: Mutex::new(Box::pin(sink)),
+            dirty: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+            polled_waker: Waker::from(Arc::clone(&polled)),
+            polled,
+        }
+    }
+
+    /// Poll the sink with `f` on behalf of the task whose waker is kept in `by`, the ext data of
+    /// `cx` is passed through
+    fn poll_by<R>(
+        &self,
+        by: impl FnOnce(&PolledBy) -> &AtomicWaker,
+        cx: &mut Context<'_>,
+        f: impl FnOnce(Pin<&mut S>, &mut Context<'_>) -> R,
+    ) -> R {
+        by(&self.polled).register(cx.waker());
+        let mut cx = ContextBuilder::from(cx).waker(&self.polled_waker).build();
+        f(self.sink.lock().as_mut(), &mut cx)
+    }
+}
+
+/// A type-erased handle flushing the shared sink, it is held by the
+/// [`Connections`](crate::opts::Connections) of the server
+pub(crate) trait FlushHandle: Send + Sync {
+    fn poll_flush(&self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>>;
+}
+
+impl<S> FlushHandle for Shared<S>
+where
+    S: Sink<Message, Error = io::Error> + Send,
+{
+    fn poll_flush(&self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let res = self.poll_by(|polled| &polled.handle, cx, Sink::<Message>::poll_flush);
+        if matches!(res, Poll::Ready(Ok(()))) {
+            self.dirty.store(false, Ordering::Release);
+        }
+        res
+    }
+}
+
+/// `AutoFlush` shares the sink with a background flusher, which flushes the sink at a fixed
+/// interval after some items were sent. So the items would not linger in the buffer if the user
+/// stops sending without an explicit flush.
+pub(crate) struct AutoFlush<S> {
+    shared: Arc<Shared<S>>,
+}
+
+pub(crate) trait AutoFlushed: Sized {
+    /// Make the sink auto flushed by the returned flusher at every `interval`, the timer is driven
+    /// by the [`Reactor`]. The flusher exits when the sink is dropped or fails.
+    ///
+    /// If the `driving` link is given, the flusher also flushes whenever the link has something to
+    /// do, so that the frames are resent and the ACKs are sent even if the application stops
+    /// using the sink.
+    fn auto_flushed(
+        self,
+        interval: Duration,
+        c_id: ConnId,
+        driving: Option<SharedLink>,
+    ) -> (AutoFlush<Self>, impl Future<Output = ()> + Send + 'static);
+
+    /// Share the sink without the background flusher, so that it could still be flushed by the
+    /// [`FlushHandle`]
+    fn shared(self) -> AutoFlush<Self>;
+}
+
+impl<S> AutoFlushed for S
+where
+    S: Sink<Message, Error = io::Error> + Send + 'static,
+{
+    fn auto_flushed(
+        self,
+        interval: Duration,
+        c_id: ConnId,
+        driving: Option<SharedLink>,
+    ) -> (AutoFlush<Self>, impl Future<Output = ()> + Send + 'static) {
+        let shared = Arc::new(Shared::new(self));
+        let flusher = flusher(Arc::downgrade(&shared), interval, c_id, driving);
+        (AutoFlush { shared }, flusher)
+    }
+
+    fn shared(self) -> AutoFlush<Self> {
+        AutoFlush {
+            shared: Arc::new(Shared::new(self)),
+        }
+    }
+}
+
+impl<S> AutoFlush<S>
+where
+    S: Sink<Message, Error = io::Error> + Send + 'static,
+{
+    /// Get the handle flushing the sink, it does not keep the sink alive
+    pub(crate) fn flush_handle(&self) -> Weak<dyn FlushHandle> {
+        Arc::downgrade(&self.shared) as Weak<dyn FlushHandle>
+    }
+}
+
+async fn flusher<S>(
+    shared: Weak<Shared<S>>,
+    interval: Duration,
+    c_id: ConnId,
+    driving: Option<SharedLink>,
+) where
+    S: Sink<Message, Error = io::Error>,
+{
+    loop {
+        // do not wake up when there is nothing to flush
+        let alive = poll_fn(|cx| {
+            let Some(shared) = shared.upgrade() else {
+                return Poll::Ready(false);
+            };
+            shared.waker.register(cx.waker());
+            if shared.dirty.swap(false, Ordering::AcqRel) {
+                return Poll::Ready(true);
+            }
+            if let Some(link) = &driving {
+                link.register_driver(cx.waker());
+                if link.needs_driving() {
+                    return Poll::Ready(true);
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        if !alive {
+            return;
+        }
+
+        let when = Instant::now() + interval;
+        let mut timer = None;
+        poll_fn(|cx| {
+            if Instant::now() >= when {
+                return Poll::Ready(());
+            }
+            // the flusher may be polled before the timer fires, the waker is updated by resetting
+            match &mut timer {
+                Some(timer) => Reactor::get().reset_timer(timer, when, cx.waker()),
+                None => {
+                    timer =
+                        Some(Reactor::get().insert_timer(c_id, TimerKind::Flush, when, cx.waker()));
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        // poll once, the sink will be flushed again at the next interval if it is pending, so that
+        // the flusher will not be stuck on the waker of the sink. The sink is polled with the waker
+        // of the tasks waiting on it, so that they are still woken.
+        let res = {
+            let mut cx = Context::from_waker(&shared.polled_waker);
+            Sink::<Message>::poll_flush(shared.sink.lock().as_mut(), &mut cx)
+        };
+        match res {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(_)) => return,
+            Poll::Pending => shared.dirty.store(true, Ordering::Release),
+        }
+    }
+}
+
+impl<S> Drop for AutoFlush<S> {
+    fn drop(&mut self) {
+        // notify the flusher to exit
+        self.shared.waker.wake();
+    }
+}
+
+impl<S, T> Sink<T> for AutoFlush<S>
+where
+    S: Sink<T, Error = io::Error>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.shared
+            .poll_by(|polled| &polled.application, cx, Sink::poll_ready)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        self.shared.sink.lock().as_mut().start_send(item)?;
+        self.shared.dirty.store(true, Ordering::Release);
+        self.shared.waker.wake();
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let res = self
+            .shared
+            .poll_by(|polled| &polled.application, cx, Sink::poll_flush);
+        if matches!(res, Poll::Ready(Ok(()))) {
+            self.shared.dirty.store(false, Ordering::Release);
+        }
+        res
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.shared
+            .poll_by(|polled| &polled.application, cx, Sink::poll_close)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::atomic::Ordering;
+    use std::task::{Context, Poll, Waker};
+
+    use futures::Sink;
+
+    use super::AutoFlushed;
+    use crate::utils::tests::TestWaker;
+    use crate::Message;
+
+    /// The sink pending on flush until it is woken, like a socket backed up
+    #[derive(Default)]
+    struct PendingSink {
+        waker: Option<Waker>,
+    }
+
+    impl Sink<Message> for PendingSink {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, _item: Message) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_flush_handle_keeps_application_waker() {
+        let mut dst = PendingSink::default().shared();
+        let handle = dst.flush_handle().upgrade().unwrap();
+
+        let (application, application_woken) = TestWaker::pair();
+        let res =
+            Sink::<Message>::poll_flush(Pin::new(&mut dst), &mut Context::from_waker(&application));
+        assert!(res.is_pending());
+        // e.g. `Connections::flush_all` polls the same sink in another task
+        let (flush_all, flush_all_woken) = TestWaker::pair();
+        assert!(handle
+            .poll_flush(&mut Context::from_waker(&flush_all))
+            .is_pending());
+
+        // the sink becomes writable, both of the tasks waiting on it are woken
+        let waker = dst.shared.sink.lock().waker.take().unwrap();
+        waker.wake();
+        assert!(application_woken.woken.load(Ordering::Relaxed));
+        assert!(flush_all_woken.woken.load(Ordering::Relaxed));
+    }
+}
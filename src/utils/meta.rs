@@ -0,0 +1,47 @@
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::Bytes;
+use futures::Stream;
+use pin_project_lite::pin_project;
+
+use crate::opts::{FrameMeta, LastFrameMeta};
+
+/// Frame metadata extension for the streams of connections
+pub trait FrameMetaStreamExt: Stream<Item = Bytes> + LastFrameMeta + Sized {
+    /// Yield the metadata of the frame carrying each payload alongside it, e.g. the reliability,
+    /// the ordering and the receive time, for the applications building their own reliability on
+    /// top or analyzing the latency.
+    fn with_frame_meta(self) -> WithFrameMeta<Self> {
+        WithFrameMeta { stream: self }
+    }
+}
+
+impl<S: Stream<Item = Bytes> + LastFrameMeta> FrameMetaStreamExt for S {}
+
+pin_project! {
+    /// The stream returned by [`FrameMetaStreamExt::with_frame_meta`]
+    pub struct WithFrameMeta<S> {
+        #[pin]
+        stream: S,
+    }
+}
+
+impl<S> Stream for WithFrameMeta<S>
+where
+    S: Stream<Item = Bytes> + LastFrameMeta,
+{
+    type Item = (Bytes, FrameMeta);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        let Some(data) = ready!(this.stream.as_mut().poll_next(cx)) else {
+            return Poll::Ready(None);
+        };
+        let meta = this
+            .stream
+            .last_frame_meta()
+            .expect("the metadata is recorded before the payload is yielded");
+        Poll::Ready(Some((data, meta)))
+    }
+}
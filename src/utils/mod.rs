@@ -1,19 +1,58 @@
+#[cfg(feature = "std")]
+mod addr;
+#[cfg(feature = "std")]
+pub(crate) mod batch;
+#[cfg(feature = "std")]
 mod bit_queue;
+mod checksum;
+#[cfg(feature = "tokio-rt")]
+mod drop;
+#[cfg(feature = "std")]
+mod fair_share;
+#[cfg(feature = "std")]
 mod fastrace;
+#[cfg(feature = "std")]
+mod flusher;
+#[cfg(feature = "std")]
 mod log;
+#[cfg(feature = "std")]
+pub(crate) mod meta;
+#[cfg(feature = "std")]
+mod pacer;
+#[cfg(feature = "std")]
+pub(crate) mod partition;
+#[cfg(feature = "std")]
+pub(crate) mod peek;
+#[cfg(feature = "std")]
 mod reactor;
 mod seq_num;
 
+#[cfg(feature = "std")]
+pub(crate) use self::addr::*;
+#[cfg(feature = "std")]
 pub(crate) use self::bit_queue::*;
+pub(crate) use self::checksum::*;
+#[cfg(feature = "tokio-rt")]
+pub(crate) use self::drop::*;
+#[cfg(feature = "std")]
+pub(crate) use self::fair_share::*;
+#[cfg(feature = "std")]
 pub(crate) use self::fastrace::*;
+#[cfg(feature = "std")]
+pub(crate) use self::flusher::*;
+#[cfg(feature = "std")]
 pub(crate) use self::log::*;
+#[cfg(feature = "std")]
+pub(crate) use self::pacer::*;
+#[cfg(feature = "std")]
 pub(crate) use self::reactor::*;
-pub(crate) use self::seq_num::*;
+pub use self::seq_num::*;
 
 /// Test utils.
 #[cfg(test)]
 pub(crate) mod tests;
 
+#[cfg(feature = "std")]
 #[inline]
 pub(crate) fn timestamp() -> i64 {
     std::time::SystemTime::now()
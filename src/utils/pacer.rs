@@ -0,0 +1,153 @@
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use super::{Reactor, TimerKind};
+use crate::ConnId;
+
+/// A token bucket spacing out the sent bytes at a rate, the tokens are refilled continuously and
+/// capped to a burst of `burst` long at the rate. A send larger than the burst is allowed once the
+/// bucket is full, the bucket then goes into debt so that the average rate is still kept.
+///
+/// It is unlimited (a no-op) until a rate is set, e.g. when pacing is disabled.
+#[derive(Debug)]
+pub(crate) struct Pacer {
+    // the rate in bytes per second, `None` means unlimited
+    rate: Option<u64>,
+    burst: Duration,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Pacer {
+    pub(crate) fn unlimited() -> Self {
+        Self {
+            rate: None,
+            burst: Duration::ZERO,
+            tokens: 0.0,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Create a full bucket refilled at `rate` bytes per second
+    pub(crate) fn new(rate: u64, burst: Duration, now: Instant) -> Self {
+        let mut pacer = Self {
+            rate: Some(rate),
+            burst,
+            tokens: 0.0,
+            refilled_at: now,
+        };
+        pacer.tokens = pacer.capacity();
+        pacer
+    }
+
+    /// The rate in bytes per second, `None` if it is unlimited
+    pub(crate) fn rate(&self) -> Option<u64> {
+        self.rate
+    }
+
+    /// Change the rate, the tokens refilled at the old rate are kept up to the new capacity
+    pub(crate) fn set_rate(&mut self, rate: u64, now: Instant) {
+        if self.rate.is_none() {
+            return;
+        }
+        self.refill(now);
+        self.rate = Some(rate);
+        self.tokens = self.tokens.min(self.capacity());
+    }
+
+    fn capacity(&self) -> f64 {
+        self.rate.unwrap_or(0) as f64 * self.burst.as_secs_f64()
+    }
+
+    // the tokens required to send `bytes`
+    fn required(&self, bytes: usize) -> f64 {
+        (bytes as f64).min(self.capacity())
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.refilled_at = self.refilled_at.max(now);
+        let refilled = self.rate.unwrap_or(0) as f64 * elapsed.as_secs_f64();
+        self.tokens = (self.tokens + refilled).min(self.capacity());
+    }
+
+    /// Consume the tokens of `bytes` if they are available at `now`
+    pub(crate) fn try_consume(&mut self, bytes: usize, now: Instant) -> bool {
+        if self.rate.is_none() {
+            return true;
+        }
+        self.refill(now);
+        if self.tokens < self.required(bytes) {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
+    }
+
+    /// The time when the tokens of `bytes` are refilled, `None` if they are available now
+    pub(crate) fn refill_at(&self, bytes: usize) -> Option<Instant> {
+        let rate = self.rate?;
+        let lack = self.required(bytes) - self.tokens;
+        (lack > 0.0).then(|| self.refilled_at + Duration::from_secs_f64(lack / rate.max(1) as f64))
+    }
+
+    /// Consume the tokens of `bytes`, or suspend the task of the connection `c_id` until they are
+    /// refilled
+    pub(crate) fn poll_consume(
+        &mut self,
+        c_id: ConnId,
+        bytes: usize,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        if self.try_consume(bytes, Instant::now()) {
+            return Poll::Ready(());
+        }
+        if let Some(refill_at) = self.refill_at(bytes) {
+            Reactor::get().insert_timer(c_id, TimerKind::Pacing, refill_at, cx.waker());
+        } else {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::Pacer;
+
+    #[test]
+    fn test_pacer_works() {
+        let mut unlimited = Pacer::unlimited();
+        assert!(unlimited.try_consume(usize::MAX, Instant::now()));
+        assert_eq!(unlimited.refill_at(usize::MAX), None);
+        unlimited.set_rate(1000, Instant::now());
+        assert_eq!(unlimited.rate(), None);
+
+        let start = Instant::now();
+        // 1000 bytes per second, 2000 bytes at most
+        let mut pacer = Pacer::new(1000, Duration::from_secs(2), start);
+        assert!(pacer.try_consume(1500, start));
+        assert!(!pacer.try_consume(1500, start));
+        assert_eq!(pacer.refill_at(1500), Some(start + Duration::from_secs(1)));
+
+        // the credit of the idle period is capped to the burst
+        let now = start + Duration::from_secs(10);
+        assert!(pacer.try_consume(2000, now));
+        assert!(!pacer.try_consume(1, now));
+
+        // a larger send waits for the full bucket, then goes into debt
+        assert_eq!(pacer.refill_at(10000), Some(now + Duration::from_secs(2)));
+        let later = now + Duration::from_secs(2);
+        assert!(pacer.try_consume(10000, later));
+        assert_eq!(pacer.refill_at(1000), Some(later + Duration::from_secs(9)));
+
+        // the tokens are capped to the new capacity
+        let idle = later + Duration::from_secs(100);
+        pacer.set_rate(100, idle);
+        assert_eq!(pacer.rate(), Some(100));
+        assert!(pacer.try_consume(200, idle));
+        assert!(!pacer.try_consume(1, idle));
+    }
+}
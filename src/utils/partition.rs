@@ -0,0 +1,244 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use futures::Stream;
+use parking_lot::Mutex;
+
+/// Partition extension for streams
+pub trait PartitionStreamExt: Stream + Sized {
+    /// Split the stream into `n` sub-streams by the key of each item, the key is taken modulo `n`.
+    /// The items are moved into the sub-stream of their key without cloning, and each sub-stream
+    /// could be consumed independently, e.g. in its own task. The received data does not carry
+    /// the ordering channel, so the key is given by the application, e.g. by the packet ID.
+    ///
+    /// Each sub-stream buffers at most `capacity` items that were received on behalf of it. When
+    /// it is full, the source is no longer polled until the slow sub-stream catches up, so the
+    /// backpressure of a sub-stream is propagated to the source instead of buffering without
+    /// limit. The items of a dropped sub-stream are discarded. The sub-streams end after the
+    /// source ends and their buffered items are drained.
+    ///
+    /// # Panics
+    /// Panics if `n` or `capacity` is zero.
+    fn partitioned<K>(self, n: usize, capacity: usize, key: K) -> Vec<Partitioned<Self, K>>
+    where
+        K: Fn(&Self::Item) -> usize,
+    {
+        assert!(n > 0, "the number of partitions must be greater than zero");
+        assert!(
+            capacity > 0,
+            "the capacity of a partition must be greater than zero"
+        );
+        let wakers = Arc::new(Wakers(Mutex::new(vec![None; n])));
+        let shared = Arc::new(Mutex::new(Shared {
+            source: Box::pin(self),
+            source_waker: Waker::from(Arc::clone(&wakers)),
+            key,
+            capacity,
+            queues: (0..n).map(|_| VecDeque::new()).collect(),
+            dropped: vec![false; n],
+            stalled: None,
+            done: false,
+        }));
+        (0..n)
+            .map(|index| Partitioned {
+                index,
+                shared: Arc::clone(&shared),
+                wakers: Arc::clone(&wakers),
+            })
+            .collect()
+    }
+}
+
+impl<S: Stream> PartitionStreamExt for S {}
+
+// The wakers of the partitions, the source wakes all of them since any partition could be the one
+// pulling the next item.
+struct Wakers(Mutex<Vec<Option<Waker>>>);
+
+impl Wakers {
+    fn register(&self, index: usize, cx: &Context<'_>) {
+        match &mut self.0.lock()[index] {
+            Some(waker) => waker.clone_from(cx.waker()),
+            slot @ None => *slot = Some(cx.waker().clone()),
+        }
+    }
+
+    fn wake_one(&self, index: usize) {
+        let waker = self.0.lock()[index].take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl Wake for Wakers {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let wakers: Vec<Waker> = self.0.lock().iter_mut().filter_map(Option::take).collect();
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+struct Shared<S: Stream, K> {
+    source: Pin<Box<S>>,
+    source_waker: Waker,
+    key: K,
+    capacity: usize,
+    queues: Vec<VecDeque<S::Item>>,
+    dropped: Vec<bool>,
+    // the item received from the source whose partition is full
+    stalled: Option<(usize, S::Item)>,
+    done: bool,
+}
+
+/// A sub-stream returned by [`PartitionStreamExt::partitioned`]
+pub struct Partitioned<S: Stream, K> {
+    index: usize,
+    shared: Arc<Mutex<Shared<S, K>>>,
+    wakers: Arc<Wakers>,
+}
+
+impl<S: Stream, K> std::fmt::Debug for Partitioned<S, K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Partitioned")
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: Stream, K> Partitioned<S, K> {
+    /// The index of this partition
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl<S: Stream, K> Drop for Partitioned<S, K> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        shared.dropped[self.index] = true;
+        shared.queues[self.index].clear();
+        if let Some((stalled, _)) = &shared.stalled
+            && *stalled == self.index
+        {
+            shared.stalled = None;
+            self.wakers.wake_by_ref();
+        }
+    }
+}
+
+impl<S, K> Stream for Partitioned<S, K>
+where
+    S: Stream,
+    K: Fn(&S::Item) -> usize,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let index = self.index;
+        let mut shared = self.shared.lock();
+        let shared = &mut *shared;
+        if let Some(item) = shared.queues[index].pop_front() {
+            // make room for the stalled one
+            if let Some((stalled, _)) = &shared.stalled
+                && *stalled == index
+            {
+                let (_, stalled_item) = shared.stalled.take().unwrap();
+                shared.queues[index].push_back(stalled_item);
+                self.wakers.wake_by_ref();
+            }
+            return Poll::Ready(Some(item));
+        }
+        if let Some((stalled, _)) = &shared.stalled {
+            if *stalled != index {
+                // wait for the full partition to be consumed
+                self.wakers.register(index, cx);
+                return Poll::Pending;
+            }
+            let (_, item) = shared.stalled.take().unwrap();
+            self.wakers.wake_by_ref();
+            return Poll::Ready(Some(item));
+        }
+        if shared.done {
+            return Poll::Ready(None);
+        }
+        let n = shared.queues.len();
+        // register before polling, the source may wake up immediately
+        self.wakers.register(index, cx);
+        let mut source_cx = Context::from_waker(&shared.source_waker);
+        loop {
+            let Poll::Ready(next) = shared.source.as_mut().poll_next(&mut source_cx) else {
+                return Poll::Pending;
+            };
+            let Some(item) = next else {
+                shared.done = true;
+                self.wakers.wake_by_ref();
+                return Poll::Ready(None);
+            };
+            let target = (shared.key)(&item) % n;
+            if target == index {
+                return Poll::Ready(Some(item));
+            }
+            if shared.dropped[target] {
+                continue;
+            }
+            if shared.queues[target].len() >= shared.capacity {
+                shared.stalled = Some((target, item));
+                self.wakers.wake_one(target);
+                return Poll::Pending;
+            }
+            shared.queues[target].push_back(item);
+            self.wakers.wake_one(target);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+
+    use super::PartitionStreamExt;
+
+    #[tokio::test]
+    async fn test_partitioned_works() {
+        let mut parts = futures::stream::iter(0..10_usize).partitioned(3, 16, |item| *item);
+        assert_eq!(parts[2].index(), 2);
+        assert_eq!(parts[1].next().await, Some(1));
+        // received on behalf of the other partitions
+        assert_eq!(parts[0].next().await, Some(0));
+        assert_eq!(parts[2].next().await, Some(2));
+
+        let mut third = parts.pop().unwrap();
+        let mut second = parts.pop().unwrap();
+        let mut first = parts.pop().unwrap();
+        assert_eq!(third.by_ref().collect::<Vec<_>>().await, vec![5, 8]);
+        assert_eq!(first.by_ref().collect::<Vec<_>>().await, vec![3, 6, 9]);
+        assert_eq!(second.by_ref().collect::<Vec<_>>().await, vec![4, 7]);
+        assert_eq!(first.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_partitioned_backpressure() {
+        let mut parts =
+            futures::stream::iter([0_usize, 0, 0, 0, 1]).partitioned(2, 2, |item| *item);
+        // the first partition is full after buffering two items, and the third item is stalled
+        let waker = futures::task::noop_waker_ref();
+        let mut cx = std::task::Context::from_waker(waker);
+        assert!(parts[1].poll_next_unpin(&mut cx).is_pending());
+        assert_eq!(parts[0].next().await, Some(0));
+        assert!(parts[1].poll_next_unpin(&mut cx).is_pending());
+        assert_eq!(parts[0].next().await, Some(0));
+        assert_eq!(parts[0].next().await, Some(0));
+        assert_eq!(parts[1].next().await, Some(1));
+        assert_eq!(parts[0].next().await, Some(0));
+        assert_eq!(parts[0].next().await, None);
+        assert_eq!(parts[1].next().await, None);
+    }
+}
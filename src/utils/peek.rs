@@ -0,0 +1,122 @@
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures::Stream;
+use pin_project_lite::pin_project;
+
+/// Peek extension for streams
+pub trait PeekStreamExt: Stream + Sized {
+    /// Hold the next item of the stream so that it could be inspected by
+    /// [`Peeking::poll_peek`] before it is consumed, e.g. to choose the handler of a message by its
+    /// header. At most one item is held, the stream is not polled again until the held item is
+    /// consumed, so the ordering and the backpressure of the stream are preserved.
+    fn peeking(self) -> Peeking<Self> {
+        Peeking {
+            stream: self,
+            held: None,
+        }
+    }
+}
+
+impl<S: Stream> PeekStreamExt for S {}
+
+pin_project! {
+    /// A stream returned by [`PeekStreamExt::peeking`]
+    #[derive(Debug)]
+    pub struct Peeking<S: Stream> {
+        #[pin]
+        stream: S,
+        // the item peeked but not consumed yet
+        held: Option<S::Item>,
+    }
+}
+
+impl<S: Stream> Peeking<S> {
+    /// Get the inner stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Get the inner stream mutably, polling it directly skips the held item
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consume the wrapper, returns the inner stream and the held item if any
+    pub fn into_inner(self) -> (S, Option<S::Item>) {
+        (self.stream, self.held)
+    }
+
+    /// Poll the next item without consuming it, it is returned again by the next `poll_peek` or
+    /// `poll_next`. Returns `Poll::Ready(None)` if the stream is ended.
+    pub fn poll_peek(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<&S::Item>> {
+        let mut this = self.project();
+        if this.held.is_none() {
+            *this.held = ready!(this.stream.as_mut().poll_next(cx));
+        }
+        Poll::Ready(this.held.as_ref())
+    }
+}
+
+impl<S: Stream> Stream for Peeking<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if let Some(item) = this.held.take() {
+            return Poll::Ready(Some(item));
+        }
+        this.stream.poll_next(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let held = usize::from(self.held.is_some());
+        let (lower, upper) = self.stream.size_hint();
+        (
+            lower.saturating_add(held),
+            upper.and_then(|len| len.checked_add(held)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::pin::{pin, Pin};
+
+    use futures::{FutureExt, Stream, StreamExt};
+
+    use super::{PeekStreamExt, Peeking};
+
+    fn peek<'a, S: Stream<Item = u32> + 'a>(
+        mut peeking: Pin<&'a mut Peeking<S>>,
+    ) -> impl Future<Output = Option<u32>> + 'a {
+        futures::future::poll_fn(move |cx| {
+            peeking.as_mut().poll_peek(cx).map(Option::<&u32>::copied)
+        })
+    }
+
+    #[tokio::test]
+    async fn test_peeking_works() {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let mut peeking = pin!(rx.peeking());
+        // nothing to peek, it is pending instead of spinning
+        assert!(peek(peeking.as_mut()).now_or_never().is_none());
+
+        tx.unbounded_send(1).unwrap();
+        tx.unbounded_send(2).unwrap();
+        assert_eq!(peek(peeking.as_mut()).await, Some(1));
+        // the held item is peeked again and the next one is left in the stream
+        assert_eq!(peek(peeking.as_mut()).await, Some(1));
+        assert_eq!(peeking.next().await, Some(1));
+        assert_eq!(peeking.next().await, Some(2));
+
+        drop(tx);
+        assert_eq!(peek(peeking.as_mut()).await, None);
+        assert_eq!(peeking.next().await, None);
+
+        let mut iter = pin!(futures::stream::iter([1, 2]).peeking());
+        assert_eq!(peek(iter.as_mut()).await, Some(1));
+        assert_eq!(iter.size_hint(), (2, Some(2)));
+    }
+}
@@ -1,28 +1,53 @@
 use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::OnceLock;
 use std::task::Waker;
 use std::time::{Duration, Instant};
 use std::{mem, panic, thread};
 
+use crate::ConnId;
+
 /// Timers are in the order in which they fire. The `usize` in this type is a timer ID used to
 /// distinguish timers that fire at the same time. The `Waker` represents the task awaiting
 /// the timer.
 type Timers = BTreeMap<(Instant, usize), Waker>;
 
-/// A distinct identifier for a connection.
+/// The category of timers, so that the timers of a connection could be cancelled by their kind
+/// without disturbing others.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub(crate) struct ConnId(u64, u64);
+pub(crate) enum TimerKind {
+    /// Waiting for the frames to be acknowledged or resent, cancelled when receiving acks
+    Retransmit,
+    /// The deadline of closing
+    CloseTimeout,
+    /// The interval of the background flusher
+    Flush,
+    /// The deadline of the server shutdown
+    Shutdown,
+    /// The deadline of an open connection request of the client handshake
+    Handshake,
+    /// The deadline of flushing all connections of the server
+    FlushAll,
+    /// The deadline of receiving on a connection
+    Recv,
+    /// The time to send the next frame set by the pacing rate
+    Pacing,
+}
 
-impl ConnId {
-    pub(crate) fn new(from_guid: u64, to_guid: u64) -> Self {
-        ConnId(from_guid, to_guid)
-    }
+/// The handle of a registered timer, used to reset or cancel the timer individually
+#[derive(Debug)]
+pub(crate) struct TimerHandle {
+    key: (ConnId, TimerKind),
+    when: Instant,
+    id: usize,
 }
 
 /// A reactor that manages timers.
 pub(crate) struct Reactor {
-    /// Map of registered timers, distinguished by their connection id.
-    conn_timers: parking_lot::Mutex<HashMap<ConnId, Timers>>,
+    /// Map of registered timers, distinguished by their connection id and kind.
+    conn_timers: parking_lot::Mutex<HashMap<(ConnId, TimerKind), Timers>>,
+    /// The next timer ID.
+    next_id: AtomicUsize,
     /// A condvar used to wake up the reactor when timers changed.
     cond: parking_lot::Condvar,
 }
@@ -47,24 +72,64 @@ impl Reactor {
 
             Reactor {
                 conn_timers: parking_lot::Mutex::new(HashMap::new()),
+                next_id: AtomicUsize::new(0),
                 cond: parking_lot::Condvar::new(),
             }
         })
     }
 
-    /// Insert a timer with the given `guid` and `when` to fire.
-    pub(crate) fn insert_timer(&self, c_id: ConnId, when: Instant, waker: &Waker) {
-        let mut timers = self.conn_timers.lock();
-        let timers = timers.entry(c_id).or_default();
-        timers.insert((when, timers.len()), waker.clone());
+    /// Insert a timer of the connection `c_id` to fire at `when`.
+    pub(crate) fn insert_timer(
+        &self,
+        c_id: ConnId,
+        kind: TimerKind,
+        when: Instant,
+        waker: &Waker,
+    ) -> TimerHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let key = (c_id, kind);
+        self.conn_timers
+            .lock()
+            .entry(key)
+            .or_default()
+            .insert((when, id), waker.clone());
+        self.cond.notify_one();
+        TimerHandle { key, when, id }
+    }
+
+    /// Reset the timer to fire at `when` with the `waker`, it is registered again if it has been
+    /// fired or cancelled.
+    pub(crate) fn reset_timer(&self, handle: &mut TimerHandle, when: Instant, waker: &Waker) {
+        let mut conn_timers = self.conn_timers.lock();
+        let timers = conn_timers.entry(handle.key).or_default();
+        timers.remove(&(handle.when, handle.id));
+        timers.insert((when, handle.id), waker.clone());
+        handle.when = when;
         self.cond.notify_one();
     }
 
-    /// Cancel all timers with the given `guid`.
-    pub(crate) fn cancel_all_timers(&self, c_id: ConnId) -> impl Iterator<Item = Waker> {
+    /// Cancel the timer, returns false if it has been fired or cancelled.
+    pub(crate) fn cancel_timer(&self, handle: TimerHandle) -> bool {
+        let mut conn_timers = self.conn_timers.lock();
+        let Some(timers) = conn_timers.get_mut(&handle.key) else {
+            return false;
+        };
+        let cancelled = timers.remove(&(handle.when, handle.id)).is_some();
+        if timers.is_empty() {
+            conn_timers.remove(&handle.key);
+        }
+        cancelled
+    }
+
+    /// Cancel all timers of the given `kind` of the connection `c_id`.
+    pub(crate) fn cancel_timers(
+        &self,
+        c_id: ConnId,
+        kind: TimerKind,
+    ) -> impl Iterator<Item = Waker> {
         let mut timers = self.conn_timers.lock();
         let res = timers
-            .remove(&c_id)
+            .remove(&(c_id, kind))
             .into_iter()
             .flat_map(BTreeMap::into_values);
         self.cond.notify_one();
@@ -99,6 +164,8 @@ impl Reactor {
                 _ => {}
             }
         }
+        // forget the connections without any timer left, or the map grows with every one of them
+        region_timers.retain(|_, timers| !timers.is_empty());
 
         if let Some(dur) = dur {
             self.cond.wait_for(&mut region_timers, dur);
@@ -112,6 +179,7 @@ impl Reactor {
 mod test {
     use super::*;
     use crate::utils::tests::TestWaker;
+    use crate::Peer;
 
     #[test]
     fn test_it_works() {
@@ -121,17 +189,92 @@ mod test {
         let when = Instant::now() + dur;
         {
             let (waker, test) = TestWaker::pair();
-            reactor.insert_timer(ConnId(1, 1), when, &waker);
-            assert_eq!(reactor.cancel_all_timers(ConnId(1, 1)).count(), 1);
+            reactor.insert_timer(
+                ConnId::new(1, 1, Peer::test().addr),
+                TimerKind::Retransmit,
+                when,
+                &waker,
+            );
+            assert_eq!(
+                reactor
+                    .cancel_timers(ConnId::new(1, 1, Peer::test().addr), TimerKind::Retransmit)
+                    .count(),
+                1
+            );
             assert!(!test.woken.load(std::sync::atomic::Ordering::Relaxed));
         }
 
         {
             let (waker, test) = TestWaker::pair();
-            reactor.insert_timer(ConnId(2, 2), when, &waker);
+            reactor.insert_timer(
+                ConnId::new(2, 2, Peer::test().addr),
+                TimerKind::Retransmit,
+                when,
+                &waker,
+            );
             std::thread::sleep(dur + Duration::from_millis(10));
-            assert_eq!(reactor.cancel_all_timers(ConnId(2, 2)).count(), 0);
+            assert_eq!(
+                reactor
+                    .cancel_timers(ConnId::new(2, 2, Peer::test().addr), TimerKind::Retransmit)
+                    .count(),
+                0
+            );
             assert!(test.woken.load(std::sync::atomic::Ordering::Relaxed));
         }
     }
+
+    #[test]
+    fn test_fired_timers_removed() {
+        let reactor = Reactor::get();
+        let c_id = ConnId::new(4, 4, Peer::test().addr);
+        let dur = Duration::from_millis(50);
+
+        let (waker, test) = TestWaker::pair();
+        reactor.insert_timer(c_id, TimerKind::Flush, Instant::now() + dur, &waker);
+        assert!(reactor
+            .conn_timers
+            .lock()
+            .contains_key(&(c_id, TimerKind::Flush)));
+        std::thread::sleep(dur + Duration::from_millis(50));
+        assert!(test.woken.load(std::sync::atomic::Ordering::Relaxed));
+        assert!(!reactor
+            .conn_timers
+            .lock()
+            .contains_key(&(c_id, TimerKind::Flush)));
+    }
+
+    #[test]
+    fn test_timer_kind_and_handle() {
+        let reactor = Reactor::get();
+        let c_id = ConnId::new(3, 3, Peer::test().addr);
+        let when = Instant::now() + Duration::from_secs(60);
+
+        let (retransmit_waker, _) = TestWaker::pair();
+        let (flush_waker, flush) = TestWaker::pair();
+        reactor.insert_timer(c_id, TimerKind::Retransmit, when, &retransmit_waker);
+        let mut handle = reactor.insert_timer(c_id, TimerKind::Flush, when, &flush_waker);
+        // only the timers of the kind are cancelled
+        assert_eq!(
+            reactor.cancel_timers(c_id, TimerKind::Retransmit).count(),
+            1
+        );
+        assert_eq!(
+            reactor.cancel_timers(c_id, TimerKind::Retransmit).count(),
+            0
+        );
+
+        // reset to fire soon
+        let dur = Duration::from_millis(100);
+        reactor.reset_timer(&mut handle, Instant::now() + dur, &flush_waker);
+        std::thread::sleep(dur + Duration::from_millis(10));
+        assert!(flush.woken.load(std::sync::atomic::Ordering::Relaxed));
+        assert!(!reactor.cancel_timer(handle));
+
+        let close_handle = reactor.insert_timer(c_id, TimerKind::CloseTimeout, when, &flush_waker);
+        assert!(reactor.cancel_timer(close_handle));
+        assert_eq!(
+            reactor.cancel_timers(c_id, TimerKind::CloseTimeout).count(),
+            0
+        );
+    }
 }
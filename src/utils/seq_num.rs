@@ -1,11 +1,11 @@
-use std::{fmt, ops};
+use core::{fmt, ops};
 
 use bytes::{Buf, BufMut};
 
 /// Unsigned 24bits integer (actually occupied 32 bits) with litter endian and wrapping checking
 #[allow(non_camel_case_types)]
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Default)]
-pub(crate) struct u24(u32);
+pub struct u24(u32);
 
 impl fmt::Debug for u24 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -44,15 +44,29 @@ impl fmt::UpperHex for u24 {
 }
 
 impl u24 {
-    pub(crate) fn to_u32(self) -> u32 {
+    pub fn to_u32(self) -> u32 {
         self.0
     }
 
     pub(crate) fn to_usize(self) -> usize {
         self.0 as usize
     }
+
+    /// Add `rhs` wrapping around at the maximum of u24, the sequence numbers of frame sets wrap
+    /// from 0xFFFFFF to 0
+    pub(crate) fn wrapping_add(self, rhs: u32) -> u24 {
+        u24(self.0.wrapping_add(rhs) & U24_MASK)
+    }
+
+    /// The number of steps from `earlier` to `self` in the wrapping space of u24. It is less than
+    /// 2^23 if `self` is not before `earlier` (serial number arithmetic).
+    pub(crate) fn distance_from(self, earlier: u24) -> u32 {
+        self.0.wrapping_sub(earlier.0) & U24_MASK
+    }
 }
 
+const U24_MASK: u32 = 0xff_ffff;
+
 macro_rules! for_all_primitives {
     ($macro:ident) => {
         $macro! { u8, u16, u32, u64, usize, i8, i16, i32, i64, isize }
@@ -329,6 +343,16 @@ mod test {
         let _b1 = a1 - 1;
     }
 
+    #[test]
+    fn test_u24_wrapping() {
+        let max: u24 = 0xff_ffff.into();
+        assert_eq!(max.wrapping_add(1).to_u32(), 0);
+        assert_eq!(max.wrapping_add(3).to_u32(), 2);
+        assert_eq!(u24::from(2).distance_from(max), 3);
+        assert_eq!(max.distance_from(2.into()), 0xff_fffd);
+        assert_eq!(max.distance_from(max), 0);
+    }
+
     #[test]
     fn test_u24_works() {
         let a1: u24 = 1.into();
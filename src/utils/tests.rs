@@ -1,11 +1,19 @@
 use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
-use std::task::Waker;
+use std::task::{Context, Poll, Waker};
 
+use bytes::{Bytes, BytesMut};
 use fastrace::collector::{SpanId, SpanRecord, TraceId};
+use futures::Sink;
 use parking_lot::Mutex;
 
+use crate::packet::connected::FramesRef;
+use crate::packet::{Packet, MAGIC};
+
 pub(crate) struct TestTraceLogGuard {
     spans: Arc<Mutex<Vec<SpanRecord>>>,
 }
@@ -106,6 +114,15 @@ pub(crate) fn test_trace_log_setup() -> TestTraceLogGuard {
     TestTraceLogGuard { spans }
 }
 
+/// Whether the IPv6 loopback could be bound, the IPv6 tests are skipped on the hosts without it
+pub(crate) fn ipv6_available() -> bool {
+    let available = std::net::UdpSocket::bind("[::1]:0").is_ok();
+    if !available {
+        log::warn!("IPv6 loopback is unavailable, skip the IPv6 cases");
+    }
+    available
+}
+
 pub(crate) struct TestWaker {
     pub(crate) woken: AtomicBool,
 }
@@ -135,3 +152,56 @@ impl TestWaker {
         (Waker::from(Arc::clone(&arc)), arc)
     }
 }
+
+/// The transport capturing the exact bytes of every datagram sent by the outgoing guard, so that
+/// they could be compared with the golden dumps of the reference `RakNet`. The clones share the
+/// captured datagrams.
+#[derive(Clone, Default)]
+pub(crate) struct WireCapture {
+    datagrams: Arc<Mutex<Vec<Bytes>>>,
+}
+
+impl WireCapture {
+    /// Take the datagrams captured so far
+    pub(crate) fn take(&self) -> Vec<Bytes> {
+        std::mem::take(&mut *self.datagrams.lock())
+    }
+}
+
+impl<'a> Sink<(Packet<FramesRef<'a>>, SocketAddr)> for WireCapture {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        (pack, _): (Packet<FramesRef<'a>>, SocketAddr),
+    ) -> Result<(), Self::Error> {
+        let mut buf = BytesMut::new();
+        pack.write(&mut buf, &MAGIC, false);
+        self.datagrams.lock().push(buf.freeze());
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Assert the datagram matches the golden hex dump, the whitespaces of the dump are ignored
+#[track_caller]
+pub(crate) fn assert_golden(datagram: &[u8], golden: &str) {
+    let actual = datagram
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let expected = golden.split_whitespace().collect::<Vec<_>>().join(" ");
+    assert_eq!(actual, expected);
+}
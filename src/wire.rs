@@ -0,0 +1,90 @@
+//! The packet codec of the connected packets, it only depends on `bytes` and `alloc`, so that it is
+//! available without `std` (`default-features = false`) for the embedded parsers. The fields of
+//! the decoded packets are public, while the ones used by the runtime only are kept private.
+
+use bytes::{Buf, BytesMut};
+
+pub use crate::errors::CodecError;
+use crate::packet::connected::DEFAULT_MAX_ACK_COUNT;
+pub use crate::packet::connected::{
+    AckOrNack, Flags, Fragment, Frame, FrameSet, Frames, FramesMut, FramesRef, Ordered, Packet,
+    Record,
+};
+use crate::packet::{read_buf, PackType};
+pub use crate::utils::u24;
+pub use crate::{Priority, Reliability};
+
+/// Decode a connected packet (a frame set, an ACK or a NACK) from a datagram, the frame bodies are
+/// left as they are. An ACK or a NACK may acknowledge at most 8192 packets. The trailing CRC-32 of
+/// the frame set is validated and stripped if `checksum` is enabled.
+///
+/// # Errors
+/// Returns [`CodecError`] if the datagram is not a valid connected packet.
+pub fn decode(buf: &mut BytesMut, checksum: bool) -> Result<Packet<FramesMut>, CodecError> {
+    let pack_type = read_buf!(buf, 1, PackType::from_u8(buf.get_u8())?);
+    if pack_type.is_frame_set() {
+        Packet::read_frame_set(buf, checksum)
+    } else if pack_type.is_ack() {
+        Packet::read_ack(buf, DEFAULT_MAX_ACK_COUNT)
+    } else if pack_type.is_nack() {
+        Packet::read_nack(buf, DEFAULT_MAX_ACK_COUNT)
+    } else {
+        Err(CodecError::InvalidPacketType(pack_type as u8))
+    }
+}
+
+/// Encode a connected packet into the buffer, the CRC-32 is appended to the frame set if
+/// `checksum` is enabled.
+pub fn encode<B: Buf + Clone>(
+    packet: Packet<FramesRef<'_, B>>,
+    buf: &mut BytesMut,
+    checksum: bool,
+) {
+    packet.write(buf, checksum);
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{Bytes, BytesMut};
+
+    use super::{decode, encode, AckOrNack, Flags, Frame, FrameSet, Packet, Record};
+    use crate::Reliability;
+
+    #[test]
+    fn test_wire_round_trip() {
+        let mut frame = Frame::new(
+            Flags::new(Reliability::Reliable, false),
+            Bytes::from_static(b"\xfehello"),
+        );
+        frame.reliable_frame_index = Some(7.into());
+        let frames = [frame];
+        let mut buf = BytesMut::new();
+        encode(
+            Packet::FrameSet(FrameSet {
+                seq_num: 3.into(),
+                set: &frames[..],
+            }),
+            &mut buf,
+            true,
+        );
+        let Packet::FrameSet(frame_set) = decode(&mut buf, true).unwrap() else {
+            panic!("expect a frame set");
+        };
+        assert_eq!(frame_set.seq_num.to_u32(), 3);
+        assert_eq!(frame_set.set.len(), 1);
+        assert_eq!(frame_set.set[0].flags.reliability(), Reliability::Reliable);
+        assert_eq!(frame_set.set[0].reliable_frame_index, Some(7.into()));
+        assert_eq!(frame_set.set[0].body.as_ref(), b"\xfehello");
+
+        let ack = AckOrNack::extend_from([1, 2, 3, 5].into_iter().map(Into::into), 100).unwrap();
+        encode(Packet::<&[Frame]>::Nack(ack), &mut buf, false);
+        let Packet::Nack(nack) = decode(&mut buf, false).unwrap() else {
+            panic!("expect a nack");
+        };
+        assert_eq!(
+            nack.records,
+            vec![Record::Range(1.into(), 3.into()), Record::Single(5.into())]
+        );
+        assert!(decode(&mut BytesMut::from(&b"\x01"[..]), false).is_err());
+    }
+}